@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use zark_waf_messenger::infrastructure::serialization::json::JsonSerializer;
+use zark_waf_messenger::infrastructure::serialization::Serializer;
+
+fuzz_target!(|data: &[u8]| {
+    let owned = JsonSerializer.deserialize(data);
+    let view = JsonSerializer.deserialize_view(data);
+    assert_eq!(owned.is_ok(), view.is_ok());
+
+    if let Ok(message) = owned {
+        let encoded = JsonSerializer.serialize(&message).unwrap();
+        assert_eq!(JsonSerializer.deserialize(&encoded).unwrap(), message);
+    }
+    let _ = JsonSerializer.deserialize_value(data);
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use zark_waf_messenger::domain::message::Message;
+use zark_waf_messenger::domain::serializable::{self, Serializable};
+
+fuzz_target!(|data: &[u8]| {
+    let owned = <Message as Serializable>::deserialize(data);
+    let view = serializable::deserialize_view(data);
+    assert_eq!(owned.is_ok(), view.is_ok());
+
+    if let Ok(view) = view {
+        // a decoded message must survive a round trip unchanged
+        let message = view.to_owned();
+        let encoded = Serializable::serialize(&message).unwrap();
+        assert_eq!(<Message as Serializable>::deserialize(&encoded).unwrap(), message);
+    }
+});
@@ -0,0 +1,27 @@
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use tokio::runtime::Runtime;
+use zark_waf_messenger::infrastructure::serialization::json::JsonSerializer;
+use zark_waf_messenger::infrastructure::serialization::Serializer;
+use zark_waf_messenger::infrastructure::transport::tcp::read_frame;
+
+// frames above this size must be rejected before their body is allocated
+const MAX_FRAME: usize = 64 * 1024;
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| tokio::runtime::Builder::new_current_thread().build().unwrap())
+}
+
+fuzz_target!(|data: &[u8]| {
+    runtime().block_on(async {
+        let mut stream = data;
+        while let Ok(frame) = read_frame(&mut stream, MAX_FRAME).await {
+            assert!(frame.len() <= MAX_FRAME);
+            let _ = JsonSerializer.deserialize(&frame);
+        }
+    });
+});
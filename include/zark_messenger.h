@@ -48,6 +48,13 @@ typedef enum ZarkMessengerError {
     ZARK_ERROR_NO_MESSAGES = -10
 } ZarkMessengerError;
 
+// A message to send, topic and payload are copied before the call returns
+typedef struct ZarkMessage {
+    const char* topic;
+    const uint8_t* payload;
+    size_t payload_length;
+} ZarkMessage;
+
 // Configuration for IPC transport
 typedef struct ZarkIpcConfig {
     const char* shared_memory_name;
@@ -56,24 +63,101 @@ typedef struct ZarkIpcConfig {
     size_t max_buffer_size;
 } ZarkIpcConfig;
 
+// What a sender does about a subscription that stopped keeping up
+typedef enum ZarkSlowConsumerPolicy {
+    ZARK_SLOW_CONSUMER_DISCONNECT,
+    ZARK_SLOW_CONSUMER_DEGRADE
+} ZarkSlowConsumerPolicy;
+
+// Credit-based flow control over TCP
+typedef struct ZarkFlowControlConfig {
+    uint32_t credits;
+    size_t buffer_size;
+    ZarkSlowConsumerPolicy slow_consumer;
+} ZarkFlowControlConfig;
+
 // Configuration for TCP transport
 typedef struct ZarkTcpConfig {
     const char* host;
     uint16_t port;
     size_t max_message_size;
+    // NULL turns flow control off
+    const ZarkFlowControlConfig* flow_control;
 } ZarkTcpConfig;
 
+// When the durable log calls fsync on its segment files
+typedef enum ZarkFsyncPolicy {
+    ZARK_FSYNC_ALWAYS,
+    ZARK_FSYNC_INTERVAL,
+    ZARK_FSYNC_NEVER
+} ZarkFsyncPolicy;
+
+// Durable topic log, zero sizes and ages mean the default or no limit
+typedef struct ZarkLogConfig {
+    const char* directory;
+    // topic patterns to log, every non-system topic is logged when there are none
+    const char* const* topics;
+    size_t topic_count;
+    uint64_t segment_bytes;
+    uint64_t retention_bytes;
+    uint64_t retention_secs;
+    ZarkFsyncPolicy fsync;
+    // only used with ZARK_FSYNC_INTERVAL
+    uint64_t fsync_interval_ms;
+    const char* const* compact_topics;
+    size_t compact_topic_count;
+    uint64_t tombstone_retention_secs;
+} ZarkLogConfig;
+
+// What a transport does with a message when its queue has no room for it
+typedef enum ZarkBackpressurePolicy {
+    ZARK_BACKPRESSURE_BLOCK,
+    // value is the deadline in milliseconds
+    ZARK_BACKPRESSURE_BLOCK_MS,
+    ZARK_BACKPRESSURE_DROP_NEWEST,
+    ZARK_BACKPRESSURE_DROP_OLDEST,
+    // value is n, one in every n messages is kept
+    ZARK_BACKPRESSURE_SAMPLE,
+    ZARK_BACKPRESSURE_ERROR
+} ZarkBackpressurePolicy;
+
+// Backpressure policy of the topics matching a pattern
+typedef struct ZarkBackpressure {
+    const char* pattern;
+    ZarkBackpressurePolicy policy;
+    uint64_t value;
+} ZarkBackpressure;
+
+// Reassembly of chunked messages, zero means the default
+typedef struct ZarkChunkingConfig {
+    uint64_t timeout_ms;
+    size_t max_pending_bytes;
+} ZarkChunkingConfig;
+
+// Holding back incomplete transactions, zero means the default
+typedef struct ZarkTransactionConfig {
+    uint64_t timeout_ms;
+    size_t max_pending_bytes;
+} ZarkTransactionConfig;
+
 // Transport type enum
 typedef enum ZarkTransportType {
     ZARK_TRANSPORT_IPC,
     ZARK_TRANSPORT_TCP
 } ZarkTransportType;
 
-// Main configuration structure
+// Main configuration structure, optional parts are NULL when unused
 typedef struct ZarkConfig {
     ZarkTransportType transport_type;
     ZarkIpcConfig* ipc_config;
     ZarkTcpConfig* tcp_config;
+    // NULL keeps messages in memory only
+    ZarkLogConfig* log_config;
+    // topics without a policy block until there is room
+    ZarkBackpressure* backpressure;
+    size_t backpressure_count;
+    ZarkChunkingConfig* chunking;
+    ZarkTransactionConfig* transactions;
 } ZarkConfig;
 
 // Opaque pointer to messenger instance
@@ -83,7 +167,7 @@ typedef void* ZarkMessenger;
 ZarkMessenger* zark_messenger_init(const ZarkConfig* config);
 
 // Send a message
-bool zark_messenger_send(ZarkMessenger* messenger, const struct Message* message);
+bool zark_messenger_send(ZarkMessenger* messenger, const ZarkMessage* message);
 
 // Receive a message
 int32_t zark_messenger_receive(
@@ -94,6 +178,24 @@ int32_t zark_messenger_receive(
     size_t buffer_len
 );
 
+// Send `count` messages at once
+bool zark_messenger_send_batch(ZarkMessenger* messenger, const ZarkMessage* messages, size_t count);
+
+// Receive up to `max` messages of a topic, waiting up to `timeout_ms` for the first one.
+// Payloads are copied back to back into `buffer` and the length of each is stored in
+// `lengths`, which must have room for `max` entries; a payload that does not fit in the
+// rest of the buffer is truncated. The first call for a topic subscribes to it, later calls
+// take what arrived in between. Returns the number of messages received, or -1 on error.
+int32_t zark_messenger_receive_batch(
+    ZarkMessenger* messenger,
+    const char* topic,
+    size_t max,
+    uint64_t timeout_ms,
+    char* buffer,
+    size_t buffer_len,
+    size_t* lengths
+);
+
 // Cleanup messenger
 void zark_messenger_cleanup(ZarkMessenger* messenger);
 
@@ -0,0 +1,67 @@
+using System;
+using System.Runtime.InteropServices;
+
+namespace ZarkWaf.Messenger
+{
+    public enum TransportType
+    {
+        Ipc,
+        Tcp
+    }
+
+    public class IpcConfig
+    {
+        public string SharedMemoryName { get; set; } = "zark";
+        public ulong MaxMessageSize { get; set; } = 4096;
+        public ulong MaxQueueSize { get; set; } = 1024;
+        public ulong MaxBufferSize { get; set; } = 4096;
+    }
+
+    public class TcpConfig
+    {
+        public string Host { get; set; } = "127.0.0.1";
+        public ushort Port { get; set; }
+        public ulong MaxMessageSize { get; set; } = 4096;
+    }
+
+    public class Config
+    {
+        public TransportType TransportType { get; set; }
+        public IpcConfig Ipc { get; set; }
+        public TcpConfig Tcp { get; set; }
+    }
+
+    // Mirrors ZarkIpcConfig in zark_messenger.h
+    [StructLayout(LayoutKind.Sequential)]
+    internal struct ZarkIpcConfig
+    {
+        public IntPtr SharedMemoryName;
+        public UIntPtr MaxMessageSize;
+        public UIntPtr MaxQueueSize;
+        public UIntPtr MaxBufferSize;
+    }
+
+    // Mirrors ZarkTcpConfig in zark_messenger.h, a null FlowControl turns flow control off
+    [StructLayout(LayoutKind.Sequential)]
+    internal struct ZarkTcpConfig
+    {
+        public IntPtr Host;
+        public ushort Port;
+        public UIntPtr MaxMessageSize;
+        public IntPtr FlowControl;
+    }
+
+    // Mirrors ZarkConfig in zark_messenger.h, optional parts are null when unused
+    [StructLayout(LayoutKind.Sequential)]
+    internal struct ZarkConfig
+    {
+        public int TransportType;
+        public IntPtr IpcConfig;
+        public IntPtr TcpConfig;
+        public IntPtr LogConfig;
+        public IntPtr Backpressure;
+        public UIntPtr BackpressureCount;
+        public IntPtr Chunking;
+        public IntPtr Transactions;
+    }
+}
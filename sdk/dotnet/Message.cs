@@ -1,14 +1,14 @@
+using System;
 using System.Runtime.InteropServices;
 
 namespace ZarkWaf.Messenger
 {
+    // Mirrors ZarkMessage in zark_messenger.h, topic and payload are copied before the call returns
     [StructLayout(LayoutKind.Sequential)]
-    internal struct Message
+    internal struct ZarkMessage
     {
-        [MarshalAs(UnmanagedType.LPStr)]
-        public string Topic;
-        [MarshalAs(UnmanagedType.LPArray, SizeParamIndex = 2)]
-        public byte[] Payload;
+        public IntPtr Topic;
+        public IntPtr Payload;
         public UIntPtr PayloadLength;
     }
-} 
\ No newline at end of file
+}
@@ -1,5 +1,7 @@
 using System;
+using System.Collections.Generic;
 using System.Runtime.InteropServices;
+using System.Text;
 
 namespace ZarkWaf.Messenger
 {
@@ -9,20 +11,36 @@ namespace ZarkWaf.Messenger
         private bool _disposed;
 
         #region Native Imports
-        
+
         [DllImport("zark_waf_messenger", CallingConvention = CallingConvention.Cdecl)]
         private static extern IntPtr zark_messenger_init(ref ZarkConfig config);
 
         [DllImport("zark_waf_messenger", CallingConvention = CallingConvention.Cdecl)]
-        private static extern bool zark_messenger_send(IntPtr messenger, ref Message message);
+        [return: MarshalAs(UnmanagedType.I1)]
+        private static extern bool zark_messenger_send(IntPtr messenger, ref ZarkMessage message);
 
         [DllImport("zark_waf_messenger", CallingConvention = CallingConvention.Cdecl)]
-        private static extern int zark_messenger_receive(IntPtr messenger, 
-            [MarshalAs(UnmanagedType.LPStr)] StringBuilder topic, 
+        private static extern int zark_messenger_receive(IntPtr messenger,
+            [Out] byte[] topic,
             UIntPtr topicLen,
-            [MarshalAs(UnmanagedType.LPArray)] byte[] buffer,
+            [Out] byte[] buffer,
             UIntPtr bufferLen);
 
+        [DllImport("zark_waf_messenger", CallingConvention = CallingConvention.Cdecl)]
+        [return: MarshalAs(UnmanagedType.I1)]
+        private static extern bool zark_messenger_send_batch(IntPtr messenger,
+            [In] ZarkMessage[] messages,
+            UIntPtr count);
+
+        [DllImport("zark_waf_messenger", CallingConvention = CallingConvention.Cdecl)]
+        private static extern int zark_messenger_receive_batch(IntPtr messenger,
+            [MarshalAs(UnmanagedType.LPUTF8Str)] string topic,
+            UIntPtr max,
+            ulong timeoutMs,
+            [Out] byte[] buffer,
+            UIntPtr bufferLen,
+            [Out] UIntPtr[] lengths);
+
         [DllImport("zark_waf_messenger", CallingConvention = CallingConvention.Cdecl)]
         private static extern void zark_messenger_cleanup(IntPtr messenger);
 
@@ -31,9 +49,33 @@ namespace ZarkWaf.Messenger
 
         #endregion
 
-        public ZarkMessenger(ZarkConfig config)
+        public ZarkMessenger(Config config)
         {
-            _messenger = zark_messenger_init(ref config);
+            // The native side copies the configuration, so it is freed right after init
+            using (var native = new NativeMemory())
+            {
+                var nativeConfig = new ZarkConfig { TransportType = (int)config.TransportType };
+                if (config.Ipc != null)
+                {
+                    nativeConfig.IpcConfig = native.Struct(new ZarkIpcConfig
+                    {
+                        SharedMemoryName = native.String(config.Ipc.SharedMemoryName),
+                        MaxMessageSize = (UIntPtr)config.Ipc.MaxMessageSize,
+                        MaxQueueSize = (UIntPtr)config.Ipc.MaxQueueSize,
+                        MaxBufferSize = (UIntPtr)config.Ipc.MaxBufferSize
+                    });
+                }
+                if (config.Tcp != null)
+                {
+                    nativeConfig.TcpConfig = native.Struct(new ZarkTcpConfig
+                    {
+                        Host = native.String(config.Tcp.Host),
+                        Port = config.Tcp.Port,
+                        MaxMessageSize = (UIntPtr)config.Tcp.MaxMessageSize
+                    });
+                }
+                _messenger = zark_messenger_init(ref nativeConfig);
+            }
             if (_messenger == IntPtr.Zero)
             {
                 throw new ZarkMessengerException("Failed to initialize messenger");
@@ -44,34 +86,83 @@ namespace ZarkWaf.Messenger
         {
             if (_disposed) throw new ObjectDisposedException(nameof(ZarkMessenger));
 
-            var message = new Message
+            using (var native = new NativeMemory())
             {
-                Topic = topic,
-                Payload = payload,
-                PayloadLength = (UIntPtr)payload.Length
-            };
+                var message = native.Message(topic, payload);
+                return zark_messenger_send(_messenger, ref message);
+            }
+        }
 
-            return zark_messenger_send(_messenger, ref message);
+        // Sends the messages at once, in order
+        public bool SendBatch(IReadOnlyList<(string Topic, byte[] Payload)> messages)
+        {
+            if (_disposed) throw new ObjectDisposedException(nameof(ZarkMessenger));
+
+            using (var native = new NativeMemory())
+            {
+                var batch = new ZarkMessage[messages.Count];
+                for (int i = 0; i < messages.Count; i++)
+                {
+                    batch[i] = native.Message(messages[i].Topic, messages[i].Payload);
+                }
+                return zark_messenger_send_batch(_messenger, batch, (UIntPtr)batch.Length);
+            }
         }
 
         public (string Topic, byte[] Payload)? Receive(int maxTopicLength = 256, int maxPayloadLength = 1024)
         {
             if (_disposed) throw new ObjectDisposedException(nameof(ZarkMessenger));
 
-            var topic = new StringBuilder(maxTopicLength);
+            var topic = new byte[maxTopicLength];
             var buffer = new byte[maxPayloadLength];
 
-            int result = zark_messenger_receive(_messenger, 
-                topic, 
+            int result = zark_messenger_receive(_messenger,
+                topic,
                 (UIntPtr)maxTopicLength,
-                buffer, 
+                buffer,
                 (UIntPtr)maxPayloadLength);
 
             if (result < 0) return null;
 
             // Trim buffer to actual received size
             Array.Resize(ref buffer, result);
-            return (topic.ToString(), buffer);
+            int topicLength = Array.IndexOf(topic, (byte)0);
+            return (Encoding.UTF8.GetString(topic, 0, topicLength < 0 ? topic.Length : topicLength), buffer);
+        }
+
+        // Receives up to `max` messages of the topic, waiting up to `timeout` for the first one.
+        // The first call for a topic subscribes to it, later calls take what arrived in between.
+        // Payloads longer than what is left of `bufferLength` are truncated.
+        public List<byte[]> ReceiveBatch(string topic, int max, TimeSpan timeout, int bufferLength = 64 * 1024)
+        {
+            if (_disposed) throw new ObjectDisposedException(nameof(ZarkMessenger));
+
+            var buffer = new byte[bufferLength];
+            var lengths = new UIntPtr[max];
+            int count = zark_messenger_receive_batch(_messenger,
+                topic,
+                (UIntPtr)max,
+                (ulong)timeout.TotalMilliseconds,
+                buffer,
+                (UIntPtr)bufferLength,
+                lengths);
+            if (count < 0)
+            {
+                throw new ZarkMessengerException("Failed to receive messages");
+            }
+
+            // Payloads are copied back to back into the buffer
+            var payloads = new List<byte[]>(count);
+            int offset = 0;
+            for (int i = 0; i < count; i++)
+            {
+                int length = (int)lengths[i];
+                var payload = new byte[length];
+                Array.Copy(buffer, offset, payload, 0, length);
+                payloads.Add(payload);
+                offset += length;
+            }
+            return payloads;
         }
 
         public void Dispose()
@@ -87,5 +178,52 @@ namespace ZarkWaf.Messenger
                 _disposed = true;
             }
         }
+
+        // Unmanaged copies of what is passed to a native call, freed once the call returned
+        private sealed class NativeMemory : IDisposable
+        {
+            private readonly List<IntPtr> _allocations = new List<IntPtr>();
+
+            public IntPtr String(string value)
+            {
+                var bytes = Encoding.UTF8.GetBytes(value + "\0");
+                return Bytes(bytes);
+            }
+
+            public IntPtr Bytes(byte[] value)
+            {
+                var pointer = Marshal.AllocHGlobal(Math.Max(value.Length, 1));
+                _allocations.Add(pointer);
+                Marshal.Copy(value, 0, pointer, value.Length);
+                return pointer;
+            }
+
+            public IntPtr Struct<T>(T value) where T : struct
+            {
+                var pointer = Marshal.AllocHGlobal(Marshal.SizeOf<T>());
+                _allocations.Add(pointer);
+                Marshal.StructureToPtr(value, pointer, false);
+                return pointer;
+            }
+
+            public ZarkMessage Message(string topic, byte[] payload)
+            {
+                return new ZarkMessage
+                {
+                    Topic = String(topic),
+                    Payload = Bytes(payload),
+                    PayloadLength = (UIntPtr)payload.Length
+                };
+            }
+
+            public void Dispose()
+            {
+                foreach (var pointer in _allocations)
+                {
+                    Marshal.FreeHGlobal(pointer);
+                }
+                _allocations.Clear();
+            }
+        }
     }
-} 
\ No newline at end of file
+}
@@ -122,7 +122,7 @@ func (m *Messenger) Send(msg *Message) error {
 	cTopic := C.CString(msg.Topic)
 	defer C.free(unsafe.Pointer(cTopic))
 
-	cMsg := C.struct_Message{
+	cMsg := C.struct_ZarkMessage{
 		topic:          cTopic,
 		payload:        (*C.uchar)(unsafe.Pointer(&msg.Payload[0])),
 		payload_length: C.size_t(len(msg.Payload)),
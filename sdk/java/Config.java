@@ -2,6 +2,7 @@ package io.zarkwaf.messenger;
 
 import com.sun.jna.Structure;
 import com.sun.jna.Pointer;
+import com.sun.jna.NativeLong;
 
 public class Config {
     private final TransportType transportType;
@@ -28,10 +29,15 @@ public class Config {
         return config;
     }
 
-    @Structure.FieldOrder({"transportType", "ipcConfig", "tcpConfig"})
+    @Structure.FieldOrder({"transportType", "ipcConfig", "tcpConfig", "logConfig", "backpressure", "backpressureCount", "chunking", "transactions"})
     public static class NativeConfig extends Structure implements Structure.ByReference {
         public int transportType;
         public Pointer ipcConfig;
         public Pointer tcpConfig;
+        public Pointer logConfig;
+        public Pointer backpressure;
+        public NativeLong backpressureCount = new NativeLong(0);
+        public Pointer chunking;
+        public Pointer transactions;
     }
 }
@@ -2,6 +2,7 @@ package io.zarkwaf.messenger;
 
 import com.sun.jna.Structure;
 import com.sun.jna.NativeLong;
+import com.sun.jna.Pointer;
 
 public class TcpConfig {
     private final String host;
@@ -22,10 +23,11 @@ public class TcpConfig {
         return config;
     }
 
-    @Structure.FieldOrder({"host", "port", "maxMessageSize"})
+    @Structure.FieldOrder({"host", "port", "maxMessageSize", "flowControl"})
     public static class NativeTcpConfig extends Structure implements Structure.ByReference {
         public String host;
         public short port;
         public NativeLong maxMessageSize;
+        public Pointer flowControl;
     }
 }
@@ -0,0 +1,322 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::application::config::ChunkingConfig;
+use crate::domain::errors::MessengerError;
+use crate::domain::headers::{CHUNK_COUNT, CHUNK_ID, CHUNK_INDEX};
+use crate::domain::message::Message;
+use crate::infrastructure::serialization::Serializer;
+use crate::utils::metrics::Metrics;
+
+// split a message whose encoding is larger than `max_len` into chunks that each fit
+// the first chunk carries the headers, key and retain flag, every chunk keeps the priority and expiry
+pub fn split(message: &Message, serializer: &dyn Serializer, max_len: usize) -> Result<Vec<Message>, MessengerError> {
+    let chunk_size = chunk_size(message, serializer, max_len)?;
+    let count = message.payload.len().div_ceil(chunk_size);
+    Ok(message.payload
+        .chunks(chunk_size)
+        .enumerate()
+        .map(|(index, payload)| chunk(message, index, count, payload.to_vec()))
+        .collect())
+}
+
+fn chunk(message: &Message, index: usize, count: usize, payload: Vec<u8>) -> Message {
+    let mut chunk = Message::new(message.topic.clone(), payload);
+    chunk.id = format!("{}#{}", message.id, index);
+    if index == 0 {
+        chunk.headers = message.headers.clone();
+        chunk.key = message.key.clone();
+        chunk.retain = message.retain;
+    }
+    chunk.headers.insert(CHUNK_ID.to_string(), message.id.clone());
+    chunk.headers.insert(CHUNK_INDEX.to_string(), index.to_string());
+    chunk.headers.insert(CHUNK_COUNT.to_string(), count.to_string());
+    chunk.priority = message.priority;
+    chunk.expires_at = message.expires_at;
+    chunk
+}
+
+// largest payload slice whose chunk fits `max_len` whatever its bytes, probed with the first chunk,
+// which carries the most, at the widest index
+fn chunk_size(message: &Message, serializer: &dyn Serializer, max_len: usize) -> Result<usize, MessengerError> {
+    let fits = |size: usize| -> Result<bool, MessengerError> {
+        let mut probe = chunk(message, 0, 1, vec![u8::MAX; size]);
+        probe.id = format!("{}#{}", message.id, u32::MAX);
+        probe.headers.insert(CHUNK_INDEX.to_string(), u32::MAX.to_string());
+        probe.headers.insert(CHUNK_COUNT.to_string(), u32::MAX.to_string());
+        Ok(serializer.serialize(&probe)?.len() <= max_len)
+    };
+
+    let (mut low, mut high) = (1, message.payload.len().clamp(1, max_len));
+    if !fits(low)? {
+        return Err(MessengerError::MessageTooLarge(message.payload.len(), max_len));
+    }
+    if fits(high)? {
+        return Ok(high);
+    }
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if fits(mid)? {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(low)
+}
+
+// chunks received so far of one message, by index
+// only the chunks that arrived take memory, whatever count the peer claims
+struct Partial {
+    chunks: BTreeMap<usize, Message>,
+    count: usize,
+    bytes: usize,
+    started: Instant,
+}
+
+// puts chunked messages back together on the receiving side
+// incomplete messages are dropped once they wait longer than the timeout, or to keep their
+// chunks within the memory cap, oldest first
+pub struct Reassembler {
+    config: ChunkingConfig,
+    // incomplete messages by topic and id
+    partial: HashMap<(String, String), Partial>,
+    pending_bytes: usize,
+    metrics: Arc<Metrics>,
+}
+
+impl Reassembler {
+    pub fn new(config: ChunkingConfig, metrics: Arc<Metrics>) -> Self {
+        Self { config, partial: HashMap::new(), pending_bytes: 0, metrics }
+    }
+
+    pub fn set_config(&mut self, config: ChunkingConfig) {
+        self.config = config;
+    }
+
+    // take a received message, returning it right away unless it is a chunk, and the whole
+    // message once its last chunk arrived
+    pub fn accept(&mut self, message: Message) -> Option<Message> {
+        let (id, index, count) = match chunk_position(&message) {
+            None => return Some(message),
+            Some(Ok(position)) => position,
+            Some(Err(e)) => {
+                log::warn!("dropping chunk {}: {}", message.id, e);
+                self.metrics.record_dropped();
+                return None;
+            }
+        };
+        self.drop_stale();
+
+        // a message with more chunks than the memory cap holds could never be put back together
+        if index >= count || count > self.config.max_pending_bytes / size_of::<Message>() {
+            log::warn!("dropping chunk {} of message {}, it claims to be chunk {} of {}", message.id, id, index, count);
+            self.metrics.record_dropped();
+            return None;
+        }
+
+        let key = (message.topic.clone(), id);
+        let len = held_bytes(&message);
+        while self.pending_bytes + len > self.config.max_pending_bytes {
+            let oldest = self.partial.iter().min_by_key(|(_, partial)| partial.started).map(|(key, _)| key.clone());
+            let full = oldest.as_ref().is_none_or(|oldest| *oldest == key);
+            if let Some(oldest) = oldest {
+                self.drop_partial(&oldest, "incomplete messages reached the memory cap");
+            }
+            if full {
+                log::warn!("dropping chunk {} of message {}, it does not fit the memory cap", index, key.1);
+                self.metrics.record_dropped();
+                return None;
+            }
+        }
+
+        let partial = self.partial.entry(key.clone()).or_insert_with(|| Partial {
+            chunks: BTreeMap::new(),
+            count,
+            bytes: 0,
+            started: Instant::now(),
+        });
+        if partial.count != count {
+            log::warn!("dropping chunk {} of message {}, earlier chunks counted {}", index, key.1, partial.count);
+            self.metrics.record_dropped();
+            return None;
+        }
+        // a chunk that arrives twice is kept once
+        if let Entry::Vacant(entry) = partial.chunks.entry(index) {
+            entry.insert(message);
+            partial.bytes += len;
+            self.pending_bytes += len;
+        }
+        if partial.chunks.len() < partial.count {
+            return None;
+        }
+
+        let partial = self.partial.remove(&key)?;
+        self.pending_bytes -= partial.bytes;
+        let mut chunks = partial.chunks.into_values();
+        let first = chunks.next()?;
+        Some(assemble(key.1, first, chunks))
+    }
+
+    // drop the incomplete messages that waited longer than the timeout
+    fn drop_stale(&mut self) {
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let stale: Vec<_> = self.partial
+            .iter()
+            .filter(|(_, partial)| partial.started.elapsed() >= timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            self.drop_partial(&key, "its chunks timed out");
+        }
+    }
+
+    fn drop_partial(&mut self, key: &(String, String), reason: &str) {
+        if let Some(partial) = self.partial.remove(key) {
+            log::warn!("dropping incomplete message {} on topic '{}', {}", key.1, key.0, reason);
+            self.pending_bytes -= partial.bytes;
+            self.metrics.record_dropped();
+        }
+    }
+}
+
+// bytes a held message counts against a memory cap: the message itself and everything it carries
+pub(crate) fn held_bytes(message: &Message) -> usize {
+    size_of::<Message>()
+        + message.topic.len()
+        + message.id.len()
+        + message.payload.len()
+        + message.headers.iter().map(|(name, value)| name.len() + value.len()).sum::<usize>()
+}
+
+// id of the message a chunk belongs to with the chunk's index and the chunk count, None for whole messages
+fn chunk_position(message: &Message) -> Option<Result<(String, usize, usize), MessengerError>> {
+    let id = message.headers.get(CHUNK_ID)?;
+    let number = |name: &str| {
+        message.headers.get(name).and_then(|value| value.parse::<usize>().ok()).ok_or_else(|| {
+            MessengerError::Deserialization(format!("chunk {} has no valid {} header", message.id, name))
+        })
+    };
+    Some(number(CHUNK_INDEX).and_then(|index| Ok((id.clone(), index, number(CHUNK_COUNT)?))))
+}
+
+// the message the chunks were split from, the first chunk brings its headers, key and flags
+fn assemble(id: String, mut message: Message, rest: impl Iterator<Item = Message>) -> Message {
+    message.id = id;
+    message.headers.remove(CHUNK_ID);
+    message.headers.remove(CHUNK_INDEX);
+    message.headers.remove(CHUNK_COUNT);
+    for chunk in rest {
+        message.payload.extend_from_slice(&chunk.payload);
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::serialization::binary::BinarySerializer;
+
+    fn capped(max_pending_bytes: usize) -> (Reassembler, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::default());
+        let config = ChunkingConfig { max_pending_bytes, ..Default::default() };
+        (Reassembler::new(config, metrics.clone()), metrics)
+    }
+
+    #[test]
+    fn chunks_are_put_back_together_in_any_order_and_once() {
+        let message = Message::new("t".into(), (0..1000).map(|byte| byte as u8).collect()).with_header("kind", "big").with_key("k");
+        let mut chunks = split(&message, &BinarySerializer, 400).unwrap();
+        assert!(chunks.len() > 2);
+        assert!(chunks.iter().all(|chunk| BinarySerializer.serialize(chunk).unwrap().len() <= 400));
+
+        let (mut reassembler, _) = capped(1 << 20);
+        chunks.reverse();
+        let last = chunks.pop().unwrap();
+        for chunk in &chunks {
+            assert_eq!(reassembler.accept(chunk.clone()), None);
+            // a chunk that arrives twice does not complete the message early
+            assert_eq!(reassembler.accept(chunk.clone()), None);
+        }
+        assert_eq!(reassembler.accept(last), Some(message));
+        assert_eq!(reassembler.pending_bytes, 0);
+    }
+
+    #[test]
+    fn chunk_counts_the_memory_cap_cannot_hold_are_dropped_up_front() {
+        let (mut reassembler, metrics) = capped(1 << 20);
+        let mut forged = chunk(&Message::new("t".into(), vec![1]), 0, usize::MAX / 2, vec![1]);
+        assert_eq!(reassembler.accept(forged.clone()), None);
+        assert!(reassembler.partial.is_empty());
+
+        // a count that fits takes memory for the chunks that arrived, not for the ones it claims
+        forged.headers.insert(CHUNK_COUNT.to_string(), ((1 << 20) / size_of::<Message>()).to_string());
+        assert_eq!(reassembler.accept(forged.clone()), None);
+        assert_eq!(reassembler.partial.values().map(|partial| partial.chunks.len()).sum::<usize>(), 1);
+        assert_eq!(reassembler.pending_bytes, held_bytes(&forged));
+        assert_eq!(metrics.snapshot().dropped, 1);
+    }
+
+    #[test]
+    fn the_oldest_incomplete_message_makes_room_under_the_memory_cap() {
+        let first = split(&Message::new("t".into(), vec![1; 1000]), &BinarySerializer, 400).unwrap();
+        let second = split(&Message::new("t".into(), vec![2; 1000]), &BinarySerializer, 400).unwrap();
+        let (mut reassembler, metrics) = capped(second.iter().map(held_bytes).sum::<usize>() + 1);
+
+        assert_eq!(reassembler.accept(first[0].clone()), None);
+        let mut whole = None;
+        for chunk in &second {
+            whole = reassembler.accept(chunk.clone());
+        }
+        assert_eq!(whole.unwrap().payload, vec![2; 1000]);
+        assert_eq!(metrics.snapshot().dropped, 1);
+        // the dropped message starts over when more of its chunks come in
+        assert_eq!(reassembler.accept(first[1].clone()), None);
+        assert_eq!(reassembler.partial.values().map(|partial| partial.chunks.len()).sum::<usize>(), 1);
+
+        // a chunk that does not fit even with nothing else held is dropped on its own
+        let (mut tight, metrics) = capped(held_bytes(&first[0]) - 1);
+        assert_eq!(tight.accept(first[0].clone()), None);
+        assert!(tight.partial.is_empty());
+        assert_eq!(metrics.snapshot().dropped, 1);
+    }
+
+    #[test]
+    fn incomplete_messages_are_dropped_once_they_time_out() {
+        let chunks = split(&Message::new("t".into(), vec![1; 1000]), &BinarySerializer, 400).unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let mut reassembler = Reassembler::new(ChunkingConfig { timeout_ms: 0, ..Default::default() }, metrics.clone());
+        assert_eq!(reassembler.accept(chunks[0].clone()), None);
+        // the next chunk finds the message timed out and starts it over
+        assert_eq!(reassembler.accept(chunks[1].clone()), None);
+        assert_eq!(metrics.snapshot().dropped, 1);
+        assert_eq!(reassembler.partial.len(), 1);
+    }
+}
@@ -22,6 +22,9 @@
 //
 // Authors: I. Zeqiri, E. Gjergji
 
+use std::collections::HashMap;
+use std::path::PathBuf;
+
 use serde::Deserialize;
 
 
@@ -35,6 +38,18 @@ pub struct Config {
     pub ipc_config: Option<IpcConfig>,
     // configuration for tcp transport, if used
     pub tcp_config: Option<TcpConfig>,
+    // durable topic log, messages only live in memory when unset
+    #[serde(default)]
+    pub log_config: Option<LogConfig>,
+    // backpressure policy by topic pattern, topics without one block until there is room
+    #[serde(default)]
+    pub backpressure: HashMap<String, BackpressurePolicy>,
+    // reassembly of messages split into chunks for being larger than the transport allows
+    #[serde(default)]
+    pub chunking: ChunkingConfig,
+    // holding back the messages of incomplete transactions
+    #[serde(default)]
+    pub transactions: TransactionConfig,
 }
 
 // enum to represent the available transport types
@@ -72,4 +87,184 @@ pub struct TcpConfig {
 
     // maximum size of messages that can be sent via tcp
     pub max_message_size: usize,
-}
\ No newline at end of file
+    // credit-based flow control, the peer sends as fast as the connection allows when unset
+    #[serde(default)]
+    pub flow_control: Option<FlowControlConfig>,
+}
+
+// credit-based flow control over tcp
+// every local subscription grants the peer credits for its topic, and the peer only sends it a
+// message while it has credits left, holding the rest back in a buffer per subscription
+#[derive(Debug, Clone, Deserialize)]
+pub struct FlowControlConfig {
+    // credits a subscription grants up front, granted again in halves as it receives messages
+    #[serde(default = "default_flow_credits")]
+    pub credits: u32,
+    // messages a sender holds back for one subscription before it counts as a slow consumer
+    #[serde(default = "default_flow_buffer")]
+    pub buffer_size: usize,
+    // what the sender does about a slow consumer
+    #[serde(default)]
+    pub slow_consumer: SlowConsumerPolicy,
+}
+
+impl Default for FlowControlConfig {
+    fn default() -> Self {
+        Self {
+            credits: default_flow_credits(),
+            buffer_size: default_flow_buffer(),
+            slow_consumer: SlowConsumerPolicy::default(),
+        }
+    }
+}
+
+fn default_flow_credits() -> u32 {
+    256
+}
+
+fn default_flow_buffer() -> usize {
+    1024
+}
+
+// how a sender deals with a subscription that stopped keeping up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum SlowConsumerPolicy {
+    // close the subscription, its subscriber gets `MessengerError::SlowConsumer` once it drained its queue
+    #[default]
+    Disconnect,
+    // stop waiting for its credits, messages that do not fit its queue are dropped on arrival
+    Degrade,
+}
+
+// reassembly of chunked messages on the receiving side
+// messages larger than the transport's max_message_size are split into chunks when published
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChunkingConfig {
+    // milliseconds an incomplete message may wait for its missing chunks before it is dropped
+    #[serde(default = "default_chunk_timeout_ms")]
+    pub timeout_ms: u64,
+    // bytes all incomplete messages together may hold, chunks count with their headers and ids,
+    // the oldest are dropped to stay below it
+    #[serde(default = "default_chunk_pending_bytes")]
+    pub max_pending_bytes: usize,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: default_chunk_timeout_ms(),
+            max_pending_bytes: default_chunk_pending_bytes(),
+        }
+    }
+}
+
+fn default_chunk_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_chunk_pending_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+// holding back the messages of transactions on the receiving side until each is complete
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransactionConfig {
+    // milliseconds an incomplete transaction may wait for its missing messages before it is dropped
+    #[serde(default = "default_transaction_timeout_ms")]
+    pub timeout_ms: u64,
+    // bytes all incomplete transactions together may hold, messages count with their headers and ids,
+    // the oldest are dropped to stay below it
+    #[serde(default = "default_transaction_pending_bytes")]
+    pub max_pending_bytes: usize,
+}
+
+impl Default for TransactionConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: default_transaction_timeout_ms(),
+            max_pending_bytes: default_transaction_pending_bytes(),
+        }
+    }
+}
+
+fn default_transaction_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_transaction_pending_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+// configuration for the durable topic log
+// every logged topic gets its own directory of segment files under `directory`
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogConfig {
+    // directory the topic logs are kept in
+    pub directory: PathBuf,
+    // topic patterns to log, every non-system topic is logged when empty
+    #[serde(default)]
+    pub topics: Vec<String>,
+    // size a segment may grow to before the next one is started, at most 4 GiB
+    #[serde(default = "default_segment_bytes")]
+    pub segment_bytes: u64,
+    // oldest segments are deleted once a topic log grows past this many bytes
+    #[serde(default)]
+    pub retention_bytes: Option<u64>,
+    // segments whose newest record is older than this many seconds are deleted
+    #[serde(default)]
+    pub retention_secs: Option<u64>,
+    // when appended records are flushed to disk
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+    // topic patterns whose logs are compacted down to the newest message per key
+    #[serde(default)]
+    pub compact_topics: Vec<String>,
+    // how long compaction keeps tombstones, so readers still see the delete
+    #[serde(default = "default_tombstone_retention_secs")]
+    pub tombstone_retention_secs: u64,
+}
+
+pub(crate) fn default_segment_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+pub(crate) fn default_tombstone_retention_secs() -> u64 {
+    24 * 60 * 60
+}
+
+// when the durable log calls fsync on its segment files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum FsyncPolicy {
+    // after every append, nothing acknowledged by the log is lost on a crash
+    Always,
+    // on the first append after the interval in milliseconds has passed, and on shutdown
+    IntervalMs(u64),
+    // leave flushing to the operating system
+    Never,
+}
+
+impl Default for FsyncPolicy {
+    fn default() -> Self {
+        FsyncPolicy::IntervalMs(1000)
+    }
+}
+
+// what a transport does with a message when its queue has no room for it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum BackpressurePolicy {
+    // wait for room however long it takes, what transports did before policies existed
+    #[default]
+    Block,
+    // wait up to the deadline in milliseconds, then fail with `MessengerError::Backpressure`
+    BlockMs(u64),
+    // drop the message being sent, failing with `MessengerError::Dropped`
+    DropNewest,
+    // drop the oldest queued message of the same topic to make room, or the new one when
+    // none is queued
+    DropOldest,
+    // keep one in every n messages, dropping the oldest queued one of the topic to make room
+    // for it, the others are dropped like with DropNewest
+    Sample(u32),
+    // fail with `MessengerError::Backpressure` right away
+    Error,
+}
@@ -0,0 +1,493 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::sync::{mpsc, Notify};
+
+use crate::application::subscription::{Envelope, GroupBalance};
+use crate::domain::filter::Filter;
+use crate::domain::headers::PARTITION;
+use crate::domain::message::Message;
+
+// subscribers of a topic sharing a group name
+// the group as a whole sees every message, each message goes to exactly one member
+// messages of partitioned topics go to the member owning their partition instead, and the
+// partitions are spread again whenever a member joins or leaves
+pub(crate) struct ConsumerGroup {
+    balance: GroupBalance,
+    // messages the group takes, all of them when unset
+    filter: Option<Arc<Filter>>,
+    members: Mutex<Members>,
+    // where the next round-robin pick starts
+    next: AtomicUsize,
+    // messages handed back by members that left, older than anything still to be delivered
+    returned: Mutex<VecDeque<Envelope>>,
+    // held by every delivery, so the members get messages in the order they were delivered
+    turn: tokio::sync::Mutex<()>,
+    // woken when a member takes a message of a partition or leaves, either may end a handoff
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct Members {
+    next_id: u64,
+    joined: Vec<Member>,
+    // partition -> member owning it
+    owners: HashMap<u32, Owner>,
+}
+
+struct Member {
+    id: u64,
+    sender: mpsc::Sender<Envelope>,
+    // set once the member started leaving, its partitions wait for the messages it hands back
+    leaving: bool,
+}
+
+// the messages of a partition are handled one after another by the same member, so the
+// partition only moves on once its owner took every message of it queued so far
+struct Owner {
+    member: u64,
+    // first deliveries of the partition queued for the owner and not taken yet
+    queued: usize,
+    // the member the partition moves to once nothing of it is queued
+    moving_to: Option<u64>,
+}
+
+impl Owner {
+    // the member owning the partition once its handoff is done
+    fn target(&self) -> u64 {
+        self.moving_to.unwrap_or(self.member)
+    }
+
+    fn move_to(&mut self, member: u64) {
+        if self.queued == 0 || member == self.member {
+            self.member = member;
+            self.moving_to = None;
+        } else {
+            self.moving_to = Some(member);
+        }
+    }
+}
+
+enum Pick {
+    Member(mpsc::Sender<Envelope>),
+    // the message's partition is changing hands, it waits until the handoff is done
+    Wait,
+    // no member is left to take the message
+    Gone,
+}
+
+impl Members {
+    // drop a member and the partitions it owns, partitions on their way to it stay where they are
+    fn forget(&mut self, id: u64) {
+        self.joined.retain(|member| member.id != id);
+        self.owners.retain(|_, owner| {
+            if owner.moving_to == Some(id) {
+                owner.moving_to = None;
+            }
+            if owner.member != id {
+                return true;
+            }
+            match owner.moving_to.take() {
+                Some(next) => {
+                    owner.member = next;
+                    owner.queued = 0;
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+
+    // drop members whose subscriber went away without leaving
+    fn forget_closed(&mut self) {
+        let closed: Vec<u64> = self.joined.iter()
+            .filter(|member| !member.leaving && member.sender.is_closed())
+            .map(|member| member.id)
+            .collect();
+        for id in closed {
+            self.forget(id);
+        }
+    }
+
+    fn owned_by(&self, member: u64) -> usize {
+        self.owners.values().filter(|owner| owner.target() == member).count()
+    }
+
+    // move partitions from the members owning the most to the ones owning the fewest until
+    // no member owns more than one partition more than another
+    fn rebalance(&mut self) {
+        let active: Vec<u64> = self.joined.iter()
+            .filter(|member| !member.leaving && !member.sender.is_closed())
+            .map(|member| member.id)
+            .collect();
+        loop {
+            let most = active.iter().copied().max_by_key(|member| self.owned_by(*member));
+            let fewest = active.iter().copied().min_by_key(|member| self.owned_by(*member));
+            let (most, fewest) = match (most, fewest) {
+                (Some(most), Some(fewest)) if self.owned_by(most) > self.owned_by(fewest) + 1 => (most, fewest),
+                _ => return,
+            };
+            let partition = self.owners.iter()
+                .filter(|(_, owner)| owner.target() == most)
+                .map(|(partition, _)| *partition)
+                .max();
+            if let Some(owner) = partition.and_then(|partition| self.owners.get_mut(&partition)) {
+                owner.move_to(fewest);
+            }
+        }
+    }
+}
+
+impl ConsumerGroup {
+    pub fn new(balance: GroupBalance, filter: Option<Arc<Filter>>) -> Self {
+        Self {
+            balance,
+            filter,
+            members: Mutex::new(Members::default()),
+            next: AtomicUsize::new(0),
+            returned: Mutex::new(VecDeque::new()),
+            turn: tokio::sync::Mutex::new(()),
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn balance(&self) -> GroupBalance {
+        self.balance
+    }
+
+    pub fn filter(&self) -> Option<&Arc<Filter>> {
+        self.filter.as_ref()
+    }
+
+    // add a member, handing it its share of the partitions, returning its id in the group
+    pub fn join(&self, member: mpsc::Sender<Envelope>) -> u64 {
+        let mut members = self.members.lock();
+        members.forget_closed();
+        let id = members.next_id;
+        members.next_id += 1;
+        members.joined.push(Member { id, sender: member, leaving: false });
+        members.rebalance();
+        id
+    }
+
+    // stop handing messages to a member about to leave, messages of its partitions wait until it left
+    pub fn leaving(&self, id: u64) {
+        if let Some(member) = self.members.lock().joined.iter_mut().find(|member| member.id == id) {
+            member.leaving = true;
+        }
+    }
+
+    // remove a member, its partitions go to the others and the messages it did not take are
+    // delivered again ahead of newer ones
+    pub fn left(&self, id: u64, orphans: Vec<Envelope>) {
+        {
+            let mut members = self.members.lock();
+            // queued while the member is still listed, so no newer message of its partitions gets ahead
+            self.returned.lock().extend(orphans);
+            members.forget(id);
+            members.rebalance();
+        }
+        self.changed.notify_waiters();
+    }
+
+    // note that a member took a message off its queue, which may let its partition move on
+    pub fn taken(&self, id: u64, message: &Message) {
+        let partition = match message.header(PARTITION).and_then(|partition| partition.parse::<u32>().ok()) {
+            Some(partition) => partition,
+            None => return,
+        };
+        let moved = {
+            let mut members = self.members.lock();
+            match members.owners.get_mut(&partition).filter(|owner| owner.member == id) {
+                Some(owner) => {
+                    owner.queued = owner.queued.saturating_sub(1);
+                    if owner.queued == 0 && owner.moving_to.is_some() {
+                        owner.move_to(owner.target());
+                        true
+                    } else {
+                        false
+                    }
+                }
+                None => false,
+            }
+        };
+        if moved {
+            self.changed.notify_waiters();
+        }
+    }
+
+    // drop members whose subscriber is gone, reporting whether any are left
+    pub fn prune(&self) -> bool {
+        let mut members = self.members.lock();
+        members.forget_closed();
+        !members.joined.is_empty()
+    }
+
+    // remove every member, which closes their channels once the subscribers see the end of the queue
+    pub fn clear(&self) {
+        *self.members.lock() = Members::default();
+        self.returned.lock().clear();
+        self.changed.notify_waiters();
+    }
+
+    // hand the envelope to one member, after the messages members that left handed back
+    // a message of a partition changing hands waits until its previous owner took the ones before it
+    // returns false when no member is left to take it
+    pub async fn deliver(&self, envelope: Envelope) -> bool {
+        self.run(Some(envelope)).await
+    }
+
+    // deliver the messages members that left handed back
+    pub async fn flush(&self) {
+        self.run(None).await;
+    }
+
+    async fn run(&self, mut envelope: Option<Envelope>) -> bool {
+        let _turn = self.turn.lock().await;
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            let popped = self.returned.lock().pop_front();
+            let (next, returned) = match popped {
+                Some(next) => (next, true),
+                None => match envelope.take() {
+                    Some(next) => (next, false),
+                    None => return false,
+                },
+            };
+            let member = match self.pick(&next) {
+                Pick::Member(member) => member,
+                Pick::Wait => {
+                    self.put_back(next, returned, &mut envelope);
+                    changed.await;
+                    continue;
+                }
+                Pick::Gone if returned => {
+                    log::warn!("dropping message {}, its consumer group has no members left", next.message.id);
+                    continue;
+                }
+                Pick::Gone => return false,
+            };
+            // a member that left in the meantime is skipped on the next pick
+            match member.send(next).await {
+                Ok(()) if returned => {}
+                Ok(()) => return true,
+                Err(mpsc::error::SendError(next)) => self.put_back(next, returned, &mut envelope),
+            }
+        }
+    }
+
+    fn put_back(&self, next: Envelope, returned: bool, envelope: &mut Option<Envelope>) {
+        if returned {
+            self.returned.lock().push_front(next);
+        } else {
+            *envelope = Some(next);
+        }
+    }
+
+    fn pick(&self, envelope: &Envelope) -> Pick {
+        let mut members = self.members.lock();
+        members.forget_closed();
+        let members = &mut *members;
+        let available: Vec<&Member> = members.joined.iter().filter(|member| !member.leaving).collect();
+        if available.is_empty() {
+            return if members.joined.is_empty() { Pick::Gone } else { Pick::Wait };
+        }
+        // redeliveries are out of order already, only first deliveries hold a handoff back
+        let first = usize::from(envelope.attempt == 1);
+
+        if let Some(partition) = envelope.message.header(PARTITION).and_then(|partition| partition.parse().ok()) {
+            if let Some(owner) = members.owners.get_mut(&partition) {
+                let member = members.joined.iter().find(|member| member.id == owner.member);
+                return match member {
+                    Some(member) if !member.leaving && owner.moving_to.is_none() => {
+                        owner.queued += first;
+                        Pick::Member(member.sender.clone())
+                    }
+                    _ => Pick::Wait,
+                };
+            }
+            // an unowned partition goes to the member owning the fewest
+            let (id, sender) = match available.iter().min_by_key(|member| members.owned_by(member.id)) {
+                Some(member) => (member.id, member.sender.clone()),
+                None => return Pick::Gone,
+            };
+            members.owners.insert(partition, Owner { member: id, queued: first, moving_to: None });
+            return Pick::Member(sender);
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % available.len();
+        let member = match self.balance {
+            GroupBalance::RoundRobin => available[start],
+            // fewest queued messages, ties go to the round-robin order
+            GroupBalance::LeastLoaded => match (0..available.len())
+                .map(|i| available[(start + i) % available.len()])
+                .min_by_key(|member| member.sender.max_capacity() - member.sender.capacity())
+            {
+                Some(member) => member,
+                None => return Pick::Gone,
+            },
+        };
+        Pick::Member(member.sender.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn envelope(payload: u8) -> Envelope {
+        Envelope { message: Message::new("t".into(), vec![payload]), attempt: 1 }
+    }
+
+    fn members(group: &ConsumerGroup, count: usize) -> Vec<mpsc::Receiver<Envelope>> {
+        (0..count)
+            .map(|_| {
+                let (tx, rx) = mpsc::channel(8);
+                group.join(tx);
+                rx
+            })
+            .collect()
+    }
+
+    fn drain(member: &mut mpsc::Receiver<Envelope>) -> Vec<u8> {
+        std::iter::from_fn(|| member.try_recv().ok()).map(|envelope| envelope.message.payload[0]).collect()
+    }
+
+    #[tokio::test]
+    async fn round_robin_takes_turns_and_skips_members_that_left() {
+        let group = ConsumerGroup::new(GroupBalance::RoundRobin, None);
+        let mut members = members(&group, 3);
+        for payload in 0..6 {
+            assert!(group.deliver(envelope(payload)).await);
+        }
+        assert_eq!(drain(&mut members[0]), vec![0, 3]);
+        assert_eq!(drain(&mut members[1]), vec![1, 4]);
+        assert_eq!(drain(&mut members[2]), vec![2, 5]);
+
+        drop(members.remove(1));
+        for payload in 0..4 {
+            assert!(group.deliver(envelope(payload)).await);
+        }
+        assert_eq!(drain(&mut members[0]).len() + drain(&mut members[1]).len(), 4);
+
+        members.clear();
+        assert!(!group.deliver(envelope(0)).await);
+        assert!(!group.prune());
+    }
+
+    #[tokio::test]
+    async fn least_loaded_picks_the_emptiest_queue() {
+        let group = ConsumerGroup::new(GroupBalance::LeastLoaded, None);
+        let mut members = members(&group, 2);
+        for payload in 0..4 {
+            assert!(group.deliver(envelope(payload)).await);
+        }
+        // the first member works off its queue, so it gets everything until it catches up
+        drain(&mut members[0]);
+        for payload in 10..12 {
+            assert!(group.deliver(envelope(payload)).await);
+        }
+        assert_eq!(drain(&mut members[0]), vec![10, 11]);
+        assert_eq!(drain(&mut members[1]).len(), 2);
+    }
+
+    fn partitioned(partition: u32) -> Envelope {
+        let mut envelope = envelope(partition as u8);
+        envelope.message.headers.insert(PARTITION.to_string(), partition.to_string());
+        envelope
+    }
+
+    #[tokio::test]
+    async fn partitions_stay_with_their_owner_until_it_leaves() {
+        let group = ConsumerGroup::new(GroupBalance::RoundRobin, None);
+        let mut members = members(&group, 2);
+        for _ in 0..2 {
+            for partition in 0..4 {
+                assert!(group.deliver(partitioned(partition)).await);
+            }
+        }
+        // the partitions are spread evenly and each one goes to the same member every time
+        let first = drain(&mut members[0]);
+        let second = drain(&mut members[1]);
+        assert_eq!(first.len(), 4);
+        assert_eq!(second.len(), 4);
+        assert!(first.iter().all(|partition| !second.contains(partition)));
+        assert_eq!(first[..2], first[2..]);
+
+        // the partitions of a member that left move to the one still there
+        drop(members.remove(0));
+        for partition in 0..4 {
+            assert!(group.deliver(partitioned(partition)).await);
+        }
+        assert_eq!(drain(&mut members[0]), vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn partitions_move_to_a_new_member_once_their_owner_took_what_was_queued() {
+        let group = Arc::new(ConsumerGroup::new(GroupBalance::RoundRobin, None));
+        let (tx, mut first) = mpsc::channel(8);
+        let owner = group.join(tx);
+        for partition in 0..4 {
+            assert!(group.deliver(partitioned(partition)).await);
+        }
+
+        // the newcomer gets half the partitions, but only after the messages queued for them were taken
+        let (tx, mut second) = mpsc::channel(8);
+        group.join(tx);
+        let delivering = tokio::spawn({
+            let group = Arc::clone(&group);
+            async move {
+                for partition in 0..4 {
+                    assert!(group.deliver(partitioned(partition)).await);
+                }
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(drain(&mut second).is_empty());
+        assert!(!delivering.is_finished());
+
+        let mut taken = Vec::new();
+        while taken.len() < 6 {
+            let envelope = first.recv().await.unwrap();
+            group.taken(owner, &envelope.message);
+            taken.push(envelope.message.payload[0]);
+        }
+        delivering.await.unwrap();
+        assert_eq!(taken, vec![0, 1, 2, 3, 0, 1]);
+        assert_eq!(drain(&mut second), vec![2, 3]);
+
+        // the partitions stay with their new owner
+        assert!(group.deliver(partitioned(3)).await);
+        assert_eq!(drain(&mut second), vec![3]);
+    }
+}
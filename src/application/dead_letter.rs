@@ -0,0 +1,213 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::domain::errors::MessengerError;
+use crate::domain::headers::{ATTEMPTS, FAILURE_REASON, ORIGINAL_TOPIC};
+use crate::domain::message::Message;
+use crate::infrastructure::transport::Transport;
+
+// number of dead letters kept per dead-letter topic for inspection and replay
+pub const DEAD_LETTER_CAPACITY: usize = 1024;
+
+// copy of a failed message addressed to a dead-letter topic
+// the id and payload are kept, the headers record where it came from and why it failed
+pub fn dead_letter(message: &Message, dead_letter_topic: &str, reason: &str, attempts: u32) -> Message {
+    let mut dead = message.clone();
+    dead.topic = dead_letter_topic.to_string();
+    dead.headers.insert(ORIGINAL_TOPIC.to_string(), message.topic.clone());
+    dead.headers.insert(FAILURE_REASON.to_string(), reason.to_string());
+    dead.headers.insert(ATTEMPTS.to_string(), attempts.to_string());
+    dead
+}
+
+// dead letter of a frame that could not be decoded, carrying the raw frame as its payload
+// it has no original topic to go back to, so it can be inspected but not replayed
+pub fn undecodable(data: &[u8], dead_letter_topic: &str, error: &MessengerError) -> Message {
+    dead_letter(&Message::new(String::new(), data.to_vec()), dead_letter_topic, &error.to_string(), 1)
+}
+
+// turn a dead letter back into the message it was made from
+pub fn restore(dead: &Message) -> Result<Message, MessengerError> {
+    let mut message = dead.clone();
+    message.topic = message.headers.remove(ORIGINAL_TOPIC).ok_or_else(|| {
+        MessengerError::Deserialization(format!("message {} has no {} header", dead.id, ORIGINAL_TOPIC))
+    })?;
+    if message.topic.is_empty() {
+        return Err(MessengerError::Deserialization(format!("message {} was never decoded and cannot be replayed", dead.id)));
+    }
+    message.headers.remove(FAILURE_REASON);
+    message.headers.remove(ATTEMPTS);
+    Ok(message)
+}
+
+// where a subscription sends the messages it gives up on
+#[derive(Clone)]
+pub struct DeadLetterSink {
+    pub topic: String,
+    pub transport: Arc<dyn Transport>,
+}
+
+impl DeadLetterSink {
+    // publish the dead letter in the background, failures are only logged
+    pub fn send(&self, message: &Message, reason: &str, attempts: u32) {
+        let dead = dead_letter(message, &self.topic, reason, attempts);
+        let transport = Arc::clone(&self.transport);
+        tokio::spawn(async move {
+            if let Err(e) = transport.send(&dead).await {
+                log::warn!("failed to dead-letter message {}: {}", dead.id, e);
+            }
+        });
+    }
+}
+
+// the most recent dead letters seen on each dead-letter topic
+pub struct DeadLetterStore {
+    entries: Mutex<HashMap<String, VecDeque<Message>>>,
+    capacity: usize,
+}
+
+impl Default for DeadLetterStore {
+    fn default() -> Self {
+        Self::new(DEAD_LETTER_CAPACITY)
+    }
+}
+
+impl DeadLetterStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    // remember a dead letter, evicting the oldest one of its topic when full
+    pub fn record(&self, message: Message) {
+        let mut entries = self.entries.lock();
+        let queue = entries.entry(message.topic.clone()).or_default();
+        if queue.len() >= self.capacity {
+            queue.pop_front();
+        }
+        queue.push_back(message);
+    }
+
+    // dead letters of a topic, oldest first
+    pub fn list(&self, topic: &str) -> Vec<Message> {
+        self.entries
+            .lock()
+            .get(topic)
+            .map(|queue| queue.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // remove a single dead letter by message id
+    pub fn take(&self, topic: &str, id: &str) -> Option<Message> {
+        let mut entries = self.entries.lock();
+        let queue = entries.get_mut(topic)?;
+        let index = queue.iter().position(|message| message.id == id)?;
+        queue.remove(index)
+    }
+
+    // remove every dead letter of a topic
+    pub fn take_all(&self, topic: &str) -> Vec<Message> {
+        self.entries
+            .lock()
+            .remove(topic)
+            .map(Vec::from)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::application::config::IpcConfig;
+    use crate::application::messenger::{Messenger, MessengerImpl};
+    use crate::application::subscription::{AckConfig, SubscriptionOptions};
+    use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+    use crate::infrastructure::serialization::json::JsonSerializer;
+    use crate::infrastructure::transport::ipc::IpcTransport;
+
+    #[test]
+    fn dead_letters_restore_unless_never_decoded() {
+        let message = Message::new("orders".into(), vec![1]).with_header("origin", "edge");
+        let dead = dead_letter(&message, "dead", "boom", 3);
+        assert_eq!(dead.header(ORIGINAL_TOPIC), Some("orders"));
+        assert_eq!(dead.header(ATTEMPTS), Some("3"));
+        assert_eq!(restore(&dead).unwrap(), message);
+
+        let garbage = undecodable(&[0xff, 0x01], "dead", &MessengerError::Deserialization("bad".into()));
+        assert_eq!(garbage.payload, vec![0xff, 0x01]);
+        assert!(restore(&garbage).is_err());
+    }
+
+    #[test]
+    fn store_keeps_the_most_recent() {
+        let store = DeadLetterStore::new(2);
+        for id in ["a", "b", "c"] {
+            let mut message = Message::new("dead".into(), vec![]);
+            message.id = id.into();
+            store.record(message);
+        }
+        let ids: Vec<_> = store.list("dead").into_iter().map(|message| message.id).collect();
+        assert_eq!(ids, ["b", "c"]);
+        assert_eq!(store.take("dead", "b").unwrap().id, "b");
+        assert_eq!(store.take_all("dead").len(), 1);
+        assert!(store.list("dead").is_empty());
+    }
+
+    #[tokio::test]
+    async fn given_up_messages_are_dead_lettered_and_replayed() {
+        let config = IpcConfig { shared_memory_name: "dead".into(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        let transport = IpcTransport::new(config, Box::new(JsonSerializer), PoolAllocator::new(16)).unwrap();
+        let messenger = MessengerImpl::new(Arc::new(transport));
+        let options = SubscriptionOptions {
+            ack: Some(AckConfig { visibility_timeout: Duration::from_secs(30), max_redeliveries: 0 }),
+            dead_letter_topic: Some("dead".into()),
+            ..Default::default()
+        };
+        let orders = messenger.subscribe_with("orders".into(), options).await.unwrap();
+        let dead = messenger.subscribe("dead".into()).await.unwrap();
+
+        messenger.publish("orders".into(), &Message::new("orders".into(), vec![9])).await.unwrap();
+        orders.receive_delivery().await.unwrap().nack_with_reason("cannot parse");
+        let letter = dead.receive().await.unwrap();
+        assert_eq!(letter.header(FAILURE_REASON), Some("cannot parse"));
+        assert_eq!(messenger.dead_letters("dead").len(), 1);
+
+        messenger.replay_dead_letter("dead", &letter.id).await.unwrap();
+        let replayed = orders.receive_delivery().await.unwrap();
+        assert_eq!(replayed.payload, vec![9]);
+        assert!(replayed.header(FAILURE_REASON).is_none());
+        replayed.ack();
+        assert!(messenger.dead_letters("dead").is_empty());
+        messenger.cleanup().await.unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::HashSet;
+use std::time::Instant;
+
+use crate::application::subscription::DedupWindow;
+
+// remembers the ids a subscription has seen, in two generations of a hash set
+// the current generation is rotated out once it holds the window's count of ids or is as old
+// as its time window, so an id is remembered for at least one and at most two windows
+pub struct DedupFilter {
+    window: DedupWindow,
+    current: HashSet<String>,
+    previous: HashSet<String>,
+    started: Instant,
+}
+
+impl DedupFilter {
+    pub fn new(window: DedupWindow) -> Self {
+        Self {
+            window,
+            current: HashSet::new(),
+            previous: HashSet::new(),
+            started: Instant::now(),
+        }
+    }
+
+    // record an id, returning whether it was seen before within the window
+    pub fn is_duplicate(&mut self, id: &str) -> bool {
+        match self.window {
+            DedupWindow::Count(count) => {
+                if self.current.len() >= count.max(1) {
+                    self.rotate();
+                }
+            }
+            DedupWindow::Time(window) => {
+                let age = self.started.elapsed();
+                if age >= window {
+                    self.rotate();
+                }
+                // nothing came in for a whole window, so the previous generation is out of it too
+                if age >= window * 2 {
+                    self.previous.clear();
+                }
+            }
+        }
+
+        if self.current.contains(id) || self.previous.contains(id) {
+            return true;
+        }
+        self.current.insert(id.to_string());
+        false
+    }
+
+    fn rotate(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+        self.started = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::config::IpcConfig;
+    use crate::application::messenger::{Messenger, MessengerImpl};
+    use crate::application::subscription::SubscriptionOptions;
+    use crate::domain::message::Message;
+    use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+    use crate::infrastructure::serialization::binary::BinarySerializer;
+    use crate::infrastructure::transport::ipc::IpcTransport;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn count_windows_remember_ids_for_one_to_two_windows() {
+        let mut filter = DedupFilter::new(DedupWindow::Count(2));
+        assert!(!filter.is_duplicate("a"));
+        assert!(!filter.is_duplicate("b"));
+        assert!(filter.is_duplicate("a"));
+        // the window is full, `c` starts the next generation and `a` and `b` are still remembered
+        assert!(!filter.is_duplicate("c"));
+        assert!(filter.is_duplicate("a"));
+        assert!(!filter.is_duplicate("d"));
+        // two generations on, the first ids are forgotten
+        assert!(!filter.is_duplicate("e"));
+        assert!(!filter.is_duplicate("a"));
+        assert!(filter.is_duplicate("e"));
+    }
+
+    #[test]
+    fn time_windows_forget_ids_after_two_windows_at_most() {
+        let window = Duration::from_millis(20);
+        let mut filter = DedupFilter::new(DedupWindow::Time(window));
+        assert!(!filter.is_duplicate("a"));
+        std::thread::sleep(window);
+        // rotated into the previous generation, still remembered
+        assert!(filter.is_duplicate("a"));
+        std::thread::sleep(window * 2);
+        assert!(!filter.is_duplicate("a"));
+    }
+
+    #[tokio::test]
+    async fn subscriptions_with_a_window_get_a_message_sent_twice_once() {
+        let config = IpcConfig { shared_memory_name: "dedup".into(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        let messenger = MessengerImpl::new(Arc::new(IpcTransport::new(config, Box::new(BinarySerializer), PoolAllocator::new(16)).unwrap()));
+        let options = SubscriptionOptions { dedup: Some(DedupWindow::Count(16)), ..Default::default() };
+        let deduped = messenger.subscribe_with("t".into(), options).await.unwrap();
+        let plain = messenger.subscribe("t".into()).await.unwrap();
+
+        let message = Message::new("t".into(), vec![1]);
+        messenger.publish("t".into(), &message).await.unwrap();
+        messenger.publish("t".into(), &message).await.unwrap();
+        messenger.publish("t".into(), &Message::new("t".into(), vec![2])).await.unwrap();
+
+        assert_eq!(deduped.receive().await.unwrap().payload, vec![1]);
+        assert_eq!(deduped.receive().await.unwrap().payload, vec![2]);
+        // subscriptions without a window get every copy
+        for payload in [1, 1, 2] {
+            assert_eq!(plain.receive().await.unwrap().payload, vec![payload]);
+        }
+        assert_eq!(messenger.metrics().duplicates, 1);
+        messenger.cleanup().await.unwrap();
+    }
+}
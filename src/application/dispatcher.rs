@@ -0,0 +1,999 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
+
+use crate::application::chunking::Reassembler;
+use crate::application::config::{ChunkingConfig, TransactionConfig};
+use crate::application::consumer_group::ConsumerGroup;
+use crate::application::dedup::DedupFilter;
+use crate::application::dead_letter::{dead_letter, undecodable, DeadLetterSink, DeadLetterStore};
+use crate::application::messenger::MessageSubscriber;
+use crate::application::schema_registry::{InvalidMessagePolicy, SchemaRegistry};
+use crate::application::subscription::{AckSender, AckTracker, Delivery, Envelope, StartPosition, SubscriptionOptions};
+use crate::application::transaction::PendingTransactions;
+use crate::domain::errors::MessengerError;
+use crate::domain::filter::Filter;
+use crate::domain::headers::{CHUNK_ID, LOG_OFFSET, ORIGINAL_TOPIC, PARTITION, TXN_ID};
+use crate::domain::message::{Message, MessageView};
+use crate::domain::topic::{is_system_topic, partition_for, TopicPattern};
+use crate::infrastructure::storage::LogStore;
+use crate::infrastructure::transport::flow::FlowGrant;
+use crate::infrastructure::transport::Transport;
+use crate::utils::metrics::Metrics;
+
+// number of messages buffered per subscriber before the dispatcher waits on it
+pub(crate) const SUBSCRIBER_QUEUE_SIZE: usize = 1024;
+// number of records read from the durable log at a time when replaying
+const REPLAY_BATCH_SIZE: usize = 256;
+
+// everything subscribed to one topic
+#[derive(Default)]
+struct TopicSubscriptions {
+    // subscribers outside any group, each gets every message
+    subscribers: Vec<Subscriber>,
+    // consumer groups by name, each group gets every message once
+    groups: HashMap<String, Arc<ConsumerGroup>>,
+    // filters the transport was last told the subscriptions want, None for every message
+    interest: Option<Vec<String>>,
+}
+
+#[derive(Clone)]
+struct Subscriber {
+    sender: mpsc::Sender<Envelope>,
+    // credits granted to the remote sender for this subscriber, when the transport has flow control
+    flow: Option<Arc<FlowGrant>>,
+    // messages the subscriber takes, all of them when unset
+    filter: Option<Arc<Filter>>,
+}
+
+impl Subscriber {
+    fn wants(&self, message: &Message) -> bool {
+        self.filter.as_ref().is_none_or(|filter| filter.matches(message))
+    }
+}
+
+// the dispatcher drains the transport and fans every received message out to
+// the subscribers registered for its topic
+pub struct Dispatcher {
+    transport: Arc<dyn Transport>,
+    subscriptions: Mutex<HashMap<String, TopicSubscriptions>>,
+    // receive loop, started lazily on the first subscription
+    worker: Mutex<Option<JoinHandle<()>>>,
+    // schemas payloads are validated against before delivery
+    schemas: RwLock<Option<Arc<SchemaRegistry>>>,
+    // topic for frames that cannot be decoded, and so cannot be routed to a subscription,
+    // and for expired messages of subscriptions without a dead-letter topic of their own
+    dead_letter_topic: RwLock<Option<String>>,
+    // dead letters seen by this dispatcher, kept for inspection and replay
+    dead_letters: Arc<DeadLetterStore>,
+    // durable log that messages of logged topics are appended to on arrival
+    log: RwLock<Option<Arc<LogStore>>>,
+    // tasks feeding subscriptions that started from a position in the log
+    replays: Mutex<Vec<JoinHandle<()>>>,
+    // last retained message of every topic, only changed while `subscriptions` is locked
+    retained: Mutex<HashMap<String, Message>>,
+    // partition counts of partitioned topics, the most specific matching pattern wins
+    partitions: RwLock<Vec<(TopicPattern, u32)>>,
+    // chunks of messages that were too large for the transport, put back together before dispatch
+    chunks: Mutex<Reassembler>,
+    // messages of transactions that are still missing some of their messages
+    transactions: Mutex<PendingTransactions>,
+    // acknowledges messages to reliable publishers for every subscription
+    acks: AckSender,
+}
+
+impl Dispatcher {
+    pub fn new(transport: Arc<dyn Transport>) -> Self {
+        Self {
+            subscriptions: Mutex::new(HashMap::new()),
+            worker: Mutex::new(None),
+            schemas: RwLock::new(None),
+            dead_letter_topic: RwLock::new(None),
+            dead_letters: Arc::new(DeadLetterStore::default()),
+            log: RwLock::new(None),
+            replays: Mutex::new(Vec::new()),
+            retained: Mutex::new(HashMap::new()),
+            partitions: RwLock::new(Vec::new()),
+            chunks: Mutex::new(Reassembler::new(ChunkingConfig::default(), transport.metrics())),
+            transactions: Mutex::new(PendingTransactions::new(TransactionConfig::default(), transport.metrics())),
+            acks: AckSender::new(Arc::clone(&transport)),
+            transport,
+        }
+    }
+
+    pub fn set_chunking(&self, config: ChunkingConfig) {
+        self.chunks.lock().set_config(config);
+    }
+
+    pub fn set_transactions(&self, config: TransactionConfig) {
+        self.transactions.lock().set_config(config);
+    }
+
+    pub fn set_dead_letter_topic(&self, topic: String) {
+        *self.dead_letter_topic.write() = Some(topic);
+    }
+
+    pub fn dead_letters(&self) -> &Arc<DeadLetterStore> {
+        &self.dead_letters
+    }
+
+    pub fn set_schema_registry(&self, registry: Arc<SchemaRegistry>) {
+        *self.schemas.write() = Some(registry);
+    }
+
+    pub fn set_log(&self, log: Arc<LogStore>) {
+        *self.log.write() = Some(log);
+    }
+
+    // split the topics matching the pattern into `count` partitions by message key
+    pub fn set_partitions(&self, pattern: TopicPattern, count: u32) {
+        let mut partitions = self.partitions.write();
+        partitions.retain(|(existing, _)| *existing != pattern);
+        partitions.push((pattern, count.max(1)));
+    }
+
+    fn partition_count(&self, topic: &str) -> Option<u32> {
+        self.partitions
+            .read()
+            .iter()
+            .filter(|(pattern, _)| pattern.matches(topic))
+            .max_by_key(|(pattern, _)| pattern.specificity())
+            .map(|(_, count)| *count)
+    }
+
+    // the message currently retained for the topic
+    pub fn retained(&self, topic: &str) -> Option<Message> {
+        self.retained.lock().get(topic).filter(|message| !message.is_expired()).cloned()
+    }
+
+    // register a new subscriber for the topic and make sure the receive loop is running
+    // subscriptions starting in the past are fed from the durable log instead of the live fan-out,
+    // which keeps them gap-free since every message is logged before it is delivered
+    pub fn subscribe(self: &Arc<Self>, topic: &str, options: SubscriptionOptions) -> TopicSubscriber {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_QUEUE_SIZE);
+        let (requeue_tx, requeue_rx) = mpsc::unbounded_channel();
+        let store = match (options.start, self.log.read().clone()) {
+            (StartPosition::Latest, _) => None,
+            (start, _) if options.group.is_some() => {
+                log::warn!("consumer groups on topic '{}' start at the latest message instead of {:?}", topic, start);
+                None
+            }
+            (_, Some(store)) if store.is_logged(topic) => Some(store),
+            (start, _) => {
+                log::warn!("topic '{}' is not logged, subscribing at the latest message instead of {:?}", topic, start);
+                None
+            }
+        };
+        let mut group = None;
+        let mut flow = None;
+        let filter = options.filter.map(Arc::new);
+        match store {
+            Some(store) => {
+                let replay = tokio::spawn(replay(store, topic.to_string(), options.start, filter, tx));
+                let mut replays = self.replays.lock();
+                replays.retain(|replay| !replay.is_finished());
+                replays.push(replay);
+            }
+            None => {
+                let mut subscriptions = self.subscriptions.lock();
+                let subscriptions = subscriptions.entry(topic.to_string()).or_default();
+                // the queue is still empty, so the retained message always fits
+                let retained = self.retained(topic);
+                let send_retained = |tx: &mpsc::Sender<Envelope>, filter: Option<&Arc<Filter>>| {
+                    let retained = retained.filter(|message| filter.is_none_or(|filter| filter.matches(message)));
+                    if let Some(message) = retained {
+                        let _ = tx.try_send(Envelope { message, attempt: 1 });
+                    }
+                };
+                match &options.group {
+                    Some(name) => {
+                        let consumer_group = subscriptions.groups
+                            .entry(name.clone())
+                            .or_insert_with(|| Arc::new(ConsumerGroup::new(options.balance, filter.clone())))
+                            .clone();
+                        if consumer_group.balance() != options.balance {
+                            log::warn!("consumer group '{}' on topic '{}' keeps {:?} balancing", name, topic, consumer_group.balance());
+                        }
+                        let group_filter = consumer_group.filter().map(|filter| filter.as_str());
+                        if group_filter != filter.as_ref().map(|filter| filter.as_str()) {
+                            log::warn!("consumer group '{}' on topic '{}' keeps the filter {:?}", name, topic, group_filter);
+                        }
+                        // only a group without members has not seen the retained message yet
+                        if !consumer_group.prune() {
+                            send_retained(&tx, consumer_group.filter());
+                        }
+                        let member = consumer_group.join(tx);
+                        group = Some((consumer_group, member));
+                    }
+                    None => {
+                        send_retained(&tx, filter.as_ref());
+                        // the peer may only send as much as fits the subscriber's queue
+                        if !is_system_topic(topic) {
+                            flow = self.transport.open_flow(topic);
+                        }
+                        subscriptions.subscribers.push(Subscriber { sender: tx, flow: flow.clone(), filter });
+                    }
+                }
+                self.update_interest(topic, subscriptions);
+            }
+        }
+        self.ensure_running();
+        let sink = |topic| DeadLetterSink { topic, transport: Arc::clone(&self.transport) };
+        let dead_letter = options.dead_letter_topic.map(sink);
+        let expired = dead_letter.clone().or_else(|| self.dead_letter_topic.read().clone().map(sink));
+        let acks = self.acks.clone();
+        TopicSubscriber {
+            receiver: AsyncMutex::new((rx, requeue_rx)),
+            tracker: options.ack.map(|config| AckTracker::start(config, requeue_tx, dead_letter, acks.clone())),
+            group,
+            flow,
+            dedup: options.dedup.map(|window| Mutex::new(DedupFilter::new(window))),
+            topic: topic.to_string(),
+            metrics: self.transport.metrics(),
+            expired,
+            acks,
+        }
+    }
+
+    // stop the receive loop and drop every subscription, which closes the subscriber channels
+    pub fn shutdown(&self) {
+        if let Some(worker) = self.worker.lock().take() {
+            worker.abort();
+        }
+        for replay in self.replays.lock().drain(..) {
+            replay.abort();
+        }
+        // groups are also held by their members, so their senders have to be dropped explicitly
+        let mut subscriptions = self.subscriptions.lock();
+        for group in subscriptions.values().flat_map(|subscriptions| subscriptions.groups.values()) {
+            group.clear();
+        }
+        subscriptions.clear();
+        drop(subscriptions);
+        if let Some(store) = self.log.read().as_ref() {
+            store.sync_all();
+        }
+    }
+
+    // start the receive loop if it is not running yet
+    pub fn ensure_running(self: &Arc<Self>) {
+        let mut worker = self.worker.lock();
+        if worker.is_none() {
+            let dispatcher = Arc::clone(self);
+            *worker = Some(tokio::spawn(async move { dispatcher.run().await }));
+        }
+    }
+
+    async fn run(self: Arc<Self>) {
+        loop {
+            let frame = match self.transport.receive_frame().await {
+                Ok(frame) => frame,
+                Err(MessengerError::ChannelClosed) => break,
+                Err(e) => {
+                    log::warn!("dispatcher failed to receive message: {}", e);
+                    continue;
+                }
+            };
+            // route on the view, the message is only copied out of the frame once something takes it
+            let decoded = frame.view().map(|view| self.wanted(&view).then(|| view.to_owned()));
+            match decoded {
+                Ok(Some(message)) => self.dispatch(message).await,
+                Ok(None) => {}
+                Err(e) => self.dead_letter_frame(frame.as_bytes(), &e).await,
+            }
+        }
+    }
+
+    // whether a received message is taken by anything on this side, decided without copying it
+    // chunks and transaction members are held until complete, dead letters are kept, retained and
+    // logged messages are stored, and expired or invalid messages are dead-lettered
+    fn wanted(&self, view: &MessageView<'_>) -> bool {
+        let held = [CHUNK_ID, TXN_ID, ORIGINAL_TOPIC].iter().any(|name| view.header(name).is_some());
+        if held || view.retain || view.is_expired() {
+            return true;
+        }
+        if self.log.read().as_ref().is_some_and(|store| store.is_logged(&view.topic)) {
+            return true;
+        }
+        let validated = self.schemas.read().as_ref().is_some_and(|registry| {
+            registry.check().on_receive() && !is_system_topic(&view.topic) && registry.schema_for(&view.topic).is_some()
+        });
+        if validated {
+            return true;
+        }
+
+        let subscriptions = self.subscriptions.lock();
+        let subscriptions = match subscriptions.get(view.topic.as_ref()) {
+            Some(subscriptions) => subscriptions,
+            None => return false,
+        };
+        let wants = |filter: Option<&Arc<Filter>>| filter.is_none_or(|filter| filter.matches_view(view));
+        if subscriptions.subscribers.iter().any(|subscriber| wants(subscriber.filter.as_ref()))
+            || subscriptions.groups.values().any(|group| wants(group.filter()))
+        {
+            return true;
+        }
+        // the peer sent it on the credits of subscriptions that filter it out
+        for flow in subscriptions.subscribers.iter().filter_map(|subscriber| subscriber.flow.as_ref()) {
+            flow.received();
+        }
+        false
+    }
+
+    // a frame that cannot be decoded is dispatched here as a raw-bytes dead letter, so the
+    // dead-letter store keeps it whichever side sent it
+    async fn dead_letter_frame(&self, data: &[u8], error: &MessengerError) {
+        let topic = match self.dead_letter_topic.read().clone() {
+            Some(topic) => topic,
+            None => {
+                log::warn!("dropping undecodable frame: {}", error);
+                return;
+            }
+        };
+        self.dispatch(undecodable(data, &topic, error)).await;
+    }
+
+    async fn dispatch(&self, mut message: Message) {
+        if message.headers.contains_key(CHUNK_ID) {
+            let topic = message.topic.clone();
+            match self.chunks.lock().accept(message) {
+                Some(whole) => message = whole,
+                None => {
+                    self.credit(&topic);
+                    return;
+                }
+            }
+        }
+
+        // the messages of a transaction are held back until all of them arrived
+        let messages = if message.headers.contains_key(TXN_ID) {
+            let (accepted, dropped) = {
+                let mut transactions = self.transactions.lock();
+                (transactions.accept(message), transactions.take_dropped())
+            };
+            for topic in dropped {
+                self.credit(&topic);
+            }
+            match accepted {
+                Some(messages) => messages,
+                None => return,
+            }
+        } else {
+            vec![message]
+        };
+
+        // a transaction is delivered whole or not at all
+        for message in &messages {
+            if !self.admit(message).await {
+                if messages.len() > 1 {
+                    let id = message.headers.get(TXN_ID).map(String::as_str).unwrap_or_default();
+                    log::warn!("dropping transaction {}, its message {} was rejected", id, message.id);
+                }
+                for message in &messages {
+                    self.credit(&message.topic);
+                }
+                return;
+            }
+        }
+
+        let mut recorded = Vec::with_capacity(messages.len());
+        for message in messages {
+            let topic = message.topic.clone();
+            match self.record(message).await {
+                Some(message) => recorded.push(message),
+                None => self.credit(&topic),
+            }
+        }
+        self.deliver(recorded).await;
+    }
+
+    // drop expired and invalid messages, dead-lettering them as configured
+    async fn admit(&self, message: &Message) -> bool {
+        if message.is_expired() {
+            self.expire(message).await;
+            return false;
+        }
+        self.validate(message).await
+    }
+
+    // take note of a message about to be delivered: dead letters are kept, partitions assigned
+    // and logged topics appended to
+    // appends write, fsync and roll segments on a blocking thread, the loop still waits for them
+    // so the log keeps the order messages arrived in
+    async fn record(&self, mut message: Message) -> Option<Message> {
+        if message.headers.contains_key(ORIGINAL_TOPIC) {
+            self.dead_letters.record(message.clone());
+        }
+
+        // unkeyed messages are spread by id, they have no order to keep
+        if let Some(count) = self.partition_count(&message.topic) {
+            let partition = partition_for(message.key.as_deref().unwrap_or(&message.id), count);
+            message.headers.insert(PARTITION.to_string(), partition.to_string());
+        }
+
+        let store = self.log.read().clone();
+        if let Some(store) = store.filter(|store| store.is_logged(&message.topic)) {
+            let appended = tokio::task::spawn_blocking(move || (store.append(&message), message)).await;
+            message = match appended {
+                Ok((Ok(offset), mut message)) => {
+                    message.headers.insert(LOG_OFFSET.to_string(), offset.to_string());
+                    message
+                }
+                Ok((Err(e), message)) => {
+                    log::warn!("failed to log message {}: {}", message.id, e);
+                    message
+                }
+                Err(e) => {
+                    log::warn!("dropping a message, appending it to the log failed: {}", e);
+                    return None;
+                }
+            };
+        }
+        Some(message)
+    }
+
+    // fan messages out to their subscribers
+    // room for all of a subscriber's messages is reserved before any of them is queued, so the
+    // messages of a transaction show up together
+    async fn deliver(&self, messages: Vec<Message>) {
+        let mut by_topic: Vec<(&str, Vec<&Message>)> = Vec::new();
+        for message in &messages {
+            match by_topic.iter_mut().find(|(topic, _)| *topic == message.topic) {
+                Some((_, topic_messages)) => topic_messages.push(message),
+                None => by_topic.push((&message.topic, vec![message])),
+            }
+        }
+
+        // clone the senders out so the lock is not held while waiting on slow subscribers
+        // the retained message is swapped under the same lock, so a concurrent new subscriber
+        // gets either the retained copy or the live message, never both
+        let targets: Vec<_> = {
+            let subscriptions = self.subscriptions.lock();
+            let mut retained = self.retained.lock();
+            for message in messages.iter().filter(|message| message.retain) {
+                if message.payload.is_empty() {
+                    retained.remove(&message.topic);
+                } else {
+                    retained.insert(message.topic.clone(), message.clone());
+                }
+            }
+            by_topic
+                .into_iter()
+                .filter_map(|(topic, topic_messages)| {
+                    let subscriptions = subscriptions.get(topic)?;
+                    let groups: Vec<_> = subscriptions.groups.values().cloned().collect();
+                    Some((topic, subscriptions.subscribers.clone(), groups, topic_messages))
+                })
+                .collect()
+        };
+
+        let mut reserved = Vec::new();
+        for (_, subscribers, _, topic_messages) in &targets {
+            for subscriber in subscribers {
+                let (wanted, unwanted): (Vec<&Message>, Vec<&Message>) =
+                    topic_messages.iter().partition(|message| subscriber.wants(message));
+                // the peer sent what another subscription wanted on this one's credits too
+                if let Some(flow) = &subscriber.flow {
+                    unwanted.iter().for_each(|_| flow.received());
+                }
+                let count = wanted.len();
+                if count == 0 {
+                    continue;
+                }
+                let permits = match subscriber.flow.as_deref() {
+                    // cut off as a slow consumer, it is pruned below
+                    Some(flow) if flow.is_evicted() => continue,
+                    // the peer no longer waits for a degraded subscriber, so it may get more than it has room for
+                    Some(flow) if flow.is_degraded() => match subscriber.sender.try_reserve_many(count) {
+                        Ok(permits) => permits,
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            for _ in 0..count {
+                                self.transport.metrics().record_dropped();
+                            }
+                            continue;
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => continue,
+                    },
+                    // a failed reservation means the subscriber was dropped, it is pruned below
+                    _ => match subscriber.sender.reserve_many(count).await {
+                        Ok(permits) => permits,
+                        Err(_) => continue,
+                    },
+                };
+                reserved.push((permits, wanted));
+            }
+        }
+        for (permits, wanted) in reserved {
+            for (permit, message) in permits.zip(wanted) {
+                permit.send(Envelope { message: message.clone(), attempt: 1 });
+            }
+        }
+        for (_, _, groups, topic_messages) in &targets {
+            for message in topic_messages {
+                for group in groups {
+                    if group.filter().is_some_and(|filter| !filter.matches(message)) {
+                        continue;
+                    }
+                    // a group without members left is pruned below
+                    group.deliver(Envelope { message: (*message).clone(), attempt: 1 }).await;
+                }
+            }
+        }
+
+        let mut subscriptions = self.subscriptions.lock();
+        for (topic, _, _, _) in &targets {
+            if let Some(subscription) = subscriptions.get_mut(*topic) {
+                subscription.subscribers.retain(|subscriber| {
+                    !subscriber.sender.is_closed() && !subscriber.flow.as_ref().is_some_and(|flow| flow.is_evicted())
+                });
+                subscription.groups.retain(|_, group| group.prune());
+                if subscription.subscribers.is_empty() && subscription.groups.is_empty() {
+                    subscriptions.remove(*topic);
+                    self.transport.set_interest(topic, None);
+                } else {
+                    self.update_interest(topic, subscription);
+                }
+            }
+        }
+    }
+
+    // tell the transport which messages of the topic its subscriptions want, so the peer can
+    // leave out the others, a logged topic keeps every message
+    fn update_interest(&self, topic: &str, subscriptions: &mut TopicSubscriptions) {
+        let logged = self.log.read().as_ref().is_some_and(|store| store.is_logged(topic));
+        let filters = subscriptions.subscribers.iter().map(|subscriber| subscriber.filter.as_ref())
+            .chain(subscriptions.groups.values().map(|group| group.filter()))
+            .map(|filter| filter.cloned())
+            .collect::<Option<Vec<_>>>()
+            .filter(|_| !logged);
+        let interest = filters.as_ref().map(|filters| filters.iter().map(|filter| filter.as_str().to_string()).collect());
+        if interest != subscriptions.interest {
+            subscriptions.interest = interest;
+            let filters: Option<Vec<Filter>> = filters.map(|filters| filters.iter().map(|filter| Filter::clone(filter)).collect());
+            self.transport.set_interest(topic, filters.as_deref());
+        }
+    }
+
+    // grant back the flow credit of a received message no subscriber is going to take: every chunk
+    // but the one completing a message, and messages dropped before delivery
+    fn credit(&self, topic: &str) {
+        let subscriptions = self.subscriptions.lock();
+        let subscribers = subscriptions.get(topic).into_iter().flat_map(|topic| &topic.subscribers);
+        for flow in subscribers.filter_map(|subscriber| subscriber.flow.as_ref()) {
+            flow.received();
+        }
+    }
+
+    // count a message that expired before it could be delivered and dead-letter it
+    async fn expire(&self, message: &Message) {
+        self.transport.metrics().record_expired();
+        let topic = match self.dead_letter_topic.read().clone() {
+            Some(topic) => topic,
+            None => {
+                log::debug!("dropping expired message {}", message.id);
+                return;
+            }
+        };
+        if let Err(e) = self.transport.send(&dead_letter(&unexpired(message), &topic, "message expired", 1)).await {
+            log::warn!("failed to dead-letter expired message {}: {}", message.id, e);
+        }
+    }
+
+    // check the payload against the registered schema, invalid messages are dropped or dead-lettered
+    async fn validate(&self, message: &Message) -> bool {
+        let registry = match self.schemas.read().clone() {
+            Some(registry) if registry.check().on_receive() && !is_system_topic(&message.topic) => registry,
+            _ => return true,
+        };
+
+        let error = match registry.validate(self.transport.serializer().as_ref(), &message.topic, &message.payload) {
+            Ok(()) => return true,
+            Err(e) => e,
+        };
+
+        match registry.policy() {
+            InvalidMessagePolicy::Reject => log::warn!("dropping invalid message {}: {}", message.id, error),
+            InvalidMessagePolicy::DeadLetter(topic) => {
+                let dead_letter = dead_letter(message, topic, &error.to_string(), 1);
+                if let Err(e) = self.transport.send(&dead_letter).await {
+                    log::warn!("failed to dead-letter invalid message {}: {}", message.id, e);
+                }
+            }
+        }
+        false
+    }
+}
+
+// copy of an expired message without its expiry, so its dead letter is not dropped in turn
+fn unexpired(message: &Message) -> Message {
+    Message { expires_at: None, ..message.clone() }
+}
+
+// feed a subscriber from the topic's durable log, following the log as messages are appended
+async fn replay(store: Arc<LogStore>, topic: String, start: StartPosition, filter: Option<Arc<Filter>>, subscriber: mpsc::Sender<Envelope>) {
+    let from = match start {
+        StartPosition::Latest => store.next_offset(&topic),
+        StartPosition::Earliest => store.earliest_offset(&topic),
+        StartPosition::Offset(offset) => Ok(offset),
+        StartPosition::Timestamp(time) => store.offset_for_time(&topic, time),
+    };
+    let mut next = match from {
+        Ok(offset) => offset,
+        Err(e) => {
+            log::warn!("failed to find {:?} in the log of topic '{}': {}", start, topic, e);
+            return;
+        }
+    };
+
+    loop {
+        let records = tokio::select! {
+            records = store.tail(&topic, next, REPLAY_BATCH_SIZE) => records,
+            _ = subscriber.closed() => return,
+        };
+        let records = match records {
+            Ok(records) => records,
+            Err(e) => {
+                log::warn!("failed to read the log of topic '{}' at offset {}: {}", topic, next, e);
+                return;
+            }
+        };
+        // retention deleted what the subscriber had not read yet, it carries on at the earliest record left
+        if store.earliest_offset(&topic).is_ok_and(|earliest| earliest > next) {
+            log::warn!("subscriber of topic '{}' fell behind retention, skipping from offset {} to {}", topic, next, records[0].offset);
+        }
+        for record in records {
+            next = record.offset + 1;
+            let mut message = record.message;
+            message.headers.insert(LOG_OFFSET.to_string(), record.offset.to_string());
+            if filter.as_ref().is_some_and(|filter| !filter.matches(&message)) {
+                continue;
+            }
+            if subscriber.send(Envelope { message, attempt: 1 }).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+type Receivers = (mpsc::Receiver<Envelope>, mpsc::UnboundedReceiver<Envelope>);
+
+// resolves once the flow is evicted, never without one
+async fn slow_consumer(flow: Option<&FlowGrant>) {
+    match flow {
+        Some(flow) => flow.evicted().await,
+        None => std::future::pending().await,
+    }
+}
+
+// subscriber handed out by the dispatcher, yields the messages of a single topic
+pub struct TopicSubscriber {
+    // new messages from the dispatcher and redeliveries from the ack tracker
+    receiver: AsyncMutex<Receivers>,
+    // set when the subscription runs in ack mode
+    tracker: Option<Arc<AckTracker>>,
+    // set when the subscriber is a member of a consumer group, with its id in the group
+    group: Option<(Arc<ConsumerGroup>, u64)>,
+    // set when the transport has flow control, credits are granted back as messages are taken
+    flow: Option<Arc<FlowGrant>>,
+    // ids seen recently, set when the subscription drops duplicates
+    dedup: Option<Mutex<DedupFilter>>,
+    topic: String,
+    // counts the messages that expired while queued for this subscriber
+    metrics: Arc<Metrics>,
+    // where those expired messages are dead-lettered
+    expired: Option<DeadLetterSink>,
+    // acknowledges messages to reliable publishers, on taking them unless the ack tracker does
+    acks: AckSender,
+}
+
+// a group member that leaves hands its queued and unacknowledged messages back to the group,
+// which delivers them ahead of newer messages of their partitions
+impl Drop for TopicSubscriber {
+    fn drop(&mut self) {
+        let (group, member) = match self.group.take() {
+            Some(group) => group,
+            None => return,
+        };
+
+        group.leaving(member);
+        let (messages, redeliveries) = self.receiver.get_mut();
+        messages.close();
+        let mut orphans = match &self.tracker {
+            Some(tracker) => tracker.release(),
+            None => Vec::new(),
+        };
+        while let Ok(envelope) = redeliveries.try_recv() {
+            orphans.push(envelope);
+        }
+        while let Ok(envelope) = messages.try_recv() {
+            orphans.push(envelope);
+        }
+        let orphaned = !orphans.is_empty();
+        group.left(member, orphans);
+
+        // without a runtime they go out with the group's next delivery
+        if let (true, Ok(runtime)) = (orphaned, tokio::runtime::Handle::try_current()) {
+            runtime.spawn(async move { group.flush().await });
+        }
+    }
+}
+
+impl TopicSubscriber {
+    // wait for the next envelope, redeliveries go first, they have already waited a visibility timeout
+    // a subscriber cut off as a slow consumer still gets what was queued before
+    async fn next(&self, receiver: &mut Receivers) -> Result<Envelope, MessengerError> {
+        let (messages, redeliveries) = receiver;
+        tokio::select! {
+            biased;
+            Some(envelope) = redeliveries.recv() => Ok(envelope),
+            envelope = messages.recv() => envelope.ok_or_else(|| self.closed()),
+            _ = slow_consumer(self.flow.as_deref()) => Err(self.closed()),
+        }
+    }
+
+    // hand out a received envelope as a delivery, unless it went stale waiting in the queue
+    fn take(&self, envelope: Envelope) -> Option<Delivery> {
+        // redeliveries were counted the first time round
+        if let (Some(flow), 1) = (&self.flow, envelope.attempt) {
+            flow.received();
+        }
+        if let (Some((group, member)), 1) = (&self.group, envelope.attempt) {
+            group.taken(*member, &envelope.message);
+        }
+        // redeliveries share the id of the first delivery on purpose
+        let duplicate = envelope.attempt == 1 && !envelope.message.id.is_empty() && self.dedup.as_ref()
+            .is_some_and(|dedup| dedup.lock().is_duplicate(&envelope.message.id));
+        if duplicate {
+            self.metrics.record_duplicate();
+            log::debug!("dropping duplicate message {}", envelope.message.id);
+            // the publisher sent it again because the first ack got lost
+            if self.tracker.is_none() {
+                self.acks.ack(&envelope.message);
+            }
+            return None;
+        }
+
+        if envelope.message.is_expired() {
+            self.metrics.record_expired();
+            match &self.expired {
+                Some(sink) => sink.send(&unexpired(&envelope.message), "message expired", envelope.attempt),
+                None => log::debug!("dropping expired message {}", envelope.message.id),
+            }
+            self.acks.ack(&envelope.message);
+            return None;
+        }
+
+        Some(match &self.tracker {
+            Some(tracker) => tracker.track(envelope),
+            None => {
+                self.acks.ack(&envelope.message);
+                Delivery::untracked(envelope.message, envelope.attempt)
+            }
+        })
+    }
+
+    // why no more messages are coming
+    fn closed(&self) -> MessengerError {
+        match &self.flow {
+            Some(flow) if flow.is_evicted() => MessengerError::SlowConsumer(self.topic.clone()),
+            _ => MessengerError::ChannelClosed,
+        }
+    }
+}
+
+#[async_trait]
+impl MessageSubscriber for TopicSubscriber {
+    // in ack mode, messages taken through `receive` are acknowledged right away
+    async fn receive(&self) -> Result<Message, MessengerError> {
+        let delivery = self.receive_delivery().await?;
+        let message = delivery.message().clone();
+        delivery.ack();
+        Ok(message)
+    }
+
+    async fn receive_delivery(&self) -> Result<Delivery, MessengerError> {
+        loop {
+            let envelope = {
+                let mut receiver = self.receiver.lock().await;
+                self.next(&mut receiver).await?
+            };
+            if let Some(delivery) = self.take(envelope) {
+                return Ok(delivery);
+            }
+        }
+    }
+
+    // the receiver stays locked for the whole batch, so it is not interleaved with other receives
+    async fn receive_batch(&self, max: usize, timeout: Duration) -> Result<Vec<Message>, MessengerError> {
+        let deadline = Instant::now() + timeout;
+        let mut batch = Vec::new();
+        let mut receiver = match timeout_at(deadline, self.receiver.lock()).await {
+            Ok(receiver) => receiver,
+            Err(_) => return Ok(batch),
+        };
+        while batch.len() < max {
+            let envelope = if batch.is_empty() {
+                match timeout_at(deadline, self.next(&mut receiver)).await {
+                    Ok(envelope) => envelope?,
+                    Err(_) => break,
+                }
+            } else {
+                let (messages, redeliveries) = &mut *receiver;
+                match redeliveries.try_recv().or_else(|_| messages.try_recv()) {
+                    Ok(envelope) => envelope,
+                    Err(_) => break,
+                }
+            };
+            // like `receive`, messages taken in ack mode are acknowledged right away
+            if let Some(delivery) = self.take(envelope) {
+                let message = delivery.message().clone();
+                delivery.ack();
+                batch.push(message);
+            }
+        }
+        Ok(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::config::IpcConfig;
+    use crate::application::messenger::{Messenger, MessengerImpl};
+    use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+    use crate::infrastructure::serialization::binary::BinarySerializer;
+    use crate::infrastructure::transport::ipc::IpcTransport;
+
+    fn messenger() -> MessengerImpl {
+        let config = IpcConfig { shared_memory_name: "dispatch".into(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        MessengerImpl::new(Arc::new(IpcTransport::new(config, Box::new(BinarySerializer), PoolAllocator::new(16)).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn messages_are_routed_on_their_view() {
+        let messenger = messenger();
+        let options = SubscriptionOptions { filter: Some(Filter::parse("kind == 'keep'").unwrap()), ..Default::default() };
+        let subscriber = messenger.subscribe_with("t".into(), options).await.unwrap();
+        messenger.publish("other".into(), &Message::new("other".into(), vec![0])).await.unwrap();
+        messenger.publish("t".into(), &Message::new("t".into(), vec![1]).with_header("kind", "drop")).await.unwrap();
+        messenger.publish("t".into(), &Message::new("t".into(), vec![2]).with_header("kind", "keep")).await.unwrap();
+        assert_eq!(subscriber.receive().await.unwrap().payload, vec![2]);
+        messenger.cleanup().await.unwrap();
+    }
+
+    // wait for the dispatcher to take in what was published so far
+    async fn settle(done: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("the dispatcher did not catch up");
+    }
+
+    #[tokio::test]
+    async fn retained_messages_reach_later_subscribers_until_cleared() {
+        let messenger = messenger();
+        messenger.publish("state".into(), &Message::new("state".into(), vec![1]).retained()).await.unwrap();
+        messenger.publish("state".into(), &Message::new("state".into(), vec![2]).retained()).await.unwrap();
+        settle(|| messenger.retained("state").is_some_and(|message| message.payload == vec![2])).await;
+
+        let late = messenger.subscribe("state".into()).await.unwrap();
+        assert_eq!(late.receive().await.unwrap().payload, vec![2]);
+
+        // an empty retained message clears the topic's last value, and is still delivered live
+        messenger.publish("state".into(), &Message::new("state".into(), Vec::new()).retained()).await.unwrap();
+        assert!(late.receive().await.unwrap().payload.is_empty());
+        assert!(messenger.retained("state").is_none());
+        let later = messenger.subscribe("state".into()).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(50), later.receive()).await.is_err());
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keyed_messages_keep_to_their_partition_owner() {
+        let messenger = messenger().with_partitions("orders", 4);
+        let options = SubscriptionOptions { group: Some("workers".into()), ..Default::default() };
+        let first = messenger.subscribe_with("orders".into(), options.clone()).await.unwrap();
+        let second = messenger.subscribe_with("orders".into(), options).await.unwrap();
+        for _ in 0..2 {
+            for key in ["a", "b", "c", "d", "e", "f"] {
+                messenger.publish("orders".into(), &Message::new("orders".into(), vec![1]).with_key(key)).await.unwrap();
+            }
+        }
+
+        let mut owners = HashMap::new();
+        for (member, subscriber) in [&first, &second].into_iter().enumerate() {
+            while let Ok(Ok(message)) = tokio::time::timeout(Duration::from_millis(50), subscriber.receive()).await {
+                let key = message.key.clone().unwrap();
+                let partition = partition_for(&key, 4).to_string();
+                assert_eq!(message.header(PARTITION), Some(partition.as_str()));
+                assert_eq!(*owners.entry(key).or_insert(member), member);
+            }
+        }
+        assert_eq!(owners.len(), 6);
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_member_that_leaves_hands_its_partitions_over_in_order() {
+        let messenger = messenger().with_partitions("orders", 4);
+        let options = SubscriptionOptions { group: Some("workers".into()), ..Default::default() };
+        let first = messenger.subscribe_with("orders".into(), options.clone()).await.unwrap();
+        for sequence in 0..3 {
+            messenger.publish("orders".into(), &Message::new("orders".into(), vec![sequence]).with_key("a")).await.unwrap();
+        }
+        assert_eq!(first.receive().await.unwrap().payload, vec![0]);
+
+        // what the first member did not take goes to the second ahead of the messages published after it left
+        let second = messenger.subscribe_with("orders".into(), options).await.unwrap();
+        drop(first);
+        for sequence in 3..6 {
+            messenger.publish("orders".into(), &Message::new("orders".into(), vec![sequence]).with_key("a")).await.unwrap();
+        }
+        let mut received = Vec::new();
+        while received.len() < 5 {
+            let message = tokio::time::timeout(Duration::from_secs(1), second.receive()).await.unwrap().unwrap();
+            received.push(message.payload[0]);
+        }
+        assert_eq!(received, vec![1, 2, 3, 4, 5]);
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_messages_are_counted_and_dead_lettered_instead_of_delivered() {
+        let messenger = messenger().with_dead_letter_topic("dlq");
+        let subscriber = messenger.subscribe("alerts".into()).await.unwrap();
+        let dead_letters = messenger.subscribe("dlq".into()).await.unwrap();
+
+        let mut stale = Message::new("alerts".into(), vec![0]);
+        stale.expires_at = Some(1);
+        assert!(matches!(messenger.publish("alerts".into(), &stale).await, Err(MessengerError::Expired(_))));
+
+        // expires while it waits in the subscriber's queue
+        let short = Message::new("alerts".into(), vec![1]).with_ttl(Duration::from_millis(20));
+        messenger.publish("alerts".into(), &short).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        let fresh = Message::new("alerts".into(), vec![2]).with_ttl(Duration::from_secs(60));
+        messenger.publish("alerts".into(), &fresh).await.unwrap();
+
+        assert_eq!(subscriber.receive().await.unwrap().payload, vec![2]);
+        let dead_letter = dead_letters.receive().await.unwrap();
+        assert_eq!(dead_letter.header(ORIGINAL_TOPIC), Some("alerts"));
+        assert_eq!(dead_letter.payload, vec![1]);
+        assert!(!dead_letter.is_expired());
+        assert_eq!(messenger.metrics().expired, 2);
+        messenger.cleanup().await.unwrap();
+    }
+}
@@ -23,28 +23,71 @@
 // Authors: I. Zeqiri, E. Gjergji
 
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 
+use crate::application::dead_letter::{dead_letter, restore};
+use crate::application::dispatcher::Dispatcher;
+use crate::application::rpc::{self, RpcClient, RpcMethodOptions};
+use crate::application::rpc_stream::{self, RpcSink, RpcStream, StreamClient};
+use crate::application::chunking::split;
+use crate::application::config::{BackpressurePolicy, ChunkingConfig, LogConfig, TransactionConfig};
+use crate::application::outbox::Outbox;
+use crate::application::scheduler::Scheduler;
+use crate::application::schema_registry::{InvalidMessagePolicy, SchemaRegistry};
+use crate::application::subscription::{AckConfig, Delivery, SubscriptionOptions};
+use crate::application::transaction::Transaction;
 use crate::domain::message::Message;
 use crate::domain::errors::MessengerError;
+use crate::domain::topic::{is_system_topic, TopicPattern};
 use crate::infrastructure::serialization::Serializer;
+use crate::infrastructure::storage::LogStore;
 use crate::infrastructure::transport::Transport;
+use crate::utils::metrics::MetricsSnapshot;
+use crate::utils::zark_uid::generate_zark_uid;
 
 
 
 #[async_trait]
 pub trait Messenger: Send + Sync {
     async fn publish(&self, topic: String, payload: &Message) -> Result<(), MessengerError>;
+    // publish several messages at once, each on its own topic
+    async fn publish_batch(&self, messages: &[Message]) -> Result<(), MessengerError>;
     async fn subscribe(&self, topic: String) -> Result<Box<dyn MessageSubscriber>, MessengerError>;
+    async fn subscribe_with(&self, topic: String, options: SubscriptionOptions) -> Result<Box<dyn MessageSubscriber>, MessengerError>;
     async fn rpc_call(&self, method: &[u8], params: &[u8]) -> Result<Vec<u8>, MessengerError>;
     async fn register_rpc_handler(&self, method: &[u8], handler: Box<dyn RpcHandler>) -> Result<(), MessengerError>;
+    // call `method` and receive its answer as a stream of items
+    async fn rpc_server_stream(&self, method: &[u8], params: &[u8]) -> Result<RpcStream, MessengerError>;
+    // call `method` streaming items both ways
+    async fn rpc_bidi_stream(&self, method: &[u8], params: &[u8]) -> Result<(RpcSink, RpcStream), MessengerError>;
+    async fn register_stream_handler(&self, method: &[u8], handler: Box<dyn StreamHandler>) -> Result<(), MessengerError>;
     async fn cleanup(&self) -> Result<(), Box<dyn std::error::Error>>;
+    fn serializer(&self) -> Arc<dyn Serializer>;
 }
 
 #[async_trait]
 pub trait MessageSubscriber: Send + Sync {
     async fn receive(&self) -> Result<Message, MessengerError>;
+
+    // receive a message as a delivery that can be acknowledged
+    // subscribers without ack mode hand out deliveries whose ack/nack are no-ops
+    async fn receive_delivery(&self) -> Result<Delivery, MessengerError> {
+        Ok(Delivery::untracked(self.receive().await?, 1))
+    }
+
+    // wait up to `timeout` for a message, then take the ones already waiting, up to `max` in all
+    // an empty batch means nothing arrived in time
+    async fn receive_batch(&self, max: usize, timeout: Duration) -> Result<Vec<Message>, MessengerError> {
+        if max == 0 {
+            return Ok(Vec::new());
+        }
+        match tokio::time::timeout(timeout, self.receive()).await {
+            Ok(message) => Ok(vec![message?]),
+            Err(_) => Ok(Vec::new()),
+        }
+    }
 }
 
 #[async_trait]
@@ -52,40 +95,399 @@ pub trait RpcHandler: Send + Sync {
     async fn handle(&self, params: &[u8]) -> Result<Vec<u8>, MessengerError>;
 }
 
+// handler of streaming calls, `requests` ends right away for server-streaming calls
+// items go out on `responses`, which is finished once the handler returns Ok and failed with its error otherwise
+#[async_trait]
+pub trait StreamHandler: Send + Sync {
+    async fn handle(&self, params: &[u8], requests: RpcStream, responses: &RpcSink) -> Result<(), MessengerError>;
+}
+
 
 //implement messenger
+// every field is shared, so clones are cheap handles onto the same messenger
+#[derive(Clone)]
 pub struct MessengerImpl {
-    transport: Arc<dyn Transport>
+    transport: Arc<dyn Transport>,
+    dispatcher: Arc<Dispatcher>,
+    rpc: Arc<RpcClient>,
+    streams: Arc<StreamClient>,
+    schemas: Option<Arc<SchemaRegistry>>,
+    log: Option<Arc<LogStore>>,
+    scheduler: Arc<Scheduler>,
+    outbox: Arc<Outbox>,
 }
 
 impl MessengerImpl {
     pub fn new(transport: Arc<dyn Transport>) -> Self {
-        Self { transport }
+        let dispatcher = Arc::new(Dispatcher::new(transport.clone()));
+        Self {
+            transport,
+            dispatcher,
+            rpc: Arc::new(RpcClient::new()),
+            streams: Arc::new(StreamClient::new()),
+            schemas: None,
+            log: None,
+            scheduler: Arc::new(Scheduler::new()),
+            outbox: Arc::new(Outbox::default()),
+        }
+    }
+
+    // validate payloads against the registry's schemas
+    pub fn with_schema_registry(mut self, registry: Arc<SchemaRegistry>) -> Self {
+        self.dispatcher.set_schema_registry(registry.clone());
+        self.schemas = Some(registry);
+        self
+    }
+
+    // check a payload about to be published, dead-lettering it when the policy says so
+    async fn validate_outgoing(&self, message: &Message) -> Result<(), MessengerError> {
+        let registry = match &self.schemas {
+            Some(registry) if registry.check().on_publish() && !is_system_topic(&message.topic) => registry,
+            _ => return Ok(()),
+        };
+
+        let error = match registry.validate(self.transport.serializer().as_ref(), &message.topic, &message.payload) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        if let InvalidMessagePolicy::DeadLetter(topic) = registry.policy() {
+            self.transport.send(&dead_letter(message, topic, &error.to_string(), 1)).await?;
+        }
+        Err(error)
+    }
+
+    // keep the messages of logged topics in a durable on-disk log that subscriptions can start from
+    pub fn with_durable_log(mut self, config: LogConfig) -> Result<Self, MessengerError> {
+        let store = Arc::new(LogStore::open(config)?);
+        self.dispatcher.set_log(store.clone());
+        self.scheduler.set_log(store.clone())?;
+        self.log = Some(store);
+        // messages still pending from the last run are due without anyone scheduling again
+        if tokio::runtime::Handle::try_current().is_ok() {
+            self.start_scheduler();
+        }
+        Ok(self)
+    }
+
+    // publish the message once `delay` has passed, returning the id to cancel it by
+    pub async fn publish_delayed(&self, topic: String, message: &Message, delay: Duration) -> Result<String, MessengerError> {
+        self.publish_at(topic, message, SystemTime::now() + delay).await
+    }
+
+    // publish the message at `at`, returning the id to cancel it by
+    // a pending message with the same id is replaced, with a durable log pending messages survive restarts
+    pub async fn publish_at(&self, topic: String, msg: &Message, at: SystemTime) -> Result<String, MessengerError> {
+        let mut message = msg.clone();
+        message.topic = topic;
+        if message.id.is_empty() {
+            message.id = generate_zark_uid();
+        }
+        self.validate_outgoing(&message).await?;
+        let id = message.id.clone();
+        self.scheduler.schedule(message, at)?;
+        self.start_scheduler();
+        Ok(id)
+    }
+
+    // drop a message scheduled by `publish_delayed` or `publish_at` before it is published,
+    // returning whether it was still pending
+    pub fn cancel_scheduled(&self, id: &str) -> Result<bool, MessengerError> {
+        self.scheduler.cancel(id)
+    }
+
+    // number of scheduled messages not published yet
+    pub fn scheduled_count(&self) -> usize {
+        self.scheduler.len()
+    }
+
+    fn start_scheduler(&self) {
+        if !self.scheduler.is_empty() {
+            self.scheduler.ensure_running(Arc::new(self.clone()));
+        }
+    }
+
+    // earliest kept offset and next offset of a logged topic
+    pub fn log_offsets(&self, topic: &str) -> Result<(u64, u64), MessengerError> {
+        let store = self.log.as_ref()
+            .ok_or_else(|| MessengerError::ConfigError("no durable log configured".to_string()))?;
+        Ok((store.earliest_offset(topic)?, store.next_offset(topic)?))
+    }
+
+    // split the topics matching the pattern into `count` partitions by message key
+    // within a consumer group every partition is handled by a single member, in order
+    pub fn with_partitions(self, pattern: impl Into<String>, count: u32) -> Self {
+        self.dispatcher.set_partitions(TopicPattern::new(pattern), count);
+        self
+    }
+
+    // keep the messages published on the topics matching the pattern until a subscriber acknowledges
+    // them, resending them every visibility timeout until then, up to `max_redeliveries` times
+    // subscriptions in ack mode acknowledge on `ack`, others as they take the message
+    // the first ack from any subscriber releases a message, so with several subscribers only one
+    // of them is sure to get it, and a resend also reaches the others again unless they drop duplicates
+    pub fn with_reliable_delivery(self, pattern: impl Into<String>, config: AckConfig) -> Self {
+        self.outbox.set_reliable(TopicPattern::new(pattern), config);
+        self
+    }
+
+    // listen for acks and resend what stays unacknowledged, once
+    fn start_outbox(&self) {
+        if self.outbox.is_empty() || !self.outbox.start() {
+            return;
+        }
+        let acks = self.dispatcher.subscribe(self.outbox.ack_topic(), SubscriptionOptions::default());
+        let messenger = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(messenger.outbox.period());
+            loop {
+                tokio::select! {
+                    ack = acks.receive() => match ack {
+                        Ok(ack) => String::from_utf8_lossy(&ack.payload).lines().for_each(|id| messenger.outbox.forget(id)),
+                        Err(_) => return,
+                    },
+                    _ = interval.tick() => {
+                        for message in messenger.outbox.due() {
+                            log::debug!("resending unacknowledged message {}", message.id);
+                            if let Err(e) = messenger.send(&message).await {
+                                log::warn!("failed to resend message {}: {}", message.id, e);
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    // send a message, in chunks when it is too large for the transport
+    async fn send(&self, message: &Message) -> Result<(), MessengerError> {
+        match self.transport.send(message).await {
+            Err(MessengerError::MessageTooLarge(..)) => self.send_split(std::slice::from_ref(message)).await,
+            result => result,
+        }
+    }
+
+    // what the transport does with messages on the topics matching the pattern when it has no room,
+    // the most specific matching pattern wins and other topics block until there is room
+    pub fn with_backpressure(self, pattern: impl Into<String>, policy: BackpressurePolicy) -> Self {
+        self.transport.set_backpressure(TopicPattern::new(pattern), policy);
+        self
+    }
+
+    // how rpc_call spreads the calls of `method` over the handlers serving it, and whether a call
+    // whose handler went away may be retried on another one
+    pub fn with_rpc_method(self, method: &[u8], options: RpcMethodOptions) -> Self {
+        self.rpc.set_options(method, options);
+        self
+    }
+
+    // start a transaction, its messages are published together on `commit` and subscribers
+    // get either all of them or none
+    pub fn begin(&self) -> Transaction {
+        Transaction::new(self.clone())
+    }
+
+    // how long and with how much memory chunks of messages too large for the transport are
+    // waited for before the incomplete message is dropped
+    pub fn with_chunking(self, config: ChunkingConfig) -> Self {
+        self.dispatcher.set_chunking(config);
+        self
+    }
+
+    // how long and with how much memory the rest of a transaction is waited for before the
+    // incomplete transaction is dropped
+    pub fn with_transactions(self, config: TransactionConfig) -> Self {
+        self.dispatcher.set_transactions(config);
+        self
+    }
+
+    // split the messages too large for the transport into chunks, the receiving dispatcher
+    // puts them back together before delivery
+    fn split_oversized(&self, messages: &[Message]) -> Result<Vec<Message>, MessengerError> {
+        let serializer = self.transport.serializer();
+        let max_len = self.transport.max_message_size();
+        let mut split_messages = Vec::with_capacity(messages.len());
+        for message in messages {
+            if serializer.serialize(message)?.len() > max_len {
+                split_messages.extend(split(message, serializer.as_ref(), max_len)?);
+            } else {
+                split_messages.push(message.clone());
+            }
+        }
+        Ok(split_messages)
+    }
+
+    // send messages too large for the transport in chunks, one at a time, so a message larger
+    // than the transport's whole queue still gets through as the receiver works the queue off
+    async fn send_split(&self, messages: &[Message]) -> Result<(), MessengerError> {
+        for message in self.split_oversized(messages)? {
+            self.transport.send(&message).await?;
+        }
+        Ok(())
+    }
+
+    // counters of the messenger and its transport
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.transport.metrics().snapshot()
+    }
+
+    // message currently retained for the topic, delivered first to every new subscriber
+    pub fn retained(&self, topic: &str) -> Option<Message> {
+        self.dispatcher.retained(topic)
+    }
+
+    // compact the closed segments of a logged topic now, keeping only the newest message per key
+    pub fn compact_log(&self, topic: &str) -> Result<(), MessengerError> {
+        let store = self.log.as_ref()
+            .ok_or_else(|| MessengerError::ConfigError("no durable log configured".to_string()))?;
+        store.compact(topic)
+    }
+
+    // send frames that cannot be decoded to this topic instead of dropping them
+    pub fn with_dead_letter_topic(self, topic: impl Into<String>) -> Self {
+        let topic = topic.into();
+        self.transport.set_dead_letter_topic(&topic);
+        self.dispatcher.set_dead_letter_topic(topic);
+        self
+    }
+
+    // dead letters received on a dead-letter topic, oldest first
+    pub fn dead_letters(&self, dead_letter_topic: &str) -> Vec<Message> {
+        self.dispatcher.dead_letters().list(dead_letter_topic)
+    }
+
+    // republish one dead letter on its original topic
+    // dead letters of frames that could not be decoded have none, they stay in the store
+    pub async fn replay_dead_letter(&self, dead_letter_topic: &str, id: &str) -> Result<(), MessengerError> {
+        let store = self.dispatcher.dead_letters();
+        let dead = store.take(dead_letter_topic, id).ok_or(MessengerError::MessageNotFound)?;
+        let message = match restore(&dead) {
+            Ok(message) => message,
+            Err(e) => {
+                store.record(dead);
+                return Err(e);
+            }
+        };
+        self.publish(message.topic.clone(), &message).await
+    }
+
+    // republish every replayable dead letter of a topic, returning how many were replayed
+    pub async fn replay_dead_letters(&self, dead_letter_topic: &str) -> Result<usize, MessengerError> {
+        let store = self.dispatcher.dead_letters();
+        let mut count = 0;
+        for dead in store.take_all(dead_letter_topic) {
+            match restore(&dead) {
+                Ok(message) => {
+                    self.publish(message.topic.clone(), &message).await?;
+                    count += 1;
+                }
+                Err(_) => store.record(dead),
+            }
+        }
+        Ok(count)
     }
 }
 
 #[async_trait]
 impl Messenger for MessengerImpl {
     async fn publish(&self, topic: String, msg: &Message) -> Result<(), MessengerError> {
-        let message = Message::new(topic.clone(), msg.payload.clone());
-        self.transport.send(&message).await
+        // keep the caller's id and headers, only the topic is taken from the argument
+        let mut message = msg.clone();
+        message.topic = topic;
+        if message.id.is_empty() {
+            message.id = generate_zark_uid();
+        }
+        self.validate_outgoing(&message).await?;
+        // logged topics are appended and retained messages kept when the dispatcher takes
+        // messages in, so it has to be running even before anyone subscribes
+        if self.log.is_some() || message.retain {
+            self.dispatcher.ensure_running();
+        }
+        self.start_scheduler();
+        if self.outbox.track(&mut message) {
+            self.start_outbox();
+        }
+        let result = self.send(&message).await;
+        if result.is_err() {
+            // the caller sees the error and decides about sending again
+            self.outbox.forget(&message.id);
+        }
+        result
+    }
+
+    async fn publish_batch(&self, msgs: &[Message]) -> Result<(), MessengerError> {
+        let mut messages = Vec::with_capacity(msgs.len());
+        for msg in msgs {
+            let mut message = msg.clone();
+            if message.id.is_empty() {
+                message.id = generate_zark_uid();
+            }
+            self.validate_outgoing(&message).await?;
+            messages.push(message);
+        }
+        if self.log.is_some() || messages.iter().any(|message| message.retain) {
+            self.dispatcher.ensure_running();
+        }
+        self.start_scheduler();
+        let mut tracked = false;
+        for message in messages.iter_mut() {
+            tracked |= self.outbox.track(message);
+        }
+        if tracked {
+            self.start_outbox();
+        }
+        // the transports encode a whole batch before sending any of it, so a batch refused
+        // for a message that is too large was not sent at all
+        let result = match self.transport.send_batch(&messages).await {
+            Err(MessengerError::MessageTooLarge(..)) => self.send_split(&messages).await,
+            result => result,
+        };
+        if result.is_err() {
+            for message in &messages {
+                self.outbox.forget(&message.id);
+            }
+        }
+        result
     }
 
     async fn subscribe(&self, topic: String) -> Result<Box<dyn MessageSubscriber>, MessengerError> {
-        self.subscribe(topic).await
+        self.subscribe_with(topic, SubscriptionOptions::default()).await
+    }
+
+    async fn subscribe_with(&self, topic: String, options: SubscriptionOptions) -> Result<Box<dyn MessageSubscriber>, MessengerError> {
+        self.start_scheduler();
+        Ok(Box::new(self.dispatcher.subscribe(&topic, options)))
     }
 
     async fn rpc_call(&self, method: &[u8], params: &[u8]) -> Result<Vec<u8>, MessengerError> {
-        self.rpc_call(method, params).await
+        self.rpc.call(self, method, params).await
     }
     
     async fn register_rpc_handler(&self, method: &[u8], handler: Box<dyn RpcHandler>) -> Result<(), MessengerError> {
-        self.register_rpc_handler(method, handler).await
+        rpc::serve(self.clone(), method, Arc::from(handler)).await
+    }
+
+    async fn rpc_server_stream(&self, method: &[u8], params: &[u8]) -> Result<RpcStream, MessengerError> {
+        self.streams.server_stream(Arc::new(self.clone()), method, params).await
+    }
+
+    async fn rpc_bidi_stream(&self, method: &[u8], params: &[u8]) -> Result<(RpcSink, RpcStream), MessengerError> {
+        self.streams.bidi_stream(Arc::new(self.clone()), method, params).await
+    }
+
+    async fn register_stream_handler(&self, method: &[u8], handler: Box<dyn StreamHandler>) -> Result<(), MessengerError> {
+        rpc_stream::serve(self.clone(), method, Arc::from(handler)).await
     }
 
     async fn cleanup(&self) -> Result<(), Box<dyn std::error::Error>> {
-        // Add your cleanup logic here
+        self.scheduler.shutdown();
+        self.dispatcher.shutdown();
+        self.transport.cleanup().await?;
         Ok(())
     }
+
+    fn serializer(&self) -> Arc<dyn Serializer> {
+        self.transport.serializer()
+    }
 }
 
@@ -24,4 +24,17 @@
 
 pub mod messenger;
 pub mod config;
-pub mod instance_manager;
\ No newline at end of file
+pub mod instance_manager;
+pub mod dispatcher;
+pub mod typed;
+pub mod rpc;
+pub mod rpc_stream;
+pub mod service;
+pub mod schema_registry;
+pub mod subscription;
+pub mod dead_letter;
+pub mod consumer_group;
+pub mod scheduler;
+pub mod chunking;
+pub mod dedup;
+pub mod transaction;pub mod outbox;
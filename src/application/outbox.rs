@@ -0,0 +1,228 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, RwLock};
+
+use crate::application::subscription::AckConfig;
+use crate::domain::headers::ACK_TO;
+use crate::domain::message::Message;
+use crate::domain::topic::{is_system_topic, TopicPattern, SYSTEM_TOPIC_PREFIX};
+use crate::utils::zark_uid::generate_zark_uid;
+
+// a published message no subscriber has acknowledged yet
+struct Unacked {
+    message: Message,
+    // times the message was sent, including the first
+    attempt: u32,
+    deadline: Instant,
+    config: AckConfig,
+}
+
+// publisher side of at-least-once delivery
+// messages on reliable topics are tagged with the topic their acks go to and kept until one
+// comes back over the transport, so a subscriber process that dies before acknowledging
+// gets them again, or whichever process subscribes next
+// the publisher cannot tell how many subscribers a topic has on the other side, so the first ack
+// releases the message: delivery is at-least-once to one consumer, and a resend reaches every
+// subscriber, including ones that already acknowledged it
+pub(crate) struct Outbox {
+    // private topic subscribers send their acks to
+    ack_topic: String,
+    reliable: RwLock<Vec<(TopicPattern, AckConfig)>>,
+    unacked: Mutex<HashMap<String, Unacked>>,
+    // whether the ack listener has been started
+    started: AtomicBool,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self {
+            ack_topic: format!("{}ack.{}", SYSTEM_TOPIC_PREFIX, generate_zark_uid()),
+            reliable: RwLock::new(Vec::new()),
+            unacked: Mutex::new(HashMap::new()),
+            started: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Outbox {
+    pub fn ack_topic(&self) -> &str {
+        &self.ack_topic
+    }
+
+    // keep the messages on the topics matching the pattern until they are acknowledged
+    pub fn set_reliable(&self, pattern: TopicPattern, config: AckConfig) {
+        let mut reliable = self.reliable.write();
+        reliable.retain(|(existing, _)| *existing != pattern);
+        reliable.push((pattern, config));
+    }
+
+    // the most specific reliable pattern matching the topic decides
+    fn config(&self, topic: &str) -> Option<AckConfig> {
+        if is_system_topic(topic) {
+            return None;
+        }
+        self.reliable
+            .read()
+            .iter()
+            .filter(|(pattern, _)| pattern.matches(topic))
+            .max_by_key(|(pattern, _)| pattern.specificity())
+            .map(|(_, config)| config.clone())
+    }
+
+    // tag a message on a reliable topic and keep a copy until it is acknowledged,
+    // returns whether the message is kept
+    pub fn track(&self, message: &mut Message) -> bool {
+        let config = match self.config(&message.topic) {
+            Some(config) => config,
+            None => return false,
+        };
+        message.headers.insert(ACK_TO.to_string(), self.ack_topic.clone());
+        self.unacked.lock().insert(message.id.clone(), Unacked {
+            message: message.clone(),
+            attempt: 1,
+            deadline: Instant::now() + config.visibility_timeout,
+            config,
+        });
+        true
+    }
+
+    // stop keeping a message, once acknowledged or when it could not be sent in the first place
+    pub fn forget(&self, id: &str) {
+        self.unacked.lock().remove(id);
+    }
+
+    // messages whose ack is overdue and that are to be sent again
+    // ones sent max_redeliveries + 1 times or expired in the meantime are given up on
+    pub fn due(&self) -> Vec<Message> {
+        let now = Instant::now();
+        let mut unacked = self.unacked.lock();
+        let mut due = Vec::new();
+        unacked.retain(|id, entry| {
+            if entry.deadline > now {
+                return true;
+            }
+            if entry.message.is_expired() {
+                log::debug!("giving up on unacknowledged message {}, it expired", id);
+                return false;
+            }
+            if entry.attempt > entry.config.max_redeliveries {
+                log::warn!("giving up on message {} after {} unacknowledged deliveries", id, entry.attempt);
+                return false;
+            }
+            entry.attempt += 1;
+            entry.deadline = now + entry.config.visibility_timeout;
+            due.push(entry.message.clone());
+            true
+        });
+        due
+    }
+
+    // how often to look for overdue messages
+    pub fn period(&self) -> Duration {
+        self.reliable
+            .read()
+            .iter()
+            .map(|(_, config)| config.visibility_timeout / 4)
+            .min()
+            .unwrap_or(Duration::from_secs(1))
+            .max(Duration::from_millis(10))
+    }
+
+    // true only for the first caller, who starts the ack listener
+    pub fn start(&self) -> bool {
+        !self.started.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unacked.lock().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::application::config::IpcConfig;
+    use crate::application::messenger::{Messenger, MessengerImpl};
+    use crate::application::subscription::SubscriptionOptions;
+    use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+    use crate::infrastructure::serialization::json::JsonSerializer;
+    use crate::infrastructure::transport::ipc::IpcTransport;
+
+    fn config(visibility_timeout: Duration, max_redeliveries: u32) -> AckConfig {
+        AckConfig { visibility_timeout, max_redeliveries }
+    }
+
+    #[test]
+    fn gives_up_after_max_redeliveries() {
+        let outbox = Outbox::default();
+        outbox.set_reliable(TopicPattern::new("waf.#"), config(Duration::ZERO, 2));
+        let mut other = Message::new("other".into(), vec![1]);
+        assert!(!outbox.track(&mut other));
+        assert!(!other.headers.contains_key(ACK_TO));
+
+        let mut message = Message::new("waf.bans".into(), vec![1]);
+        message.id = "m".into();
+        assert!(outbox.track(&mut message));
+        assert_eq!(message.header(ACK_TO), Some(outbox.ack_topic()));
+        assert_eq!(outbox.due().len(), 1);
+        assert_eq!(outbox.due().len(), 1);
+        assert!(outbox.due().is_empty());
+        assert!(outbox.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_messages_are_resent_until_acked() {
+        let ipc = IpcConfig { shared_memory_name: "outbox".into(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        let transport = IpcTransport::new(ipc, Box::new(JsonSerializer), PoolAllocator::new(16)).unwrap();
+        let messenger = MessengerImpl::new(Arc::new(transport))
+            .with_reliable_delivery("orders", config(Duration::from_millis(50), 5));
+        let ack_mode = SubscriptionOptions { ack: Some(AckConfig::default()), ..Default::default() };
+
+        // the first subscriber goes away without acknowledging
+        let crashed = messenger.subscribe_with("orders".into(), ack_mode.clone()).await.unwrap();
+        let mut message = Message::new("orders".into(), vec![7]);
+        message.id = "order-1".into();
+        messenger.publish("orders".into(), &message).await.unwrap();
+        let delivery = crashed.receive_delivery().await.unwrap();
+        drop(delivery);
+        drop(crashed);
+
+        // the publisher sends it again to whoever subscribes next
+        let next = messenger.subscribe_with("orders".into(), ack_mode).await.unwrap();
+        let delivery = next.receive_delivery().await.unwrap();
+        assert_eq!(delivery.id, message.id);
+        assert_eq!(delivery.payload, vec![7]);
+        delivery.ack();
+
+        assert!(tokio::time::timeout(Duration::from_millis(200), next.receive_delivery()).await.is_err());
+        messenger.cleanup().await.unwrap();
+    }
+}
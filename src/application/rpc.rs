@@ -0,0 +1,578 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::sync::{oneshot, Notify};
+
+use crate::application::messenger::{Messenger, RpcHandler};
+use crate::application::typed::{decode_payload, encode_payload};
+use crate::domain::errors::MessengerError;
+use crate::domain::message::Message;
+use crate::domain::rpc_presence::RpcPresence;
+use crate::domain::rpc_request::RpcRequest;
+use crate::domain::rpc_response::RpcResponse;
+use crate::utils::zark_uid::generate_zark_uid;
+
+// every rpc topic is a system topic under this prefix
+pub const RPC_TOPIC_PREFIX: &str = "$zark.rpc.";
+// how long a caller waits for a response before giving up
+pub const RPC_TIMEOUT: Duration = Duration::from_secs(30);
+// handlers announce themselves on this topic so callers can spread calls over them
+pub const PRESENCE_TOPIC: &str = "$zark.rpc.presence";
+// how often a handler announces itself
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+// a handler not announced for this long is taken to be gone
+pub const HANDLER_TTL: Duration = Duration::from_secs(3);
+// how long the first call of a client waits for handlers to answer its discovery
+const DISCOVERY_WAIT: Duration = Duration::from_millis(200);
+
+// how rpc_call spreads the calls of a method over the handlers serving it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RpcBalance {
+    // handlers take turns
+    #[default]
+    RoundRobin,
+    // the handler with the fewest calls of this client waiting on it
+    LeastOutstanding,
+    Random,
+}
+
+// per-method call options
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RpcMethodOptions {
+    pub balance: RpcBalance,
+    // the method may run more than once for a call, so a call whose handler went away is
+    // retried on another one instead of failing
+    pub idempotent: bool,
+}
+
+// a handler some process announced for a method
+struct KnownHandler {
+    topic: String,
+    seen: Instant,
+    // calls of this process waiting on the handler
+    outstanding: usize,
+}
+
+#[derive(Default)]
+struct MethodHandlers {
+    handlers: Vec<KnownHandler>,
+    // where the next round-robin pick starts
+    next: usize,
+}
+
+// handlers announced on the presence topic, by method, shared by the callers of a process
+pub(crate) struct HandlerDirectory {
+    handlers: Mutex<HashMap<String, MethodHandlers>>,
+    // woken whenever a handler announces itself
+    announced: Notify,
+    // whether the presence listener has been started
+    listening: tokio::sync::Mutex<bool>,
+}
+
+impl HandlerDirectory {
+    pub(crate) fn new() -> Self {
+        Self {
+            handlers: Mutex::new(HashMap::new()),
+            announced: Notify::new(),
+            listening: tokio::sync::Mutex::new(false),
+        }
+    }
+
+    // make sure a live handler of `method` is known, asking every handler to announce itself
+    // and waiting DISCOVERY_WAIT for one otherwise
+    pub(crate) async fn discover(self: &Arc<Self>, messenger: &dyn Messenger, method: &str) -> Result<(), MessengerError> {
+        self.ensure_listening(messenger).await?;
+        if self.live_handlers(method) > 0 {
+            return Ok(());
+        }
+
+        publish_presence(messenger, &RpcPresence::Discover).await?;
+        let discovered = async {
+            loop {
+                let announced = self.announced.notified();
+                if self.live_handlers(method) > 0 {
+                    break;
+                }
+                announced.await;
+            }
+        };
+        match tokio::time::timeout(DISCOVERY_WAIT, discovered).await {
+            Ok(()) => Ok(()),
+            Err(_) => Err(MessengerError::NoRpcHandler(method.to_string())),
+        }
+    }
+
+    // pick a live handler of `method` not tried yet, counting the call as outstanding on it
+    pub(crate) fn pick(&self, method: &str, balance: RpcBalance, tried: &[String]) -> Option<String> {
+        let mut handlers = self.handlers.lock();
+        let method = handlers.get_mut(method)?;
+        method.handlers.retain(|handler| handler.seen.elapsed() < HANDLER_TTL);
+        let candidates: Vec<usize> = (0..method.handlers.len())
+            .filter(|&i| !tried.contains(&method.handlers[i].topic))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let start = method.next % candidates.len();
+        method.next = method.next.wrapping_add(1);
+        let index = match balance {
+            RpcBalance::RoundRobin => candidates[start],
+            // fewest outstanding calls, ties go to the round-robin order
+            RpcBalance::LeastOutstanding => (0..candidates.len())
+                .map(|i| candidates[(start + i) % candidates.len()])
+                .min_by_key(|&i| method.handlers[i].outstanding)?,
+            RpcBalance::Random => candidates[rand::thread_rng().gen_range(0..candidates.len())],
+        };
+        let handler = &mut method.handlers[index];
+        handler.outstanding += 1;
+        Some(handler.topic.clone())
+    }
+
+    pub(crate) fn finished(&self, method: &str, topic: &str) {
+        let mut handlers = self.handlers.lock();
+        let handler = handlers.get_mut(method)
+            .and_then(|method| method.handlers.iter_mut().find(|handler| handler.topic == topic));
+        if let Some(handler) = handler {
+            handler.outstanding = handler.outstanding.saturating_sub(1);
+        }
+    }
+
+    pub(crate) fn is_live(&self, method: &str, topic: &str) -> bool {
+        self.handlers.lock().get(method)
+            .is_some_and(|method| method.handlers.iter().any(|handler| handler.topic == topic && handler.seen.elapsed() < HANDLER_TTL))
+    }
+
+    pub(crate) fn live_handlers(&self, method: &str) -> usize {
+        self.handlers.lock().get(method)
+            .map_or(0, |method| method.handlers.iter().filter(|handler| handler.seen.elapsed() < HANDLER_TTL).count())
+    }
+
+    // subscribe to the presence topic once, keeping track of the handlers announced
+    async fn ensure_listening(self: &Arc<Self>, messenger: &dyn Messenger) -> Result<(), MessengerError> {
+        let mut listening = self.listening.lock().await;
+        if *listening {
+            return Ok(());
+        }
+
+        let presence = messenger.subscribe(PRESENCE_TOPIC.to_string()).await?;
+        let serializer = messenger.serializer();
+        let directory = Arc::clone(self);
+        tokio::spawn(async move {
+            while let Ok(message) = presence.receive().await {
+                match decode_payload::<RpcPresence>(serializer.as_ref(), PRESENCE_TOPIC, &message.payload) {
+                    Ok(RpcPresence::Announce { method, handler }) => {
+                        let mut handlers = directory.handlers.lock();
+                        let method = handlers.entry(method).or_default();
+                        match method.handlers.iter_mut().find(|known| known.topic == handler) {
+                            Some(known) => known.seen = Instant::now(),
+                            None => method.handlers.push(KnownHandler { topic: handler, seen: Instant::now(), outstanding: 0 }),
+                        }
+                        drop(handlers);
+                        directory.announced.notify_waiters();
+                    }
+                    Ok(RpcPresence::Discover) => {}
+                    Err(e) => log::warn!("dropping malformed rpc presence message: {}", e),
+                }
+            }
+        });
+
+        *listening = true;
+        Ok(())
+    }
+}
+
+// how one attempt of a call ended
+enum Attempt {
+    Answered(RpcResponse),
+    // the handler stopped announcing itself before answering
+    Gone,
+    TimedOut,
+}
+
+// caller side of rpc: publishes requests and routes responses back to the waiting calls
+pub struct RpcClient {
+    // private topic every response for this client is published to
+    reply_topic: String,
+    // request id -> waiting call
+    pending: Arc<Mutex<HashMap<String, oneshot::Sender<RpcResponse>>>>,
+    // handlers announced for every method
+    directory: Arc<HandlerDirectory>,
+    options: Mutex<HashMap<String, RpcMethodOptions>>,
+    // whether the reply listener has been started
+    listening: tokio::sync::Mutex<bool>,
+}
+
+impl Default for RpcClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RpcClient {
+    pub fn new() -> Self {
+        Self {
+            reply_topic: format!("{}reply.{}", RPC_TOPIC_PREFIX, generate_zark_uid()),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            directory: Arc::new(HandlerDirectory::new()),
+            options: Mutex::new(HashMap::new()),
+            listening: tokio::sync::Mutex::new(false),
+        }
+    }
+
+    // set how calls of `method` are spread over its handlers and whether they may be retried
+    pub fn set_options(&self, method: &[u8], options: RpcMethodOptions) {
+        self.options.lock().insert(String::from_utf8_lossy(method).into_owned(), options);
+    }
+
+    // publish a request for `method` to one of its handlers and wait for its response
+    // a request only ever goes to a single handler, so without one announced the call fails with `NoRpcHandler`
+    pub async fn call(&self, messenger: &dyn Messenger, method: &[u8], params: &[u8]) -> Result<Vec<u8>, MessengerError> {
+        self.ensure_listening(messenger).await?;
+        let name = String::from_utf8_lossy(method).into_owned();
+        self.directory.discover(messenger, &name).await?;
+
+        let options = self.options.lock().get(&name).copied().unwrap_or_default();
+        let deadline = tokio::time::Instant::now() + RPC_TIMEOUT;
+        let mut tried = Vec::new();
+        loop {
+            let gone = || MessengerError::RpcError(format!("handler of {} went away mid-call", name));
+            let handler = match self.directory.pick(&name, options.balance, &tried) {
+                Some(handler) => handler,
+                None if tried.is_empty() => return Err(MessengerError::NoRpcHandler(name)),
+                // every handler left was tried
+                None => return Err(gone()),
+            };
+            let attempt = self.attempt(messenger, &name, &handler, params, deadline).await;
+            self.directory.finished(&name, &handler);
+
+            let response = match attempt? {
+                Attempt::Answered(response) => response,
+                Attempt::Gone if options.idempotent => {
+                    log::warn!("handler of {} went away mid-call, retrying on another one", name);
+                    tried.push(handler);
+                    continue;
+                }
+                Attempt::Gone => return Err(gone()),
+                Attempt::TimedOut => return Err(MessengerError::RpcError(format!("call to {} timed out", name))),
+            };
+            return match response.error {
+                Some(error) => Err(MessengerError::RpcError(String::from_utf8_lossy(&error).into_owned())),
+                None => Ok(response.result.to_vec()),
+            };
+        }
+    }
+
+    // send the request to `handler` and wait for the response, watching that the handler stays alive
+    async fn attempt(
+        &self,
+        messenger: &dyn Messenger,
+        method: &str,
+        handler: &str,
+        params: &[u8],
+        deadline: tokio::time::Instant,
+    ) -> Result<Attempt, MessengerError> {
+        let request = RpcRequest::new(method.to_string(), params.to_vec(), self.reply_topic.clone());
+        let request_id = request.id().to_string();
+        let (tx, mut rx) = oneshot::channel();
+        self.pending.lock().insert(request_id.clone(), tx);
+
+        let payload = encode_payload(messenger.serializer().as_ref(), &request)?;
+        if let Err(e) = messenger.publish(handler.to_string(), &Message::new(handler.to_string(), payload)).await {
+            self.pending.lock().remove(&request_id);
+            return Err(e);
+        }
+
+        let mut check = tokio::time::interval(ANNOUNCE_INTERVAL);
+        let attempt = loop {
+            tokio::select! {
+                response = &mut rx => match response {
+                    Ok(response) => break Attempt::Answered(response),
+                    Err(_) => return Err(MessengerError::ChannelClosed),
+                },
+                _ = check.tick() => {
+                    if !self.directory.is_live(method, handler) {
+                        break Attempt::Gone;
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline) => break Attempt::TimedOut,
+            }
+        };
+        self.pending.lock().remove(&request_id);
+        Ok(attempt)
+    }
+
+    // subscribe to the reply topic once, routing every response to its pending call
+    async fn ensure_listening(&self, messenger: &dyn Messenger) -> Result<(), MessengerError> {
+        let mut listening = self.listening.lock().await;
+        if *listening {
+            return Ok(());
+        }
+
+        let subscriber = messenger.subscribe(self.reply_topic.clone()).await?;
+        let serializer = messenger.serializer();
+        let pending = Arc::clone(&self.pending);
+        let reply_topic = self.reply_topic.clone();
+        tokio::spawn(async move {
+            while let Ok(message) = subscriber.receive().await {
+                match decode_payload::<RpcResponse>(serializer.as_ref(), &reply_topic, &message.payload) {
+                    Ok(response) => {
+                        if let Some(call) = pending.lock().remove(&response.id) {
+                            let _ = call.send(response);
+                        }
+                    }
+                    Err(e) => log::warn!("dropping malformed rpc response: {}", e),
+                }
+            }
+        });
+
+        *listening = true;
+        Ok(())
+    }
+}
+
+async fn publish_presence(messenger: &dyn Messenger, presence: &RpcPresence) -> Result<(), MessengerError> {
+    let payload = encode_payload(messenger.serializer().as_ref(), presence)?;
+    messenger.publish(PRESENCE_TOPIC.to_string(), &Message::new(PRESENCE_TOPIC.to_string(), payload)).await
+}
+
+// serve requests for `method` with `handler`, every request is handled on its own task
+// the handler takes requests on a topic of its own, which it announces every ANNOUNCE_INTERVAL
+// so callers can spread their calls
+pub async fn serve<M>(messenger: M, method: &[u8], handler: Arc<dyn RpcHandler>) -> Result<(), MessengerError>
+where
+    M: Messenger + Clone + 'static,
+{
+    let topic = format!("{}handler.{}", RPC_TOPIC_PREFIX, generate_zark_uid());
+    let subscriber = messenger.subscribe(topic.clone()).await?;
+    announce(messenger.clone(), String::from_utf8_lossy(method).into_owned(), topic.clone()).await?;
+    tokio::spawn(async move {
+        while let Ok(message) = subscriber.receive().await {
+            let request: RpcRequest = match decode_payload(messenger.serializer().as_ref(), &topic, &message.payload) {
+                Ok(request) => request,
+                Err(e) => {
+                    log::warn!("dropping malformed rpc request: {}", e);
+                    continue;
+                }
+            };
+
+            let messenger = messenger.clone();
+            let handler = Arc::clone(&handler);
+            tokio::spawn(async move {
+                let response = match handler.handle(&request.params).await {
+                    Ok(result) => RpcResponse { id: request.id().to_string(), result: result.into(), error: None },
+                    Err(e) => RpcResponse { id: request.id().to_string(), result: Arc::from(Vec::new()), error: Some(e.to_string().into_bytes()) },
+                };
+                let published = match encode_payload(messenger.serializer().as_ref(), &response) {
+                    Ok(payload) => messenger.publish(request.reply_to.clone(), &Message::new(request.reply_to.clone(), payload)).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = published {
+                    log::warn!("failed to publish rpc response for {}: {}", request.method, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+// announce that `handler` takes calls of `method`, every ANNOUNCE_INTERVAL and whenever a caller asks
+pub(crate) async fn announce<M>(messenger: M, method: String, handler: String) -> Result<(), MessengerError>
+where
+    M: Messenger + 'static,
+{
+    let presence = messenger.subscribe(PRESENCE_TOPIC.to_string()).await?;
+    let announcement = RpcPresence::Announce { method, handler };
+    tokio::spawn(async move {
+        // the first tick is right away
+        let mut heartbeat = tokio::time::interval(ANNOUNCE_INTERVAL);
+        loop {
+            tokio::select! {
+                message = presence.receive() => {
+                    let Ok(message) = message else { break };
+                    if let Ok(RpcPresence::Discover) = decode_payload(messenger.serializer().as_ref(), PRESENCE_TOPIC, &message.payload) {
+                        publish_announcement(&messenger, &announcement).await;
+                    }
+                }
+                _ = heartbeat.tick() => publish_announcement(&messenger, &announcement).await,
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn publish_announcement<M: Messenger>(messenger: &M, announcement: &RpcPresence) {
+    if let Err(e) = publish_presence(messenger, announcement).await {
+        log::warn!("failed to announce rpc handler: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::config::IpcConfig;
+    use crate::application::messenger::MessengerImpl;
+    use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+    use crate::infrastructure::serialization::binary::BinarySerializer;
+    use crate::infrastructure::transport::ipc::IpcTransport;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn messenger(name: &str) -> MessengerImpl {
+        let config = IpcConfig { shared_memory_name: name.into(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        MessengerImpl::new(Arc::new(IpcTransport::new(config, Box::new(BinarySerializer), PoolAllocator::new(16)).unwrap()))
+    }
+
+    // answers with its own number, counting the calls it ran
+    struct Numbered {
+        number: u8,
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl RpcHandler for Numbered {
+        async fn handle(&self, _params: &[u8]) -> Result<Vec<u8>, MessengerError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![self.number])
+        }
+    }
+
+    async fn numbered(messenger: &MessengerImpl, count: u8) -> Vec<Arc<Numbered>> {
+        let mut handlers = Vec::new();
+        for number in 0..count {
+            let handler = Arc::new(Numbered { number, calls: AtomicUsize::new(0) });
+            serve(messenger.clone(), b"number", Arc::clone(&handler) as Arc<dyn RpcHandler>).await.unwrap();
+            handlers.push(handler);
+        }
+        handlers
+    }
+
+    // wait until `client` heard of `count` handlers of `method`
+    async fn discovered(client: &RpcClient, messenger: &MessengerImpl, method: &str, count: usize) {
+        client.directory.discover(messenger, method).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while client.directory.live_handlers(method) < count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    // make `client` believe in a handler of `method` nobody serves, which goes quiet right away
+    fn phantom(client: &RpcClient, method: &str) {
+        let topic = format!("{}handler.phantom", RPC_TOPIC_PREFIX);
+        let mut handlers = client.directory.handlers.lock();
+        let method = handlers.get_mut(method).unwrap();
+        let seen = Instant::now() - HANDLER_TTL + Duration::from_millis(100);
+        method.handlers.insert(0, KnownHandler { topic, seen, outstanding: 0 });
+        // the next round-robin pick is the phantom
+        method.next = 0;
+    }
+
+    #[tokio::test]
+    async fn calls_take_turns_over_the_handlers_and_run_once() {
+        let messenger = messenger("rpc_round_robin");
+        let handlers = numbered(&messenger, 3).await;
+        let client = RpcClient::new();
+        discovered(&client, &messenger, "number", 3).await;
+
+        let mut answers = Vec::new();
+        for _ in 0..6 {
+            answers.push(client.call(&messenger, b"number", &[]).await.unwrap()[0]);
+        }
+        answers.sort();
+        assert_eq!(answers, vec![0, 0, 1, 1, 2, 2]);
+        for handler in &handlers {
+            assert_eq!(handler.calls.load(Ordering::SeqCst), 2);
+        }
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[test]
+    fn least_outstanding_picks_the_handler_with_the_fewest_calls_waiting() {
+        let directory = HandlerDirectory::new();
+        let topics = ["a", "b", "c"].map(String::from);
+        directory.handlers.lock().entry("m".into()).or_default().handlers.extend(
+            topics.iter().map(|topic| KnownHandler { topic: topic.clone(), seen: Instant::now(), outstanding: 0 }),
+        );
+
+        let mut picked: Vec<String> = (0..3).map(|_| directory.pick("m", RpcBalance::LeastOutstanding, &[]).unwrap()).collect();
+        picked.sort();
+        assert_eq!(picked, topics);
+        directory.finished("m", "b");
+        assert_eq!(directory.pick("m", RpcBalance::LeastOutstanding, &[]).as_deref(), Some("b"));
+        // handlers already tried are skipped
+        directory.finished("m", "a");
+        let tried = vec!["a".to_string()];
+        assert_ne!(directory.pick("m", RpcBalance::LeastOutstanding, &tried).as_deref(), Some("a"));
+        assert_eq!(directory.pick("m", RpcBalance::Random, &topics), None);
+    }
+
+    #[tokio::test]
+    async fn idempotent_calls_are_retried_on_another_handler_when_theirs_goes_away() {
+        let messenger = messenger("rpc_failover");
+        let handlers = numbered(&messenger, 1).await;
+        let client = RpcClient::new();
+        client.set_options(b"number", RpcMethodOptions { idempotent: true, ..Default::default() });
+        discovered(&client, &messenger, "number", 1).await;
+        phantom(&client, "number");
+
+        assert_eq!(client.call(&messenger, b"number", &[]).await.unwrap(), vec![0]);
+        assert_eq!(handlers[0].calls.load(Ordering::SeqCst), 1);
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_calls_fail_when_their_handler_goes_away_and_run_nowhere_else() {
+        let messenger = messenger("rpc_no_failover");
+        let handlers = numbered(&messenger, 1).await;
+        let client = RpcClient::new();
+        discovered(&client, &messenger, "number", 1).await;
+        phantom(&client, "number");
+
+        let result = client.call(&messenger, b"number", &[]).await;
+        assert!(matches!(result, Err(MessengerError::RpcError(e)) if e.contains("went away")));
+        assert_eq!(handlers[0].calls.load(Ordering::SeqCst), 0);
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn calls_of_a_method_nobody_serves_fail_instead_of_going_anywhere() {
+        let messenger = messenger("rpc_unserved");
+        let handlers = numbered(&messenger, 1).await;
+        let client = RpcClient::new();
+
+        let result = client.call(&messenger, b"missing", &[]).await;
+        assert!(matches!(result, Err(MessengerError::NoRpcHandler(method)) if method == "missing"));
+        assert_eq!(handlers[0].calls.load(Ordering::SeqCst), 0);
+        messenger.cleanup().await.unwrap();
+    }
+}
@@ -0,0 +1,637 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::Instant;
+
+use crate::application::messenger::{Messenger, StreamHandler};
+use crate::application::rpc::{self, HandlerDirectory, RpcBalance, RPC_TIMEOUT, RPC_TOPIC_PREFIX};
+use crate::application::typed::{decode_payload, encode_payload};
+use crate::domain::errors::MessengerError;
+use crate::domain::message::Message;
+use crate::domain::rpc_frame::{StreamFrame, StreamFrameKind};
+use crate::utils::zark_uid::generate_zark_uid;
+
+// items a receiver takes before granting more, it grants them back half a window at a time
+pub const STREAM_WINDOW: u32 = 32;
+
+// streaming handlers of a method are announced as `stream.<method>`, apart from its unary handlers
+fn stream_method(method: &[u8]) -> String {
+    format!("stream.{}", String::from_utf8_lossy(method))
+}
+
+// private topic one side of a call takes its frames on
+fn inbox_topic() -> String {
+    format!("{}inbox.{}", RPC_TOPIC_PREFIX, generate_zark_uid())
+}
+
+type Item = Result<Vec<u8>, MessengerError>;
+type Routes = Arc<Mutex<HashMap<String, Route>>>;
+
+// state of one side of a call shared by its halves and the frame router
+struct Shared {
+    // inbox frames for the other side go to, and the only one frames of the call are taken from
+    peer: String,
+    // when the last frame of the other side arrived, items or not
+    heard: Mutex<Instant>,
+    // items the other side still takes
+    credits: Semaphore,
+    // this side ended, failed or cancelled its stream
+    done: AtomicBool,
+    // the other side ended, failed or cancelled its stream
+    peer_done: AtomicBool,
+    cancelled: AtomicBool,
+    // the call was cancelled before the other side's stream was over
+    cut: AtomicBool,
+}
+
+impl Shared {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.done.store(true, Ordering::SeqCst);
+        if !self.peer_done.swap(true, Ordering::SeqCst) {
+            self.cut.store(true, Ordering::SeqCst);
+        }
+        self.credits.close();
+    }
+}
+
+// where the frames of a call arriving on an inbox go
+struct Route {
+    // items for the receiving half, None once the other side's stream is over
+    items: Option<mpsc::Sender<Item>>,
+    shared: Arc<Shared>,
+}
+
+// hand a frame to the call it belongs to
+fn route(routes: &Routes, frame: StreamFrame) {
+    let mut routes = routes.lock();
+    let Some(route) = routes.get_mut(&frame.stream) else {
+        log::debug!("dropping rpc stream frame for unknown stream {}", frame.stream);
+        return;
+    };
+    // the stream id alone is not proof enough, the frame must come from the other side of the call
+    if route.shared.peer != frame.reply_to {
+        log::warn!("dropping rpc stream frame for stream {} from {}, the call is with {}", frame.stream, frame.reply_to, route.shared.peer);
+        return;
+    }
+    *route.shared.heard.lock() = Instant::now();
+    match frame.kind {
+        StreamFrameKind::Item(item) => {
+            if let Some(items) = &route.items {
+                if items.try_send(Ok(item)).is_err() {
+                    log::warn!("rpc stream {} sent more items than it had credits for, dropping one", frame.stream);
+                }
+            }
+        }
+        StreamFrameKind::Credit(credits) => route.shared.credits.add_permits(credits as usize),
+        StreamFrameKind::End => {
+            route.items = None;
+            route.shared.peer_done.store(true, Ordering::SeqCst);
+        }
+        StreamFrameKind::Error(error) => {
+            if let Some(items) = route.items.take() {
+                let _ = items.try_send(Err(MessengerError::RpcError(String::from_utf8_lossy(&error).into_owned())));
+            }
+            route.shared.peer_done.store(true, Ordering::SeqCst);
+        }
+        StreamFrameKind::Cancel => {
+            route.items = None;
+            route.shared.cancel();
+        }
+        StreamFrameKind::Open { .. } => log::warn!("dropping rpc stream open frame for existing stream {}", frame.stream),
+    }
+}
+
+// one side of a call, shared by its sending and receiving halves
+struct Link {
+    messenger: Arc<dyn Messenger>,
+    stream: String,
+    inbox: String,
+    shared: Arc<Shared>,
+    routes: Routes,
+}
+
+impl Link {
+    async fn send(&self, kind: StreamFrameKind) -> Result<(), MessengerError> {
+        send_frame(self.messenger.as_ref(), &self.shared.peer, StreamFrame { stream: self.stream.clone(), reply_to: self.inbox.clone(), kind }).await
+    }
+
+    async fn cancel(&self) -> Result<(), MessengerError> {
+        if self.shared.cancelled.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.shared.cancel();
+        self.routes.lock().remove(&self.stream);
+        self.send(StreamFrameKind::Cancel).await
+    }
+}
+
+// a call dropped before both streams were over is cancelled, so the other side stops too
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.routes.lock().remove(&self.stream);
+        let over = self.shared.done.load(Ordering::SeqCst) && self.shared.peer_done.load(Ordering::SeqCst);
+        if over || self.shared.cancelled.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let messenger = Arc::clone(&self.messenger);
+            let peer = self.shared.peer.clone();
+            let frame = StreamFrame { stream: self.stream.clone(), reply_to: self.inbox.clone(), kind: StreamFrameKind::Cancel };
+            runtime.spawn(async move {
+                let _ = send_frame(messenger.as_ref(), &peer, frame).await;
+            });
+        }
+    }
+}
+
+async fn send_frame(messenger: &dyn Messenger, topic: &str, frame: StreamFrame) -> Result<(), MessengerError> {
+    let payload = encode_payload(messenger.serializer().as_ref(), &frame)?;
+    messenger.publish(topic.to_string(), &Message::new(topic.to_string(), payload)).await
+}
+
+// register a call on an inbox, `credits` being the items the other side takes at first
+fn open(messenger: Arc<dyn Messenger>, stream: String, inbox: String, peer: String, routes: Routes, credits: u32) -> (RpcSink, RpcStream) {
+    let shared = Arc::new(Shared {
+        peer,
+        heard: Mutex::new(Instant::now()),
+        credits: Semaphore::new(credits as usize),
+        done: AtomicBool::new(false),
+        peer_done: AtomicBool::new(false),
+        cancelled: AtomicBool::new(false),
+        cut: AtomicBool::new(false),
+    });
+    // room for a full window and the error that may end it
+    let (tx, rx) = mpsc::channel(STREAM_WINDOW as usize + 1);
+    routes.lock().insert(stream.clone(), Route { items: Some(tx), shared: Arc::clone(&shared) });
+    let link = Arc::new(Link { messenger, stream, inbox, shared, routes });
+    (RpcSink { link: Arc::clone(&link) }, RpcStream { link, items: rx, consumed: 0, idle: RPC_TIMEOUT, deadline: None })
+}
+
+// sending half of a streaming call
+pub struct RpcSink {
+    link: Arc<Link>,
+}
+
+impl RpcSink {
+    // send an item, waiting while the other side has no room for it
+    pub async fn send(&self, item: &[u8]) -> Result<(), MessengerError> {
+        if self.link.shared.done.load(Ordering::SeqCst) && !self.link.shared.cancelled.load(Ordering::SeqCst) {
+            return Err(MessengerError::RpcError(format!("rpc stream {} is already finished", self.link.stream)));
+        }
+        let permit = self.link.shared.credits.acquire().await
+            .map_err(|_| MessengerError::StreamCancelled(self.link.stream.clone()))?;
+        permit.forget();
+        self.link.send(StreamFrameKind::Item(item.to_vec())).await
+    }
+
+    // end the stream, the other side gets every item sent so far and then its end
+    pub async fn finish(&self) -> Result<(), MessengerError> {
+        self.close(StreamFrameKind::End).await
+    }
+
+    // end the stream with an error the other side receives after the items sent so far
+    pub async fn fail(&self, error: &str) -> Result<(), MessengerError> {
+        self.close(StreamFrameKind::Error(error.as_bytes().to_vec())).await
+    }
+
+    // stop the call, both streams end with `StreamCancelled` on either side
+    pub async fn cancel(&self) -> Result<(), MessengerError> {
+        self.link.cancel().await
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.link.shared.cancelled.load(Ordering::SeqCst)
+    }
+
+    async fn close(&self, kind: StreamFrameKind) -> Result<(), MessengerError> {
+        if self.is_cancelled() {
+            return Err(MessengerError::StreamCancelled(self.link.stream.clone()));
+        }
+        if self.link.shared.done.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.link.send(kind).await
+    }
+}
+
+// receiving half of a streaming call
+pub struct RpcStream {
+    link: Arc<Link>,
+    items: mpsc::Receiver<Item>,
+    // items taken since credits were last granted back
+    consumed: u32,
+    // how long the other side may send nothing at all before the call fails
+    idle: Duration,
+    // when the call fails however busy the other side is, if ever
+    deadline: Option<Instant>,
+}
+
+impl RpcStream {
+    // fail the call once the other side sent no frame for `idle`, the rpc timeout by default
+    pub fn with_idle_timeout(mut self, idle: Duration) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    // fail the call once `timeout` from now has passed, even while items keep coming
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(Instant::now() + timeout);
+        self
+    }
+
+    // wait for the next item, None once the other side ended its stream
+    // an error frame from the other side comes back as `RpcError`, a cancelled call as `StreamCancelled`,
+    // and a call that went idle or passed its deadline is cancelled and fails with `RpcError`
+    pub async fn next(&mut self) -> Result<Option<Vec<u8>>, MessengerError> {
+        // items still queued when the call was cancelled are not handed out
+        if self.link.shared.cut.load(Ordering::SeqCst) {
+            return Err(MessengerError::StreamCancelled(self.link.stream.clone()));
+        }
+        let item = loop {
+            let idle_until = *self.link.shared.heard.lock() + self.idle;
+            let until = self.deadline.map_or(idle_until, |deadline| deadline.min(idle_until));
+            match tokio::time::timeout_at(until, self.items.recv()).await {
+                Ok(Some(item)) => break item?,
+                Ok(None) if self.link.shared.cut.load(Ordering::SeqCst) => {
+                    return Err(MessengerError::StreamCancelled(self.link.stream.clone()));
+                }
+                Ok(None) => return Ok(None),
+                Err(_) => {}
+            }
+
+            let now = Instant::now();
+            let reason = if self.deadline.is_some_and(|deadline| deadline <= now) {
+                "passed its deadline"
+            } else if *self.link.shared.heard.lock() + self.idle <= now {
+                "went idle"
+            } else {
+                // credits or other frames kept the call alive
+                continue;
+            };
+            let _ = self.link.cancel().await;
+            return Err(MessengerError::RpcError(format!("rpc stream {} {}", self.link.stream, reason)));
+        };
+
+        self.consumed += 1;
+        if self.consumed >= STREAM_WINDOW / 2 {
+            if let Err(e) = self.link.send(StreamFrameKind::Credit(self.consumed)).await {
+                log::warn!("failed to grant credits on rpc stream {}: {}", self.link.stream, e);
+            }
+            self.consumed = 0;
+        }
+        Ok(Some(item))
+    }
+
+    // stop the call, both streams end with `StreamCancelled` on either side
+    pub async fn cancel(&self) -> Result<(), MessengerError> {
+        self.link.cancel().await
+    }
+}
+
+// caller side of streaming rpc: opens calls and routes the frames of every call back to it
+pub struct StreamClient {
+    // private topic the frames of every call of this client arrive on
+    inbox: String,
+    routes: Routes,
+    // handlers announced for every method, each call is opened on one of them
+    directory: Arc<HandlerDirectory>,
+    // whether the inbox listener has been started
+    listening: tokio::sync::Mutex<bool>,
+}
+
+impl Default for StreamClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamClient {
+    pub fn new() -> Self {
+        Self {
+            inbox: inbox_topic(),
+            routes: Arc::new(Mutex::new(HashMap::new())),
+            directory: Arc::new(HandlerDirectory::new()),
+            listening: tokio::sync::Mutex::new(false),
+        }
+    }
+
+    // open a call of `method`, the handler answers with a stream of items
+    pub async fn server_stream(&self, messenger: Arc<dyn Messenger>, method: &[u8], params: &[u8]) -> Result<RpcStream, MessengerError> {
+        let (sink, stream) = self.open(messenger, method, params).await?;
+        // the caller has nothing to stream
+        sink.finish().await?;
+        Ok(stream)
+    }
+
+    // open a call of `method` streaming items both ways
+    // the caller may send once the handler granted its first credits
+    pub async fn bidi_stream(&self, messenger: Arc<dyn Messenger>, method: &[u8], params: &[u8]) -> Result<(RpcSink, RpcStream), MessengerError> {
+        self.open(messenger, method, params).await
+    }
+
+    async fn open(&self, messenger: Arc<dyn Messenger>, method: &[u8], params: &[u8]) -> Result<(RpcSink, RpcStream), MessengerError> {
+        self.ensure_listening(messenger.as_ref()).await?;
+        let name = stream_method(method);
+        self.directory.discover(messenger.as_ref(), &name).await?;
+        // a stream is opened on a single handler, taking turns, and not counted against it once open
+        let handler = self.directory.pick(&name, RpcBalance::RoundRobin, &[])
+            .ok_or_else(|| MessengerError::NoRpcHandler(name.clone()))?;
+        self.directory.finished(&name, &handler);
+
+        let stream = generate_zark_uid();
+        let (sink, receiver) = open(messenger, stream, self.inbox.clone(), handler, Arc::clone(&self.routes), 0);
+        let open = StreamFrameKind::Open { method: String::from_utf8_lossy(method).into_owned(), params: params.to_vec(), credits: STREAM_WINDOW };
+        if let Err(e) = sink.link.send(open).await {
+            // nothing reached the handler, so there is nobody to cancel
+            sink.link.shared.cancel();
+            return Err(e);
+        }
+        Ok((sink, receiver))
+    }
+
+    // subscribe to the inbox once and route every frame to its call
+    async fn ensure_listening(&self, messenger: &dyn Messenger) -> Result<(), MessengerError> {
+        let mut listening = self.listening.lock().await;
+        if *listening {
+            return Ok(());
+        }
+
+        let subscriber = messenger.subscribe(self.inbox.clone()).await?;
+        let serializer = messenger.serializer();
+        let routes = Arc::clone(&self.routes);
+        let inbox = self.inbox.clone();
+        tokio::spawn(async move {
+            while let Ok(message) = subscriber.receive().await {
+                match decode_payload::<StreamFrame>(serializer.as_ref(), &inbox, &message.payload) {
+                    Ok(frame) => route(&routes, frame),
+                    Err(e) => log::warn!("dropping malformed rpc stream frame: {}", e),
+                }
+            }
+        });
+
+        *listening = true;
+        Ok(())
+    }
+}
+
+// serve streaming calls of `method` with `handler`, every call is handled on its own task
+// the handler announces its inbox so every call is opened on it alone, and its stream
+// is finished when it returns Ok, and failed with its error otherwise
+pub async fn serve<M>(messenger: M, method: &[u8], handler: Arc<dyn StreamHandler>) -> Result<(), MessengerError>
+where
+    M: Messenger + Clone + 'static,
+{
+    let inbox = inbox_topic();
+    let frames = messenger.subscribe(inbox.clone()).await?;
+    rpc::announce(messenger.clone(), stream_method(method), inbox.clone()).await?;
+    let messenger: Arc<dyn Messenger> = Arc::new(messenger);
+    let routes: Routes = Arc::new(Mutex::new(HashMap::new()));
+    tokio::spawn(async move {
+        while let Ok(message) = frames.receive().await {
+            let frame: StreamFrame = match decode_payload(messenger.serializer().as_ref(), &inbox, &message.payload) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    log::warn!("dropping malformed rpc stream frame: {}", e);
+                    continue;
+                }
+            };
+
+            let StreamFrameKind::Open { method, params, credits } = frame.kind else {
+                route(&routes, frame);
+                continue;
+            };
+            let (sink, requests) = open(Arc::clone(&messenger), frame.stream, inbox.clone(), frame.reply_to, Arc::clone(&routes), credits);
+            let handler = Arc::clone(&handler);
+            tokio::spawn(async move {
+                // the first credits also tell the caller where the call's frames go
+                if let Err(e) = sink.link.send(StreamFrameKind::Credit(STREAM_WINDOW)).await {
+                    log::warn!("failed to answer rpc stream call to {}: {}", method, e);
+                    return;
+                }
+                let closed = match handler.handle(&params, requests, &sink).await {
+                    Ok(()) => sink.finish().await,
+                    Err(e) => sink.fail(&e.to_string()).await,
+                };
+                match closed {
+                    Ok(()) | Err(MessengerError::StreamCancelled(_)) => {}
+                    Err(e) => log::warn!("failed to end rpc stream call to {}: {}", method, e),
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::config::IpcConfig;
+    use crate::application::messenger::MessengerImpl;
+    use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+    use crate::infrastructure::serialization::binary::BinarySerializer;
+    use crate::infrastructure::transport::ipc::IpcTransport;
+    use async_trait::async_trait;
+    use std::sync::atomic::AtomicUsize;
+
+    fn messenger(name: &str) -> MessengerImpl {
+        let config = IpcConfig { shared_memory_name: name.into(), max_message_size: 4096, max_queue_size: 1000, max_buffer_size: 4096 };
+        MessengerImpl::new(Arc::new(IpcTransport::new(config, Box::new(BinarySerializer), PoolAllocator::new(16)).unwrap()))
+    }
+
+    // answers with `params[0]` items counting up, and fails when asked for none
+    struct Count {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl StreamHandler for Count {
+        async fn handle(&self, params: &[u8], _requests: RpcStream, responses: &RpcSink) -> Result<(), MessengerError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if params[0] == 0 {
+                return Err(MessengerError::RpcError("nothing to count".into()));
+            }
+            for item in 0..params[0] {
+                responses.send(&[item]).await?;
+            }
+            Ok(())
+        }
+    }
+
+    // sends every item it takes back
+    struct Echo;
+
+    #[async_trait]
+    impl StreamHandler for Echo {
+        async fn handle(&self, _params: &[u8], mut requests: RpcStream, responses: &RpcSink) -> Result<(), MessengerError> {
+            while let Some(item) = requests.next().await? {
+                responses.send(&item).await?;
+            }
+            Ok(())
+        }
+    }
+
+    // sends items until the call is cancelled, then says so
+    struct Endless {
+        stopped: mpsc::Sender<MessengerError>,
+    }
+
+    #[async_trait]
+    impl StreamHandler for Endless {
+        async fn handle(&self, _params: &[u8], _requests: RpcStream, responses: &RpcSink) -> Result<(), MessengerError> {
+            loop {
+                if let Err(e) = responses.send(&[1]).await {
+                    let _ = self.stopped.send(e).await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn collect(mut stream: RpcStream) -> Result<Vec<u8>, MessengerError> {
+        let mut items = Vec::new();
+        while let Some(item) = stream.next().await? {
+            items.extend(item);
+        }
+        Ok(items)
+    }
+
+    #[tokio::test]
+    async fn every_call_is_served_by_a_single_handler() {
+        let messenger = messenger("rpc_stream_single");
+        let handlers: Vec<Arc<Count>> = (0..2).map(|_| Arc::new(Count { calls: AtomicUsize::new(0) })).collect();
+        for handler in &handlers {
+            serve(messenger.clone(), b"count", Arc::clone(handler) as Arc<dyn StreamHandler>).await.unwrap();
+        }
+
+        for calls in 1..=4 {
+            let stream = messenger.rpc_server_stream(b"count", &[3]).await.unwrap();
+            assert_eq!(collect(stream).await.unwrap(), vec![0, 1, 2]);
+            let served: usize = handlers.iter().map(|handler| handler.calls.load(Ordering::SeqCst)).sum();
+            assert_eq!(served, calls);
+        }
+        // the handlers took turns
+        for handler in &handlers {
+            assert_eq!(handler.calls.load(Ordering::SeqCst), 2);
+        }
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn items_stream_both_ways_past_the_credit_window() {
+        let messenger = messenger("rpc_stream_bidi");
+        messenger.register_stream_handler(b"echo", Box::new(Echo)).await.unwrap();
+
+        let (sink, stream) = messenger.rpc_bidi_stream(b"echo", &[]).await.unwrap();
+        let items = STREAM_WINDOW * 3;
+        let sending = tokio::spawn(async move {
+            for item in 0..items {
+                sink.send(&[item as u8]).await.unwrap();
+            }
+            sink.finish().await.unwrap();
+        });
+        let echoed = collect(stream).await.unwrap();
+        sending.await.unwrap();
+        assert_eq!(echoed, (0..items).map(|item| item as u8).collect::<Vec<_>>());
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn handler_errors_end_the_stream_after_its_items() {
+        let messenger = messenger("rpc_stream_error");
+        messenger.register_stream_handler(b"count", Box::new(Count { calls: AtomicUsize::new(0) })).await.unwrap();
+
+        let result = collect(messenger.rpc_server_stream(b"count", &[0]).await.unwrap()).await;
+        assert!(matches!(result, Err(MessengerError::RpcError(e)) if e.contains("nothing to count")));
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_call_stops_its_handler() {
+        let messenger = messenger("rpc_stream_cancel");
+        let (stopped, mut stops) = mpsc::channel(1);
+        messenger.register_stream_handler(b"endless", Box::new(Endless { stopped })).await.unwrap();
+
+        let mut stream = messenger.rpc_server_stream(b"endless", &[]).await.unwrap();
+        assert_eq!(stream.next().await.unwrap(), Some(vec![1]));
+        stream.cancel().await.unwrap();
+        assert!(matches!(stream.next().await, Err(MessengerError::StreamCancelled(_))));
+
+        let stop = tokio::time::timeout(Duration::from_secs(5), stops.recv()).await.unwrap();
+        assert!(matches!(stop, Some(MessengerError::StreamCancelled(_))));
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn calls_fail_once_past_their_deadline_or_idle() {
+        let messenger = messenger("rpc_stream_deadline");
+        let (stopped, mut stops) = mpsc::channel(1);
+        messenger.register_stream_handler(b"endless", Box::new(Endless { stopped })).await.unwrap();
+        messenger.register_stream_handler(b"echo", Box::new(Echo)).await.unwrap();
+
+        // items that keep coming do not keep a call alive past its deadline
+        let stream = messenger.rpc_server_stream(b"endless", &[]).await.unwrap();
+        let result = collect(stream.with_timeout(Duration::from_millis(200))).await;
+        assert!(matches!(result, Err(MessengerError::RpcError(e)) if e.ends_with("passed its deadline")));
+        let stop = tokio::time::timeout(Duration::from_secs(5), stops.recv()).await.unwrap();
+        assert!(matches!(stop, Some(MessengerError::StreamCancelled(_))));
+
+        // a handler waiting on the caller sends nothing back
+        let (_sink, stream) = messenger.rpc_bidi_stream(b"echo", &[]).await.unwrap();
+        let mut stream = stream.with_idle_timeout(Duration::from_millis(200));
+        let result = stream.next().await;
+        assert!(matches!(result, Err(MessengerError::RpcError(e)) if e.ends_with("went idle")));
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn frames_from_anyone_but_the_other_side_are_dropped() {
+        let messenger = messenger("rpc_stream_forged");
+        messenger.register_stream_handler(b"echo", Box::new(Echo)).await.unwrap();
+
+        let (sink, mut stream) = messenger.rpc_bidi_stream(b"echo", &[]).await.unwrap();
+        let forged = StreamFrame { stream: stream.link.stream.clone(), reply_to: inbox_topic(), kind: StreamFrameKind::Item(vec![0]) };
+        send_frame(&messenger, &stream.link.inbox, forged).await.unwrap();
+        sink.send(&[1]).await.unwrap();
+        sink.finish().await.unwrap();
+        assert_eq!(stream.next().await.unwrap(), Some(vec![1]));
+        assert_eq!(stream.next().await.unwrap(), None);
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn calls_of_a_method_nobody_serves_fail() {
+        let messenger = messenger("rpc_stream_unserved");
+        let result = messenger.rpc_server_stream(b"missing", &[]).await;
+        assert!(matches!(result, Err(MessengerError::NoRpcHandler(method)) if method == "stream.missing"));
+        messenger.cleanup().await.unwrap();
+    }
+}
@@ -0,0 +1,441 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::application::messenger::Messenger;
+use crate::domain::errors::MessengerError;
+use crate::domain::headers::DELIVER_AT;
+use crate::domain::message::Message;
+use crate::domain::serializable::Serializable;
+use crate::domain::topic::SCHEDULE_TOPIC;
+use crate::infrastructure::storage::LogStore;
+use crate::utils::clock::{now_millis, to_millis};
+
+// granularity of the timer wheel, scheduled messages fire up to one tick late
+pub const TICK: Duration = Duration::from_millis(10);
+// slots of the timer wheel, messages due further out than one turn stay in their slot for later turns
+const WHEEL_SLOTS: usize = 512;
+// number of records read at a time when recovering the schedule from the durable log
+const RECOVERY_BATCH_SIZE: usize = 256;
+
+// first tick at or after a point in time, so nothing fires early
+fn tick_of(millis: u64) -> u64 {
+    millis.div_ceil(TICK.as_millis() as u64)
+}
+
+// last tick that has fully started by now
+fn current_tick() -> u64 {
+    now_millis() / TICK.as_millis() as u64
+}
+
+struct Entry {
+    id: String,
+    due_tick: u64,
+    message: Message,
+}
+
+// hashed timer wheel, a slot holds every entry due on a tick congruent to it
+struct TimerWheel {
+    slots: Vec<Vec<Entry>>,
+    // last tick the wheel advanced to
+    current: u64,
+    // id -> due tick of every pending entry, to find its slot on cancel
+    due: HashMap<String, u64>,
+    // due tick -> number of entries due on it, the first one is when the worker wakes next
+    ticks: BTreeMap<u64, usize>,
+}
+
+impl TimerWheel {
+    fn new(current: u64) -> Self {
+        Self {
+            slots: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            current,
+            due: HashMap::new(),
+            ticks: BTreeMap::new(),
+        }
+    }
+
+    // add an entry, replacing a pending one with the same id
+    // entries already due fire on the next tick
+    fn insert(&mut self, id: String, due_tick: u64, message: Message) {
+        self.remove(&id);
+        let due_tick = due_tick.max(self.current + 1);
+        self.due.insert(id.clone(), due_tick);
+        *self.ticks.entry(due_tick).or_default() += 1;
+        self.slots[due_tick as usize % WHEEL_SLOTS].push(Entry { id, due_tick, message });
+    }
+
+    fn remove(&mut self, id: &str) -> bool {
+        match self.due.remove(id) {
+            Some(due_tick) => {
+                self.slots[due_tick as usize % WHEEL_SLOTS].retain(|entry| entry.id != id);
+                self.untick(due_tick);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // move the wheel to tick `now`, taking every entry that became due, oldest first
+    // a wheel that fell a whole turn behind visits every slot once
+    fn advance(&mut self, now: u64) -> Vec<Entry> {
+        let steps = now.saturating_sub(self.current).min(WHEEL_SLOTS as u64);
+        let mut due = Vec::new();
+        for step in 1..=steps {
+            let slot = &mut self.slots[(self.current + step) as usize % WHEEL_SLOTS];
+            let mut index = 0;
+            while index < slot.len() {
+                if slot[index].due_tick <= now {
+                    due.push(slot.swap_remove(index));
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        self.current = self.current.max(now);
+        for entry in &due {
+            self.due.remove(&entry.id);
+            self.untick(entry.due_tick);
+        }
+        due.sort_by_key(|entry| entry.due_tick);
+        due
+    }
+
+    fn untick(&mut self, due_tick: u64) {
+        if let Some(count) = self.ticks.get_mut(&due_tick) {
+            *count -= 1;
+            if *count == 0 {
+                self.ticks.remove(&due_tick);
+            }
+        }
+    }
+
+    // earliest tick an entry is due on
+    fn next_due(&self) -> Option<u64> {
+        self.ticks.keys().next().copied()
+    }
+
+    fn len(&self) -> usize {
+        self.due.len()
+    }
+}
+
+// holds messages published for later and publishes them once they are due
+// with a durable log, pending messages are kept in its SCHEDULE_TOPIC and survive restarts
+pub struct Scheduler {
+    wheel: Mutex<TimerWheel>,
+    log: RwLock<Option<Arc<LogStore>>>,
+    // woken on every new entry, the worker waits on it while the wheel is empty
+    scheduled: Notify,
+    // task firing due messages, started on first use
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            wheel: Mutex::new(TimerWheel::new(current_tick())),
+            log: RwLock::new(None),
+            scheduled: Notify::new(),
+            worker: Mutex::new(None),
+        }
+    }
+
+    // persist the schedule in the durable log, picking up the messages still pending in it
+    // messages scheduled before the log was set are written to it as well
+    pub fn set_log(&self, store: Arc<LogStore>) -> Result<(), MessengerError> {
+        let mut pending: HashMap<String, Message> = HashMap::new();
+        let mut next = store.earliest_offset(SCHEDULE_TOPIC)?;
+        loop {
+            let records = store.read(SCHEDULE_TOPIC, next, RECOVERY_BATCH_SIZE)?;
+            let last = match records.last() {
+                Some(record) => record.offset,
+                None => break,
+            };
+            for record in records {
+                let id = match &record.message.key {
+                    Some(id) => id.clone(),
+                    None => continue,
+                };
+                if record.message.is_tombstone() {
+                    pending.remove(&id);
+                } else {
+                    pending.insert(id, record.message);
+                }
+            }
+            next = last + 1;
+        }
+
+        // the log is set under the wheel lock so every message scheduled from here on is appended by
+        // `schedule`, the ones already waiting are written out after the lock is released
+        let waiting: Vec<_> = {
+            let wheel = self.wheel.lock();
+            *self.log.write() = Some(Arc::clone(&store));
+            wheel.slots.iter().flatten().map(|entry| (entry.id.clone(), entry.due_tick, entry.message.clone())).collect()
+        };
+        for (id, due_tick, message) in &waiting {
+            store.append(&schedule_record(id, Some(message), due_tick * TICK.as_millis() as u64)?)?;
+        }
+        // those that fired or were cancelled meanwhile may have been forgotten before they were written
+        for (id, _, _) in &waiting {
+            if !self.wheel.lock().due.contains_key(id) {
+                self.forget(id)?;
+            }
+        }
+
+        let mut wheel = self.wheel.lock();
+        for (id, record) in pending {
+            let due = record.header(DELIVER_AT).and_then(|due| due.parse::<u64>().ok()).unwrap_or(0);
+            match Message::deserialize(&record.payload) {
+                Ok(message) if !wheel.due.contains_key(&id) => wheel.insert(id, tick_of(due), message),
+                Ok(_) => {}
+                Err(e) => log::warn!("dropping unreadable scheduled message {}: {}", id, e),
+            }
+        }
+        drop(wheel);
+        self.scheduled.notify_one();
+        Ok(())
+    }
+
+    // hold the message until `at`, replacing a pending message with the same id
+    // the entry goes into the wheel first so `set_log` either writes it out or has set the log by now
+    pub fn schedule(&self, message: Message, at: SystemTime) -> Result<(), MessengerError> {
+        let due = to_millis(at);
+        let record = schedule_record(&message.id, Some(&message), due)?;
+        let id = message.id.clone();
+        self.wheel.lock().insert(id.clone(), tick_of(due), message);
+        let store = self.log.read().clone();
+        if let Some(store) = store {
+            if let Err(e) = store.append(&record) {
+                self.wheel.lock().remove(&id);
+                return Err(e);
+            }
+            // it may have fired before its record was written
+            if !self.wheel.lock().due.contains_key(&id) {
+                self.forget(&id)?;
+            }
+        }
+        self.scheduled.notify_one();
+        Ok(())
+    }
+
+    // drop a pending message, returning whether it was still pending
+    pub fn cancel(&self, id: &str) -> Result<bool, MessengerError> {
+        if !self.wheel.lock().remove(id) {
+            return Ok(false);
+        }
+        self.forget(id)?;
+        Ok(true)
+    }
+
+    // number of messages waiting to be published
+    pub fn len(&self) -> usize {
+        self.wheel.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // start the worker if it is not running yet, it publishes due messages through `messenger`
+    pub fn ensure_running(self: &Arc<Self>, messenger: Arc<dyn Messenger>) {
+        let mut worker = self.worker.lock();
+        if worker.is_none() {
+            *worker = Some(tokio::spawn(Arc::clone(self).run(messenger)));
+        }
+    }
+
+    // stop the worker, pending messages stay in the durable log for the next start
+    pub fn shutdown(&self) {
+        if let Some(worker) = self.worker.lock().take() {
+            worker.abort();
+        }
+    }
+
+    // sleeps until the earliest entry is due, or until a new entry may be due before that
+    async fn run(self: Arc<Self>, messenger: Arc<dyn Messenger>) {
+        loop {
+            // registered before checking so an entry added in between is not missed
+            let scheduled = self.scheduled.notified();
+            let next = self.wheel.lock().next_due();
+            let due_at = match next {
+                Some(tick) => tick * TICK.as_millis() as u64,
+                None => {
+                    scheduled.await;
+                    continue;
+                }
+            };
+            let wait = due_at.saturating_sub(now_millis());
+            if wait > 0 {
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_millis(wait)) => {}
+                    _ = scheduled => continue,
+                }
+            }
+
+            let due = self.wheel.lock().advance(current_tick());
+            for entry in due {
+                if let Err(e) = messenger.publish(entry.message.topic.clone(), &entry.message).await {
+                    log::warn!("failed to publish scheduled message {}: {}", entry.id, e);
+                }
+                if let Err(e) = self.forget(&entry.id) {
+                    log::warn!("failed to remove scheduled message {} from the log: {}", entry.id, e);
+                }
+            }
+        }
+    }
+
+    // tombstone a message that fired or was cancelled in the durable log
+    fn forget(&self, id: &str) -> Result<(), MessengerError> {
+        if let Some(store) = self.log.read().as_ref() {
+            store.append(&schedule_record(id, None, 0)?)?;
+        }
+        Ok(())
+    }
+}
+
+// log record of a scheduled message, keyed by its id so compaction keeps only the latest state
+// a pending message is stored whole with its due time, a tombstone marks it as done
+fn schedule_record(id: &str, message: Option<&Message>, due: u64) -> Result<Message, MessengerError> {
+    let record = match message {
+        Some(message) => Message::new(SCHEDULE_TOPIC.to_string(), message.serialize()?)
+            .with_header(DELIVER_AT, due.to_string()),
+        None => Message::new(SCHEDULE_TOPIC.to_string(), Vec::new()),
+    };
+    Ok(record.with_key(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::application::config::{FsyncPolicy, IpcConfig, LogConfig};
+    use crate::application::messenger::MessengerImpl;
+    use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+    use crate::infrastructure::serialization::json::JsonSerializer;
+    use crate::infrastructure::transport::ipc::IpcTransport;
+    use crate::utils::zark_uid::generate_zark_uid;
+
+    fn message(id: &str) -> Message {
+        let mut message = Message::new("t".into(), id.as_bytes().to_vec());
+        message.id = id.into();
+        message
+    }
+
+    fn ids(entries: Vec<Entry>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.id).collect()
+    }
+
+    #[test]
+    fn the_wheel_fires_entries_in_due_order_across_turns() {
+        let mut wheel = TimerWheel::new(100);
+        wheel.insert("late".into(), 100 + WHEEL_SLOTS as u64 + 5, message("late"));
+        wheel.insert("b".into(), 110, message("b"));
+        wheel.insert("a".into(), 105, message("a"));
+        wheel.insert("overdue".into(), 50, message("overdue"));
+        wheel.insert("cancelled".into(), 107, message("cancelled"));
+        assert!(wheel.remove("cancelled"));
+        assert!(!wheel.remove("cancelled"));
+        assert_eq!(wheel.next_due(), Some(101));
+
+        assert_eq!(ids(wheel.advance(101)), vec!["overdue"]);
+        assert_eq!(ids(wheel.advance(120)), vec!["a", "b"]);
+        assert_eq!(wheel.next_due(), Some(100 + WHEEL_SLOTS as u64 + 5));
+        // a full turn later the slot comes round again, only now is the late entry due
+        assert!(wheel.advance(100 + WHEEL_SLOTS as u64).is_empty());
+        assert_eq!(ids(wheel.advance(100 + 2 * WHEEL_SLOTS as u64)), vec!["late"]);
+        assert_eq!(wheel.len(), 0);
+        assert_eq!(wheel.next_due(), None);
+    }
+
+    fn log_config(directory: PathBuf) -> LogConfig {
+        LogConfig {
+            directory,
+            topics: vec!["nothing".into()],
+            segment_bytes: 1024 * 1024,
+            retention_bytes: None,
+            retention_secs: None,
+            fsync: FsyncPolicy::Never,
+            compact_topics: Vec::new(),
+            tombstone_retention_secs: 0,
+        }
+    }
+
+    #[test]
+    fn pending_messages_are_recovered_from_the_log() {
+        let directory = std::env::temp_dir().join(format!("zark-scheduler-{}", generate_zark_uid()));
+        let later = SystemTime::now() + Duration::from_secs(3600);
+
+        let scheduler = Scheduler::new();
+        // scheduled before the log is set, written out by `set_log`
+        scheduler.schedule(message("early"), later).unwrap();
+        scheduler.set_log(Arc::new(LogStore::open(log_config(directory.clone())).unwrap())).unwrap();
+        scheduler.schedule(message("kept"), later).unwrap();
+        scheduler.schedule(message("cancelled"), later).unwrap();
+        assert!(scheduler.cancel("cancelled").unwrap());
+        drop(scheduler);
+
+        let recovered = Scheduler::new();
+        recovered.set_log(Arc::new(LogStore::open(log_config(directory.clone())).unwrap())).unwrap();
+        let mut pending: Vec<_> = recovered.wheel.lock().due.keys().cloned().collect();
+        pending.sort();
+        assert_eq!(pending, vec!["early", "kept"]);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_worker_sleeps_until_the_next_message_is_due() {
+        let config = IpcConfig { shared_memory_name: "scheduler".into(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        let transport = IpcTransport::new(config, Box::new(JsonSerializer), PoolAllocator::new(16)).unwrap();
+        let messenger = MessengerImpl::new(Arc::new(transport));
+        let subscriber = messenger.subscribe("t".into()).await.unwrap();
+
+        let start = Instant::now();
+        messenger.publish_delayed("t".into(), &message("second"), Duration::from_millis(80)).await.unwrap();
+        messenger.publish_delayed("t".into(), &message("first"), Duration::from_millis(40)).await.unwrap();
+        assert_eq!(messenger.scheduled_count(), 2);
+
+        assert_eq!(subscriber.receive().await.unwrap().id, "first");
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(subscriber.receive().await.unwrap().id, "second");
+        assert!(start.elapsed() >= Duration::from_millis(80));
+        assert_eq!(messenger.scheduled_count(), 0);
+        messenger.cleanup().await.unwrap();
+    }
+}
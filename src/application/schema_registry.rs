@@ -0,0 +1,321 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::application::messenger::Messenger;
+use crate::domain::errors::MessengerError;
+use crate::domain::message::Message;
+use crate::domain::schema::JsonSchema;
+use crate::domain::topic::TopicPattern;
+use crate::infrastructure::serialization::Serializer;
+use crate::utils::zark_uid::generate_zark_uid;
+
+// schema queries are published on this topic, the registry answers on the topic each query names
+pub const SCHEMA_QUERY_TOPIC: &str = "$zark.schema.query";
+// how long fetch_schema and list_schemas wait for a registry to answer
+pub const SCHEMA_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// where payloads are validated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaCheck {
+    // reject invalid payloads before they are sent
+    OnPublish,
+    // filter invalid payloads before they are delivered to subscribers
+    OnReceive,
+    Both,
+}
+
+impl SchemaCheck {
+    pub fn on_publish(self) -> bool {
+        matches!(self, SchemaCheck::OnPublish | SchemaCheck::Both)
+    }
+
+    pub fn on_receive(self) -> bool {
+        matches!(self, SchemaCheck::OnReceive | SchemaCheck::Both)
+    }
+}
+
+// what happens to a payload that fails validation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidMessagePolicy {
+    // drop the message, publishers get a `SchemaViolation` error
+    Reject,
+    // republish the message on the given topic instead of its own
+    DeadLetter(String),
+}
+
+// one registered version of a pattern's schema
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SchemaVersion {
+    pub pattern: String,
+    pub version: u32,
+    pub schema: JsonSchema,
+}
+
+// query published on `$zark.schema.query`, encoded as json so sdks can send it with a plain publish
+// with `list` set the latest schema of every pattern is returned, with `topic` set the schema
+// applying to that topic, otherwise `pattern` and optionally `version` select a registered schema
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SchemaQuery {
+    // topic the registry publishes its `SchemaReply` on
+    pub reply_to: String,
+    #[serde(default)]
+    pub list: bool,
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub version: Option<u32>,
+}
+
+// answer to a schema query, encoded as json, empty when nothing matched
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SchemaReply {
+    pub schemas: Vec<SchemaVersion>,
+}
+
+// maps topic patterns to versioned json schemas and validates payloads against them
+// when several patterns match a topic the most specific one wins
+pub struct SchemaRegistry {
+    entries: RwLock<Vec<(TopicPattern, Vec<SchemaVersion>)>>,
+    check: SchemaCheck,
+    policy: InvalidMessagePolicy,
+}
+
+impl SchemaRegistry {
+    pub fn new(check: SchemaCheck, policy: InvalidMessagePolicy) -> Self {
+        Self {
+            entries: RwLock::new(Vec::new()),
+            check,
+            policy,
+        }
+    }
+
+    pub fn check(&self) -> SchemaCheck {
+        self.check
+    }
+
+    pub fn policy(&self) -> &InvalidMessagePolicy {
+        &self.policy
+    }
+
+    // register a new schema version for the pattern and return its version number
+    pub fn register(&self, pattern: &str, schema: serde_json::Value) -> u32 {
+        let mut entries = self.entries.write();
+        let index = match entries.iter().position(|(existing, _)| existing.as_str() == pattern) {
+            Some(index) => index,
+            None => {
+                entries.push((TopicPattern::new(pattern), Vec::new()));
+                entries.len() - 1
+            }
+        };
+        let versions = &mut entries[index].1;
+        let version = versions.last().map_or(1, |latest| latest.version + 1);
+        versions.push(SchemaVersion {
+            pattern: pattern.to_string(),
+            version,
+            schema: JsonSchema(schema),
+        });
+        version
+    }
+
+    // latest schema of the most specific pattern matching the topic
+    pub fn schema_for(&self, topic: &str) -> Option<SchemaVersion> {
+        self.entries
+            .read()
+            .iter()
+            .filter(|(pattern, _)| pattern.matches(topic))
+            .max_by_key(|(pattern, _)| pattern.specificity())
+            .and_then(|(_, versions)| versions.last().cloned())
+    }
+
+    // a registered version of a pattern's schema, the latest when `version` is none
+    pub fn version(&self, pattern: &str, version: Option<u32>) -> Option<SchemaVersion> {
+        self.entries
+            .read()
+            .iter()
+            .find(|(existing, _)| existing.as_str() == pattern)
+            .and_then(|(_, versions)| match version {
+                Some(version) => versions.iter().find(|v| v.version == version).cloned(),
+                None => versions.last().cloned(),
+            })
+    }
+
+    // latest schema of every registered pattern
+    pub fn list(&self) -> Vec<SchemaVersion> {
+        self.entries
+            .read()
+            .iter()
+            .filter_map(|(_, versions)| versions.last().cloned())
+            .collect()
+    }
+
+    // validate a payload published on the topic, topics without a schema always pass
+    pub fn validate(&self, serializer: &dyn Serializer, topic: &str, payload: &[u8]) -> Result<(), MessengerError> {
+        let schema = match self.schema_for(topic) {
+            Some(schema) => schema,
+            None => return Ok(()),
+        };
+        let value = serializer.deserialize_value(payload)
+            .map_err(|e| MessengerError::SchemaViolation(topic.to_string(), e.to_string()))?;
+        schema.schema.validate(&value)
+            .map_err(|reason| MessengerError::SchemaViolation(topic.to_string(), format!("schema {} v{}: {}", schema.pattern, schema.version, reason)))
+    }
+
+    // schemas a query asks for
+    pub fn answer(&self, query: &SchemaQuery) -> SchemaReply {
+        let schemas = match (query.list, &query.topic, &query.pattern) {
+            (true, _, _) => self.list(),
+            (false, Some(topic), _) => self.schema_for(topic).into_iter().collect(),
+            (false, None, Some(pattern)) => self.version(pattern, query.version).into_iter().collect(),
+            (false, None, None) => {
+                log::warn!("schema query needs a topic, a pattern or list");
+                Vec::new()
+            }
+        };
+        SchemaReply { schemas }
+    }
+
+    // answer schema queries published on `$zark.schema.query` so other modules and sdks can fetch schemas
+    pub async fn serve<M>(self: &Arc<Self>, messenger: &M) -> Result<(), MessengerError>
+    where
+        M: Messenger + Clone + 'static,
+    {
+        let queries = messenger.subscribe(SCHEMA_QUERY_TOPIC.to_string()).await?;
+        let registry = Arc::clone(self);
+        let messenger = messenger.clone();
+        tokio::spawn(async move {
+            while let Ok(message) = queries.receive().await {
+                let query: SchemaQuery = match serde_json::from_slice(&message.payload) {
+                    Ok(query) => query,
+                    Err(e) => {
+                        log::warn!("dropping malformed schema query: {}", e);
+                        continue;
+                    }
+                };
+                let published = match serde_json::to_vec(&registry.answer(&query)) {
+                    Ok(payload) => messenger.publish(query.reply_to.clone(), &Message::new(query.reply_to.clone(), payload)).await,
+                    Err(e) => Err(MessengerError::Serialization(e.to_string())),
+                };
+                if let Err(e) = published {
+                    log::warn!("failed to answer schema query: {}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+// fetch the schema that applies to a topic from a registry served over the messenger
+pub async fn fetch_schema<M>(messenger: &M, topic: &str) -> Result<Option<SchemaVersion>, MessengerError>
+where
+    M: Messenger + ?Sized,
+{
+    let reply = query(messenger, SchemaQuery { topic: Some(topic.to_string()), ..Default::default() }).await?;
+    Ok(reply.schemas.into_iter().next())
+}
+
+// fetch the latest schema of every pattern from a registry served over the messenger
+pub async fn list_schemas<M>(messenger: &M) -> Result<Vec<SchemaVersion>, MessengerError>
+where
+    M: Messenger + ?Sized,
+{
+    Ok(query(messenger, SchemaQuery { list: true, ..Default::default() }).await?.schemas)
+}
+
+// publish a query and wait for the registry's reply on a topic of our own
+async fn query<M>(messenger: &M, mut query: SchemaQuery) -> Result<SchemaReply, MessengerError>
+where
+    M: Messenger + ?Sized,
+{
+    query.reply_to = format!("$zark.schema.reply.{}", generate_zark_uid());
+    let replies = messenger.subscribe(query.reply_to.clone()).await?;
+    let payload = serde_json::to_vec(&query).map_err(|e| MessengerError::Serialization(e.to_string()))?;
+    messenger.publish(SCHEMA_QUERY_TOPIC.to_string(), &Message::new(SCHEMA_QUERY_TOPIC.to_string(), payload)).await?;
+
+    let reply = tokio::time::timeout(SCHEMA_QUERY_TIMEOUT, replies.receive()).await
+        .map_err(|_| MessengerError::TransportError("no schema registry answered the query".to_string()))??;
+    serde_json::from_slice(&reply.payload).map_err(|e| MessengerError::Deserialization(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::application::config::IpcConfig;
+    use crate::application::messenger::MessengerImpl;
+    use crate::application::typed::TypedMessenger;
+    use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+    use crate::infrastructure::serialization::json::JsonSerializer;
+    use crate::infrastructure::transport::ipc::IpcTransport;
+
+    fn registry() -> Arc<SchemaRegistry> {
+        let registry = Arc::new(SchemaRegistry::new(SchemaCheck::Both, InvalidMessagePolicy::DeadLetter("invalid".into())));
+        registry.register("waf.bans.*", json!({"type": "object", "required": ["ip"]}));
+        registry.register("waf.bans.*", json!({"type": "object", "required": ["ip", "secs"], "properties": {"secs": {"type": "integer", "minimum": 1}}}));
+        registry
+    }
+
+    #[test]
+    fn latest_version_applies() {
+        let registry = registry();
+        assert_eq!(registry.schema_for("waf.bans.ip").unwrap().version, 2);
+        assert_eq!(registry.version("waf.bans.*", Some(1)).unwrap().version, 1);
+        assert!(registry.schema_for("waf.other").is_none());
+        assert!(registry.validate(&JsonSerializer, "waf.bans.ip", br#"{"ip": "1.2.3.4", "secs": 5}"#).is_ok());
+        assert!(matches!(
+            registry.validate(&JsonSerializer, "waf.bans.ip", br#"{"ip": "1.2.3.4", "secs": 0}"#),
+            Err(MessengerError::SchemaViolation(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_are_dead_lettered_and_schemas_served() {
+        let config = IpcConfig { shared_memory_name: "schemas".into(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        let transport = IpcTransport::new(config, Box::new(JsonSerializer), PoolAllocator::new(16)).unwrap();
+        let registry = registry();
+        let messenger = MessengerImpl::new(Arc::new(transport)).with_schema_registry(Arc::clone(&registry));
+        registry.serve(&messenger).await.unwrap();
+
+        let invalid = messenger.subscribe("invalid".into()).await.unwrap();
+        let bans = messenger.subscribe("waf.bans.ip".into()).await.unwrap();
+        let rejected = messenger.publish_typed("waf.bans.ip", &json!({"ip": "1.2.3.4"})).await;
+        assert!(matches!(rejected, Err(MessengerError::SchemaViolation(..))));
+        assert!(invalid.receive().await.is_ok());
+        messenger.publish_typed("waf.bans.ip", &json!({"ip": "1.2.3.4", "secs": 3})).await.unwrap();
+        assert!(bans.receive().await.is_ok());
+
+        assert_eq!(fetch_schema(&messenger, "waf.bans.ip").await.unwrap().unwrap().version, 2);
+        assert!(fetch_schema(&messenger, "waf.other").await.unwrap().is_none());
+        assert_eq!(list_schemas(&messenger).await.unwrap(), vec![registry.schema_for("waf.bans.ip").unwrap()]);
+        messenger.cleanup().await.unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::application::messenger::{Messenger, RpcHandler};
+use crate::application::typed::{decode_payload, encode_payload};
+use crate::domain::errors::MessengerError;
+use crate::infrastructure::serialization::Serializer;
+
+// services declared with `rpc_service!` are implemented with this attribute
+pub use async_trait::async_trait;
+
+pub type MethodFuture<Reply> = Pin<Box<dyn Future<Output = Result<Reply, MessengerError>> + Send>>;
+
+// call a typed method, encoding the request and decoding the reply with the messenger's serializer
+pub async fn call<M, Request, Reply>(messenger: &M, method: &str, request: &Request) -> Result<Reply, MessengerError>
+where
+    M: Messenger + ?Sized,
+    Request: Serialize + ?Sized,
+    Reply: DeserializeOwned,
+{
+    let params = encode_payload(messenger.serializer().as_ref(), request)?;
+    let result = messenger.rpc_call(method.as_bytes(), &params).await?;
+    decode_payload(messenger.serializer().as_ref(), method, &result)
+}
+
+// rpc handler serving one typed method of a service
+pub struct MethodHandler<S: ?Sized, Request, Reply> {
+    service: Arc<S>,
+    serializer: Arc<dyn Serializer>,
+    method: &'static str,
+    call: fn(Arc<S>, Request) -> MethodFuture<Reply>,
+    _marker: PhantomData<fn(Request) -> Reply>,
+}
+
+impl<S: ?Sized, Request, Reply> MethodHandler<S, Request, Reply> {
+    pub fn new(service: Arc<S>, serializer: Arc<dyn Serializer>, method: &'static str, call: fn(Arc<S>, Request) -> MethodFuture<Reply>) -> Self {
+        Self { service, serializer, method, call, _marker: PhantomData }
+    }
+}
+
+#[async_trait]
+impl<S, Request, Reply> RpcHandler for MethodHandler<S, Request, Reply>
+where
+    S: Send + Sync + ?Sized,
+    Request: DeserializeOwned,
+    Reply: Serialize,
+{
+    async fn handle(&self, params: &[u8]) -> Result<Vec<u8>, MessengerError> {
+        let request = decode_payload(self.serializer.as_ref(), self.method, params)?;
+        let reply = (self.call)(Arc::clone(&self.service), request).await?;
+        encode_payload(self.serializer.as_ref(), &reply)
+    }
+}
+
+// declare an rpc service as a trait, generating the trait, a typed client and the server registration
+//
+//     rpc_service! {
+//         pub trait BanService => BanServiceClient {
+//             async fn ban(&self, req: BanRequest) -> BanReply;
+//         }
+//     }
+//
+// every method is called as `<trait>.<method>`, e.g. `BanService.ban`, with serde-encoded request and reply
+// implementations return `Result<Reply, MessengerError>` and are served with `Arc::new(service).serve(&messenger)`,
+// callers use `BanServiceClient::new(messenger).ban(&request)`
+#[macro_export]
+macro_rules! rpc_service {
+    (
+        $(#[$attr:meta])*
+        $vis:vis trait $service:ident => $client:ident {
+            $(
+                $(#[$method_attr:meta])*
+                async fn $method:ident(&self, $req:ident: $request:ty) -> $reply:ty;
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        #[$crate::application::service::async_trait]
+        $vis trait $service: Send + Sync + 'static {
+            $(
+                $(#[$method_attr])*
+                async fn $method(&self, $req: $request) -> ::std::result::Result<$reply, $crate::domain::errors::MessengerError>;
+            )*
+
+            // answer every method of the service over rpc
+            async fn serve<M>(self: ::std::sync::Arc<Self>, messenger: &M) -> ::std::result::Result<(), $crate::domain::errors::MessengerError>
+            where
+                M: $crate::application::messenger::Messenger + ?Sized,
+                Self: Sized,
+            {
+                let serializer = messenger.serializer();
+                $(
+                    let method = concat!(stringify!($service), ".", stringify!($method));
+                    let handler = $crate::application::service::MethodHandler::new(
+                        ::std::sync::Arc::clone(&self),
+                        ::std::sync::Arc::clone(&serializer),
+                        method,
+                        |service: ::std::sync::Arc<Self>, $req: $request| -> $crate::application::service::MethodFuture<$reply> {
+                            Box::pin(async move { service.$method($req).await })
+                        },
+                    );
+                    messenger.register_rpc_handler(method.as_bytes(), Box::new(handler)).await?;
+                )*
+                Ok(())
+            }
+        }
+
+        // typed client of the service's methods
+        $vis struct $client<M> {
+            messenger: M,
+        }
+
+        impl<M: $crate::application::messenger::Messenger> $client<M> {
+            pub fn new(messenger: M) -> Self {
+                Self { messenger }
+            }
+
+            $(
+                $(#[$method_attr])*
+                pub async fn $method(&self, $req: &$request) -> ::std::result::Result<$reply, $crate::domain::errors::MessengerError> {
+                    let method = concat!(stringify!($service), ".", stringify!($method));
+                    $crate::application::service::call(&self.messenger, method, $req).await
+                }
+            )*
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::config::IpcConfig;
+    use crate::application::messenger::MessengerImpl;
+    use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+    use crate::infrastructure::serialization::binary::BinarySerializer;
+    use crate::infrastructure::transport::ipc::IpcTransport;
+    use parking_lot::Mutex;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize)]
+    struct BanRequest {
+        ip: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct BanReply {
+        banned: usize,
+    }
+
+    crate::rpc_service! {
+        // bans and unbans addresses
+        trait BanService => BanServiceClient {
+            async fn ban(&self, req: BanRequest) -> BanReply;
+            async fn unban(&self, req: BanRequest) -> BanReply;
+        }
+    }
+
+    #[derive(Default)]
+    struct Bans {
+        banned: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl BanService for Bans {
+        async fn ban(&self, req: BanRequest) -> Result<BanReply, MessengerError> {
+            let mut banned = self.banned.lock();
+            banned.push(req.ip);
+            Ok(BanReply { banned: banned.len() })
+        }
+
+        async fn unban(&self, req: BanRequest) -> Result<BanReply, MessengerError> {
+            let mut banned = self.banned.lock();
+            let before = banned.len();
+            banned.retain(|ip| *ip != req.ip);
+            if banned.len() == before {
+                return Err(MessengerError::RpcError(format!("{} is not banned", req.ip)));
+            }
+            Ok(BanReply { banned: banned.len() })
+        }
+    }
+
+    #[tokio::test]
+    async fn declared_services_are_served_and_called_by_method() {
+        let config = IpcConfig { shared_memory_name: "service".into(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        let messenger = MessengerImpl::new(Arc::new(IpcTransport::new(config, Box::new(BinarySerializer), PoolAllocator::new(16)).unwrap()));
+        let service = Arc::new(Bans::default());
+        Arc::clone(&service).serve(&messenger).await.unwrap();
+
+        let client = BanServiceClient::new(messenger.clone());
+        let request = BanRequest { ip: "10.0.0.1".into() };
+        assert_eq!(client.ban(&request).await.unwrap(), BanReply { banned: 1 });
+        assert_eq!(client.unban(&request).await.unwrap(), BanReply { banned: 0 });
+        assert_eq!(*service.banned.lock(), Vec::<String>::new());
+
+        // the implementation's error comes back to the caller
+        let missing = client.unban(&request).await;
+        assert!(matches!(missing, Err(MessengerError::RpcError(e)) if e.contains("10.0.0.1 is not banned")));
+
+        // every method is called as `<trait>.<method>`
+        let reply: BanReply = call(&messenger, "BanService.ban", &request).await.unwrap();
+        assert_eq!(reply, BanReply { banned: 1 });
+        messenger.cleanup().await.unwrap();
+    }
+}
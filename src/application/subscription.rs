@@ -0,0 +1,439 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+
+use crate::application::dead_letter::DeadLetterSink;
+use crate::domain::filter::Filter;
+use crate::domain::headers::ACK_TO;
+use crate::domain::message::{Message, Priority};
+use crate::infrastructure::transport::Transport;
+
+// per-subscription delivery options
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionOptions {
+    // when set, messages must be acknowledged and are redelivered otherwise
+    pub ack: Option<AckConfig>,
+    // topic that messages are republished to once redelivery gives up on them
+    pub dead_letter_topic: Option<String>,
+    // where in the topic's durable log delivery starts, only logged topics can start in the past
+    pub start: StartPosition,
+    // consumer group to join, messages are then shared among the group's members instead of
+    // every subscriber getting a copy
+    pub group: Option<String>,
+    // how the group spreads messages over its members, the first member's choice sticks
+    pub balance: GroupBalance,
+    // when set, messages whose id the subscription has already seen within the window are
+    // dropped, redeliveries of unacknowledged messages still come through
+    pub dedup: Option<DedupWindow>,
+    // when set, only messages matching the filter are delivered, a consumer group keeps its first
+    // member's filter; over tcp with flow control the peer is told, so it only sends messages
+    // some subscription of the topic wants
+    pub filter: Option<Filter>,
+}
+
+// how far back a subscription remembers message ids to drop duplicates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupWindow {
+    // at least the last this many ids
+    Count(usize),
+    // at least the ids seen within this long
+    Time(Duration),
+}
+
+// how a consumer group picks the member that gets a message
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GroupBalance {
+    // members take turns
+    #[default]
+    RoundRobin,
+    // the member with the fewest messages waiting in its queue
+    LeastLoaded,
+}
+
+// position in a durable topic log that a subscription starts reading from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StartPosition {
+    // only messages arriving after the subscription was made
+    #[default]
+    Latest,
+    // the oldest message still kept by retention
+    Earliest,
+    // the message with this offset, or the oldest one when it was already deleted
+    Offset(u64),
+    // the first message appended at or after this time
+    Timestamp(SystemTime),
+}
+
+// at-least-once delivery settings
+#[derive(Debug, Clone)]
+pub struct AckConfig {
+    // how long a delivered message may stay unacknowledged before it is redelivered
+    pub visibility_timeout: Duration,
+    // how many times a message is redelivered before it is given up on
+    pub max_redeliveries: u32,
+}
+
+impl Default for AckConfig {
+    fn default() -> Self {
+        Self {
+            visibility_timeout: Duration::from_secs(30),
+            max_redeliveries: 5,
+        }
+    }
+}
+
+// a message queued for a subscriber together with its delivery attempt, starting at 1
+pub(crate) struct Envelope {
+    pub message: Message,
+    pub attempt: u32,
+}
+
+// a delivered message
+// in ack mode it stays in flight until `ack` or `nack` is called, if neither
+// happens within the visibility timeout it is delivered again
+pub struct Delivery {
+    message: Message,
+    attempt: u32,
+    receipt: Option<(Arc<AckTracker>, u64)>,
+}
+
+impl Delivery {
+    pub(crate) fn untracked(message: Message, attempt: u32) -> Self {
+        Self { message, attempt, receipt: None }
+    }
+
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+
+    pub fn into_message(self) -> Message {
+        self.message
+    }
+
+    // how many times this message has been delivered, including this delivery
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    // mark the message as handled
+    pub fn ack(self) {
+        if let Some((tracker, tag)) = &self.receipt {
+            tracker.ack(*tag);
+        }
+    }
+
+    // mark the message as failed so it is redelivered right away
+    pub fn nack(self) {
+        if let Some((tracker, tag)) = &self.receipt {
+            tracker.nack(*tag, None);
+        }
+    }
+
+    // like nack, recording why handling failed in case the message ends up dead-lettered
+    pub fn nack_with_reason(self, reason: impl Into<String>) {
+        if let Some((tracker, tag)) = &self.receipt {
+            tracker.nack(*tag, Some(reason.into()));
+        }
+    }
+}
+
+impl Deref for Delivery {
+    type Target = Message;
+
+    fn deref(&self) -> &Self::Target {
+        &self.message
+    }
+}
+
+// most message ids acknowledged in one ack frame
+const ACK_BATCH_SIZE: usize = 256;
+
+// ack topic and id of a message waiting to be acknowledged
+type PendingAck = (String, String);
+
+// sends the ack frames reliable publishers wait for back over the transport
+// acks of every subscription go through one queue, drained by a single task that acknowledges
+// the ids queued while it was sending the last frame together, one frame per publisher
+#[derive(Clone)]
+pub(crate) struct AckSender {
+    transport: Arc<dyn Transport>,
+    // started on the first ack, since the sender can be created outside a tokio runtime
+    queue: Arc<Mutex<Option<mpsc::UnboundedSender<PendingAck>>>>,
+}
+
+impl AckSender {
+    pub fn new(transport: Arc<dyn Transport>) -> Self {
+        Self { transport, queue: Arc::new(Mutex::new(None)) }
+    }
+
+    // acknowledge the message to its publisher, when the publisher asked for it
+    pub fn ack(&self, message: &Message) {
+        let ack_to = match message.headers.get(ACK_TO) {
+            Some(ack_to) => ack_to,
+            None => return,
+        };
+        let mut queue = self.queue.lock();
+        if queue.as_ref().is_none_or(|queue| queue.is_closed()) {
+            let handle = match tokio::runtime::Handle::try_current() {
+                Ok(handle) => handle,
+                Err(_) => {
+                    log::warn!("cannot acknowledge message {} outside a tokio runtime", message.id);
+                    return;
+                }
+            };
+            let (tx, rx) = mpsc::unbounded_channel();
+            handle.spawn(send_acks(Arc::clone(&self.transport), rx));
+            *queue = Some(tx);
+        }
+        if let Some(queue) = queue.as_ref() {
+            let _ = queue.send((ack_to.clone(), message.id.clone()));
+        }
+    }
+}
+
+// send queued acks as frames of newline separated message ids, one per ack topic
+async fn send_acks(transport: Arc<dyn Transport>, mut queue: mpsc::UnboundedReceiver<PendingAck>) {
+    while let Some(first) = queue.recv().await {
+        let mut frames: Vec<(String, Vec<String>)> = Vec::new();
+        let mut next = Some(first);
+        let mut taken = 0;
+        while let Some((ack_to, id)) = next {
+            match frames.iter_mut().find(|(topic, _)| *topic == ack_to) {
+                Some((_, ids)) => ids.push(id),
+                None => frames.push((ack_to, vec![id])),
+            }
+            taken += 1;
+            next = if taken < ACK_BATCH_SIZE { queue.try_recv().ok() } else { None };
+        }
+        for (ack_to, ids) in frames {
+            let ack = Message::new(ack_to, ids.join("\n").into_bytes()).with_priority(Priority::High);
+            if let Err(e) = transport.send(&ack).await {
+                log::warn!("failed to acknowledge messages {}: {}", ids.join(", "), e);
+            }
+        }
+    }
+}
+
+struct InFlight {
+    message: Message,
+    attempt: u32,
+    deadline: Instant,
+    // why the last delivery failed
+    reason: Option<String>,
+}
+
+// tracks the unacknowledged deliveries of one subscription and puts expired or
+// rejected ones back on the subscriber's queue
+pub(crate) struct AckTracker {
+    config: AckConfig,
+    in_flight: Mutex<HashMap<u64, InFlight>>,
+    next_tag: AtomicU64,
+    requeue: mpsc::UnboundedSender<Envelope>,
+    dead_letter: Option<DeadLetterSink>,
+    acks: AckSender,
+}
+
+impl AckTracker {
+    // create the tracker and start its redelivery timer
+    // the timer stops on its own once the tracker is dropped
+    pub fn start(
+        config: AckConfig,
+        requeue: mpsc::UnboundedSender<Envelope>,
+        dead_letter: Option<DeadLetterSink>,
+        acks: AckSender,
+    ) -> Arc<Self> {
+        let tracker = Arc::new(Self {
+            config,
+            in_flight: Mutex::new(HashMap::new()),
+            next_tag: AtomicU64::new(0),
+            requeue,
+            dead_letter,
+            acks,
+        });
+
+        let weak = Arc::downgrade(&tracker);
+        let period = (tracker.config.visibility_timeout / 4).max(Duration::from_millis(10));
+        tokio::spawn(Self::redeliver_expired(weak, period));
+        tracker
+    }
+
+    // hand out a delivery and start its visibility timeout
+    pub fn track(self: &Arc<Self>, envelope: Envelope) -> Delivery {
+        let tag = self.next_tag.fetch_add(1, Ordering::SeqCst);
+        self.in_flight.lock().insert(tag, InFlight {
+            message: envelope.message.clone(),
+            attempt: envelope.attempt,
+            deadline: Instant::now() + self.config.visibility_timeout,
+            reason: None,
+        });
+        Delivery {
+            message: envelope.message,
+            attempt: envelope.attempt,
+            receipt: Some((Arc::clone(self), tag)),
+        }
+    }
+
+    // take back every unacknowledged delivery, for handing them to another subscriber
+    // acks and nacks for released deliveries are ignored
+    pub fn release(&self) -> Vec<Envelope> {
+        let mut released: Vec<(u64, InFlight)> = self.in_flight.lock().drain().collect();
+        released.sort_by_key(|(tag, _)| *tag);
+        released
+            .into_iter()
+            .map(|(_, entry)| Envelope { message: entry.message, attempt: entry.attempt + 1 })
+            .collect()
+    }
+
+    fn ack(&self, tag: u64) {
+        let entry = self.in_flight.lock().remove(&tag);
+        if let Some(entry) = entry {
+            self.acks.ack(&entry.message);
+        }
+    }
+
+    fn nack(&self, tag: u64, reason: Option<String>) {
+        let entry = self.in_flight.lock().remove(&tag);
+        if let Some(mut entry) = entry {
+            entry.reason = reason.or(Some("rejected by subscriber".to_string()));
+            self.redeliver(entry);
+        }
+    }
+
+    fn redeliver(&self, entry: InFlight) {
+        if entry.attempt > self.config.max_redeliveries {
+            let reason = entry.reason.as_deref().unwrap_or("not acknowledged within the visibility timeout");
+            match &self.dead_letter {
+                Some(sink) => sink.send(&entry.message, reason, entry.attempt),
+                None => log::warn!("giving up on message {} after {} deliveries: {}", entry.message.id, entry.attempt, reason),
+            }
+            // the publisher would only send it again
+            self.acks.ack(&entry.message);
+            return;
+        }
+        let _ = self.requeue.send(Envelope { message: entry.message, attempt: entry.attempt + 1 });
+    }
+
+    async fn redeliver_expired(tracker: Weak<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let tracker = match tracker.upgrade() {
+                Some(tracker) => tracker,
+                None => return,
+            };
+
+            let now = Instant::now();
+            let expired: Vec<InFlight> = {
+                let mut in_flight = tracker.in_flight.lock();
+                let tags: Vec<u64> = in_flight
+                    .iter()
+                    .filter(|(_, entry)| entry.deadline <= now)
+                    .map(|(tag, _)| *tag)
+                    .collect();
+                tags.iter().filter_map(|tag| in_flight.remove(tag)).collect()
+            };
+            for entry in expired {
+                tracker.redeliver(entry);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::config::IpcConfig;
+    use crate::application::messenger::{Messenger, MessengerImpl};
+    use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+    use crate::infrastructure::serialization::json::JsonSerializer;
+    use crate::infrastructure::transport::ipc::IpcTransport;
+
+    fn messenger() -> MessengerImpl {
+        let config = IpcConfig { shared_memory_name: "acks".into(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        MessengerImpl::new(Arc::new(IpcTransport::new(config, Box::new(JsonSerializer), PoolAllocator::new(16)).unwrap()))
+    }
+
+    fn ack_mode(max_redeliveries: u32) -> SubscriptionOptions {
+        SubscriptionOptions {
+            ack: Some(AckConfig { visibility_timeout: Duration::from_millis(50), max_redeliveries }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_deliveries_come_back_until_given_up() {
+        let messenger = messenger();
+        let subscriber = messenger.subscribe_with("t".into(), ack_mode(2)).await.unwrap();
+        messenger.publish("t".into(), &Message::new("t".into(), vec![1])).await.unwrap();
+
+        // dropped without an ack, it comes back after the visibility timeout
+        let delivery = subscriber.receive_delivery().await.unwrap();
+        assert_eq!(delivery.attempt(), 1);
+        drop(delivery);
+        let delivery = subscriber.receive_delivery().await.unwrap();
+        assert_eq!(delivery.attempt(), 2);
+        delivery.nack();
+        let delivery = subscriber.receive_delivery().await.unwrap();
+        assert_eq!(delivery.attempt(), 3);
+        delivery.nack();
+        assert!(tokio::time::timeout(Duration::from_millis(200), subscriber.receive_delivery()).await.is_err());
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn acks_queued_together_share_a_frame() {
+        let config = IpcConfig { shared_memory_name: "acks".into(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        let transport: Arc<dyn Transport> = Arc::new(IpcTransport::new(config, Box::new(JsonSerializer), PoolAllocator::new(16)).unwrap());
+        let acks = AckSender::new(Arc::clone(&transport));
+        for id in ["a", "b", "c"] {
+            let mut message = Message::new("t".into(), vec![]).with_header(ACK_TO, "publisher");
+            message.id = id.into();
+            acks.ack(&message);
+        }
+        acks.ack(&Message::new("t".into(), vec![]));
+
+        let frame = transport.receive().await.unwrap();
+        assert_eq!(frame.topic, "publisher");
+        assert_eq!(frame.payload, b"a\nb\nc");
+        assert!(tokio::time::timeout(Duration::from_millis(50), transport.receive()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn acknowledged_deliveries_stay_acknowledged() {
+        let messenger = messenger();
+        let subscriber = messenger.subscribe_with("t".into(), ack_mode(2)).await.unwrap();
+        messenger.publish("t".into(), &Message::new("t".into(), vec![2])).await.unwrap();
+        subscriber.receive_delivery().await.unwrap().ack();
+        assert!(tokio::time::timeout(Duration::from_millis(200), subscriber.receive_delivery()).await.is_err());
+        messenger.cleanup().await.unwrap();
+    }
+}
@@ -0,0 +1,358 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::application::chunking::held_bytes;
+use crate::application::config::TransactionConfig;
+use crate::application::dispatcher::SUBSCRIBER_QUEUE_SIZE;
+use crate::application::messenger::{Messenger, MessengerImpl};
+use crate::domain::errors::MessengerError;
+use crate::domain::headers::{TXN_COUNT, TXN_ID, TXN_INDEX};
+use crate::domain::message::Message;
+use crate::utils::metrics::Metrics;
+use crate::utils::zark_uid::generate_zark_uid;
+
+// most messages one transaction may publish, a subscriber's queue has to be able to take all of them at once
+pub const MAX_TRANSACTION_MESSAGES: usize = SUBSCRIBER_QUEUE_SIZE;
+
+// messages published together, subscribers get either all of them or none
+// nothing is sent before `commit`, dropping the transaction aborts it
+pub struct Transaction {
+    messenger: MessengerImpl,
+    id: String,
+    messages: Vec<Message>,
+}
+
+impl Transaction {
+    pub(crate) fn new(messenger: MessengerImpl) -> Self {
+        Self { messenger, id: generate_zark_uid(), messages: Vec::new() }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    // add a message to publish on `topic` when the transaction commits
+    pub fn publish(&mut self, topic: String, msg: &Message) -> Result<(), MessengerError> {
+        if self.messages.len() >= MAX_TRANSACTION_MESSAGES {
+            return Err(MessengerError::TransactionTooLarge(self.messages.len() + 1, MAX_TRANSACTION_MESSAGES));
+        }
+        let mut message = msg.clone();
+        message.topic = topic;
+        if message.id.is_empty() {
+            message.id = generate_zark_uid();
+        }
+        self.messages.push(message);
+        Ok(())
+    }
+
+    // publish every message of the transaction, the receiving dispatcher holds them back
+    // until all of them arrived and then hands them to subscribers together
+    pub async fn commit(self) -> Result<(), MessengerError> {
+        let count = self.messages.len();
+        let messages: Vec<Message> = self.messages
+            .into_iter()
+            .enumerate()
+            .map(|(index, mut message)| {
+                message.headers.insert(TXN_ID.to_string(), self.id.clone());
+                message.headers.insert(TXN_INDEX.to_string(), index.to_string());
+                message.headers.insert(TXN_COUNT.to_string(), count.to_string());
+                message
+            })
+            .collect();
+        self.messenger.publish_batch(&messages).await
+    }
+
+    // drop the transaction without publishing anything
+    pub fn abort(self) {}
+}
+
+// messages received so far of one transaction
+struct Partial {
+    messages: Vec<Option<Message>>,
+    missing: usize,
+    bytes: usize,
+    started: Instant,
+}
+
+// holds back the messages of transactions on the receiving side until each is complete
+// incomplete transactions are dropped once they wait longer than the timeout, or to keep their
+// messages within the memory cap, oldest first
+pub struct PendingTransactions {
+    config: TransactionConfig,
+    pending: HashMap<String, Partial>,
+    pending_bytes: usize,
+    // topics of the messages dropped since `take_dropped` was last called, so their flow credits can be granted back
+    dropped: Vec<String>,
+    metrics: Arc<Metrics>,
+}
+
+impl PendingTransactions {
+    pub fn new(config: TransactionConfig, metrics: Arc<Metrics>) -> Self {
+        Self { config, pending: HashMap::new(), pending_bytes: 0, dropped: Vec::new(), metrics }
+    }
+
+    pub fn set_config(&mut self, config: TransactionConfig) {
+        self.config = config;
+    }
+
+    // topics of the messages dropped since the last call
+    pub fn take_dropped(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.dropped)
+    }
+
+    fn drop_message(&mut self, message: Message) {
+        self.metrics.record_dropped();
+        self.dropped.push(message.topic);
+    }
+
+    // take a received message, returning it right away unless it is part of a transaction,
+    // and every message of the transaction in order once the last one arrived
+    pub fn accept(&mut self, message: Message) -> Option<Vec<Message>> {
+        let (id, index, count) = match transaction_position(&message) {
+            None => return Some(vec![message]),
+            Some(Ok(position)) => position,
+            Some(Err(e)) => {
+                log::warn!("dropping message {}: {}", message.id, e);
+                self.drop_message(message);
+                return None;
+            }
+        };
+        self.drop_stale();
+
+        if index >= count || count > MAX_TRANSACTION_MESSAGES {
+            log::warn!("dropping message {}, it claims to be message {} of {} in transaction {}", message.id, index, count, id);
+            self.drop_message(message);
+            return None;
+        }
+
+        let len = held_bytes(&message);
+        while self.pending_bytes + len > self.config.max_pending_bytes {
+            let oldest = self.pending.iter().min_by_key(|(_, partial)| partial.started).map(|(id, _)| id.clone());
+            let full = oldest.as_ref().is_none_or(|oldest| *oldest == id);
+            if let Some(oldest) = oldest {
+                self.drop_partial(&oldest, "incomplete transactions reached the memory cap");
+            }
+            if full {
+                log::warn!("dropping message {} of transaction {}, it does not fit the memory cap", message.id, id);
+                self.drop_message(message);
+                return None;
+            }
+        }
+
+        let partial = self.pending.entry(id.clone()).or_insert_with(|| Partial {
+            messages: std::iter::repeat_with(|| None).take(count).collect(),
+            missing: count,
+            bytes: 0,
+            started: Instant::now(),
+        });
+        if partial.messages.len() != count {
+            log::warn!("dropping message {}, earlier messages of transaction {} counted {}", message.id, id, partial.messages.len());
+            self.drop_message(message);
+            return None;
+        }
+        // a message that arrives twice is kept once
+        if partial.messages[index].is_none() {
+            partial.messages[index] = Some(message);
+            partial.missing -= 1;
+            partial.bytes += len;
+            self.pending_bytes += len;
+        } else {
+            self.dropped.push(message.topic);
+        }
+        if partial.missing > 0 {
+            return None;
+        }
+
+        let partial = self.pending.remove(&id)?;
+        self.pending_bytes -= partial.bytes;
+        Some(partial.messages.into_iter().flatten().map(|mut message| {
+            message.headers.remove(TXN_INDEX);
+            message.headers.remove(TXN_COUNT);
+            message
+        }).collect())
+    }
+
+    // drop the transactions whose remaining messages did not arrive in time
+    fn drop_stale(&mut self) {
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let stale: Vec<String> = self.pending
+            .iter()
+            .filter(|(_, partial)| partial.started.elapsed() >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in stale {
+            self.drop_partial(&id, "the rest of its messages did not arrive in time");
+        }
+    }
+
+    fn drop_partial(&mut self, id: &str, reason: &str) {
+        if let Some(partial) = self.pending.remove(id) {
+            log::warn!("dropping incomplete transaction {}, {} of its messages are missing and {}", id, partial.missing, reason);
+            self.pending_bytes -= partial.bytes;
+            for message in partial.messages.into_iter().flatten() {
+                self.drop_message(message);
+            }
+        }
+    }
+}
+
+// id of the transaction a message belongs to with the message's index and the message count,
+// None for messages outside a transaction
+fn transaction_position(message: &Message) -> Option<Result<(String, usize, usize), MessengerError>> {
+    let id = message.headers.get(TXN_ID)?;
+    let number = |name: &str| {
+        message.headers.get(name).and_then(|value| value.parse::<usize>().ok()).ok_or_else(|| {
+            MessengerError::Deserialization(format!("message {} has no valid {} header", message.id, name))
+        })
+    };
+    Some(number(TXN_INDEX).and_then(|index| Ok((id.clone(), index, number(TXN_COUNT)?))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::config::IpcConfig;
+    use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+    use crate::infrastructure::serialization::binary::BinarySerializer;
+    use crate::infrastructure::transport::ipc::IpcTransport;
+
+    fn messenger() -> MessengerImpl {
+        let config = IpcConfig { shared_memory_name: "txn".into(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        MessengerImpl::new(Arc::new(IpcTransport::new(config, Box::new(BinarySerializer), PoolAllocator::new(16)).unwrap()))
+    }
+
+    // the messages of a committed transaction as they come out of the messenger
+    fn members(id: &str, count: usize) -> Vec<Message> {
+        (0..count)
+            .map(|index| {
+                Message::new(format!("t{}", index), vec![index as u8])
+                    .with_header(TXN_ID, id)
+                    .with_header(TXN_INDEX, index.to_string())
+                    .with_header(TXN_COUNT, count.to_string())
+            })
+            .collect()
+    }
+
+    fn pending(config: TransactionConfig) -> (PendingTransactions, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::default());
+        (PendingTransactions::new(config, metrics.clone()), metrics)
+    }
+
+    #[tokio::test]
+    async fn committed_transactions_reach_every_topic_and_aborted_ones_none() {
+        let messenger = messenger();
+        let first = messenger.subscribe("a".into()).await.unwrap();
+        let second = messenger.subscribe("b".into()).await.unwrap();
+
+        let mut aborted = messenger.begin();
+        aborted.publish("a".into(), &Message::new("a".into(), vec![0])).unwrap();
+        aborted.abort();
+        let mut transaction = messenger.begin();
+        transaction.publish("a".into(), &Message::new("a".into(), vec![1])).unwrap();
+        transaction.publish("b".into(), &Message::new("b".into(), vec![2])).unwrap();
+        let id = transaction.id().to_string();
+        transaction.commit().await.unwrap();
+
+        let message = first.receive().await.unwrap();
+        assert_eq!(message.payload, vec![1]);
+        assert_eq!(message.header(TXN_ID), Some(id.as_str()));
+        assert_eq!(message.header(TXN_INDEX), None);
+        assert_eq!(second.receive().await.unwrap().payload, vec![2]);
+        messenger.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn transactions_are_capped_at_what_a_subscriber_queue_holds() {
+        let mut transaction = messenger().begin();
+        for _ in 0..MAX_TRANSACTION_MESSAGES {
+            transaction.publish("t".into(), &Message::new("t".into(), vec![])).unwrap();
+        }
+        assert!(matches!(
+            transaction.publish("t".into(), &Message::new("t".into(), vec![])),
+            Err(MessengerError::TransactionTooLarge(..))
+        ));
+    }
+
+    #[test]
+    fn members_are_held_until_the_last_one_arrives_and_come_out_in_order() {
+        let (mut transactions, metrics) = pending(TransactionConfig::default());
+        let mut messages = members("x", 3);
+        let last = messages.remove(0);
+        for message in messages.iter().rev() {
+            assert_eq!(transactions.accept(message.clone()), None);
+        }
+        // a member that arrives twice is kept once, its credit still goes back
+        assert_eq!(transactions.accept(messages[0].clone()), None);
+        assert_eq!(transactions.take_dropped(), vec!["t1".to_string()]);
+
+        let whole = transactions.accept(last).unwrap();
+        assert_eq!(whole.iter().map(|message| message.payload[0]).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(transactions.pending_bytes, 0);
+        assert_eq!(metrics.snapshot().dropped, 0);
+
+        // a member claiming a position outside its transaction is dropped
+        let mut stray = members("y", 2).remove(1);
+        stray.headers.insert(TXN_INDEX.to_string(), "5".into());
+        assert_eq!(transactions.accept(stray), None);
+        assert_eq!(transactions.take_dropped(), vec!["t1".to_string()]);
+        assert_eq!(metrics.snapshot().dropped, 1);
+    }
+
+    #[test]
+    fn the_oldest_incomplete_transaction_makes_room_under_the_memory_cap() {
+        let first = members("first", 2);
+        let second = members("second", 2);
+        let cap = second.iter().map(held_bytes).sum::<usize>() + 1;
+        let (mut transactions, metrics) = pending(TransactionConfig { max_pending_bytes: cap, ..Default::default() });
+
+        assert_eq!(transactions.accept(first[0].clone()), None);
+        assert_eq!(transactions.accept(second[0].clone()), None);
+        assert_eq!(transactions.accept(second[1].clone()).map(|messages| messages.len()), Some(2));
+        assert_eq!(transactions.take_dropped(), vec!["t0".to_string()]);
+        assert_eq!(metrics.snapshot().dropped, 1);
+        assert!(transactions.pending.is_empty());
+
+        // a member that does not fit even with nothing else held is dropped on its own
+        let (mut tight, _) = pending(TransactionConfig { max_pending_bytes: 1, ..Default::default() });
+        assert_eq!(tight.accept(first[0].clone()), None);
+        assert!(tight.pending.is_empty());
+        assert_eq!(tight.take_dropped(), vec!["t0".to_string()]);
+    }
+
+    #[test]
+    fn incomplete_transactions_are_dropped_once_they_time_out() {
+        let (mut transactions, metrics) = pending(TransactionConfig { timeout_ms: 0, ..Default::default() });
+        let messages = members("x", 2);
+        assert_eq!(transactions.accept(messages[0].clone()), None);
+        // the next member finds its transaction timed out and starts it over
+        assert_eq!(transactions.accept(messages[1].clone()), None);
+        assert_eq!(transactions.take_dropped(), vec!["t0".to_string()]);
+        assert_eq!(metrics.snapshot().dropped, 1);
+        assert_eq!(transactions.pending_bytes, held_bytes(&messages[1]));
+    }
+}
@@ -0,0 +1,165 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::application::messenger::{MessageSubscriber, Messenger};
+use crate::domain::errors::MessengerError;
+use crate::domain::message::Message;
+use crate::infrastructure::serialization::Serializer;
+
+// typed publish/subscribe on top of any messenger
+// payloads are encoded in the payload format of the serializer configured on the messenger's transport
+#[async_trait]
+pub trait TypedMessenger: Messenger {
+    // encode the value and publish it on the topic
+    async fn publish_typed<T>(&self, topic: &str, value: &T) -> Result<(), MessengerError>
+    where
+        T: Serialize + Sync,
+    {
+        let payload = encode_payload(self.serializer().as_ref(), value)?;
+        let message = Message::new(topic.to_string(), payload);
+        self.publish(topic.to_string(), &message).await
+    }
+
+    // subscribe to the topic and decode every received payload as `T`
+    async fn subscribe_typed<T>(&self, topic: &str) -> Result<TypedSubscriber<T>, MessengerError>
+    where
+        T: DeserializeOwned,
+    {
+        let inner = self.subscribe(topic.to_string()).await?;
+        Ok(TypedSubscriber {
+            inner,
+            serializer: self.serializer(),
+            topic: topic.to_string(),
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<M: Messenger + ?Sized> TypedMessenger for M {}
+
+// subscriber that yields decoded values instead of raw messages
+pub struct TypedSubscriber<T> {
+    inner: Box<dyn MessageSubscriber>,
+    serializer: Arc<dyn Serializer>,
+    topic: String,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> TypedSubscriber<T> {
+    // wait for the next message and decode its payload
+    // a payload that does not match `T` is reported as a deserialization error naming the topic
+    pub async fn receive(&self) -> Result<T, MessengerError> {
+        let message = self.inner.receive().await?;
+        decode_payload(self.serializer.as_ref(), &self.topic, &message.payload)
+    }
+
+    // topic this subscriber is listening on
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+// encode a serde value with the given serializer
+pub fn encode_payload<T: Serialize + ?Sized>(serializer: &dyn Serializer, value: &T) -> Result<Vec<u8>, MessengerError> {
+    serializer.payload_format().encode(value)
+}
+
+// decode a payload received on `topic` into a serde value with the given serializer
+pub fn decode_payload<T: DeserializeOwned>(serializer: &dyn Serializer, topic: &str, payload: &[u8]) -> Result<T, MessengerError> {
+    serializer.payload_format().decode(payload).map_err(|e| match e {
+        MessengerError::Deserialization(reason) => MessengerError::Deserialization(format!("topic '{}': {}", topic, reason)),
+        e => e,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::application::config::IpcConfig;
+    use crate::application::messenger::MessengerImpl;
+    use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+    use crate::infrastructure::serialization::binary::BinarySerializer;
+    use crate::infrastructure::serialization::json::JsonSerializer;
+    use crate::infrastructure::serialization::PayloadFormat;
+    use crate::infrastructure::transport::ipc::IpcTransport;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Ban {
+        ip: String,
+        secs: u32,
+    }
+
+    // publish and receive a typed value over a messenger framing messages with `serializer`,
+    // returning the raw payload that went over the transport
+    async fn round_trip(name: &str, serializer: Box<dyn Serializer>) -> Vec<u8> {
+        let config = IpcConfig { shared_memory_name: name.into(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        let transport = IpcTransport::new(config, serializer, PoolAllocator::new(16)).unwrap();
+        let messenger = MessengerImpl::new(Arc::new(transport));
+
+        let bans = messenger.subscribe_typed::<Ban>("bans").await.unwrap();
+        let numbers = messenger.subscribe_typed::<u32>("bans").await.unwrap();
+        let raw = messenger.subscribe("bans".into()).await.unwrap();
+        messenger.publish_typed("bans", &Ban { ip: "1.2.3.4".into(), secs: 5 }).await.unwrap();
+
+        assert_eq!(bans.receive().await.unwrap(), Ban { ip: "1.2.3.4".into(), secs: 5 });
+        match numbers.receive().await {
+            Err(MessengerError::Deserialization(reason)) => assert!(reason.contains("bans")),
+            other => panic!("expected a deserialization error, got {:?}", other.map(|_| ())),
+        }
+        let payload = raw.receive().await.unwrap().payload;
+        messenger.cleanup().await.unwrap();
+        payload
+    }
+
+    #[tokio::test]
+    async fn typed_values_round_trip_in_the_json_serializers_format() {
+        let payload = round_trip("typed_json", Box::new(JsonSerializer)).await;
+        assert_eq!(serde_json::from_slice::<Ban>(&payload).unwrap(), Ban { ip: "1.2.3.4".into(), secs: 5 });
+    }
+
+    #[tokio::test]
+    async fn typed_values_round_trip_in_the_binary_serializers_format() {
+        let payload = round_trip("typed_binary", Box::new(BinarySerializer)).await;
+        assert!(serde_json::from_slice::<Ban>(&payload).is_err());
+        assert_eq!(PayloadFormat::Cbor.decode::<Ban>(&payload).unwrap(), Ban { ip: "1.2.3.4".into(), secs: 5 });
+    }
+
+    #[test]
+    fn wide_integers_and_bytes_survive() {
+        let value = (u64::MAX, i128::MIN, u128::MAX, vec![0u8, 255]);
+        for serializer in [&JsonSerializer as &dyn Serializer, &BinarySerializer] {
+            let payload = encode_payload(serializer, &value).unwrap();
+            assert_eq!(decode_payload::<(u64, i128, u128, Vec<u8>)>(serializer, "wide", &payload).unwrap(), value);
+        }
+    }
+}
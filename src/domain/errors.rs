@@ -63,5 +63,32 @@ pub enum MessengerError {
     MessageNotFound,
 
     #[error("You sucked all memory, there is left no more")]
-    MemoryUnavailable
+    MemoryUnavailable,
+
+    #[error("Schema violation on topic {0}: {1}")]
+    SchemaViolation(String, String), // (topic, reason)
+
+    #[error("Message {0} expired before it could be delivered")]
+    Expired(String), // message id
+
+    #[error("Topic {0} has no room for more messages")]
+    Backpressure(String), // topic
+
+    #[error("Message {0} was dropped by its topic's backpressure policy")]
+    Dropped(String), // message id
+
+    #[error("Subscription to {0} was closed for not keeping up")]
+    SlowConsumer(String), // topic
+
+    #[error("Transaction too large: {0} messages, max {1}")]
+    TransactionTooLarge(usize, usize), // (messages, max_messages)
+
+    #[error("Invalid filter '{0}': {1}")]
+    InvalidFilter(String, String), // (expression, reason)
+
+    #[error("RPC stream {0} was cancelled")]
+    StreamCancelled(String), // stream id
+
+    #[error("No handler of RPC method {0} is announced")]
+    NoRpcHandler(String), // method
 }
\ No newline at end of file
@@ -0,0 +1,466 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::cell::OnceCell;
+use std::cmp::Ordering;
+
+use serde_json::Value as Json;
+
+use crate::domain::errors::MessengerError;
+use crate::domain::message::{Message, MessageView};
+
+// content-based subscription filter over message headers and json payload fields
+//
+// names refer to headers, names starting with `payload.` to fields of a json payload, e.g.
+//   country in ('DE', 'FR') && severity >= 3
+//   payload.request.method == "POST" || !x-trusted
+// comparisons are `==`, `!=`, `<`, `<=`, `>`, `>=` and `in (...)` against string, number and
+// boolean literals, a bare name checks that the field is there, and `&&`, `||`, `!` and
+// parentheses combine them
+// values compare as numbers when both sides are numbers, comparisons on a missing field are false
+// filters come from peers too, parsing and evaluating recurse over the expression, so its
+// size and nesting are capped to keep the stack bounded
+const MAX_TOKENS: usize = 1024;
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Exists(Field),
+    Compare(Field, Op, Literal),
+    In(Field, Vec<Literal>),
+}
+
+#[derive(Debug, Clone)]
+enum Field {
+    Header(String),
+    Payload(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+}
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Self, MessengerError> {
+        let invalid = |reason: String| MessengerError::InvalidFilter(source.to_string(), reason);
+        let tokens = tokenize(source).map_err(invalid)?;
+        if tokens.len() > MAX_TOKENS {
+            return Err(invalid(format!("more than {} tokens", MAX_TOKENS)));
+        }
+        let mut parser = Parser { tokens, position: 0, depth: 0 };
+        let expr = parser.or().map_err(invalid)?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            return Err(invalid(format!("unexpected {:?}", token)));
+        }
+        Ok(Self { source: source.to_string(), expr })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        // the payload is only decoded when the filter looks into it, and then once
+        let payload = OnceCell::new();
+        self.expr.eval(message, &payload)
+    }
+
+    // same as `matches`, on a message still borrowed from its receive buffer
+    pub fn matches_view(&self, view: &MessageView<'_>) -> bool {
+        let payload = OnceCell::new();
+        self.expr.eval(view, &payload)
+    }
+}
+
+// what a filter reads from a message, owned or viewed
+trait Fields {
+    fn header(&self, name: &str) -> Option<&str>;
+    fn payload(&self) -> &[u8];
+}
+
+impl Fields for Message {
+    fn header(&self, name: &str) -> Option<&str> {
+        Message::header(self, name)
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+impl Fields for MessageView<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        MessageView::header(self, name)
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+impl Expr {
+    fn eval(&self, message: &dyn Fields, payload: &OnceCell<Option<Json>>) -> bool {
+        match self {
+            Expr::Or(left, right) => left.eval(message, payload) || right.eval(message, payload),
+            Expr::And(left, right) => left.eval(message, payload) && right.eval(message, payload),
+            Expr::Not(inner) => !inner.eval(message, payload),
+            Expr::Exists(field) => field.value(message, payload).is_some(),
+            Expr::Compare(field, op, literal) => field.value(message, payload).is_some_and(|value| {
+                let ordering = compare(&value, literal);
+                match op {
+                    Op::Eq => ordering == Some(Ordering::Equal),
+                    Op::Ne => ordering.is_some_and(|ordering| ordering != Ordering::Equal),
+                    Op::Lt => ordering == Some(Ordering::Less),
+                    Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                    Op::Gt => ordering == Some(Ordering::Greater),
+                    Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+                }
+            }),
+            Expr::In(field, literals) => field.value(message, payload).is_some_and(|value| {
+                literals.iter().any(|literal| compare(&value, literal) == Some(Ordering::Equal))
+            }),
+        }
+    }
+}
+
+impl Field {
+    // value of the field in the message, None when it is missing or not a scalar
+    fn value(&self, message: &dyn Fields, payload: &OnceCell<Option<Json>>) -> Option<Literal> {
+        match self {
+            Field::Header(name) => message.header(name).map(|value| Literal::Str(value.to_string())),
+            Field::Payload(path) => {
+                let json = payload.get_or_init(|| serde_json::from_slice(message.payload()).ok()).as_ref()?;
+                match path.iter().try_fold(json, |json, segment| json.get(segment))? {
+                    Json::String(value) => Some(Literal::Str(value.clone())),
+                    Json::Number(value) => value.as_f64().map(Literal::Num),
+                    Json::Bool(value) => Some(Literal::Bool(*value)),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+// headers are strings, so a header compares with a number or boolean literal by its parsed value
+fn compare(value: &Literal, literal: &Literal) -> Option<Ordering> {
+    match (value, literal) {
+        (Literal::Num(value), Literal::Num(literal)) => value.partial_cmp(literal),
+        (Literal::Str(value), Literal::Num(literal)) => value.parse::<f64>().ok()?.partial_cmp(literal),
+        (Literal::Str(value), Literal::Str(literal)) => Some(value.as_str().cmp(literal)),
+        (Literal::Bool(value), Literal::Bool(literal)) => Some(value.cmp(literal)),
+        (Literal::Str(value), Literal::Bool(literal)) => Some(value.parse::<bool>().ok()?.cmp(literal)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Literal(Literal),
+    Op(Op),
+    In,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Comma,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        let mut take = |token: Token, len: usize| {
+            for _ in 0..len {
+                chars.next();
+            }
+            tokens.push(token);
+        };
+        let next = source[start + c.len_utf8()..].chars().next();
+        match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                chars.next();
+            }
+            ('(', _) => take(Token::Open, 1),
+            (')', _) => take(Token::Close, 1),
+            (',', _) => take(Token::Comma, 1),
+            ('&', Some('&')) => take(Token::And, 2),
+            ('|', Some('|')) => take(Token::Or, 2),
+            ('=', Some('=')) => take(Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => take(Token::Op(Op::Ne), 2),
+            ('<', Some('=')) => take(Token::Op(Op::Le), 2),
+            ('>', Some('=')) => take(Token::Op(Op::Ge), 2),
+            ('!', _) => take(Token::Not, 1),
+            ('<', _) => take(Token::Op(Op::Lt), 1),
+            ('>', _) => take(Token::Op(Op::Gt), 1),
+            ('\'' | '"', _) => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped)) => value.push(escaped),
+                            None => return Err("unterminated string".to_string()),
+                        },
+                        Some((_, quote)) if quote == c => break,
+                        Some((_, other)) => value.push(other),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Literal(Literal::Str(value)));
+            }
+            (c, _) if c.is_ascii_digit() || c == '-' => {
+                let mut end = start;
+                while let Some(&(index, c)) = chars.peek() {
+                    if !(c.is_ascii_digit() || c == '.' || c == '-' || c == 'e' || c == 'E') {
+                        break;
+                    }
+                    end = index + c.len_utf8();
+                    chars.next();
+                }
+                let number = &source[start..end];
+                let value = number.parse().map_err(|_| format!("invalid number '{}'", number))?;
+                tokens.push(Token::Literal(Literal::Num(value)));
+            }
+            (c, _) if c.is_alphabetic() || c == '_' => {
+                let mut end = start;
+                while let Some(&(index, c)) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_' || c == '-' || c == '.') {
+                        break;
+                    }
+                    end = index + c.len_utf8();
+                    chars.next();
+                }
+                tokens.push(match &source[start..end] {
+                    "in" => Token::In,
+                    "true" => Token::Literal(Literal::Bool(true)),
+                    "false" => Token::Literal(Literal::Bool(false)),
+                    name => Token::Name(name.to_string()),
+                });
+            }
+            (c, _) => return Err(format!("unexpected character '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+// recursive descent over the tokens, `||` binds weaker than `&&`, which binds weaker than `!`
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    // `!` and parentheses the parser is inside of
+    depth: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, expected: &Token) -> bool {
+        if self.tokens.get(self.position) == Some(expected) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.not()?;
+        while self.eat(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.eat(&Token::Not) {
+            let inner = self.nested(Self::not)?;
+            return Ok(Expr::Not(Box::new(inner)));
+        }
+        if self.eat(&Token::Open) {
+            let expr = self.nested(Self::or)?;
+            return match self.next() {
+                Some(Token::Close) => Ok(expr),
+                other => Err(format!("expected ')', found {:?}", other)),
+            };
+        }
+        self.comparison()
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Expr, String>) -> Result<Expr, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(format!("nested more than {} levels deep", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let field = match self.next() {
+            Some(Token::Name(name)) => match name.strip_prefix("payload.") {
+                Some(path) => Field::Payload(path.split('.').map(str::to_string).collect()),
+                None => Field::Header(name),
+            },
+            other => return Err(format!("expected a field name, found {:?}", other)),
+        };
+        match self.tokens.get(self.position).cloned() {
+            Some(Token::Op(op)) => {
+                self.position += 1;
+                Ok(Expr::Compare(field, op, self.literal()?))
+            }
+            Some(Token::In) => {
+                self.position += 1;
+                if !self.eat(&Token::Open) {
+                    return Err("expected '(' after 'in'".to_string());
+                }
+                let mut literals = vec![self.literal()?];
+                while self.eat(&Token::Comma) {
+                    literals.push(self.literal()?);
+                }
+                match self.next() {
+                    Some(Token::Close) => Ok(Expr::In(field, literals)),
+                    other => Err(format!("expected ')', found {:?}", other)),
+                }
+            }
+            _ => Ok(Expr::Exists(field)),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Literal, String> {
+        match self.next() {
+            Some(Token::Literal(literal)) => Ok(literal),
+            other => Err(format!("expected a value, found {:?}", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(payload: &str) -> Message {
+        Message::new("t".into(), payload.as_bytes().to_vec())
+            .with_header("country", "DE")
+            .with_header("severity", "4")
+            .with_header("x-trusted", "true")
+    }
+
+    fn matches(source: &str, message: &Message) -> bool {
+        let filter = Filter::parse(source).unwrap();
+        // views and owned messages read the same fields
+        assert_eq!(filter.matches(message), filter.matches_view(&message.as_view()));
+        filter.matches(message)
+    }
+
+    #[test]
+    fn headers_compare_as_numbers_strings_or_booleans() {
+        let message = message("");
+        assert!(matches("country == 'DE'", &message));
+        assert!(matches("country != \"FR\"", &message));
+        assert!(matches("severity >= 3 && severity < 5", &message));
+        // 4 is more than 10 as a string, but headers compare with numbers by value
+        assert!(!matches("severity > 10", &message));
+        assert!(matches("country in ('FR', 'DE')", &message));
+        assert!(matches("x-trusted == true", &message));
+        assert!(matches("x-trusted && !missing", &message));
+        // comparisons on a missing field are false either way
+        assert!(!matches("missing == 'a'", &message));
+        assert!(!matches("missing != 'a'", &message));
+    }
+
+    #[test]
+    fn payload_fields_are_read_from_json() {
+        let message = message(r#"{"request": {"method": "POST", "size": 120.5, "tls": false, "tags": ["a"]}}"#);
+        assert!(matches("payload.request.method == 'POST'", &message));
+        assert!(matches("payload.request.size > 100", &message));
+        assert!(matches("payload.request.tls == false", &message));
+        // arrays and objects are not values
+        assert!(!matches("payload.request.tags", &message));
+        assert!(!matches("payload.request", &message));
+        assert!(!matches("payload.request.method == 'POST'", &Message::new("t".into(), b"not json".to_vec())));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or_and_parentheses_group() {
+        let message = message("");
+        assert!(matches("country == 'DE' || missing && missing", &message));
+        assert!(!matches("(country == 'DE' || missing) && missing", &message));
+        assert!(matches("!(missing || country == 'FR')", &message));
+        assert!(matches("!!country", &message));
+    }
+
+    #[test]
+    fn malformed_filters_are_rejected() {
+        for source in ["", "country ==", "== 'DE'", "country == 'DE", "(country", "country in 'DE'", "a && && b", "a # b", "a b"] {
+            assert!(matches!(Filter::parse(source), Err(MessengerError::InvalidFilter(..))), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn deep_and_long_filters_are_rejected_before_they_can_exhaust_the_stack() {
+        let nested = |depth: usize| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Filter::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(matches!(Filter::parse(&nested(MAX_DEPTH + 1)), Err(MessengerError::InvalidFilter(..))));
+        assert!(matches!(Filter::parse(&"(".repeat(100_000)), Err(MessengerError::InvalidFilter(..))));
+        assert!(matches!(Filter::parse(&format!("{}a", "!".repeat(100_000))), Err(MessengerError::InvalidFilter(..))));
+        let long = vec!["a"; MAX_TOKENS].join(" && ");
+        assert!(matches!(Filter::parse(&long), Err(MessengerError::InvalidFilter(..))));
+    }
+}
@@ -0,0 +1,58 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+// header names reserved by the messenger
+
+// dead letters: topic the message was originally published on
+pub const ORIGINAL_TOPIC: &str = "x-original-topic";
+// dead letters: why the message could not be handled
+pub const FAILURE_REASON: &str = "x-failure-reason";
+// dead letters: how many delivery attempts were made
+pub const ATTEMPTS: &str = "x-attempts";
+
+// durable log: offset the message was appended at in its topic's log
+pub const LOG_OFFSET: &str = "x-log-offset";
+
+// partitioned topics: partition the message was assigned to from its key
+pub const PARTITION: &str = "x-partition";
+
+// scheduled messages: when the message is due, in milliseconds since the unix epoch
+pub const DELIVER_AT: &str = "x-deliver-at";
+
+// chunks: id of the message the chunk is part of
+pub const CHUNK_ID: &str = "x-chunk-id";
+// chunks: position of the chunk in its message, starting at 0
+pub const CHUNK_INDEX: &str = "x-chunk-index";
+// chunks: number of chunks the message was split into
+pub const CHUNK_COUNT: &str = "x-chunk-count";
+
+// transactions: id of the transaction the message was published in
+pub const TXN_ID: &str = "x-txn-id";
+// transactions: position of the message in its transaction, starting at 0
+pub const TXN_INDEX: &str = "x-txn-index";
+// transactions: number of messages the transaction published
+pub const TXN_COUNT: &str = "x-txn-count";
+
+// at-least-once delivery: topic the subscriber acknowledges the message on
+pub const ACK_TO: &str = "x-ack-to";
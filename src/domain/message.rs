@@ -22,7 +22,11 @@
 //
 // Authors: I. Zeqiri, E. Gjergji
 
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
 use serde::{Serialize, Deserialize};
+use crate::utils::clock::{now_millis, to_millis};
 use crate::utils::zark_uid::generate_zark_uid;
 
 
@@ -35,6 +39,57 @@ pub struct Message {
     pub id: String,
     // payload contains the actual content of the message as a byte vector
     pub payload: Vec<u8>,
+    // headers carry metadata about the message, see `domain::headers` for the reserved names
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    // key identifies the entity the message is about, compacted logs keep the newest message per key
+    // a keyed message with an empty payload is a tombstone that deletes the key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    // retain asks the receiving side to keep the message as the topic's last value and hand it
+    // to every later subscriber, a retained message with an empty payload clears it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub retain: bool,
+    // priority decides the order queued messages are sent in, see `Priority`
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+    // expires_at is when the message goes stale, in milliseconds since the unix epoch
+    // expired messages are dropped instead of delivered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// priority of a message in the transport queues
+/// higher priorities are served first, lower ones still get a share so they never starve
+pub enum Priority {
+    // bulk traffic such as telemetry
+    Low = 0,
+    #[default]
+    Normal = 1,
+    // rule updates and other control traffic
+    High = 2,
+    // block verdicts and anything else that must not wait
+    Critical = 3,
+}
+
+impl Priority {
+    // number of priority levels
+    pub const LEVELS: usize = 4;
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Priority::Low),
+            1 => Some(Priority::Normal),
+            2 => Some(Priority::High),
+            3 => Some(Priority::Critical),
+            _ => None,
+        }
+    }
+
+    pub fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
 }
 
 impl Message {
@@ -42,6 +97,127 @@ impl Message {
     pub fn new(topic: String, payload: Vec<u8>) -> Self {
         // create a new message with the given topic and payload
         // generate a unique id for the message using the zark_uid generator
-        Self { topic, id: generate_zark_uid(), payload }
+        Self { topic, id: generate_zark_uid(), payload, headers: BTreeMap::new(), key: None, retain: false, priority: Priority::Normal, expires_at: None }
+    }
+
+    // with_key sets the message key and returns the message, for chaining after new
+    pub fn with_key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    // retained marks the message to be kept as its topic's last value, for chaining after new
+    pub fn retained(mut self) -> Self {
+        self.retain = true;
+        self
+    }
+
+    // with_priority sets the message priority and returns the message, for chaining after new
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    // with_ttl makes the message expire `ttl` from now, for chaining after new
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.expires_at = Some(to_millis(SystemTime::now() + ttl));
+        self
+    }
+
+    // is_expired reports whether the message is past its expiry time
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now_millis())
+    }
+
+    // is_tombstone reports whether the message deletes its key from a compacted log
+    pub fn is_tombstone(&self) -> bool {
+        self.key.is_some() && self.payload.is_empty()
+    }
+
+    // with_header sets a header and returns the message, for chaining after new
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name.into(), value.into());
+        self
+    }
+
+    // header looks up a header value by name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
     }
-}
\ No newline at end of file
+
+    // as_view borrows the message as a view without copying any field
+    pub fn as_view(&self) -> MessageView<'_> {
+        MessageView {
+            topic: Cow::Borrowed(&self.topic),
+            id: Cow::Borrowed(&self.id),
+            payload: Cow::Borrowed(&self.payload),
+            headers: self.headers
+                .iter()
+                .map(|(name, value)| (Cow::Borrowed(name.as_str()), Cow::Borrowed(value.as_str())))
+                .collect(),
+            key: self.key.as_deref().map(Cow::Borrowed),
+            retain: self.retain,
+            priority: self.priority,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// message view is a message decoded in place from a receive buffer
+/// fields borrow from the buffer whenever the wire format allows it, so the
+/// view cannot outlive the buffer it was decoded from
+pub struct MessageView<'a> {
+    pub topic: Cow<'a, str>,
+    pub id: Cow<'a, str>,
+    pub payload: Cow<'a, [u8]>,
+    // headers in name order
+    pub headers: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+    pub key: Option<Cow<'a, str>>,
+    pub retain: bool,
+    pub priority: Priority,
+    pub expires_at: Option<u64>,
+}
+
+impl MessageView<'_> {
+    // to_owned copies the view into a message that owns its data
+    pub fn to_owned(&self) -> Message {
+        Message {
+            topic: self.topic.to_string(),
+            id: self.id.to_string(),
+            payload: self.payload.to_vec(),
+            headers: self.headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            key: self.key.as_ref().map(|key| key.to_string()),
+            retain: self.retain,
+            priority: self.priority,
+            expires_at: self.expires_at,
+        }
+    }
+
+    // is_expired reports whether the message is past its expiry time
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now_millis())
+    }
+
+    // header looks up a header value by name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_ref())
+    }
+
+    // is_borrowed reports whether no field had to be copied out of the buffer
+    pub fn is_borrowed(&self) -> bool {
+        matches!(self.topic, Cow::Borrowed(_))
+            && matches!(self.id, Cow::Borrowed(_))
+            && matches!(self.payload, Cow::Borrowed(_))
+            && self.headers
+                .iter()
+                .all(|(name, value)| matches!(name, Cow::Borrowed(_)) && matches!(value, Cow::Borrowed(_)))
+            && !matches!(self.key, Some(Cow::Owned(_)))
+    }
+}
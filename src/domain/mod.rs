@@ -26,6 +26,11 @@ pub mod message;
 pub mod errors;
 pub mod rpc_request;
 pub mod rpc_response;
+pub mod rpc_frame;
+pub mod rpc_presence;
 pub mod topic;
 pub mod serializable;
+pub mod schema;
+pub mod headers;
+pub mod filter;
 
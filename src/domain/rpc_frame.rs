@@ -0,0 +1,51 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use serde::{Serialize, Deserialize};
+
+// what a streaming rpc frame carries
+#[derive(Serialize, Deserialize, Debug)]
+pub enum StreamFrameKind {
+    // caller -> handler: start a call of `method`, the caller takes `credits` items before granting more
+    Open { method: String, params: Vec<u8>, credits: u32 },
+    // one item of the sender's stream
+    Item(Vec<u8>),
+    // the receiver made room for `credits` more items
+    Credit(u32),
+    // the sender's stream is complete
+    End,
+    // the sender's stream failed, no more items follow
+    Error(Vec<u8>),
+    // either side gave up on the call, both streams stop
+    Cancel,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamFrame {
+    // id of the call the frame belongs to
+    pub stream: String,
+    // topic the sender takes the frames of this call on
+    pub reply_to: String,
+    pub kind: StreamFrameKind,
+}
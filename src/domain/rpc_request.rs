@@ -23,10 +23,23 @@
 // Authors: I. Zeqiri, E. Gjergji
 
 use serde::{Serialize, Deserialize};
+use crate::utils::zark_uid::generate_zark_uid;
 
 #[derive(Serialize, Deserialize)]
 pub struct RpcRequest {
     id: String,
     pub method: String,
     pub params: Vec<u8>,
+    // topic the handler publishes its response to
+    pub reply_to: String,
+}
+
+impl RpcRequest {
+    pub fn new(method: String, params: Vec<u8>, reply_to: String) -> Self {
+        Self { id: generate_zark_uid(), method, params, reply_to }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
 }
\ No newline at end of file
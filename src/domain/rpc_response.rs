@@ -23,10 +23,13 @@
 // Authors: I. Zeqiri, E. Gjergji
 
 use std::sync::Arc;
+use serde::{Serialize, Deserialize};
 
 
+#[derive(Serialize, Deserialize)]
 pub struct RpcResponse {
-
+    // id of the request this response answers
+    pub id: String,
     pub result : Arc<[u8]>,
     pub error: Option<Vec<u8>>,
 }
@@ -22,8 +22,9 @@
 //
 // Authors: I. Zeqiri, E. Gjergji
 
+use std::borrow::Cow;
 use crate::domain::errors::MessengerError;
-use crate::domain::message::Message;
+use crate::domain::message::{Message, MessageView, Priority};
 
 pub trait Serializable: Sized {
     fn serialize(&self) -> Result<Vec<u8>, MessengerError>;
@@ -47,48 +48,152 @@ impl Serializable for Message {
         // serialize payload
         result.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
         result.extend_from_slice(&self.payload);
+
+        // serialize headers as a count followed by length-prefixed name/value pairs
+        result.extend_from_slice(&(self.headers.len() as u32).to_le_bytes());
+        for (name, value) in &self.headers {
+            for field in [name.as_bytes(), value.as_bytes()] {
+                result.extend_from_slice(&(field.len() as u32).to_le_bytes());
+                result.extend_from_slice(field);
+            }
+        }
+
+        // serialize key as a presence flag followed by the length-prefixed key
+        match &self.key {
+            Some(key) => {
+                result.push(1);
+                result.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                result.extend_from_slice(key.as_bytes());
+            }
+            None => result.push(0),
+        }
+
+        // serialize retain flag and priority
+        result.push(self.retain as u8);
+        result.push(self.priority as u8);
+
+        // serialize expiry as a presence flag followed by the little-endian timestamp
+        match self.expires_at {
+            Some(expires_at) => {
+                result.push(1);
+                result.extend_from_slice(&expires_at.to_le_bytes());
+            }
+            None => result.push(0),
+        }
         
         Ok(result)
     }
 
     fn deserialize(data: &[u8]) -> Result<Self, MessengerError> {
-        let mut cursor = 0;
-        
-        // helper function to read a length-prefixed string
-        let read_string = |cursor: &mut usize| -> Result<String, MessengerError> {
-            if *cursor + 4 > data.len() {
-                return Err(MessengerError::Deserialization("Incomplete data".to_string()));
-            }
-            let len = u32::from_le_bytes([data[*cursor], data[*cursor+1], data[*cursor+2], data[*cursor+3]]) as usize;
-            *cursor += 4;
-            if *cursor + len > data.len() {
-                return Err(MessengerError::Deserialization("Incomplete data".to_string()));
-            }
-            let s = String::from_utf8(data[*cursor..*cursor+len].to_vec())
-                .map_err(|e| MessengerError::Deserialization(e.to_string()))?;
-            *cursor += len;
-            Ok(s)
-        };
-        
-        // Read topic
-        let topic = read_string(&mut cursor)?;
-        
-        // Read id
-        let id = read_string(&mut cursor)?;
-        
-        // Read payload
-        if cursor + 4 > data.len() {
+        deserialize_view(data).map(|view| view.to_owned())
+    }
+}
+
+// decode a binary encoded message in place, every field of the returned view
+// borrows from `data`
+pub fn deserialize_view(data: &[u8]) -> Result<MessageView<'_>, MessengerError> {
+    let mut cursor = 0;
+
+    // helper function to read a little-endian u32
+    let read_u32 = |cursor: &mut usize| -> Result<usize, MessengerError> {
+        if data.len() - *cursor < 4 {
             return Err(MessengerError::Deserialization("Incomplete data".to_string()));
         }
-        let payload_len = u32::from_le_bytes([data[cursor], data[cursor+1], data[cursor+2], data[cursor+3]]) as usize;
-        cursor += 4;
-        if cursor + payload_len > data.len() {
+        let value = u32::from_le_bytes([data[*cursor], data[*cursor+1], data[*cursor+2], data[*cursor+3]]) as usize;
+        *cursor += 4;
+        Ok(value)
+    };
+
+    // helper function to read a length-prefixed byte slice
+    let read_bytes = |cursor: &mut usize| -> Result<&[u8], MessengerError> {
+        let len = read_u32(cursor)?;
+        // compare against the remaining bytes so a hostile length cannot overflow the cursor
+        if len > data.len() - *cursor {
             return Err(MessengerError::Deserialization("Incomplete data".to_string()));
         }
-        let payload = data[cursor..cursor+payload_len].to_vec();
-        
-        Ok(Message { topic, id, payload })
+        let bytes = &data[*cursor..*cursor+len];
+        *cursor += len;
+        Ok(bytes)
+    };
+
+    // helper function to read a length-prefixed string
+    let read_str = |cursor: &mut usize| -> Result<&str, MessengerError> {
+        std::str::from_utf8(read_bytes(cursor)?)
+            .map_err(|e| MessengerError::Deserialization(e.to_string()))
+    };
+
+    // Read topic
+    let topic = read_str(&mut cursor)?;
+
+    // Read id
+    let id = read_str(&mut cursor)?;
+
+    // Read payload
+    let payload = read_bytes(&mut cursor)?;
+
+    // Read headers, absent in messages encoded before headers existed
+    let mut headers = Vec::new();
+    if cursor < data.len() {
+        let count = read_u32(&mut cursor)?;
+        for _ in 0..count {
+            let name = read_str(&mut cursor)?;
+            let value = read_str(&mut cursor)?;
+            headers.push((Cow::Borrowed(name), Cow::Borrowed(value)));
+        }
     }
+
+    // Read key, absent in messages encoded before keys existed
+    let mut key = None;
+    if cursor < data.len() {
+        let present = data[cursor];
+        cursor += 1;
+        match present {
+            0 => {}
+            1 => key = Some(Cow::Borrowed(read_str(&mut cursor)?)),
+            _ => return Err(MessengerError::Deserialization("Invalid key flag".to_string())),
+        }
+    }
+
+    // Read retain flag and priority, absent in messages encoded before they existed
+    let mut retain = false;
+    if let Some(&flag) = data.get(cursor) {
+        retain = match flag {
+            0 => false,
+            1 => true,
+            _ => return Err(MessengerError::Deserialization("Invalid retain flag".to_string())),
+        };
+        cursor += 1;
+    }
+    let mut priority = Priority::Normal;
+    if let Some(&level) = data.get(cursor) {
+        priority = Priority::from_u8(level)
+            .ok_or_else(|| MessengerError::Deserialization("Invalid priority".to_string()))?;
+        cursor += 1;
+    }
+
+    // Read expiry, absent in messages encoded before expiry existed
+    let expires_at = match data.get(cursor) {
+        None | Some(0) => None,
+        Some(1) => {
+            let bytes = data.get(cursor + 1..cursor + 9)
+                .ok_or_else(|| MessengerError::Deserialization("Incomplete data".to_string()))?;
+            let mut expires_at = [0u8; 8];
+            expires_at.copy_from_slice(bytes);
+            Some(u64::from_le_bytes(expires_at))
+        }
+        Some(_) => return Err(MessengerError::Deserialization("Invalid expiry flag".to_string())),
+    };
+
+    Ok(MessageView {
+        topic: Cow::Borrowed(topic),
+        id: Cow::Borrowed(id),
+        payload: Cow::Borrowed(payload),
+        headers,
+        key,
+        retain,
+        priority,
+        expires_at,
+    })
 }
 
 // Implement Serializable for Vec<u8> (raw bytes)
@@ -23,5 +23,68 @@
 // Authors: I. Zeqiri, E. Gjergji
 
 
+// topics starting with this prefix carry the messenger's own traffic (rpc, control frames)
+// and are exempt from per-topic policies such as schema validation
+pub const SYSTEM_TOPIC_PREFIX: &str = "$zark.";
+// durable log topic that pending scheduled messages are kept in
+pub const SCHEDULE_TOPIC: &str = "$zark.schedule";
+
+pub fn is_system_topic(topic: &str) -> bool {
+    topic.starts_with(SYSTEM_TOPIC_PREFIX)
+}
+
 #[derive(Clone, Hash, Eq, PartialEq, Default, Debug)]
-pub struct Topic(pub Vec<u8>);
\ No newline at end of file
+pub struct Topic(pub Vec<u8>);
+
+// topic pattern used to attach configuration to a family of topics
+// topics are dot-separated, `*` matches exactly one segment and a trailing `#`
+// matches any number of remaining segments, e.g. `waf.rules.*` or `waf.#`
+#[derive(Clone, Hash, Eq, PartialEq, Debug)]
+pub struct TopicPattern(String);
+
+impl TopicPattern {
+    pub fn new(pattern: impl Into<String>) -> Self {
+        Self(pattern.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // check whether a concrete topic falls under this pattern
+    pub fn matches(&self, topic: &str) -> bool {
+        let mut segments = topic.split('.');
+        for expected in self.0.split('.') {
+            match expected {
+                "#" => return true,
+                "*" => {
+                    if segments.next().is_none() {
+                        return false;
+                    }
+                }
+                literal => {
+                    if segments.next() != Some(literal) {
+                        return false;
+                    }
+                }
+            }
+        }
+        segments.next().is_none()
+    }
+
+    // number of literal segments, a higher value means a more specific pattern
+    pub fn specificity(&self) -> usize {
+        self.0.split('.').filter(|segment| *segment != "*" && *segment != "#").count()
+    }
+}
+
+// partition of a partitioned topic that a message key belongs to
+// the hash is fnv-1a so every process maps a key to the same partition
+pub fn partition_for(key: &str, partitions: u32) -> u32 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    (hash % partitions.max(1) as u64) as u32
+}
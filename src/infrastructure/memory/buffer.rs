@@ -25,6 +25,7 @@
 use std::sync::Arc;
 use std::ops::{Deref, DerefMut};
 use std::ptr::NonNull;
+use parking_lot::Mutex;
 use shared_memory::{Shmem, ShmemConf};
 
 
@@ -33,21 +34,37 @@ pub struct Buffer {
 }
 
 struct BufferInner {
-    ptr: NonNull<u8>,
+    mapping: Mapping,
     size: usize,
-    shm: Shmem,
     destructor: Option<Box<dyn Fn(*mut u8, usize) + Send + Sync>>,
 }
 
+// a shared-memory mapping and the start of its bytes, the only part of a buffer that is
+// not Send/Sync on its own
+struct Mapping {
+    ptr: NonNull<u8>,
+    shm: Shmem,
+}
+
+// SAFETY: `Shmem` and `NonNull` are not Send only because they hold raw pointers to the
+// mapping. The mapping is owned exclusively by this value, `ptr` points into it and stays
+// valid until `shm` is dropped together with it, and unmapping works from any thread.
+unsafe impl Send for Mapping {}
+
+// SAFETY: shared references never write through `ptr`: `Buffer` only reads through `Deref`,
+// and writes need `&mut Buffer`, which is not `Clone`, so its `Arc` is not shared while the
+// bytes are mutably borrowed.
+unsafe impl Sync for Mapping {}
+
 impl Buffer {
     pub fn new(size: usize) -> Result<Self, std::io::Error> {
-        let shm = ShmemConf::new().size(size).create().unwrap();
+        let shm = ShmemConf::new().size(size).create()
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
         let ptr = NonNull::new(shm.as_ptr() as *mut u8).unwrap();
         Ok(Self {
             inner: Arc::new(BufferInner {
-                ptr,
+                mapping: Mapping { ptr, shm },
                 size,
-                shm,
                 destructor: None,
             }),
         })
@@ -59,9 +76,8 @@ impl Buffer {
         let size = shm.len();
         Ok(Self {
             inner: Arc::new(BufferInner {
-                ptr,
+                mapping: Mapping { ptr, shm },
                 size,
-                shm,
                 destructor: None,
             }),
         })
@@ -89,20 +105,20 @@ impl Deref for Buffer {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        unsafe { std::slice::from_raw_parts(self.inner.ptr.as_ptr(), self.inner.size) }
+        unsafe { std::slice::from_raw_parts(self.inner.mapping.ptr.as_ptr(), self.inner.size) }
     }
 }
 
 impl DerefMut for Buffer {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { std::slice::from_raw_parts_mut(self.inner.ptr.as_ptr(), self.inner.size) }
+        unsafe { std::slice::from_raw_parts_mut(self.inner.mapping.ptr.as_ptr(), self.inner.size) }
     }
 }
 
 impl Drop for BufferInner {
     fn drop(&mut self) {
         if let Some(destructor) = &self.destructor {
-            destructor(self.ptr.as_ptr(), self.size);
+            destructor(self.mapping.ptr.as_ptr(), self.size);
         }
     }
 }
@@ -117,6 +133,10 @@ impl PartialEq for Buffer {
 pub struct BufferPool {
     buffers: Vec<Buffer>,
     size: usize,
+    // buffers the pool may have out or idle at once
+    max_buffers: usize,
+    // buffers created and not yet dropped, idle or out
+    created: usize,
 }
 
 impl BufferPool {
@@ -128,16 +148,96 @@ impl BufferPool {
         Ok(Self {
             buffers,
             size: buffer_size,
+            max_buffers: pool_size,
+            created: pool_size,
         })
     }
 
+    // create a pool that keeps up to `pool_size` buffers but only allocates them on demand
+    pub fn lazy(buffer_size: usize, pool_size: usize) -> Self {
+        Self {
+            buffers: Vec::with_capacity(pool_size),
+            size: buffer_size,
+            max_buffers: pool_size,
+            created: 0,
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.size
+    }
+
+    // take an idle buffer, or create one while fewer than `pool_size` exist
     pub fn get_buffer(&mut self) -> Option<Buffer> {
-        self.buffers.pop().or_else(|| Buffer::new(self.size).ok())
+        if let Some(buffer) = self.buffers.pop() {
+            return Some(buffer);
+        }
+        if self.created >= self.max_buffers {
+            return None;
+        }
+        let buffer = Buffer::new(self.size).ok()?;
+        self.created += 1;
+        Some(buffer)
     }
 
     pub fn return_buffer(&mut self, buffer: Buffer) {
-        if self.buffers.len() < self.buffers.capacity() {
+        if self.buffers.len() < self.max_buffers {
             self.buffers.push(buffer);
+        } else {
+            self.created = self.created.saturating_sub(1);
+        }
+    }
+}
+
+// buffer borrowed from a shared pool, handed back to the pool when dropped
+pub struct PooledBuffer {
+    buffer: Option<Buffer>,
+    pool: Arc<Mutex<BufferPool>>,
+}
+
+impl PooledBuffer {
+    pub fn take(pool: &Arc<Mutex<BufferPool>>) -> Option<Self> {
+        let buffer = pool.lock().get_buffer()?;
+        Some(Self {
+            buffer: Some(buffer),
+            pool: Arc::clone(pool),
+        })
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.buffer.as_deref().unwrap_or_default()
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.buffer.as_deref_mut().unwrap_or_default()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.lock().return_buffer(buffer);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lazy_pool_caps_its_buffers() {
+        let pool = Arc::new(Mutex::new(BufferPool::lazy(64, 2)));
+        let first = PooledBuffer::take(&pool).unwrap();
+        let _second = PooledBuffer::take(&pool).unwrap();
+        assert!(PooledBuffer::take(&pool).is_none());
+        drop(first);
+        assert!(PooledBuffer::take(&pool).is_some());
+    }
+}
@@ -25,3 +25,5 @@
 pub mod memory;
 pub mod transport;
 pub mod serialization;
+pub mod storage;
+pub mod queue;
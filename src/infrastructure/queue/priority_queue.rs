@@ -0,0 +1,258 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::VecDeque;
+
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use crate::domain::message::Priority;
+
+// how many times in a row a waiting level may be passed over for a higher one
+// before it is served anyway, so low priorities keep at least 1 in every
+// STARVATION_LIMIT + 1 slots while higher ones are busy
+pub const STARVATION_LIMIT: u32 = 8;
+
+struct Levels<T> {
+    queues: [VecDeque<T>; Priority::LEVELS],
+    // times each waiting level was passed over since it was last served
+    skipped: [u32; Priority::LEVELS],
+    len: usize,
+    closed: bool,
+}
+
+impl<T> Levels<T> {
+    fn pop(&mut self) -> Option<T> {
+        let starved = (0..Priority::LEVELS)
+            .filter(|&level| !self.queues[level].is_empty() && self.skipped[level] >= STARVATION_LIMIT)
+            .max_by_key(|&level| self.skipped[level]);
+        let level = starved.or_else(|| (0..Priority::LEVELS).rev().find(|&level| !self.queues[level].is_empty()))?;
+
+        for lower in 0..level {
+            if !self.queues[lower].is_empty() {
+                self.skipped[lower] += 1;
+            }
+        }
+        self.skipped[level] = 0;
+        self.len -= 1;
+        self.queues[level].pop_front()
+    }
+}
+
+// why `try_push` handed an item back
+pub enum PushError<T> {
+    Full(T),
+    Closed(T),
+}
+
+// bounded queue with one fifo per priority level
+// pop serves the highest waiting level first, except that a level passed over
+// STARVATION_LIMIT times in a row is served next
+pub struct PriorityQueue<T> {
+    levels: Mutex<Levels<T>>,
+    capacity: usize,
+    readable: Notify,
+    writable: Notify,
+}
+
+impl<T> PriorityQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            levels: Mutex::new(Levels {
+                queues: std::array::from_fn(|_| VecDeque::new()),
+                skipped: [0; Priority::LEVELS],
+                len: 0,
+                closed: false,
+            }),
+            capacity: capacity.max(1),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        }
+    }
+
+    // add an item, waiting while the queue is full
+    // the item is handed back when the queue is closed
+    pub async fn push(&self, priority: Priority, item: T) -> Result<(), T> {
+        loop {
+            let writable = self.writable.notified();
+            {
+                let mut levels = self.levels.lock();
+                if levels.closed {
+                    return Err(item);
+                }
+                if levels.len < self.capacity {
+                    levels.queues[priority as usize].push_back(item);
+                    levels.len += 1;
+                    drop(levels);
+                    self.readable.notify_one();
+                    return Ok(());
+                }
+            }
+            writable.await;
+        }
+    }
+
+    // add an item if there is room right away
+    pub fn try_push(&self, priority: Priority, item: T) -> Result<(), PushError<T>> {
+        let mut levels = self.levels.lock();
+        if levels.closed {
+            return Err(PushError::Closed(item));
+        }
+        if levels.len >= self.capacity {
+            return Err(PushError::Full(item));
+        }
+        levels.queues[priority as usize].push_back(item);
+        levels.len += 1;
+        drop(levels);
+        self.readable.notify_one();
+        Ok(())
+    }
+
+    // take the next item, waiting while the queue is empty
+    // returns None once the queue is closed
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            let readable = self.readable.notified();
+            {
+                let mut levels = self.levels.lock();
+                if levels.closed {
+                    return None;
+                }
+                if let Some(item) = levels.pop() {
+                    drop(levels);
+                    self.writable.notify_one();
+                    return Some(item);
+                }
+            }
+            readable.await;
+        }
+    }
+
+    // take the next item if there is one right away
+    pub fn try_pop(&self) -> Option<T> {
+        let mut levels = self.levels.lock();
+        if levels.closed {
+            return None;
+        }
+        let item = levels.pop()?;
+        drop(levels);
+        self.writable.notify_one();
+        Some(item)
+    }
+
+    // close the queue, dropping everything still queued and waking every waiter
+    pub fn close(&self) {
+        let mut levels = self.levels.lock();
+        levels.closed = true;
+        levels.queues.iter_mut().for_each(VecDeque::clear);
+        levels.len = 0;
+        drop(levels);
+        self.readable.notify_waiters();
+        self.writable.notify_waiters();
+    }
+
+    // drop the queued items `keep` returns false for, making room for waiting pushes
+    pub fn retain(&self, mut keep: impl FnMut(&T) -> bool) {
+        let mut levels = self.levels.lock();
+        for queue in levels.queues.iter_mut() {
+            queue.retain(&mut keep);
+        }
+        let len = levels.queues.iter().map(VecDeque::len).sum();
+        let removed = levels.len - len;
+        levels.len = len;
+        drop(levels);
+        for _ in 0..removed {
+            self.writable.notify_one();
+        }
+    }
+
+    // remove the oldest item `matches` returns true for, searching the lowest priority first
+    pub fn take_first(&self, mut matches: impl FnMut(&T) -> bool) -> Option<T> {
+        let mut levels = self.levels.lock();
+        let (level, index) = levels.queues.iter().enumerate().find_map(|(level, queue)| {
+            queue.iter().position(&mut matches).map(|index| (level, index))
+        })?;
+        let item = levels.queues[level].remove(index);
+        levels.len -= 1;
+        drop(levels);
+        self.writable.notify_one();
+        item
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn higher_priorities_go_first_in_fifo_order() {
+        let queue = PriorityQueue::new(16);
+        for (priority, item) in [(Priority::Low, 1), (Priority::Critical, 2), (Priority::Normal, 3), (Priority::Critical, 4), (Priority::High, 5)] {
+            assert!(queue.try_push(priority, item).is_ok());
+        }
+        let order: Vec<_> = std::iter::from_fn(|| queue.try_pop()).collect();
+        assert_eq!(order, vec![2, 4, 5, 3, 1]);
+    }
+
+    #[test]
+    fn a_low_priority_is_served_after_being_passed_over_starvation_limit_times() {
+        let queue = PriorityQueue::new(64);
+        assert!(queue.try_push(Priority::Low, 0).is_ok());
+        for item in 1..=20 {
+            assert!(queue.try_push(Priority::Critical, item).is_ok());
+        }
+        let position = std::iter::from_fn(|| queue.try_pop()).position(|item| item == 0);
+        assert_eq!(position, Some(STARVATION_LIMIT as usize));
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_holds_pushes_until_there_is_room() {
+        let queue = Arc::new(PriorityQueue::new(1));
+        assert!(queue.try_push(Priority::Normal, 1).is_ok());
+        assert!(matches!(queue.try_push(Priority::Normal, 2), Err(PushError::Full(2))));
+
+        let pusher = Arc::clone(&queue);
+        let push = tokio::spawn(async move { pusher.push(Priority::Normal, 2).await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!push.is_finished());
+        assert_eq!(queue.pop().await, Some(1));
+        assert!(push.await.unwrap().is_ok());
+        assert_eq!(queue.take_first(|item| *item == 2), Some(2));
+
+        queue.close();
+        assert_eq!(queue.pop().await, None);
+        assert!(matches!(queue.try_push(Priority::Normal, 3), Err(PushError::Closed(3))));
+    }
+}
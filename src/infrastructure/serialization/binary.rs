@@ -0,0 +1,85 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use async_trait::async_trait;
+use crate::domain::message::{Message, MessageView};
+use crate::domain::errors::MessengerError;
+use crate::domain::serializable::{self, Serializable};
+use super::{PayloadFormat, Serializer};
+
+// binary serializer implementation
+// uses the length-prefixed layout of `Serializable`, which lets receivers decode
+// messages as views without copying topic, id or payload out of the receive buffer
+pub struct BinarySerializer;
+
+#[async_trait]
+impl Serializer for BinarySerializer {
+    // convert message to length-prefixed bytes
+    fn serialize(&self, msg: &Message) -> Result<Vec<u8>, MessengerError> {
+        Serializable::serialize(msg)
+    }
+
+    // convert length-prefixed bytes to message
+    fn deserialize(&self, data: &[u8]) -> Result<Message, MessengerError> {
+        <Message as Serializable>::deserialize(data)
+    }
+
+    // decode length-prefixed bytes into a fully borrowed view
+    fn deserialize_view<'a>(&self, data: &'a [u8]) -> Result<MessageView<'a>, MessengerError> {
+        serializable::deserialize_view(data)
+    }
+
+    // the length-prefixed topic and id, then the payload length, the trailing fields are optional
+    fn payload_prefix(&self, topic: &str, id: &str, payload_len: usize) -> Option<Vec<u8>> {
+        let mut prefix = Vec::with_capacity(12 + topic.len() + id.len());
+        for field in [topic.as_bytes(), id.as_bytes()] {
+            prefix.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            prefix.extend_from_slice(field);
+        }
+        prefix.extend_from_slice(&(payload_len as u32).to_le_bytes());
+        Some(prefix)
+    }
+
+    // typed payloads are binary too
+    fn payload_format(&self) -> PayloadFormat {
+        PayloadFormat::Cbor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn view_borrows_every_field() {
+        let message = Message::new("waf.bans".to_string(), vec![1, 2, 3])
+            .with_header("origin", "edge")
+            .with_key("1.2.3.4");
+        let frame = BinarySerializer.serialize(&message).unwrap();
+        let view = BinarySerializer.deserialize_view(&frame).unwrap();
+        assert!(view.is_borrowed());
+        assert_eq!(view.header("origin"), Some("edge"));
+        assert_eq!(view.to_owned(), message);
+    }
+}
@@ -22,15 +22,40 @@
 //
 // Authors: I. Zeqiri, E. Gjergji
 
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use async_trait::async_trait;
+use serde::Deserialize;
 use serde_json;
-use crate::domain::message::Message;
+use crate::domain::message::{Message, MessageView, Priority};
 use crate::domain::errors::MessengerError;
 use super::Serializer;
 
 // json serializer implementation
 pub struct JsonSerializer;
 
+// borrowed counterpart of the json message layout
+// topic and id borrow from the input unless they contain escapes, the payload is
+// encoded as a json array and therefore always decoded into a fresh vector
+#[derive(Deserialize)]
+struct JsonMessageView<'a> {
+    #[serde(borrow)]
+    topic: Cow<'a, str>,
+    #[serde(borrow)]
+    id: Cow<'a, str>,
+    payload: Vec<u8>,
+    #[serde(borrow, default)]
+    headers: BTreeMap<Cow<'a, str>, Cow<'a, str>>,
+    #[serde(borrow, default)]
+    key: Option<Cow<'a, str>>,
+    #[serde(default)]
+    retain: bool,
+    #[serde(default)]
+    priority: Priority,
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
 #[async_trait]
 impl Serializer for JsonSerializer {
     // convert message to json bytes
@@ -44,4 +69,34 @@ impl Serializer for JsonSerializer {
         serde_json::from_slice(data)
             .map_err(|e| MessengerError::Serialization(e.to_string()))
     }
-}
\ No newline at end of file
+
+    // decode json bytes into a view over the input
+    fn deserialize_view<'a>(&self, data: &'a [u8]) -> Result<MessageView<'a>, MessengerError> {
+        let view: JsonMessageView<'a> = serde_json::from_slice(data)
+            .map_err(|e| MessengerError::Deserialization(e.to_string()))?;
+        Ok(MessageView {
+            topic: view.topic,
+            id: view.id,
+            payload: Cow::Owned(view.payload),
+            headers: view.headers.into_iter().collect(),
+            key: view.key,
+            retain: view.retain,
+            priority: view.priority,
+            expires_at: view.expires_at,
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn view_matches_owned_decoding() {
+        let message = Message::new("waf.bans".to_string(), vec![1, 2, 3]).with_header("origin", "edge");
+        let frame = JsonSerializer.serialize(&message).unwrap();
+        let view = JsonSerializer.deserialize_view(&frame).unwrap();
+        // json arrays cannot be borrowed, strings without escapes can
+        assert!(matches!(view.topic, Cow::Borrowed(_)));
+        assert_eq!(view.to_owned(), JsonSerializer.deserialize(&frame).unwrap());
+    }
+}
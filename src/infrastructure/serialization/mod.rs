@@ -23,13 +23,68 @@
 // Authors: I. Zeqiri, E. Gjergji
 
 use async_trait::async_trait;
-use crate::domain::message::Message;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::domain::message::{Message, MessageView};
 use crate::domain::errors::MessengerError;
 
 pub mod json;
+pub mod binary;
+
+// format typed payloads are encoded in, picked by the serializer that frames the messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadFormat {
+    Json,
+    // compact and self-describing, keeps 128-bit integers that json cannot carry
+    Cbor,
+}
+
+impl PayloadFormat {
+    // encode a serde value as a payload in this format
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, MessengerError> {
+        match self {
+            PayloadFormat::Json => serde_json::to_vec(value)
+                .map_err(|e| MessengerError::Serialization(e.to_string())),
+            PayloadFormat::Cbor => {
+                let mut payload = Vec::new();
+                ciborium::into_writer(value, &mut payload)
+                    .map_err(|e| MessengerError::Serialization(e.to_string()))?;
+                Ok(payload)
+            }
+        }
+    }
+
+    // decode a payload in this format into a serde value
+    pub fn decode<T: DeserializeOwned>(self, data: &[u8]) -> Result<T, MessengerError> {
+        match self {
+            PayloadFormat::Json => serde_json::from_slice(data)
+                .map_err(|e| MessengerError::Deserialization(e.to_string())),
+            PayloadFormat::Cbor => ciborium::from_reader(data)
+                .map_err(|e| MessengerError::Deserialization(e.to_string())),
+        }
+    }
+}
 
 #[async_trait]
 pub trait Serializer: Send + Sync {
     fn serialize(&self, msg: &Message) -> Result<Vec<u8>, MessengerError>;
     fn deserialize(&self, data: &[u8]) -> Result<Message, MessengerError>;
-}
\ No newline at end of file
+    // decode a message in place, borrowing from `data` wherever the format allows
+    fn deserialize_view<'a>(&self, data: &'a [u8]) -> Result<MessageView<'a>, MessengerError>;
+
+    // bytes that go ahead of a `payload_len` byte payload so that, followed by the raw payload,
+    // they decode as a message, None when the format cannot carry a payload verbatim
+    fn payload_prefix(&self, _topic: &str, _id: &str, _payload_len: usize) -> Option<Vec<u8>> {
+        None
+    }
+
+    // format the typed api encodes payloads in, json unless a serializer says otherwise
+    fn payload_format(&self) -> PayloadFormat {
+        PayloadFormat::Json
+    }
+
+    // decode a payload produced by the typed api as a dynamic value, e.g. for schema validation
+    fn deserialize_value(&self, data: &[u8]) -> Result<serde_json::Value, MessengerError> {
+        self.payload_format().decode(data)
+    }
+}
@@ -0,0 +1,226 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+pub mod segment;
+pub mod topic_log;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use crate::application::config::LogConfig;
+use crate::domain::errors::MessengerError;
+use crate::domain::message::Message;
+use crate::domain::topic::{is_system_topic, TopicPattern, SCHEDULE_TOPIC};
+use crate::utils::clock::to_millis;
+
+use self::topic_log::TopicLog;
+
+// a message read back from a topic log
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub offset: u64,
+    // milliseconds since the epoch at which the record was appended
+    pub timestamp: u64,
+    pub message: Message,
+}
+
+struct TopicHandle {
+    log: Mutex<TopicLog>,
+    // woken after every append, for readers waiting at the end of the log
+    appended: Notify,
+}
+
+// durable logs of every logged topic, opened on first use
+pub struct LogStore {
+    config: LogConfig,
+    patterns: Vec<TopicPattern>,
+    compact_patterns: Vec<TopicPattern>,
+    topics: Mutex<HashMap<String, Arc<TopicHandle>>>,
+}
+
+impl LogStore {
+    pub fn open(config: LogConfig) -> Result<Self, MessengerError> {
+        fs::create_dir_all(&config.directory)?;
+        let patterns = config.topics.iter().map(TopicPattern::new).collect();
+        let compact_patterns = config.compact_topics.iter().map(TopicPattern::new).collect();
+        Ok(Self {
+            config,
+            patterns,
+            compact_patterns,
+            topics: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // whether messages on the topic are written to the log
+    pub fn is_logged(&self, topic: &str) -> bool {
+        !topic.is_empty()
+            && !is_system_topic(topic)
+            && (self.patterns.is_empty() || self.patterns.iter().any(|pattern| pattern.matches(topic)))
+    }
+
+    // whether the topic's log is compacted down to the newest message per key
+    pub fn is_compacted(&self, topic: &str) -> bool {
+        topic == SCHEDULE_TOPIC || self.compact_patterns.iter().any(|pattern| pattern.matches(topic))
+    }
+
+    // compact the topic's log now instead of waiting for its active segment to fill up
+    pub fn compact(&self, topic: &str) -> Result<(), MessengerError> {
+        Ok(self.handle(topic)?.log.lock().compact()?)
+    }
+
+    // append a message to its topic's log, returning its offset
+    pub fn append(&self, message: &Message) -> Result<u64, MessengerError> {
+        let handle = self.handle(&message.topic)?;
+        let offset = handle.log.lock().append(message)?;
+        handle.appended.notify_waiters();
+        Ok(offset)
+    }
+
+    // read up to `max` records of the topic starting at offset `from`
+    pub fn read(&self, topic: &str, from: u64, max: usize) -> Result<Vec<LogRecord>, MessengerError> {
+        Ok(self.handle(topic)?.log.lock().read(from, max)?)
+    }
+
+    // like `read`, but waits for the next append when there is nothing at `from` yet
+    // the log is read on a blocking thread, it may have to wait out an append's fsync for the lock
+    pub async fn tail(&self, topic: &str, from: u64, max: usize) -> Result<Vec<LogRecord>, MessengerError> {
+        let handle = self.handle(topic)?;
+        loop {
+            // registered before reading so an append in between is not missed
+            let appended = handle.appended.notified();
+            let reader = Arc::clone(&handle);
+            let records = tokio::task::spawn_blocking(move || reader.log.lock().read(from, max))
+                .await
+                .map_err(io::Error::other)??;
+            if !records.is_empty() {
+                return Ok(records);
+            }
+            appended.await;
+        }
+    }
+
+    // offset of the oldest record still kept for the topic
+    pub fn earliest_offset(&self, topic: &str) -> Result<u64, MessengerError> {
+        Ok(self.handle(topic)?.log.lock().earliest_offset())
+    }
+
+    // offset the next message appended to the topic will get
+    pub fn next_offset(&self, topic: &str) -> Result<u64, MessengerError> {
+        Ok(self.handle(topic)?.log.lock().next_offset())
+    }
+
+    // offset of the first record of the topic appended at or after `time`
+    pub fn offset_for_time(&self, topic: &str, time: SystemTime) -> Result<u64, MessengerError> {
+        Ok(self.handle(topic)?.log.lock().offset_for_timestamp(to_millis(time))?)
+    }
+
+    // flush every topic log to disk
+    pub fn sync_all(&self) {
+        let handles: Vec<_> = self.topics.lock().values().cloned().collect();
+        for handle in handles {
+            if let Err(e) = handle.log.lock().sync() {
+                log::warn!("failed to sync topic log: {}", e);
+            }
+        }
+    }
+
+    fn handle(&self, topic: &str) -> Result<Arc<TopicHandle>, MessengerError> {
+        let mut topics = self.topics.lock();
+        if let Some(handle) = topics.get(topic) {
+            return Ok(Arc::clone(handle));
+        }
+        if !self.is_logged(topic) && topic != SCHEDULE_TOPIC {
+            return Err(MessengerError::ConfigError(format!("topic '{}' is not logged", topic)));
+        }
+        // pending scheduled messages must outlive retention, compaction keeps their log small instead
+        let config = match topic {
+            SCHEDULE_TOPIC => LogConfig { retention_bytes: None, retention_secs: None, ..self.config.clone() },
+            _ => self.config.clone(),
+        };
+        let log = TopicLog::open(self.topic_directory(topic), config, self.is_compacted(topic))?;
+        let handle = Arc::new(TopicHandle { log: Mutex::new(log), appended: Notify::new() });
+        topics.insert(topic.to_string(), Arc::clone(&handle));
+        Ok(handle)
+    }
+
+    // topics are escaped into a single path component, anything outside [A-Za-z0-9._-]
+    // and a leading dot are percent-encoded
+    fn topic_directory(&self, topic: &str) -> PathBuf {
+        let mut name = String::with_capacity(topic.len());
+        for (i, byte) in topic.bytes().enumerate() {
+            match byte {
+                b'.' if i == 0 => name.push_str("%2E"),
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'-' => name.push(byte as char),
+                _ => name.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+        self.config.directory.join(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::application::config::FsyncPolicy;
+    use crate::utils::zark_uid::generate_zark_uid;
+
+    #[tokio::test]
+    async fn tail_waits_for_the_next_append() {
+        let directory = std::env::temp_dir().join(format!("zark-log-store-{}", generate_zark_uid()));
+        let store = Arc::new(LogStore::open(LogConfig {
+            directory: directory.clone(),
+            topics: vec!["waf.#".into()],
+            segment_bytes: 1024 * 1024,
+            retention_bytes: None,
+            retention_secs: None,
+            fsync: FsyncPolicy::Always,
+            compact_topics: Vec::new(),
+            tombstone_retention_secs: 0,
+        }).unwrap());
+        assert!(store.is_logged("waf.bans"));
+        assert!(!store.is_logged("other"));
+        assert!(store.append(&Message::new("other".into(), vec![0])).is_err());
+
+        let tailing = Arc::clone(&store);
+        let tail = tokio::spawn(async move { tailing.tail("waf.bans", 0, 10).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!tail.is_finished());
+        assert_eq!(store.append(&Message::new("waf.bans".into(), vec![1])).unwrap(), 0);
+
+        let records = tail.await.unwrap().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].message.payload, vec![1]);
+        assert_eq!(store.next_offset("waf.bans").unwrap(), 1);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,446 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Take, Write};
+use std::path::{Path, PathBuf};
+
+use crate::domain::message::Message;
+use crate::domain::serializable::Serializable;
+
+use super::LogRecord;
+
+// record header: body length, crc32 of the body, offset and timestamp in milliseconds
+const RECORD_HEADER_LEN: u64 = 24;
+// an index entry is written once this many bytes were appended since the previous one
+const INDEX_INTERVAL_BYTES: u64 = 4096;
+// index entry: offset relative to the segment base and byte position in the log file
+const INDEX_ENTRY_LEN: usize = 8;
+
+// outcome of compacting a segment
+pub(crate) enum Compaction {
+    // no record was dropped
+    Unchanged,
+    // the segment was rewritten with the remaining records
+    Rewritten(Segment),
+    // every record was dropped, the segment can be deleted
+    Emptied,
+}
+
+// one file of a topic log, named after the offset of its first record
+// records are appended to `<base>.log`, `<base>.index` maps every few kilobytes
+// of records to their position so reads do not have to scan the whole file
+pub(crate) struct Segment {
+    base_offset: u64,
+    next_offset: u64,
+    size: u64,
+    last_timestamp: Option<u64>,
+    log_path: PathBuf,
+    index_path: PathBuf,
+    log: File,
+    index_file: File,
+    index: Vec<(u32, u32)>,
+    // bytes appended since the last index entry
+    unindexed: u64,
+    // records a newer record with the same key replaced, kept up to date by compacted logs
+    superseded: u64,
+    // append time of the oldest tombstone still in the segment
+    oldest_tombstone: Option<u64>,
+}
+
+impl Segment {
+    // open the segment starting at `base_offset`, creating it when missing
+    // a torn record at the end of the file, left by a crash mid-append, is cut off
+    pub fn open(dir: &Path, base_offset: u64) -> io::Result<Self> {
+        let log_path = dir.join(format!("{:020}.log", base_offset));
+        let index_path = dir.join(format!("{:020}.index", base_offset));
+        Self::open_files(log_path, index_path, base_offset)
+    }
+
+    fn open_files(log_path: PathBuf, index_path: PathBuf, base_offset: u64) -> io::Result<Self> {
+        let log = OpenOptions::new().create(true).read(true).append(true).open(&log_path)?;
+        let index_file = OpenOptions::new().create(true).read(true).append(true).open(&index_path)?;
+        let file_len = log.metadata()?.len();
+
+        let mut raw = Vec::new();
+        (&index_file).read_to_end(&mut raw)?;
+        let mut index: Vec<(u32, u32)> = raw
+            .chunks_exact(INDEX_ENTRY_LEN)
+            .map(|entry| (read_u32(&entry[0..4]), read_u32(&entry[4..8])))
+            .take_while(|&(_, position)| (position as u64) < file_len)
+            .collect();
+
+        // only the records after the last index entry have to be scanned
+        let mut reader = BufReader::new(File::open(&log_path)?);
+        let mut body = Vec::new();
+        let (next_offset, size, last_timestamp) = loop {
+            let (mut next_offset, mut position) = match index.last() {
+                Some(&(relative, position)) => (base_offset + relative as u64, position as u64),
+                None => (base_offset, 0),
+            };
+            reader.seek(SeekFrom::Start(position))?;
+            let mut last_timestamp = None;
+            while let Some((offset, timestamp)) = read_record(&mut reader, &mut body)? {
+                // compaction leaves gaps between offsets, but they never go backwards
+                if offset < next_offset {
+                    break;
+                }
+                next_offset = offset + 1;
+                position += RECORD_HEADER_LEN + body.len() as u64;
+                last_timestamp = Some(timestamp);
+            }
+            // the indexed record itself was torn, fall back to the previous entry
+            if last_timestamp.is_none() && !index.is_empty() {
+                index.pop();
+                continue;
+            }
+            break (next_offset, position, last_timestamp);
+        };
+
+        if size < file_len {
+            log::warn!("truncating {} torn bytes at the end of {}", file_len - size, log_path.display());
+            log.set_len(size)?;
+        }
+        if raw.len() != index.len() * INDEX_ENTRY_LEN {
+            index_file.set_len((index.len() * INDEX_ENTRY_LEN) as u64)?;
+        }
+
+        let unindexed = size - index.last().map_or(0, |&(_, position)| position as u64);
+        Ok(Self {
+            base_offset,
+            next_offset,
+            size,
+            last_timestamp,
+            log_path,
+            index_path,
+            log,
+            index_file,
+            index,
+            unindexed,
+            superseded: 0,
+            oldest_tombstone: None,
+        })
+    }
+
+    pub fn base_offset(&self) -> u64 {
+        self.base_offset
+    }
+
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn last_timestamp(&self) -> Option<u64> {
+        self.last_timestamp
+    }
+
+    // bytes a record with this body takes up in the segment
+    pub fn record_len(body: &[u8]) -> u64 {
+        RECORD_HEADER_LEN + body.len() as u64
+    }
+
+    pub fn append(&mut self, timestamp: u64, body: &[u8]) -> io::Result<u64> {
+        self.append_at(self.next_offset, timestamp, body)?;
+        Ok(self.next_offset - 1)
+    }
+
+    // append a record with an explicit offset, which must not be below `next_offset`
+    fn append_at(&mut self, offset: u64, timestamp: u64, body: &[u8]) -> io::Result<()> {
+        if self.unindexed >= INDEX_INTERVAL_BYTES {
+            let relative = (offset - self.base_offset) as u32;
+            let position = self.size as u32;
+            let mut entry = [0u8; INDEX_ENTRY_LEN];
+            entry[0..4].copy_from_slice(&relative.to_le_bytes());
+            entry[4..8].copy_from_slice(&position.to_le_bytes());
+            self.index_file.write_all(&entry)?;
+            self.index.push((relative, position));
+            self.unindexed = 0;
+        }
+
+        // header and body go out in a single write so readers never see half a header
+        let mut record = Vec::with_capacity(Self::record_len(body) as usize);
+        record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(body).to_le_bytes());
+        record.extend_from_slice(&offset.to_le_bytes());
+        record.extend_from_slice(&timestamp.to_le_bytes());
+        record.extend_from_slice(body);
+        self.log.write_all(&record)?;
+
+        self.next_offset = offset + 1;
+        self.size += record.len() as u64;
+        self.unindexed += record.len() as u64;
+        self.last_timestamp = Some(timestamp);
+        Ok(())
+    }
+
+    // append up to `max` records starting at offset `from` to `out`
+    pub fn read(&self, from: u64, max: usize, out: &mut Vec<LogRecord>) -> io::Result<()> {
+        for record in self.records(from)?.take(max.saturating_sub(out.len())) {
+            out.push(record?);
+        }
+        Ok(())
+    }
+
+    // the records starting at offset `from`, read from the file one at a time
+    pub fn records(&self, from: u64) -> io::Result<Records> {
+        let entry = self.index.partition_point(|&(relative, _)| self.base_offset + relative as u64 <= from);
+        let position = match entry {
+            0 => 0,
+            entry => self.index[entry - 1].1 as u64,
+        };
+
+        let mut file = File::open(&self.log_path)?;
+        file.seek(SeekFrom::Start(position))?;
+        Ok(Records { reader: BufReader::new(file.take(self.size - position)), body: Vec::new(), from })
+    }
+
+    // offset of the first record appended at or after `timestamp`
+    pub fn offset_for_timestamp(&self, timestamp: u64) -> io::Result<Option<u64>> {
+        let mut reader = BufReader::new(File::open(&self.log_path)?.take(self.size));
+        let mut body = Vec::new();
+        while let Some((offset, appended)) = read_record(&mut reader, &mut body)? {
+            if appended >= timestamp {
+                return Ok(Some(offset));
+            }
+        }
+        Ok(None)
+    }
+
+    // a record of the segment was replaced by a newer one with the same key
+    pub fn supersede(&mut self) {
+        self.superseded += 1;
+    }
+
+    // a tombstone was appended to the segment at `timestamp`
+    pub fn add_tombstone(&mut self, timestamp: u64) {
+        self.oldest_tombstone = Some(self.oldest_tombstone.map_or(timestamp, |oldest| oldest.min(timestamp)));
+    }
+
+    // whether compacting would drop anything: a superseded record or a tombstone appended before the cutoff
+    pub fn is_dirty(&self, tombstone_cutoff: u64) -> bool {
+        self.superseded > 0 || self.oldest_tombstone.is_some_and(|oldest| oldest < tombstone_cutoff)
+    }
+
+    // rewrite the segment with only the records `keep` accepts, offsets stay as they were
+    // records are streamed to `.cleaned` files that replace the originals once complete
+    pub fn compact(&mut self, mut keep: impl FnMut(&LogRecord) -> bool) -> io::Result<Compaction> {
+        let cleaned_log = cleaned_path(&self.log_path);
+        let cleaned_index = cleaned_path(&self.index_path);
+        remove_if_exists(&cleaned_log)?;
+        remove_if_exists(&cleaned_index)?;
+        let mut cleaned = Self::open_files(cleaned_log.clone(), cleaned_index.clone(), self.base_offset)?;
+        let mut dropped = false;
+        for record in self.records(self.base_offset)? {
+            let record = record?;
+            if !keep(&record) {
+                dropped = true;
+                continue;
+            }
+            let body = record.message.serialize()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            cleaned.append_at(record.offset, record.timestamp, &body)?;
+            if record.message.is_tombstone() {
+                cleaned.add_tombstone(record.timestamp);
+            }
+        }
+
+        let empty = cleaned.size == 0;
+        if !dropped || empty {
+            // what `keep` kept is all that is left to drop later
+            self.superseded = 0;
+            self.oldest_tombstone = cleaned.oldest_tombstone;
+            drop(cleaned);
+            remove_if_exists(&cleaned_log)?;
+            remove_if_exists(&cleaned_index)?;
+            return Ok(if empty { Compaction::Emptied } else { Compaction::Unchanged });
+        }
+        cleaned.sync()?;
+        let oldest_tombstone = cleaned.oldest_tombstone;
+        drop(cleaned);
+
+        // the old index goes first, a crash before both renames are done leaves a log
+        // without an index, which `open` handles by scanning the whole file
+        fs::remove_file(&self.index_path)?;
+        fs::rename(&cleaned_log, &self.log_path)?;
+        fs::rename(&cleaned_index, &self.index_path)?;
+        let mut segment = Self::open_files(self.log_path.clone(), self.index_path.clone(), self.base_offset)?;
+        segment.oldest_tombstone = oldest_tombstone;
+        Ok(Compaction::Rewritten(segment))
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.log.sync_data()?;
+        self.index_file.sync_data()
+    }
+
+    pub fn delete(self) -> io::Result<()> {
+        fs::remove_file(&self.log_path)?;
+        fs::remove_file(&self.index_path)
+    }
+}
+
+// records of a segment from some offset on, decoded as they are read
+pub(crate) struct Records {
+    reader: BufReader<Take<File>>,
+    body: Vec<u8>,
+    from: u64,
+}
+
+impl Iterator for Records {
+    type Item = io::Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (offset, timestamp) = match read_record(&mut self.reader, &mut self.body) {
+                Ok(Some(record)) => record,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            if offset < self.from {
+                continue;
+            }
+            let message = Message::deserialize(&self.body)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+            return Some(message.map(|message| LogRecord { offset, timestamp, message }));
+        }
+    }
+}
+
+// path a compaction writes to before it replaces `path`
+fn cleaned_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".cleaned");
+    PathBuf::from(name)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+// read the next record into `body`, returning its offset and timestamp
+// a record cut short or failing its checksum ends the log just like the end of the file
+fn read_record(reader: &mut impl Read, body: &mut Vec<u8>) -> io::Result<Option<(u64, u64)>> {
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = read_u32(&header[0..4]) as usize;
+    let crc = read_u32(&header[4..8]);
+
+    // read through `take` so a garbage length cannot trigger a huge allocation
+    body.clear();
+    reader.by_ref().take(len as u64).read_to_end(body)?;
+    if body.len() < len || crc32fast::hash(body) != crc {
+        return Ok(None);
+    }
+    Ok(Some((read_u64(&header[8..16]), read_u64(&header[16..24]))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::zark_uid::generate_zark_uid;
+
+    fn directory() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zark-segment-{}", generate_zark_uid()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn body(payload: u8) -> Vec<u8> {
+        Message::new("t".into(), vec![payload; 512]).serialize().unwrap()
+    }
+
+    #[test]
+    fn torn_and_corrupt_records_are_cut_off_on_open() {
+        let dir = directory();
+        let mut segment = Segment::open(&dir, 10).unwrap();
+        for payload in 0..3 {
+            segment.append(1, &body(payload)).unwrap();
+        }
+        let size = segment.size();
+        drop(segment);
+
+        // flip a byte in the last body, its crc no longer matches
+        let path = dir.join(format!("{:020}.log", 10));
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut segment = Segment::open(&dir, 10).unwrap();
+        assert_eq!(segment.next_offset(), 12);
+        assert_eq!(segment.size(), size - Segment::record_len(&body(2)));
+        assert_eq!(segment.append(2, &body(9)).unwrap(), 12);
+
+        let mut records = Vec::new();
+        segment.read(10, usize::MAX, &mut records).unwrap();
+        let offsets: Vec<_> = records.iter().map(|record| record.offset).collect();
+        assert_eq!(offsets, vec![10, 11, 12]);
+        assert_eq!(records[2].message.payload, vec![9; 512]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_start_from_the_index_and_the_index_survives_reopening() {
+        let dir = directory();
+        let mut segment = Segment::open(&dir, 0).unwrap();
+        for payload in 0..40 {
+            segment.append(u64::from(payload), &body(payload)).unwrap();
+        }
+        assert!(segment.index.len() > 2);
+        let index = segment.index.clone();
+        drop(segment);
+
+        let segment = Segment::open(&dir, 0).unwrap();
+        assert_eq!(segment.index, index);
+        assert_eq!(segment.next_offset(), 40);
+        let mut records = Vec::new();
+        segment.read(25, 3, &mut records).unwrap();
+        let offsets: Vec<_> = records.iter().map(|record| record.offset).collect();
+        assert_eq!(offsets, vec![25, 26, 27]);
+        assert_eq!(segment.offset_for_timestamp(30).unwrap(), Some(30));
+        assert_eq!(segment.offset_for_timestamp(40).unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,420 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::application::config::{FsyncPolicy, LogConfig};
+use crate::domain::message::Message;
+use crate::domain::serializable::Serializable;
+use crate::utils::clock::now_millis;
+
+use super::segment::{Compaction, Segment};
+use super::LogRecord;
+
+// how often appends check whether old segments have to be deleted
+const RETENTION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// append-only log of a single topic, split into segments that retention deletes oldest first
+// the last segment is the active one, it is never deleted so offsets keep counting up
+// compacted logs also drop records superseded by a newer record with the same key
+pub struct TopicLog {
+    dir: PathBuf,
+    config: LogConfig,
+    compacted: bool,
+    segments: Vec<Segment>,
+    // offset of the newest record of every key, kept by compacted logs as records are appended
+    // so a compaction only rewrites the segments holding something superseded
+    latest: HashMap<String, u64>,
+    last_sync: Instant,
+    unsynced: bool,
+    last_retention_check: Instant,
+}
+
+impl TopicLog {
+    pub fn open(dir: PathBuf, config: LogConfig, compacted: bool) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        let mut bases = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            // leftovers of a compaction that did not finish
+            if path.extension().is_some_and(|extension| extension == "cleaned") {
+                fs::remove_file(&path)?;
+            } else if path.extension().is_some_and(|extension| extension == "log") {
+                if let Some(base) = path.file_stem().and_then(|stem| stem.to_str()?.parse::<u64>().ok()) {
+                    bases.push(base);
+                }
+            }
+        }
+        bases.sort_unstable();
+        if bases.is_empty() {
+            bases.push(0);
+        }
+
+        let segments = bases
+            .into_iter()
+            .map(|base| Segment::open(&dir, base))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut log = Self {
+            dir,
+            config,
+            compacted,
+            segments,
+            latest: HashMap::new(),
+            last_sync: Instant::now(),
+            unsynced: false,
+            last_retention_check: Instant::now(),
+        };
+        log.enforce_retention()?;
+        if compacted {
+            // the only full pass over the log, later compactions go by what appends superseded
+            for i in 0..log.segments.len() {
+                let base = log.segments[i].base_offset();
+                for record in log.segments[i].records(base)? {
+                    let record = record?;
+                    log.note(record.offset, record.timestamp, &record.message);
+                }
+            }
+            log.compact()?;
+        }
+        Ok(log)
+    }
+
+    pub fn earliest_offset(&self) -> u64 {
+        self.segments[0].base_offset()
+    }
+
+    pub fn next_offset(&self) -> u64 {
+        self.active().next_offset()
+    }
+
+    // append a message, returning its offset
+    pub fn append(&mut self, message: &Message) -> io::Result<u64> {
+        let body = message.serialize()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+        let segment_bytes = self.config.segment_bytes.min(u32::MAX as u64);
+        let active = self.active();
+        let rolled = active.size() > 0 && active.size() + Segment::record_len(&body) > segment_bytes;
+        if rolled {
+            self.roll()?;
+        }
+
+        let timestamp = now_millis();
+        let offset = self.active_mut().append(timestamp, &body)?;
+        if self.compacted {
+            self.note(offset, timestamp, message);
+        }
+        match self.config.fsync {
+            FsyncPolicy::Always => self.active().sync()?,
+            FsyncPolicy::IntervalMs(interval) => {
+                self.unsynced = true;
+                if self.last_sync.elapsed() >= Duration::from_millis(interval) {
+                    self.sync()?;
+                }
+            }
+            FsyncPolicy::Never => {}
+        }
+
+        if rolled || self.last_retention_check.elapsed() >= RETENTION_CHECK_INTERVAL {
+            self.enforce_retention()?;
+        }
+        // a segment was just closed, so there is something new to compact
+        if rolled && self.compacted {
+            self.compact()?;
+        }
+        Ok(offset)
+    }
+
+    // read up to `max` records starting at `from`, records already deleted by retention are skipped
+    // retention and compaction only touch segment files under the same lock as reads, so a read
+    // never has a file removed under it, a reader left behind carries on at the earliest offset
+    pub fn read(&self, from: u64, max: usize) -> io::Result<Vec<LogRecord>> {
+        let from = from.max(self.earliest_offset());
+        let first = self.segments.partition_point(|segment| segment.next_offset() <= from);
+        let mut records = Vec::new();
+        for segment in &self.segments[first..] {
+            segment.read(from, max, &mut records)?;
+            if records.len() >= max {
+                break;
+            }
+        }
+        Ok(records)
+    }
+
+    // offset of the first record appended at or after `timestamp` milliseconds since the epoch
+    pub fn offset_for_timestamp(&self, timestamp: u64) -> io::Result<u64> {
+        for segment in &self.segments {
+            if segment.last_timestamp().is_some_and(|last| last >= timestamp) {
+                if let Some(offset) = segment.offset_for_timestamp(timestamp)? {
+                    return Ok(offset);
+                }
+            }
+        }
+        Ok(self.next_offset())
+    }
+
+    // flush appends that the fsync policy has not synced yet
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            self.active().sync()?;
+            self.unsynced = false;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    // delete the oldest segments until the log is within its size and age limits
+    pub fn enforce_retention(&mut self) -> io::Result<()> {
+        self.last_retention_check = Instant::now();
+
+        if let Some(max_age) = self.config.retention_secs {
+            let cutoff = now_millis().saturating_sub(max_age.saturating_mul(1000));
+            let expired = |segment: &Segment| segment.last_timestamp().is_some_and(|last| last < cutoff);
+            // roll an expired active segment so it can be deleted like the others
+            if expired(self.active()) {
+                self.roll()?;
+            }
+            while self.segments.len() > 1 && expired(&self.segments[0]) {
+                self.segments.remove(0).delete()?;
+            }
+        }
+
+        if let Some(max_bytes) = self.config.retention_bytes {
+            let mut total: u64 = self.segments.iter().map(Segment::size).sum();
+            while self.segments.len() > 1 && total > max_bytes {
+                let segment = self.segments.remove(0);
+                total -= segment.size();
+                segment.delete()?;
+            }
+        }
+
+        let earliest = self.earliest_offset();
+        self.latest.retain(|_, offset| *offset >= earliest);
+        Ok(())
+    }
+
+    // keep only the newest record of every key in the closed segments
+    // tombstones are kept until `tombstone_retention_secs` passed, unkeyed records are never dropped
+    // segments without a superseded record or an old enough tombstone are left alone
+    pub fn compact(&mut self) -> io::Result<()> {
+        let tombstone_cutoff = now_millis().saturating_sub(self.config.tombstone_retention_secs.saturating_mul(1000));
+        let mut i = 0;
+        while i < self.segments.len() - 1 {
+            if !self.segments[i].is_dirty(tombstone_cutoff) {
+                i += 1;
+                continue;
+            }
+            let latest = &self.latest;
+            // keys whose newest record is a tombstone that goes away, there is nothing left of them
+            let mut deleted = Vec::new();
+            let compaction = self.segments[i].compact(|record| match &record.message.key {
+                Some(key) if latest.get(key) != Some(&record.offset) => false,
+                Some(key) if record.message.is_tombstone() && record.timestamp < tombstone_cutoff => {
+                    deleted.push(key.clone());
+                    false
+                }
+                _ => true,
+            })?;
+            for key in deleted {
+                self.latest.remove(&key);
+            }
+            match compaction {
+                Compaction::Unchanged => i += 1,
+                Compaction::Rewritten(segment) => {
+                    self.segments[i] = segment;
+                    i += 1;
+                }
+                Compaction::Emptied => self.segments.remove(i).delete()?,
+            }
+        }
+        Ok(())
+    }
+
+    // take note of a record of a compacted log, counting the record it supersedes against its segment
+    fn note(&mut self, offset: u64, timestamp: u64, message: &Message) {
+        let key = match &message.key {
+            Some(key) => key,
+            None => return,
+        };
+        if message.is_tombstone() {
+            self.segment_of(offset).add_tombstone(timestamp);
+        }
+        if let Some(previous) = self.latest.insert(key.clone(), offset) {
+            if previous >= self.earliest_offset() {
+                self.segment_of(previous).supersede();
+            }
+        }
+    }
+
+    fn segment_of(&mut self, offset: u64) -> &mut Segment {
+        let i = self.segments.partition_point(|segment| segment.next_offset() <= offset);
+        let last = self.segments.len() - 1;
+        &mut self.segments[i.min(last)]
+    }
+
+    fn roll(&mut self) -> io::Result<()> {
+        if self.config.fsync != FsyncPolicy::Never {
+            self.sync()?;
+        }
+        let segment = Segment::open(&self.dir, self.next_offset())?;
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    fn active(&self) -> &Segment {
+        self.segments.last().expect("a topic log always has an active segment")
+    }
+
+    fn active_mut(&mut self) -> &mut Segment {
+        self.segments.last_mut().expect("a topic log always has an active segment")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::zark_uid::generate_zark_uid;
+
+    fn config(dir: &std::path::Path) -> LogConfig {
+        LogConfig {
+            directory: dir.to_path_buf(),
+            topics: Vec::new(),
+            segment_bytes: 2048,
+            retention_bytes: None,
+            retention_secs: None,
+            fsync: FsyncPolicy::Never,
+            compact_topics: Vec::new(),
+            tombstone_retention_secs: 0,
+        }
+    }
+
+    fn directory() -> PathBuf {
+        std::env::temp_dir().join(format!("zark-topic-log-{}", generate_zark_uid()))
+    }
+
+    fn message(payload: u8) -> Message {
+        Message::new("t".into(), vec![payload; 256])
+    }
+
+    #[test]
+    fn records_are_read_back_across_segments_and_restarts() {
+        let dir = directory();
+        let mut log = TopicLog::open(dir.clone(), config(&dir), false).unwrap();
+        for payload in 0..20 {
+            assert_eq!(log.append(&message(payload)).unwrap(), u64::from(payload));
+        }
+        assert!(log.segments.len() > 1);
+        drop(log);
+
+        let log = TopicLog::open(dir.clone(), config(&dir), false).unwrap();
+        assert_eq!(log.next_offset(), 20);
+        let records = log.read(3, 100).unwrap();
+        assert_eq!(records.len(), 17);
+        for (record, payload) in records.iter().zip(3..) {
+            assert_eq!(record.offset, u64::from(payload));
+            assert_eq!(record.message.payload, vec![payload; 256]);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn readers_behind_retention_skip_to_the_earliest_offset() {
+        let dir = directory();
+        let config = LogConfig { retention_bytes: Some(4096), ..config(&dir) };
+        let mut log = TopicLog::open(dir.clone(), config, false).unwrap();
+        for payload in 0..40 {
+            log.append(&message(payload)).unwrap();
+        }
+        let earliest = log.earliest_offset();
+        assert!(earliest > 0);
+        assert!(log.segments.iter().map(Segment::size).sum::<u64>() <= 4096 + 2048);
+
+        let records = log.read(0, 1).unwrap();
+        assert_eq!(records[0].offset, earliest);
+        assert_eq!(log.read(40, 1).unwrap().len(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn keyed(key: &str, payload: u8) -> Message {
+        Message::new("t".into(), vec![payload; 256]).with_key(key)
+    }
+
+    #[test]
+    fn compaction_keeps_the_newest_record_of_every_key() {
+        let dir = directory();
+        let mut log = TopicLog::open(dir.clone(), config(&dir), true).unwrap();
+        for payload in 0..30 {
+            log.append(&keyed(["a", "b", "c"][payload as usize % 3], payload)).unwrap();
+        }
+        log.append(&message(100)).unwrap();
+        // close the active segment so every record can be compacted
+        log.roll().unwrap();
+        log.compact().unwrap();
+        let closed = &log.segments[..log.segments.len() - 1];
+        assert!(closed.iter().all(|segment| !segment.is_dirty(0)));
+
+        let records = log.read(0, 100).unwrap();
+        let kept: Vec<_> = records.iter().map(|record| (record.message.key.clone(), record.offset)).collect();
+        assert_eq!(kept, vec![
+            (Some("a".to_string()), 27),
+            (Some("b".to_string()), 28),
+            (Some("c".to_string()), 29),
+            (None, 30),
+        ]);
+        drop(log);
+
+        // reopening rebuilds the newest offsets, so records superseded before the restart still go
+        let mut log = TopicLog::open(dir.clone(), config(&dir), true).unwrap();
+        log.append(&keyed("a", 200)).unwrap();
+        log.roll().unwrap();
+        log.compact().unwrap();
+        let keys: Vec<_> = log.read(0, 100).unwrap().into_iter().map(|record| record.offset).collect();
+        assert_eq!(keys, vec![28, 29, 30, 31]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tombstones_go_once_their_retention_passed() {
+        let dir = directory();
+        let mut log = TopicLog::open(dir.clone(), config(&dir), true).unwrap();
+        log.append(&keyed("a", 1)).unwrap();
+        log.append(&keyed("b", 2)).unwrap();
+        log.append(&Message::new("t".into(), Vec::new()).with_key("a")).unwrap();
+        log.roll().unwrap();
+        // the tombstone is appended in the current millisecond, the cutoff has to move past it
+        std::thread::sleep(Duration::from_millis(2));
+        log.compact().unwrap();
+
+        let keys: Vec<_> = log.read(0, 100).unwrap().into_iter().map(|record| record.message.key).collect();
+        assert_eq!(keys, vec![Some("b".to_string())]);
+        assert!(!log.latest.contains_key("a"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::HashMap;
+
+use parking_lot::{Mutex, RwLock};
+
+use crate::application::config::BackpressurePolicy;
+use crate::domain::topic::TopicPattern;
+
+// backpressure policies of a transport by topic pattern, the most specific matching pattern wins
+#[derive(Default)]
+pub struct Backpressure {
+    policies: RwLock<Vec<(TopicPattern, BackpressurePolicy)>>,
+    // messages seen under pressure per sampled topic
+    samples: Mutex<HashMap<String, u64>>,
+}
+
+impl Backpressure {
+    pub fn set(&self, pattern: TopicPattern, policy: BackpressurePolicy) {
+        let mut policies = self.policies.write();
+        policies.retain(|(existing, _)| *existing != pattern);
+        policies.push((pattern, policy));
+    }
+
+    pub fn policy(&self, topic: &str) -> BackpressurePolicy {
+        self.policies
+            .read()
+            .iter()
+            .filter(|(pattern, _)| pattern.matches(topic))
+            .max_by_key(|(pattern, _)| pattern.specificity())
+            .map(|(_, policy)| *policy)
+            .unwrap_or_default()
+    }
+
+    // whether a message arriving on a full sampled topic is the one in `every` that is kept
+    pub fn sample(&self, topic: &str, every: u32) -> bool {
+        let mut samples = self.samples.lock();
+        let seen = samples.entry(topic.to_string()).or_default();
+        let keep = seen.is_multiple_of(every.max(1) as u64);
+        *seen += 1;
+        keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_most_specific_policy_wins_and_others_block() {
+        let backpressure = Backpressure::default();
+        backpressure.set(TopicPattern::new("waf.#"), BackpressurePolicy::DropOldest);
+        backpressure.set(TopicPattern::new("waf.telemetry.*"), BackpressurePolicy::Sample(3));
+        assert_eq!(backpressure.policy("waf.telemetry.cpu"), BackpressurePolicy::Sample(3));
+        assert_eq!(backpressure.policy("waf.bans"), BackpressurePolicy::DropOldest);
+        assert_eq!(backpressure.policy("other"), BackpressurePolicy::Block);
+
+        let kept: Vec<_> = (0..6).map(|_| backpressure.sample("waf.telemetry.cpu", 3)).collect();
+        assert_eq!(kept, vec![true, false, false, true, false, false]);
+    }
+}
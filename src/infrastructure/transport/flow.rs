@@ -0,0 +1,294 @@
+// MIT License
+// 
+// Copyright (c) 2024 ZARK-WAF
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+// Authors: I. Zeqiri, E. Gjergji
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Notify};
+
+use crate::application::config::{FlowControlConfig, SlowConsumerPolicy};
+use crate::domain::errors::MessengerError;
+use crate::domain::message::Priority;
+
+// control frames of the credit protocol, sent as json payloads in frames the tcp transport
+// tags as control frames, so they never go through the message serializer
+// flows are numbered by the receiving side, which opens and closes them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FlowControl {
+    // receiver -> sender: a subscription to `topic` takes up to `credits` messages
+    Open { flow: u64, topic: String, credits: u32 },
+    // receiver -> sender: the subscription made room for `credits` more messages
+    Credit { flow: u64, credits: u32 },
+    // receiver -> sender: the subscription is gone
+    Close { flow: u64 },
+    // sender -> receiver: the subscription was too slow and is cut off
+    Evict { flow: u64 },
+    // sender -> receiver: the subscription was too slow and no longer holds messages back
+    Degrade { flow: u64 },
+    // receiver -> sender: the subscriptions to `topic` only want messages matching one of
+    // `filters`, None for every message
+    Interest { topic: String, filters: Option<Vec<String>> },
+}
+
+impl FlowControl {
+    pub fn to_payload(&self) -> Result<Vec<u8>, MessengerError> {
+        serde_json::to_vec(self).map_err(|e| MessengerError::Serialization(e.to_string()))
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self, MessengerError> {
+        serde_json::from_slice(payload).map_err(|e| MessengerError::Deserialization(e.to_string()))
+    }
+}
+
+const OPEN: u8 = 0;
+const DEGRADED: u8 = 1;
+const EVICTED: u8 = 2;
+
+// receiving end of a flow, held by the subscription it was opened for
+// dropping it closes the flow
+pub struct FlowGrant {
+    id: u64,
+    // credits are granted back once this many messages were received
+    batch: u32,
+    received: AtomicU32,
+    state: AtomicU8,
+    evicted: Notify,
+    control: mpsc::UnboundedSender<FlowControl>,
+}
+
+impl FlowGrant {
+    // open a flow for `topic`, granting the sender the configured credits up front
+    pub fn open(id: u64, topic: &str, config: &FlowControlConfig, control: mpsc::UnboundedSender<FlowControl>) -> Self {
+        let credits = config.credits.max(1);
+        let _ = control.send(FlowControl::Open { flow: id, topic: topic.to_string(), credits });
+        Self {
+            id,
+            batch: credits.div_ceil(2),
+            received: AtomicU32::new(0),
+            state: AtomicU8::new(OPEN),
+            evicted: Notify::new(),
+            control,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // count a message taken off the subscription's queue, granting credits back in batches
+    pub fn received(&self) {
+        if self.received.fetch_add(1, Ordering::SeqCst) + 1 == self.batch {
+            self.received.fetch_sub(self.batch, Ordering::SeqCst);
+            let _ = self.control.send(FlowControl::Credit { flow: self.id, credits: self.batch });
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == DEGRADED
+    }
+
+    pub fn is_evicted(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == EVICTED
+    }
+
+    // wait until the sender cuts the flow off
+    pub async fn evicted(&self) {
+        loop {
+            // registered before checking so an eviction in between is not missed
+            let evicted = self.evicted.notified();
+            if self.is_evicted() {
+                return;
+            }
+            evicted.await;
+        }
+    }
+
+    pub(crate) fn degrade(&self) {
+        let _ = self.state.compare_exchange(OPEN, DEGRADED, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    pub(crate) fn evict(&self) {
+        self.state.store(EVICTED, Ordering::SeqCst);
+        self.evicted.notify_waiters();
+    }
+}
+
+impl Drop for FlowGrant {
+    fn drop(&mut self) {
+        let _ = self.control.send(FlowControl::Close { flow: self.id });
+    }
+}
+
+struct Outbound {
+    topic: String,
+    credits: u32,
+    // frames held back waiting for this flow's credits
+    held: usize,
+    degraded: bool,
+}
+
+struct Held<T> {
+    flows: Vec<u64>,
+    priority: Priority,
+    frame: T,
+}
+
+// sending end of every flow the peer opened
+// a frame on a topic goes out once every open flow of that topic has a credit for it, until
+// then it is held back, behind any frame held for the same flows so each topic stays in order
+pub struct OutboundFlows<T> {
+    flows: HashMap<u64, Outbound>,
+    held: VecDeque<Held<T>>,
+    buffer_size: usize,
+    policy: SlowConsumerPolicy,
+}
+
+impl<T> OutboundFlows<T> {
+    pub fn new(config: &FlowControlConfig) -> Self {
+        Self {
+            flows: HashMap::new(),
+            held: VecDeque::new(),
+            buffer_size: config.buffer_size,
+            policy: config.slow_consumer,
+        }
+    }
+
+    pub fn open(&mut self, flow: u64, topic: String, credits: u32) {
+        self.flows.insert(flow, Outbound { topic, credits, held: 0, degraded: false });
+    }
+
+    pub fn credit(&mut self, flow: u64, credits: u32) {
+        if let Some(outbound) = self.flows.get_mut(&flow) {
+            outbound.credits = outbound.credits.saturating_add(credits);
+        }
+    }
+
+    pub fn close(&mut self, flow: u64) {
+        self.flows.remove(&flow);
+    }
+
+    // take a frame on `topic`, returning it with the flows whose credits it took when it can go out right away
+    // a held frame that overflows a flow's buffer makes that flow a slow consumer, the control
+    // frames telling the peer what was done about it are returned alongside
+    pub fn admit(&mut self, topic: &str, priority: Priority, frame: T) -> (Option<(T, Vec<u64>)>, Vec<FlowControl>) {
+        let flows: Vec<u64> = self.flows
+            .iter()
+            .filter(|(_, outbound)| outbound.topic == topic && !outbound.degraded)
+            .map(|(flow, _)| *flow)
+            .collect();
+        if flows.iter().all(|flow| self.flows[flow].credits > 0 && self.flows[flow].held == 0) {
+            for flow in &flows {
+                if let Some(outbound) = self.flows.get_mut(flow) {
+                    outbound.credits -= 1;
+                }
+            }
+            return (Some((frame, flows)), Vec::new());
+        }
+
+        let mut control = Vec::new();
+        for flow in &flows {
+            let outbound = match self.flows.get_mut(flow) {
+                Some(outbound) => outbound,
+                None => continue,
+            };
+            outbound.held += 1;
+            if outbound.held <= self.buffer_size {
+                continue;
+            }
+            log::warn!("subscription {} to '{}' is not keeping up, {:?}", flow, outbound.topic, self.policy);
+            match self.policy {
+                SlowConsumerPolicy::Disconnect => {
+                    self.flows.remove(flow);
+                    control.push(FlowControl::Evict { flow: *flow });
+                }
+                SlowConsumerPolicy::Degrade => {
+                    outbound.degraded = true;
+                    outbound.held = 0;
+                    control.push(FlowControl::Degrade { flow: *flow });
+                }
+            }
+        }
+        self.held.push_back(Held { flows, priority, frame });
+        (None, control)
+    }
+
+    // give back the credits a frame took when it is dropped before going out, the peer never
+    // sees it and so never grants them back
+    pub fn refund(&mut self, flows: &[u64]) {
+        for flow in flows {
+            self.credit(*flow, 1);
+        }
+    }
+
+    // take the held frames that can go out now, oldest first, with the flows whose credits they took
+    pub fn release(&mut self) -> Vec<(Priority, T, Vec<u64>)> {
+        let mut released = Vec::new();
+        // flows with an older frame still held, later frames for them have to wait too
+        let mut blocked = HashSet::new();
+        let held = std::mem::take(&mut self.held);
+        for entry in held {
+            let flows: Vec<u64> = entry.flows
+                .iter()
+                .copied()
+                .filter(|flow| self.flows.get(flow).is_some_and(|outbound| !outbound.degraded))
+                .collect();
+            if flows.iter().all(|flow| !blocked.contains(flow) && self.flows[flow].credits > 0) {
+                for flow in &flows {
+                    if let Some(outbound) = self.flows.get_mut(flow) {
+                        outbound.credits -= 1;
+                        outbound.held -= 1;
+                    }
+                }
+                released.push((entry.priority, entry.frame, flows));
+            } else {
+                blocked.extend(flows);
+                self.held.push_back(entry);
+            }
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refunded_credits_let_held_frames_out_in_order() {
+        let mut flows = OutboundFlows::new(&FlowControlConfig { credits: 1, ..Default::default() });
+        flows.open(0, "t".into(), 1);
+        let (first, _) = flows.admit("t", Priority::Normal, 1);
+        assert_eq!(first, Some((1, vec![0])));
+        assert_eq!(flows.admit("t", Priority::Normal, 2).0, None);
+        assert_eq!(flows.admit("t", Priority::Normal, 3).0, None);
+        assert!(flows.release().is_empty());
+
+        // the first frame was dropped before it went out, so its credit is the sender's to give back
+        flows.refund(&[0]);
+        assert_eq!(flows.release(), vec![(Priority::Normal, 2, vec![0])]);
+        flows.credit(0, 1);
+        assert_eq!(flows.release(), vec![(Priority::Normal, 3, vec![0])]);
+    }
+}
@@ -1,133 +1,194 @@
-use super::Transport;
-use crate::application::config::IpcConfig;
+use super::backpressure::Backpressure;
+use super::{unexpired, ReceivedFrame, Transport};
+use crate::application::config::{BackpressurePolicy, IpcConfig};
+use crate::application::dead_letter::undecodable;
 use crate::domain::errors::MessengerError;
-use crate::domain::message::Message;
+use crate::domain::message::{Message, Priority};
+use crate::domain::topic::TopicPattern;
+use crate::infrastructure::memory::buffer::{BufferPool, PooledBuffer};
 use crate::infrastructure::memory::pool_allocator::PoolAllocator;
+use crate::infrastructure::queue::priority_queue::PriorityQueue;
 use crate::infrastructure::serialization::Serializer;
+use crate::utils::clock::now_millis;
+use crate::utils::metrics::Metrics;
+use crate::utils::zark_uid::generate_zark_uid;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::collections::HashMap;
-use tokio::time::{timeout, Duration};
+use tokio::time::{timeout_at, Duration, Instant};
 
 use async_trait::async_trait;
-use tokio::sync::{Mutex, mpsc};
+use tokio::sync::{Mutex, Notify};
+
+// a queued message, either serialized on the heap or written in place into a shared slot
+enum QueuedMessage {
+    Heap(Vec<u8>),
+    Shared(PooledBuffer, usize),
+}
+
+impl QueuedMessage {
+    fn len(&self) -> usize {
+        match self {
+            QueuedMessage::Heap(data) => data.len(),
+            QueuedMessage::Shared(_, len) => *len,
+        }
+    }
+}
+
+// a stored message with its topic and expiry, so it can be dropped or purged without decoding it
+struct Pending {
+    data: QueuedMessage,
+    topic: String,
+    expires_at: Option<u64>,
+}
+
+impl Pending {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
 
 pub struct IpcTransport {
-    messages: Mutex<HashMap<u64, Vec<u8>>>, // Map of message IDs to data
+    messages: Mutex<HashMap<u64, Pending>>, // Map of message IDs to data
     next_id: AtomicU64,                     // Atomic counter for message IDs
     total_memory: AtomicUsize,              // Total memory currently used
+    reserved: AtomicUsize,                  // Messages holding memory, at most max_queue_size so queueing never waits
     max_memory: usize,                      // Maximum allowed memory usage
-    tx: mpsc::Sender<u64>,                  // Sender for message IDs
-    rx: Mutex<Option<mpsc::Receiver<u64>>>, // Receiver for message IDs
+    freed: Notify,                          // Woken whenever memory is given back
+    queue: PriorityQueue<u64>,              // Message IDs, served by priority
     config: IpcConfig,
-    serializer: Box<dyn Serializer>,
+    serializer: Arc<dyn Serializer>,
     buffer_pool: PoolAllocator<Vec<u8>>,
+    slot_pool: Arc<parking_lot::Mutex<BufferPool>>, // Shared-memory slots handed out by `loan`
+    metrics: Arc<Metrics>,
+    backpressure: Backpressure,
+    dead_letter_topic: parking_lot::RwLock<Option<String>>, // Where frames that cannot be decoded go
 }
 
 #[async_trait]
 impl Transport for IpcTransport {
     async fn send(&self, message: &Message) -> Result<(), MessengerError> {
         // Serialize the message
-        let serialized_data = self.serializer.serialize(message)
-            .map_err(|e| MessengerError::Serialization(e.to_string()))?;
-
+        let serialized_data = self.encode(message)?;
         let total_len = serialized_data.len();
-        if total_len > self.config.max_message_size {
-            return Err(MessengerError::MessageTooLarge(total_len, self.config.max_message_size));
-        }
 
-        // Wait until enough memory is available
-        loop {
-            let current_memory = self.total_memory.load(Ordering::SeqCst);
-            if current_memory + total_len <= self.max_memory {
-                match self.total_memory.compare_exchange(
-                    current_memory,
-                    current_memory + total_len,
-                    Ordering::SeqCst,
-                    Ordering::SeqCst,
-                ) {
-                    Ok(_) => break,
-                    Err(_) => continue,
-                }
-            } else {
-                // Not enough memory, wait with a timeout
-                if timeout(Duration::from_millis(100), tokio::task::yield_now()).await.is_err() {
-                    return Err(MessengerError::MemoryUnavailable);
-                }
-            }
-        }
-        
+        // Make room as the topic's backpressure policy says
+        self.reserve(&message.topic, &message.id, total_len).await?;
 
         // Generate a unique message ID
         let message_id = self.next_id.fetch_add(1, Ordering::SeqCst);
 
-        // Store the message
-        {
-            let mut messages = self.messages.lock().await;
-            messages.insert(message_id, serialized_data);
-        }
+        let pending = Pending {
+            data: QueuedMessage::Heap(serialized_data),
+            topic: message.topic.clone(),
+            expires_at: message.expires_at,
+        };
+        self.enqueue(message_id, pending, message.priority).await
+    }
 
-        // Send the message ID to the receiver
-        self.tx.send(message_id).await.map_err(|_| MessengerError::ChannelClosed)?;
+    async fn receive(&self) -> Result<Message, MessengerError> {
+        self.receive_frame().await?.into_message()
+    }
 
-        Ok(())
+    async fn send_batch(&self, messages: &[Message]) -> Result<(), MessengerError> {
+        // Expired messages are left out, then the rest is serialized before anything is queued
+        let messages = unexpired(messages, &self.metrics);
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let encoded = messages.iter().map(|message| self.encode(message)).collect::<Result<Vec<_>, _>>()?;
+        let total_len = encoded.iter().map(Vec::len).sum();
+
+        // The whole batch is reserved before any of it is queued, so it is queued entirely or not at all
+        self.reserve_batch(&messages, total_len).await?;
+        let batch = messages.iter().zip(encoded).map(|(message, data)| {
+            let message_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            let pending = Pending {
+                data: QueuedMessage::Heap(data),
+                topic: message.topic.clone(),
+                expires_at: message.expires_at,
+            };
+            (message_id, pending, message.priority)
+        });
+        self.enqueue_batch(batch.collect()).await
     }
 
-    async fn receive(&self) -> Result<Message, MessengerError> {
-        // Wait for a message ID from the channel
-        let message_id = {
-            let mut rx_lock = self.rx.lock().await;
-    
-            if rx_lock.is_none() {
-                return Err(MessengerError::ChannelClosed);
+    async fn receive_batch(&self, max: usize, timeout: Duration) -> Result<Vec<Message>, MessengerError> {
+        let deadline = Instant::now() + timeout;
+        let mut batch = Vec::new();
+        while batch.is_empty() && max > 0 {
+            // Wait for the first message ID, then take the ones already queued behind it
+            let first = match timeout_at(deadline, self.queue.pop()).await {
+                Ok(message_id) => message_id.ok_or(MessengerError::ChannelClosed)?,
+                Err(_) => break,
+            };
+            let mut message_ids = vec![first];
+            while message_ids.len() < max {
+                match self.queue.try_pop() {
+                    Some(message_id) => message_ids.push(message_id),
+                    None => break,
+                }
             }
-    
-            let rx = rx_lock.as_mut().unwrap();
-    
-            match rx.recv().await {
-                Some(id) => id,
-                None => return Err(MessengerError::ChannelClosed),
+
+            // Retrieve and remove them under one lock, skipping the ones purged as expired
+            let taken: Vec<QueuedMessage> = {
+                let mut messages = self.messages.lock().await;
+                message_ids.iter().filter_map(|message_id| messages.remove(message_id)).map(|pending| pending.data).collect()
+            };
+
+            for queued in taken {
+                self.release(queued.len());
+                let frame = match queued {
+                    QueuedMessage::Heap(data) => ReceivedFrame::new(data, self.serializer.clone()),
+                    QueuedMessage::Shared(buffer, len) => ReceivedFrame::shared(buffer, len, self.serializer.clone()),
+                };
+                match frame.view().map(|view| view.to_owned()) {
+                    Ok(message) => batch.push(message),
+                    Err(e) => self.dead_letter_frame(frame.as_bytes(), &e).await,
+                }
             }
-        };    
+        }
+        Ok(batch)
+    }
 
-        // Retrieve and remove the message
-        let serialized_data = {
-            let mut messages = self.messages.lock().await;
-            messages.remove(&message_id).ok_or(MessengerError::MessageNotFound)?
-        };
+    async fn receive_frame(&self) -> Result<ReceivedFrame, MessengerError> {
+        let queued = loop {
+            // Wait for the next message ID, highest priority first
+            let message_id = self.queue.pop().await.ok_or(MessengerError::ChannelClosed)?;
 
-        // Deserialize the message
-        let message = self.serializer.deserialize(&serialized_data)
-            .map_err(|e| MessengerError::Deserialization(e.to_string()))?;
+            // Retrieve and remove the message, the frame takes over its buffer
+            // a missing message was purged as expired right after its id was taken
+            let pending = self.messages.lock().await.remove(&message_id);
+            if let Some(pending) = pending {
+                break pending.data;
+            }
+        };
 
         // Update total memory usage
-        self.total_memory.fetch_sub(serialized_data.len(), Ordering::SeqCst);
+        self.release(queued.len());
 
-        Ok(message)
+        Ok(match queued {
+            QueuedMessage::Heap(data) => ReceivedFrame::new(data, self.serializer.clone()),
+            QueuedMessage::Shared(buffer, len) => ReceivedFrame::shared(buffer, len, self.serializer.clone()),
+        })
     }
 
     async fn cleanup(&self) -> Result<(), MessengerError> {
-        // Clear messages
+        // Clear messages, giving back what they hold
+        // loaned slots not committed yet keep their reservation and give it back when dropped
         {
             let mut messages = self.messages.lock().await;
-            messages.clear();
+            for (_, pending) in messages.drain() {
+                self.release(pending.data.len());
+            }
         }
     
-        // Reset total memory
-        self.total_memory.store(0, Ordering::SeqCst);
-    
         // Reset next_id
         self.next_id.store(0, Ordering::SeqCst);
     
-        // Close the channel by dropping the sender
-        // Drop the Sender
-        // Cloning `tx` to drop it explicitly
-        let _ = self.tx.clone();
-    
-        // Drop the Receiver
-        {
-            let mut rx_lock = self.rx.lock().await;
-            *rx_lock = None;
-        }
+        // Close the queue, pending receives and later sends fail from now on
+        self.queue.close();
     
         Ok(())
     }    
@@ -144,6 +205,22 @@ impl Transport for IpcTransport {
         self.config.max_message_size
     }
 
+    fn serializer(&self) -> Arc<dyn Serializer> {
+        self.serializer.clone()
+    }
+
+    fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    fn set_backpressure(&self, pattern: TopicPattern, policy: BackpressurePolicy) {
+        self.backpressure.set(pattern, policy);
+    }
+
+    fn set_dead_letter_topic(&self, topic: &str) {
+        *self.dead_letter_topic.write() = Some(topic.to_string());
+    }
+
     async fn close(&self) -> Result<(), Box<dyn std::error::Error + 'static>> {
         self.cleanup().await.map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
     }
@@ -155,18 +232,548 @@ impl IpcTransport {
         serializer: Box<dyn Serializer>,
         buffer_pool: PoolAllocator<Vec<u8>>,
     ) -> Result<Self, MessengerError> {
-        let (tx, rx) = mpsc::channel(config.max_queue_size);
-
         Ok(Self {
             messages: Mutex::new(HashMap::new()),
             next_id: AtomicU64::new(0),
             total_memory: AtomicUsize::new(0),
+            reserved: AtomicUsize::new(0),
             max_memory: config.max_message_size * config.max_queue_size,
-            tx,
-            rx: Mutex::new(Some(rx)),
+            freed: Notify::new(),
+            queue: PriorityQueue::new(config.max_queue_size),
+            slot_pool: Arc::new(parking_lot::Mutex::new(BufferPool::lazy(config.max_message_size, config.max_queue_size))),
             config,
-            serializer,
+            serializer: Arc::from(serializer),
             buffer_pool,
+            metrics: Arc::new(Metrics::default()),
+            backpressure: Backpressure::default(),
+            dead_letter_topic: parking_lot::RwLock::new(None),
         })
     }
+
+    /// Loan a shared-memory slot with room for a `size` byte payload on `topic`
+    ///
+    /// The slot is laid out in the transport serializer's format, the returned `SendSlot`
+    /// derefs to its payload region so producers can write the payload in place.
+    /// Nothing is visible to receivers until `SendSlot::commit` is called, and
+    /// dropping an uncommitted slot gives its memory back. Serializers that cannot
+    /// carry a payload verbatim, such as json, cannot loan slots.
+    pub async fn loan(&self, topic: &str, size: usize) -> Result<SendSlot<'_>, MessengerError> {
+        let id = generate_zark_uid();
+        let header = self.serializer.payload_prefix(topic, &id, size)
+            .ok_or_else(|| MessengerError::Serialization("the serializer cannot lay out loaned slots".to_string()))?;
+        let header_len = header.len();
+        let total_len = header_len + size;
+        if total_len > self.config.max_message_size {
+            return Err(MessengerError::MessageTooLarge(total_len, self.config.max_message_size));
+        }
+
+        self.reserve(topic, &id, total_len).await?;
+
+        let mut buffer = match PooledBuffer::take(&self.slot_pool) {
+            Some(buffer) => buffer,
+            None => {
+                self.release(total_len);
+                return Err(MessengerError::NoFreeSlots);
+            }
+        };
+
+        // write the header, the payload region follows it
+        buffer[..header_len].copy_from_slice(&header);
+
+        Ok(SendSlot {
+            transport: self,
+            buffer: Some(buffer),
+            topic: topic.to_string(),
+            id,
+            header_len,
+            total_len,
+            priority: Priority::Normal,
+        })
+    }
+
+    // make room for a `len` byte message with id `id` on `topic`, as the topic's backpressure policy says
+    // expired messages are purged first, they never count against the limits
+    async fn reserve(&self, topic: &str, id: &str, len: usize) -> Result<(), MessengerError> {
+        let policy = self.backpressure.policy(topic);
+        let deadline = match policy {
+            BackpressurePolicy::BlockMs(wait) => Some(Instant::now() + Duration::from_millis(wait)),
+            _ => None,
+        };
+        // sampling decides once per message, later rounds only make room for a kept one
+        let mut sampled = false;
+        loop {
+            // registered before checking so memory freed in between is not missed
+            let freed = self.freed.notified();
+            if self.try_reserve(1, len) {
+                return Ok(());
+            }
+            if self.purge_expired().await > 0 {
+                continue;
+            }
+
+            match (policy, deadline) {
+                (BackpressurePolicy::Block, _) => freed.await,
+                (_, Some(deadline)) => {
+                    if timeout_at(deadline, freed).await.is_err() {
+                        return Err(MessengerError::Backpressure(topic.to_string()));
+                    }
+                }
+                (BackpressurePolicy::BlockMs(_) | BackpressurePolicy::Error, _) => {
+                    return Err(MessengerError::Backpressure(topic.to_string()));
+                }
+                (BackpressurePolicy::DropNewest, _) => return Err(self.dropped(id)),
+                (BackpressurePolicy::DropOldest, _) => {
+                    if !self.drop_oldest(topic).await {
+                        return Err(self.dropped(id));
+                    }
+                }
+                (BackpressurePolicy::Sample(every), _) => {
+                    if !sampled && !self.backpressure.sample(topic, every) {
+                        return Err(self.dropped(id));
+                    }
+                    sampled = true;
+                    if !self.drop_oldest(topic).await {
+                        return Err(self.dropped(id));
+                    }
+                }
+            }
+        }
+    }
+
+    // make room for a whole batch of `len` bytes at once
+    // the batch waits as long as the most impatient policy of its topics allows, policies that drop or
+    // fail right away fail the whole batch with `MessengerError::Backpressure` instead of splitting it
+    async fn reserve_batch(&self, messages: &[&Message], len: usize) -> Result<(), MessengerError> {
+        let first_topic = || messages.first().map(|message| message.topic.clone()).unwrap_or_default();
+        // a batch bigger than the whole queue never fits
+        if len > self.max_memory {
+            return Err(MessengerError::MessageTooLarge(len, self.max_memory));
+        }
+        if messages.len() > self.config.max_queue_size {
+            return Err(MessengerError::Backpressure(first_topic()));
+        }
+        let mut deadline = None;
+        let mut impatient = None;
+        for message in messages {
+            match self.backpressure.policy(&message.topic) {
+                BackpressurePolicy::Block => {}
+                BackpressurePolicy::BlockMs(wait) => {
+                    let until = Instant::now() + Duration::from_millis(wait);
+                    deadline = Some(deadline.map_or(until, |deadline: Instant| deadline.min(until)));
+                }
+                _ => impatient = impatient.or(Some(&message.topic)),
+            }
+        }
+
+        loop {
+            // registered before checking so memory freed in between is not missed
+            let freed = self.freed.notified();
+            if self.try_reserve(messages.len(), len) {
+                return Ok(());
+            }
+            if self.purge_expired().await > 0 {
+                continue;
+            }
+            if let Some(topic) = impatient {
+                return Err(MessengerError::Backpressure(topic.clone()));
+            }
+            match deadline {
+                Some(deadline) => {
+                    if timeout_at(deadline, freed).await.is_err() {
+                        return Err(MessengerError::Backpressure(first_topic()));
+                    }
+                }
+                None => freed.await,
+            }
+        }
+    }
+
+    // account `len` bytes and `count` queue slots against the limits if both have room
+    fn try_reserve(&self, count: usize, len: usize) -> bool {
+        let max_messages = self.config.max_queue_size;
+        if self.reserved.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |reserved| (reserved + count <= max_messages).then_some(reserved + count)).is_err() {
+            return false;
+        }
+        let max_memory = self.max_memory;
+        if self.total_memory.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| (used + len <= max_memory).then_some(used + len)).is_err() {
+            self.reserved.fetch_sub(count, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    // refuse stale messages and serialize the rest, checking them against the size limit
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, MessengerError> {
+        if message.is_expired() {
+            self.metrics.record_expired();
+            return Err(MessengerError::Expired(message.id.clone()));
+        }
+
+        let data = self.serializer.serialize(message)
+            .map_err(|e| MessengerError::Serialization(e.to_string()))?;
+        if data.len() > self.config.max_message_size {
+            return Err(MessengerError::MessageTooLarge(data.len(), self.config.max_message_size));
+        }
+        Ok(data)
+    }
+
+    // give back the memory and queue slot of a message that was received or dropped
+    fn release(&self, len: usize) {
+        self.total_memory.fetch_sub(len, Ordering::SeqCst);
+        self.reserved.fetch_sub(1, Ordering::SeqCst);
+        self.freed.notify_waiters();
+    }
+
+    // forward a frame that cannot be decoded to the dead-letter topic, as the dispatcher does
+    async fn dead_letter_frame(&self, data: &[u8], error: &MessengerError) {
+        let topic = match self.dead_letter_topic.read().clone() {
+            Some(topic) => topic,
+            None => {
+                log::warn!("dropping message that cannot be decoded: {}", error);
+                return;
+            }
+        };
+        if let Err(e) = self.send(&undecodable(data, &topic, error)).await {
+            log::warn!("failed to dead-letter message that cannot be decoded: {}", e);
+        }
+    }
+
+    // count a message dropped by backpressure, returning the error reported to its publisher
+    fn dropped(&self, id: &str) -> MessengerError {
+        self.metrics.record_dropped();
+        MessengerError::Dropped(id.to_string())
+    }
+
+    // drop the oldest queued message of the topic, returning whether there was one
+    async fn drop_oldest(&self, topic: &str) -> bool {
+        let mut messages = self.messages.lock().await;
+        let oldest = messages
+            .iter()
+            .filter(|(_, pending)| pending.topic == topic)
+            .map(|(id, _)| *id)
+            .min();
+        let (id, pending) = match oldest.and_then(|id| messages.remove(&id).map(|pending| (id, pending))) {
+            Some(oldest) => oldest,
+            None => return false,
+        };
+        drop(messages);
+
+        self.queue.retain(|queued| *queued != id);
+        self.metrics.record_dropped();
+        self.release(pending.data.len());
+        true
+    }
+
+    // drop queued messages that expired before anyone received them and free their memory
+    // and queue slots, returning how many bytes were freed
+    async fn purge_expired(&self) -> usize {
+        let now = now_millis();
+        let mut messages = self.messages.lock().await;
+        let expired: Vec<u64> = messages
+            .iter()
+            .filter(|(_, pending)| pending.is_expired(now))
+            .map(|(id, _)| *id)
+            .collect();
+
+        if expired.is_empty() {
+            return 0;
+        }
+
+        let mut freed = 0;
+        for id in &expired {
+            if let Some(pending) = messages.remove(id) {
+                freed += pending.data.len();
+                self.metrics.record_expired();
+                self.release(pending.data.len());
+            }
+        }
+        self.queue.retain(|id| !expired.contains(id));
+        freed
+    }
+
+    // store a message whose memory is already reserved and notify the receiver
+    async fn enqueue(&self, message_id: u64, pending: Pending, priority: Priority) -> Result<(), MessengerError> {
+        // Store the message
+        {
+            let mut messages = self.messages.lock().await;
+            messages.insert(message_id, pending);
+        }
+
+        // Queue the message ID for the receiver, giving the message back when the queue is closed
+        if self.queue.push(priority, message_id).await.is_err() {
+            if let Some(pending) = self.messages.lock().await.remove(&message_id) {
+                self.release(pending.data.len());
+            }
+            return Err(MessengerError::ChannelClosed);
+        }
+
+        Ok(())
+    }
+
+    // store several reserved messages under one lock, then queue them in order
+    async fn enqueue_batch(&self, batch: Vec<(u64, Pending, Priority)>) -> Result<(), MessengerError> {
+        let mut queued = Vec::with_capacity(batch.len());
+        {
+            let mut messages = self.messages.lock().await;
+            for (message_id, pending, priority) in batch {
+                messages.insert(message_id, pending);
+                queued.push((message_id, priority));
+            }
+        }
+
+        for (index, &(message_id, priority)) in queued.iter().enumerate() {
+            if self.queue.push(priority, message_id).await.is_err() {
+                let mut messages = self.messages.lock().await;
+                for (message_id, _) in &queued[index..] {
+                    if let Some(pending) = messages.remove(message_id) {
+                        self.release(pending.data.len());
+                    }
+                }
+                return Err(MessengerError::ChannelClosed);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Shared-memory slot loaned by `IpcTransport::loan`
+///
+/// Derefs to the payload region of the slot.
+pub struct SendSlot<'a> {
+    transport: &'a IpcTransport,
+    buffer: Option<PooledBuffer>,
+    topic: String,
+    id: String,
+    header_len: usize,
+    total_len: usize,
+    priority: Priority,
+}
+
+impl SendSlot<'_> {
+    /// Id of the message being written
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Queue the slot with the given priority instead of `Priority::Normal`
+    ///
+    /// This only affects the order messages are received in, the message
+    /// itself is decoded with the normal priority.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Publish the slot, making the message visible to receivers
+    pub async fn commit(mut self) -> Result<(), MessengerError> {
+        let buffer = self.buffer.take().ok_or(MessengerError::NoFreeSlots)?;
+        let transport = self.transport;
+        let message_id = transport.next_id.fetch_add(1, Ordering::SeqCst);
+        let pending = Pending {
+            data: QueuedMessage::Shared(buffer, self.total_len),
+            topic: std::mem::take(&mut self.topic),
+            expires_at: None,
+        };
+        transport.enqueue(message_id, pending, self.priority).await
+    }
+}
+
+impl Deref for SendSlot<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match &self.buffer {
+            Some(buffer) => &buffer[self.header_len..self.total_len],
+            None => &[],
+        }
+    }
+}
+
+impl DerefMut for SendSlot<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match &mut self.buffer {
+            Some(buffer) => &mut buffer[self.header_len..self.total_len],
+            None => &mut [],
+        }
+    }
+}
+
+impl Drop for SendSlot<'_> {
+    fn drop(&mut self) {
+        // an uncommitted slot still holds its memory reservation
+        if self.buffer.take().is_some() {
+            self.transport.release(self.total_len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::messenger::{Messenger, MessengerImpl};
+    use crate::infrastructure::serialization::binary::BinarySerializer;
+    use crate::infrastructure::serialization::json::JsonSerializer;
+
+    fn transport(max_queue_size: usize) -> IpcTransport {
+        let config = IpcConfig { shared_memory_name: "loan".into(), max_message_size: 4096, max_queue_size, max_buffer_size: 4096 };
+        IpcTransport::new(config, Box::new(BinarySerializer), PoolAllocator::new(16)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn loaned_slots_are_received_in_place() {
+        let transport = transport(4);
+        for round in 0..10u8 {
+            let mut slot = transport.loan("big", 100).await.unwrap();
+            slot.fill(round);
+            let id = slot.id().to_string();
+            slot.commit().await.unwrap();
+
+            let frame = transport.receive_frame().await.unwrap();
+            let view = frame.view().unwrap();
+            assert!(view.is_borrowed());
+            assert_eq!(view.id, id);
+            assert_eq!(view.topic, "big");
+            assert_eq!(&*view.payload, &[round; 100][..]);
+        }
+        assert!(matches!(transport.loan("big", 5000).await, Err(MessengerError::MessageTooLarge(..))));
+    }
+
+    #[tokio::test]
+    async fn loans_need_a_serializer_that_carries_payloads_verbatim() {
+        let config = IpcConfig { shared_memory_name: "loan".into(), max_message_size: 4096, max_queue_size: 4, max_buffer_size: 4096 };
+        let transport = IpcTransport::new(config, Box::new(JsonSerializer), PoolAllocator::new(16)).unwrap();
+        assert!(matches!(transport.loan("t", 10).await, Err(MessengerError::Serialization(_))));
+        assert_eq!(transport.reserved.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn batches_dead_letter_frames_they_cannot_decode() {
+        let transport = transport(4);
+        transport.set_dead_letter_topic("dead");
+        assert!(transport.try_reserve(1, 3));
+        let garbage = Pending { data: QueuedMessage::Heap(vec![0xff; 3]), topic: "t".into(), expires_at: None };
+        transport.enqueue(100, garbage, Priority::Normal).await.unwrap();
+        transport.send(&Message::new("t".into(), vec![1])).await.unwrap();
+
+        let batch = transport.receive_batch(10, Duration::from_millis(50)).await.unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].payload, vec![1]);
+        let dead = transport.receive().await.unwrap();
+        assert_eq!(dead.topic, "dead");
+        assert_eq!(dead.payload, vec![0xff; 3]);
+    }
+
+    #[tokio::test]
+    async fn batches_leave_out_expired_members() {
+        let transport = transport(4);
+        let mut stale = Message::new("t".into(), vec![0]);
+        stale.expires_at = Some(1);
+        let batch = [Message::new("t".into(), vec![1]), stale, Message::new("t".into(), vec![2])];
+        transport.send_batch(&batch).await.unwrap();
+
+        let received = transport.receive_batch(10, Duration::from_millis(50)).await.unwrap();
+        assert_eq!(received.iter().map(|message| message.payload[0]).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(transport.metrics().snapshot().expired, 1);
+    }
+
+    #[tokio::test]
+    async fn messages_larger_than_the_whole_queue_get_through_in_chunks() {
+        let config = IpcConfig { shared_memory_name: "chunks".into(), max_message_size: 256, max_queue_size: 4, max_buffer_size: 256 };
+        let transport = IpcTransport::new(config, Box::new(BinarySerializer), PoolAllocator::new(16)).unwrap();
+        let messenger = MessengerImpl::new(Arc::new(transport));
+        let subscriber = messenger.subscribe("big".into()).await.unwrap();
+
+        // both are several times the 1 KiB the queue holds at once
+        let single = Message::new("big".into(), (0..10_000).map(|i| i as u8).collect());
+        messenger.publish("big".into(), &single).await.unwrap();
+        let batched = [Message::new("big".into(), vec![1]), Message::new("big".into(), vec![2; 5_000])];
+        messenger.publish_batch(&batched).await.unwrap();
+
+        for expected in [&single.payload, &batched[0].payload, &batched[1].payload] {
+            let received = tokio::time::timeout(Duration::from_secs(5), subscriber.receive()).await.unwrap().unwrap();
+            assert_eq!(&received.payload, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn received_frames_hold_their_slots() {
+        let transport = transport(2);
+        let mut frames = Vec::new();
+        for _ in 0..2 {
+            transport.loan("t", 10).await.unwrap().commit().await.unwrap();
+            frames.push(transport.receive_frame().await.unwrap());
+        }
+        assert!(matches!(transport.loan("t", 10).await, Err(MessengerError::NoFreeSlots)));
+        frames.pop();
+        assert!(transport.loan("t", 10).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn cleanup_leaves_outstanding_loans_accounted() {
+        let transport = transport(4);
+        transport.send(&Message::new("t".into(), vec![1])).await.unwrap();
+        let slot = transport.loan("t", 10).await.unwrap();
+        transport.cleanup().await.unwrap();
+        assert_eq!(transport.reserved.load(Ordering::SeqCst), 1);
+        drop(slot);
+        assert_eq!(transport.reserved.load(Ordering::SeqCst), 0);
+        assert_eq!(transport.total_memory.load(Ordering::SeqCst), 0);
+    }
+
+    fn message(topic: &str, payload: u8) -> Message {
+        Message::new(topic.into(), vec![payload])
+    }
+
+    async fn drain(transport: &IpcTransport) -> Vec<u8> {
+        let mut payloads = Vec::new();
+        while let Ok(Ok(message)) = tokio::time::timeout(Duration::from_millis(10), transport.receive()).await {
+            payloads.push(message.payload[0]);
+        }
+        payloads
+    }
+
+    #[tokio::test]
+    async fn full_queues_block_by_default_until_there_is_room() {
+        let transport = Arc::new(transport(1));
+        transport.send(&message("t", 1)).await.unwrap();
+        let sender = Arc::clone(&transport);
+        let send = tokio::spawn(async move { sender.send(&message("t", 2)).await });
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(!send.is_finished());
+        assert_eq!(transport.receive().await.unwrap().payload, vec![1]);
+        send.await.unwrap().unwrap();
+        assert_eq!(transport.receive().await.unwrap().payload, vec![2]);
+
+        transport.set_backpressure(TopicPattern::new("t"), BackpressurePolicy::BlockMs(20));
+        transport.send(&message("t", 3)).await.unwrap();
+        assert!(matches!(transport.send(&message("t", 4)).await, Err(MessengerError::Backpressure(_))));
+    }
+
+    #[tokio::test]
+    async fn drop_policies_make_room_within_the_topic() {
+        let transport = transport(2);
+        transport.set_backpressure(TopicPattern::new("newest"), BackpressurePolicy::DropNewest);
+        transport.set_backpressure(TopicPattern::new("oldest"), BackpressurePolicy::DropOldest);
+        transport.send(&message("oldest", 1)).await.unwrap();
+        transport.send(&message("oldest", 2)).await.unwrap();
+        transport.send(&message("oldest", 3)).await.unwrap();
+        assert!(matches!(transport.send(&message("newest", 4)).await, Err(MessengerError::Dropped(_))));
+        assert_eq!(drain(&transport).await, vec![2, 3]);
+        assert_eq!(transport.metrics().snapshot().dropped, 2);
+    }
+
+    #[tokio::test]
+    async fn batches_are_queued_whole_or_not_at_all() {
+        let transport = transport(3);
+        transport.set_backpressure(TopicPattern::new("t"), BackpressurePolicy::Error);
+        transport.send(&message("t", 0)).await.unwrap();
+        let batch: Vec<_> = (1..4).map(|payload| message("t", payload)).collect();
+        assert!(matches!(transport.send_batch(&batch).await, Err(MessengerError::Backpressure(_))));
+        assert_eq!(drain(&transport).await, vec![0]);
+
+        transport.send_batch(&batch).await.unwrap();
+        assert_eq!(drain(&transport).await, vec![1, 2, 3]);
+        let too_many: Vec<_> = (0..4).map(|payload| message("t", payload)).collect();
+        assert!(matches!(transport.send_batch(&too_many).await, Err(MessengerError::Backpressure(_))));
+    }
 }
@@ -24,10 +24,21 @@
 
 
 
+use std::sync::Arc;
+use std::time::Duration;
 use async_trait::async_trait;
+use crate::application::config::BackpressurePolicy;
 use crate::domain::errors::MessengerError;
-use crate::domain::message::Message;
+use crate::domain::filter::Filter;
+use crate::domain::message::{Message, MessageView};
+use crate::domain::topic::TopicPattern;
+use crate::infrastructure::memory::buffer::PooledBuffer;
+use self::flow::FlowGrant;
+use crate::infrastructure::serialization::Serializer;
+use crate::utils::metrics::Metrics;
 
+pub mod backpressure;
+pub mod flow;
 pub mod ipc;
 pub mod tcp;
 
@@ -41,6 +52,34 @@ pub trait Transport: Send + Sync {
     /// Receive a message asynchronously
     async fn receive(&self) -> Result<Message, MessengerError>;
 
+    /// Receive the next raw frame without decoding it, so it can be viewed in place
+    async fn receive_frame(&self) -> Result<ReceivedFrame, MessengerError>;
+
+    /// Send several messages at once, in order
+    ///
+    /// Messages already expired are counted and left out rather than failing the batch.
+    /// On error, the messages before the failing one may already have been sent.
+    async fn send_batch(&self, messages: &[Message]) -> Result<(), MessengerError> {
+        for message in unexpired(messages, &self.metrics()) {
+            self.send(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for a message, then take the ones already waiting, up to `max` in all
+    ///
+    /// Returns an empty batch when nothing arrived in time. Transports that cannot
+    /// tell what is waiting return one message at a time.
+    async fn receive_batch(&self, max: usize, timeout: Duration) -> Result<Vec<Message>, MessengerError> {
+        if max == 0 {
+            return Ok(Vec::new());
+        }
+        match tokio::time::timeout(timeout, self.receive()).await {
+            Ok(message) => Ok(vec![message?]),
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
     /// Perform any necessary cleanup operations
     async fn cleanup(&self) -> Result<(), MessengerError>;
 
@@ -53,6 +92,93 @@ pub trait Transport: Send + Sync {
     /// Get the maximum message size supported by this transport
     fn max_message_size(&self) -> usize;
 
+    /// Get the serializer used to encode messages on this transport
+    fn serializer(&self) -> Arc<dyn Serializer>;
+
+    /// Get the counters of this transport, shared with the messenger built on it
+    fn metrics(&self) -> Arc<Metrics>;
+
+    /// Set what happens to messages on the topics matching `pattern` when the transport has no room for them
+    fn set_backpressure(&self, pattern: TopicPattern, policy: BackpressurePolicy);
+
+    /// Open a credit-based flow for a local subscription to `topic`, so the peer only sends
+    /// its messages while the subscription has room for them
+    ///
+    /// Returns None when the transport has no flow control.
+    fn open_flow(&self, _topic: &str) -> Option<Arc<FlowGrant>> {
+        None
+    }
+
+    /// Tell the peer which messages of `topic` the local subscriptions want, so it can leave
+    /// out the ones matching none of the filters, None asks for every message
+    ///
+    /// Transports without a peer to tell ignore this.
+    fn set_interest(&self, _topic: &str, _filters: Option<&[Filter]>) {}
+
+    /// Send frames that cannot be decoded to `topic` instead of dropping them
+    ///
+    /// Only used where the transport decodes frames itself, such as `receive_batch`.
+    fn set_dead_letter_topic(&self, _topic: &str) {}
+
     /// Close the transport
     async fn close(&self) -> Result<(), Box<dyn std::error::Error>>;
-}
\ No newline at end of file
+}
+
+// the messages of a batch that have not expired yet, the expired ones are counted
+pub(crate) fn unexpired<'a>(messages: &'a [Message], metrics: &Metrics) -> Vec<&'a Message> {
+    messages
+        .iter()
+        .filter(|message| {
+            if message.is_expired() {
+                metrics.record_expired();
+            }
+            !message.is_expired()
+        })
+        .collect()
+}
+
+/// A received, still encoded message
+///
+/// The frame owns the buffer the transport read the message into. `view` decodes
+/// it in place, so the hot path can inspect a message without copying it.
+pub struct ReceivedFrame {
+    data: FrameData,
+    serializer: Arc<dyn Serializer>,
+}
+
+enum FrameData {
+    // heap buffer filled by the transport
+    Owned(Vec<u8>),
+    // shared-memory slot written in place by a producer, `len` bytes are used
+    Shared(PooledBuffer, usize),
+}
+
+impl ReceivedFrame {
+    pub fn new(data: Vec<u8>, serializer: Arc<dyn Serializer>) -> Self {
+        Self { data: FrameData::Owned(data), serializer }
+    }
+
+    /// Frame backed by a shared-memory slot holding a message encoded with `serializer`
+    pub fn shared(buffer: PooledBuffer, len: usize, serializer: Arc<dyn Serializer>) -> Self {
+        Self { data: FrameData::Shared(buffer, len), serializer }
+    }
+
+    /// Decode the frame as a view borrowing from the receive buffer
+    pub fn view(&self) -> Result<MessageView<'_>, MessengerError> {
+        self.serializer.deserialize_view(self.as_bytes())
+            .map_err(|e| MessengerError::Deserialization(e.to_string()))
+    }
+
+    /// Decode the frame into an owned message
+    pub fn into_message(self) -> Result<Message, MessengerError> {
+        self.view().map(|view| view.to_owned())
+    }
+
+    /// Raw encoded bytes of the frame
+    pub fn as_bytes(&self) -> &[u8] {
+        match &self.data {
+            FrameData::Owned(data) => data,
+            FrameData::Shared(buffer, len) => &buffer[..*len],
+        }
+    }
+}
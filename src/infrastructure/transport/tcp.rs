@@ -22,79 +22,181 @@
 //
 // Authors: I. Zeqiri, E. Gjergji
 
-use super::Transport;
-use crate::application::config::TcpConfig;
+use super::backpressure::Backpressure;
+use super::flow::{FlowControl, FlowGrant, OutboundFlows};
+use super::{unexpired, ReceivedFrame, Transport};
+use crate::application::config::{BackpressurePolicy, FlowControlConfig, TcpConfig};
+use crate::application::dead_letter::undecodable;
 use crate::domain::errors::MessengerError;
-use crate::domain::message::Message;
+use crate::domain::filter::Filter;
+use crate::domain::headers::{CHUNK_ID, TXN_ID};
+use crate::domain::message::{Message, Priority};
 use crate::domain::serializable::Serializable;
+use crate::domain::topic::{is_system_topic, TopicPattern};
+use crate::infrastructure::queue::priority_queue::{PriorityQueue, PushError};
 use crate::infrastructure::serialization::Serializer;
+use crate::utils::clock::now_millis;
+use crate::utils::metrics::Metrics;
 use async_trait::async_trait;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::{HashMap, VecDeque};
+use std::io::IoSlice;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+
+// number of frames waiting for the writer task before senders have to wait
+const WRITE_QUEUE_SIZE: usize = 1024;
+// most frames the writer task writes at once
+const WRITE_BATCH_SIZE: usize = 64;
+// number of frames the reader task reads ahead of the receiver
+const READ_QUEUE_SIZE: usize = 1024;
+// set in the length prefix of flow control frames, which carry a json payload instead of a message
+const CONTROL_FRAME: u32 = 1 << 31;
+
+// frames read from the connection, an error ends the stream
+type Incoming = mpsc::Receiver<Result<Vec<u8>, MessengerError>>;
+
+// a frame waiting to be written, with the channel its sender waits on for the result
+struct OutgoingFrame {
+    frame: Vec<u8>,
+    // message id and expiry, a frame that expires while it waits is dropped unwritten
+    id: String,
+    // topic of the message, for dropping the oldest frame of a topic under backpressure
+    topic: String,
+    expires_at: Option<u64>,
+    // flows of the peer whose credits the frame took, given back when it is dropped unwritten
+    debited: Vec<u64>,
+    written: oneshot::Sender<Result<(), MessengerError>>,
+}
 
 // tcp transport struct for handling tcp connections
 pub struct TcpTransport {
     // optional listener for server mode
     listener: Option<Arc<TcpListener>>,
-    // optional stream for client mode or accepted connection
-    stream: Option<Arc<Mutex<TcpStream>>>,
+    // frames read by the reader task, which owns the read half of the client or accepted
+    // connection and handles flow control frames itself
+    incoming: Option<Arc<Mutex<Incoming>>>,
+    // the sending end of `incoming`, for frames handed back to this side such as dead letters
+    loopback: Option<mpsc::Sender<Result<Vec<u8>, MessengerError>>>,
+    reader: Option<JoinHandle<()>>,
+    // frames waiting for the writer task, which owns the write half of the connection
+    // and writes them highest priority first
+    writer: Option<Arc<PriorityQueue<OutgoingFrame>>>,
+    // credit state of the connection, set when flow control is configured
+    flows: Option<Arc<ConnectionFlows>>,
+    // control frames for the writer task, which writes them ahead of every queued frame
+    control: Option<mpsc::UnboundedSender<FlowControl>>,
+    // which messages the peer's subscriptions want, the rest is not sent at all
+    interest: Option<Arc<PeerInterest>>,
     // configuration for tcp connection
     config: TcpConfig,
     // serializer for message encoding/decoding
-    serializer: Box<dyn Serializer>,
+    serializer: Arc<dyn Serializer>,
+    metrics: Arc<Metrics>,
+    // what happens to frames of a topic when the write queue is full
+    backpressure: Backpressure,
+    // where frames that cannot be decoded go
+    dead_letter_topic: parking_lot::RwLock<Option<String>>,
 }
 
 #[async_trait]
 impl Transport for TcpTransport {
     // send a message over tcp
     async fn send(&self, message: &Message) -> Result<(), MessengerError> {
-        if let Some(stream) = &self.stream {
-            let mut stream = stream.lock().await;
-            
-            // Serialize the message using the configured serializer
-            let serialized_payload = self.serializer.serialize(message)
-                .map_err(|e| MessengerError::Serialization(e.to_string()))?;
-            
-            let total_len = serialized_payload.len();
-            if total_len > self.max_message_size() {
-                return Err(MessengerError::MessageTooLarge(total_len, self.max_message_size()));
-            }
-
-            // Write length prefix and serialized data
-            stream.write_all(&(total_len as u32).to_be_bytes()).await?;
-            stream.write_all(&serialized_payload).await?;
-            stream.flush().await?;
-            
-            Ok(())
+        if let Some(writer) = &self.writer {
+            let frame = self.encode(message)?;
+
+            // Queue the frame and wait until the writer task has written it
+            match self.queue_message(writer, message, frame).await? {
+                Some(result) => result.await.map_err(|_| MessengerError::ChannelClosed)?,
+                None => Ok(()),
+            }
         } else {
             Err(MessengerError::TransportError("Not connected".into()))
         }
     }
 
-    // receive a message over tcp
-    async fn receive(&self) -> Result<Message, MessengerError> {
-        if let Some(stream) = &self.stream {
-            let mut stream = stream.lock().await;
+    // send a batch of messages over tcp
+    async fn send_batch(&self, messages: &[Message]) -> Result<(), MessengerError> {
+        let writer = self.writer.as_ref()
+            .ok_or_else(|| MessengerError::TransportError("Not connected".into()))?;
+
+        // Expired messages are left out, then the rest is serialized before anything is queued
+        let messages = unexpired(messages, &self.metrics);
+        let frames = messages.iter().map(|message| self.encode(message)).collect::<Result<Vec<_>, _>>()?;
 
-            // Read message length
-            let mut len_bytes = [0u8; 4];
-            stream.read_exact(&mut len_bytes).await?;
-            let msg_len = u32::from_be_bytes(len_bytes) as usize;
+        // Queue every frame before waiting on any, so the writer task finds them all
+        // queued and writes them with a single vectored write
+        let mut results = Vec::with_capacity(messages.len());
+        for (message, frame) in messages.iter().zip(frames) {
+            if let Some(result) = self.queue_message(writer, message, frame).await? {
+                results.push(result);
+            }
+        }
+        for result in results {
+            result.await.map_err(|_| MessengerError::ChannelClosed)??;
+        }
+        Ok(())
+    }
 
-            // Read serialized message
-            let mut buffer = vec![0u8; msg_len];
-            stream.read_exact(&mut buffer).await?;
+    // receive a message over tcp
+    async fn receive(&self) -> Result<Message, MessengerError> {
+        self.receive_frame().await?.into_message()
+    }
 
-            // Deserialize using the configured serializer
-            self.serializer.deserialize(&buffer)
-                .map_err(|e| MessengerError::Deserialization(e.to_string()))
+    // receive a raw frame over tcp, the frame keeps the read buffer for in-place decoding
+    async fn receive_frame(&self) -> Result<ReceivedFrame, MessengerError> {
+        if let Some(incoming) = &self.incoming {
+            let buffer = incoming.lock().await.recv().await.ok_or(MessengerError::ChannelClosed)??;
+            Ok(ReceivedFrame::new(buffer, self.serializer.clone()))
         } else {
             Err(MessengerError::TransportError("Not connected".into()))
         }
     }
 
+    // receive the frames already read, up to `max`, waiting up to `timeout` for the first
+    async fn receive_batch(&self, max: usize, timeout_after: Duration) -> Result<Vec<Message>, MessengerError> {
+        let incoming = self.incoming.as_ref()
+            .ok_or_else(|| MessengerError::TransportError("Not connected".into()))?;
+        let mut incoming = incoming.lock().await;
+        let mut batch = Vec::new();
+        if max == 0 {
+            return Ok(batch);
+        }
+
+        let deadline = Instant::now() + timeout_after;
+        while batch.is_empty() {
+            let first = match timeout_at(deadline, incoming.recv()).await {
+                Ok(frame) => frame.ok_or(MessengerError::ChannelClosed)??,
+                Err(_) => break,
+            };
+            let mut frames = vec![first];
+            // a failed connection ends the batch, the next receive reports it
+            while frames.len() < max {
+                match incoming.try_recv() {
+                    Ok(Ok(frame)) => frames.push(frame),
+                    _ => break,
+                }
+            }
+
+            // a frame that cannot be decoded is dead-lettered, the rest of the batch is still returned
+            for frame in frames {
+                let frame = ReceivedFrame::new(frame, self.serializer.clone());
+                match frame.view().map(|view| view.to_owned()) {
+                    Ok(message) => batch.push(message),
+                    Err(e) => self.dead_letter_frame(frame.as_bytes(), &e),
+                }
+            }
+        }
+        Ok(batch)
+    }
+
     // cleanup function (no-op for tcp)
   async fn cleanup(&self) -> Result<(), MessengerError> {
         // here is nothing to clean up. this is a memoryless transport and it's the responsibility of the network to clean up after itself.
@@ -117,6 +219,38 @@ impl Transport for TcpTransport {
         self.config.max_message_size
     }
 
+    // get the serializer used for message encoding
+    fn serializer(&self) -> Arc<dyn Serializer> {
+        self.serializer.clone()
+    }
+
+    // get the counters of this transport
+    fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    // set the backpressure policy of the topics matching the pattern
+    fn set_backpressure(&self, pattern: TopicPattern, policy: BackpressurePolicy) {
+        self.backpressure.set(pattern, policy);
+    }
+
+    // open a flow so the peer only sends messages of the topic while the subscription has credits
+    fn open_flow(&self, topic: &str) -> Option<Arc<FlowGrant>> {
+        Some(self.flows.as_ref()?.open_inbound(topic))
+    }
+
+    // tell the peer which messages of the topic the subscriptions want, with or without flow control
+    fn set_interest(&self, topic: &str, filters: Option<&[Filter]>) {
+        if let Some(control) = &self.control {
+            let filters = filters.map(|filters| filters.iter().map(|filter| filter.as_str().to_string()).collect());
+            let _ = control.send(FlowControl::Interest { topic: topic.to_string(), filters });
+        }
+    }
+
+    fn set_dead_letter_topic(&self, topic: &str) {
+        *self.dead_letter_topic.write() = Some(topic.to_string());
+    }
+
     async fn close(&self) -> Result<(), Box<dyn std::error::Error>> {
         Ok(())
     }
@@ -133,12 +267,7 @@ impl TcpTransport {
         // bind to the address
         let listener = TcpListener::bind(&addr).await?;
         // return new TcpTransport instance
-        Ok(Self {
-            listener: Some(Arc::new(listener)),
-            stream: None,
-            config,
-            serializer,
-        })
+        Ok(Self::new(Some(Arc::new(listener)), config, serializer))
     }
 
     // create a new tcp client
@@ -151,12 +280,78 @@ impl TcpTransport {
         // connect to the server
         let stream = TcpStream::connect(&addr).await?;
         // return new TcpTransport instance
-        Ok(Self {
-            listener: None,
-            stream: Some(Arc::new(Mutex::new(stream))),
+        let mut transport = Self::new(None, config, serializer);
+        transport.attach(stream);
+        Ok(transport)
+    }
+
+    // address the server listens on, for servers bound to port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.as_ref()?.local_addr().ok()
+    }
+
+    fn new(listener: Option<Arc<TcpListener>>, config: TcpConfig, serializer: Box<dyn Serializer>) -> Self {
+        Self {
+            listener,
+            incoming: None,
+            loopback: None,
+            reader: None,
+            writer: None,
+            flows: None,
+            control: None,
+            interest: None,
             config,
-            serializer,
-        })
+            serializer: Arc::from(serializer),
+            metrics: Arc::new(Metrics::default()),
+            backpressure: Backpressure::default(),
+            dead_letter_topic: parking_lot::RwLock::new(None),
+        }
+    }
+
+    // start the reader and writer tasks of a new connection, replacing the previous one
+    fn attach(&mut self, stream: TcpStream) {
+        self.detach();
+        let (reader, writer) = stream.into_split();
+        let (control, control_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::channel(READ_QUEUE_SIZE);
+        let interest = Arc::new(PeerInterest::default());
+        let flows = self.config.flow_control.as_ref().map(|config| {
+            Arc::new(ConnectionFlows::new(config.clone(), control.clone(), self.metrics.clone()))
+        });
+
+        let queue = Arc::new(PriorityQueue::new(WRITE_QUEUE_SIZE));
+        tokio::spawn(write_frames(
+            writer,
+            Arc::clone(&queue),
+            control_rx,
+            flows.clone(),
+            self.metrics.clone(),
+        ));
+        self.reader = Some(tokio::spawn(read_frames(
+            reader,
+            self.config.max_message_size,
+            incoming_tx.clone(),
+            Arc::clone(&interest),
+            flows.clone(),
+            Arc::clone(&queue),
+        )));
+        self.loopback = Some(incoming_tx);
+        self.incoming = Some(Arc::new(Mutex::new(incoming)));
+        self.writer = Some(queue);
+        self.flows = flows;
+        self.control = Some(control);
+        self.interest = Some(interest);
+    }
+
+    // stop the tasks of the current connection, they would otherwise wait on it forever
+    fn detach(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            reader.abort();
+        }
+        self.loopback = None;
+        if let Some(writer) = self.writer.take() {
+            writer.close();
+        }
     }
 
     // accept a new connection (for server mode)
@@ -166,11 +361,707 @@ impl TcpTransport {
             // accept a new connection
             let (stream, _) = listener.accept().await?;
             // store the new stream
-            self.stream = Some(Arc::new(Mutex::new(stream)));
+            self.attach(stream);
             Ok(())
         } else {
             // return error if not in server mode
             Err(MessengerError::TransportError("Not a server".into()))
         }
     }
+
+    // refuse stale messages and serialize the rest, length prefix and serialized data go out as one frame
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, MessengerError> {
+        if message.is_expired() {
+            self.metrics.record_expired();
+            return Err(MessengerError::Expired(message.id.clone()));
+        }
+        encode_frame(self.serializer.as_ref(), message, self.max_message_size())
+    }
+
+    // queue an encoded message for the writer task, returning where it reports the frame written
+    // or None when the frame is held back for flow control credits
+    async fn queue_message(
+        &self,
+        writer: &PriorityQueue<OutgoingFrame>,
+        message: &Message,
+        frame: Vec<u8>,
+    ) -> Result<Option<oneshot::Receiver<Result<(), MessengerError>>>, MessengerError> {
+        let (written, result) = oneshot::channel();
+        let outgoing = OutgoingFrame {
+            frame,
+            id: message.id.clone(),
+            topic: message.topic.clone(),
+            expires_at: message.expires_at,
+            debited: Vec::new(),
+            written,
+        };
+
+        // no peer subscription wants the message, so it is not sent at all
+        let system = is_system_topic(&message.topic);
+        if !system && self.interest.as_ref().is_some_and(|interest| !interest.wanted(message)) {
+            return Ok(None);
+        }
+
+        // Frames for peer subscriptions without credits left are held back and sent
+        // once they grant more, the sender does not wait for those
+        let outgoing = match &self.flows {
+            Some(flows) if !system => {
+                match flows.admit(writer, message.priority, outgoing).await {
+                    Some(outgoing) => outgoing,
+                    None => return Ok(None),
+                }
+            }
+            _ => outgoing,
+        };
+
+        self.queue_frame(writer, message.priority, outgoing).await?;
+        Ok(Some(result))
+    }
+
+    // hand a frame to the writer task as the topic's backpressure policy says
+    async fn queue_frame(
+        &self,
+        writer: &PriorityQueue<OutgoingFrame>,
+        priority: Priority,
+        mut outgoing: OutgoingFrame,
+    ) -> Result<(), MessengerError> {
+        let policy = self.backpressure.policy(&outgoing.topic);
+        if policy == BackpressurePolicy::Block {
+            return writer.push(priority, outgoing).await.map_err(|_| MessengerError::ChannelClosed);
+        }
+        if let BackpressurePolicy::BlockMs(wait) = policy {
+            let topic = outgoing.topic.clone();
+            let debited = outgoing.debited.clone();
+            return match timeout(Duration::from_millis(wait), writer.push(priority, outgoing)).await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(_)) => Err(MessengerError::ChannelClosed),
+                Err(_) => {
+                    self.refund(writer, &debited).await;
+                    Err(MessengerError::Backpressure(topic))
+                }
+            };
+        }
+
+        // sampling decides once per frame, later rounds only make room for a kept one
+        let mut sampled = false;
+        loop {
+            outgoing = match writer.try_push(priority, outgoing) {
+                Ok(()) => return Ok(()),
+                Err(PushError::Closed(_)) => return Err(MessengerError::ChannelClosed),
+                Err(PushError::Full(outgoing)) => outgoing,
+            };
+
+            let keep = match policy {
+                BackpressurePolicy::Block | BackpressurePolicy::BlockMs(_) | BackpressurePolicy::Error => {
+                    self.refund(writer, &outgoing.debited).await;
+                    return Err(MessengerError::Backpressure(outgoing.topic));
+                }
+                BackpressurePolicy::DropNewest => false,
+                BackpressurePolicy::DropOldest => true,
+                BackpressurePolicy::Sample(every) => sampled || self.backpressure.sample(&outgoing.topic, every),
+            };
+            sampled = true;
+            let oldest = if keep {
+                writer.take_first(|queued| queued.topic == outgoing.topic)
+            } else {
+                None
+            };
+            match oldest {
+                Some(oldest) => {
+                    self.refund(writer, &oldest.debited).await;
+                    let error = self.dropped(&oldest.id);
+                    let _ = oldest.written.send(Err(error));
+                }
+                None => {
+                    self.refund(writer, &outgoing.debited).await;
+                    return Err(self.dropped(&outgoing.id));
+                }
+            }
+        }
+    }
+
+    // give back the credits of a frame dropped before it was written, queueing the frames they let out
+    async fn refund(&self, writer: &PriorityQueue<OutgoingFrame>, debited: &[u64]) {
+        if let Some(flows) = self.flows.as_ref().filter(|_| !debited.is_empty()) {
+            for (priority, frame) in flows.refund(debited) {
+                let _ = writer.push(priority, frame).await;
+            }
+        }
+    }
+
+    // hand a frame that cannot be decoded back to this side as a dead letter, the peer sent it
+    // and has no use for it, while the local dead-letter store and subscribers do
+    // the incoming queue is not waited on, the caller may hold it while draining it
+    fn dead_letter_frame(&self, data: &[u8], error: &MessengerError) {
+        let topic = match self.dead_letter_topic.read().clone() {
+            Some(topic) => topic,
+            None => {
+                log::warn!("dropping message that cannot be decoded: {}", error);
+                return;
+            }
+        };
+        let dead = self.serializer.serialize(&undecodable(data, &topic, error))
+            .map_err(|e| MessengerError::Serialization(e.to_string()));
+        let queued = match (dead, &self.loopback) {
+            (Ok(dead), Some(loopback)) => loopback.try_send(Ok(dead)).map_err(|_| MessengerError::Backpressure(topic)),
+            (Ok(_), None) => Err(MessengerError::TransportError("Not connected".into())),
+            (Err(e), _) => Err(e),
+        };
+        if let Err(e) = queued {
+            log::warn!("failed to dead-letter message that cannot be decoded: {}", e);
+        }
+    }
+
+    // count a frame dropped by backpressure, returning the error reported to its sender
+    fn dropped(&self, id: &str) -> MessengerError {
+        self.metrics.record_dropped();
+        MessengerError::Dropped(id.to_string())
+    }
+}
+
+impl Drop for TcpTransport {
+    fn drop(&mut self) {
+        self.detach();
+    }
+}
+
+// which messages the peer's subscriptions want, told by its Interest frames
+#[derive(Default)]
+struct PeerInterest {
+    // filters the peer's subscriptions want messages of a topic to match, every message of
+    // the topics missing here is wanted
+    filters: parking_lot::Mutex<HashMap<String, Vec<Filter>>>,
+}
+
+impl PeerInterest {
+    // whether one of the peer's subscriptions wants the message
+    // chunks and transaction members always are, the peer filters them once they are whole,
+    // and until then a chunk may lack the headers the filters look at
+    fn wanted(&self, message: &Message) -> bool {
+        if message.headers.contains_key(CHUNK_ID) || message.headers.contains_key(TXN_ID) {
+            return true;
+        }
+        match self.filters.lock().get(&message.topic) {
+            Some(filters) => filters.iter().any(|filter| filter.matches(message)),
+            None => true,
+        }
+    }
+
+    // a filter that does not parse here asks for every message, so nothing the peer wants is lost
+    fn set(&self, topic: String, filters: Option<Vec<String>>) {
+        let filters = filters.map(|filters| filters.iter().map(|filter| Filter::parse(filter)).collect::<Result<Vec<_>, _>>());
+        let mut interest = self.filters.lock();
+        match filters {
+            Some(Ok(filters)) => {
+                interest.insert(topic, filters);
+            }
+            Some(Err(e)) => {
+                log::warn!("peer sent an invalid filter for topic '{}', sending it every message: {}", topic, e);
+                interest.remove(&topic);
+            }
+            None => {
+                interest.remove(&topic);
+            }
+        }
+    }
+}
+
+// flow control state of one connection
+struct ConnectionFlows {
+    config: FlowControlConfig,
+    // flows the peer opened for its subscriptions, holding back frames they have no credits for
+    outbound: parking_lot::Mutex<OutboundFlows<OutgoingFrame>>,
+    // flows opened for local subscriptions, by id
+    inbound: parking_lot::Mutex<HashMap<u64, Weak<FlowGrant>>>,
+    next_flow: AtomicU64,
+    // control frames for the writer task, which writes them ahead of every queued frame
+    control: mpsc::UnboundedSender<FlowControl>,
+    metrics: Arc<Metrics>,
+}
+
+impl ConnectionFlows {
+    fn new(config: FlowControlConfig, control: mpsc::UnboundedSender<FlowControl>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            outbound: parking_lot::Mutex::new(OutboundFlows::new(&config)),
+            config,
+            inbound: parking_lot::Mutex::new(HashMap::new()),
+            next_flow: AtomicU64::new(0),
+            control,
+            metrics,
+        }
+    }
+
+    fn open_inbound(&self, topic: &str) -> Arc<FlowGrant> {
+        let id = self.next_flow.fetch_add(1, Ordering::SeqCst);
+        let grant = Arc::new(FlowGrant::open(id, topic, &self.config, self.control.clone()));
+        let mut inbound = self.inbound.lock();
+        inbound.retain(|_, grant| grant.strong_count() > 0);
+        inbound.insert(id, Arc::downgrade(&grant));
+        grant
+    }
+
+    // take a frame for the writer, returning it when the peer's flows have credits for it
+    async fn admit(&self, writer: &PriorityQueue<OutgoingFrame>, priority: Priority, outgoing: OutgoingFrame) -> Option<OutgoingFrame> {
+        let topic = outgoing.topic.clone();
+        let (admitted, released) = {
+            let mut outbound = self.outbound.lock();
+            let (admitted, control) = outbound.admit(&topic, priority, outgoing);
+            // a slow consumer that was cut off or degraded no longer holds frames back
+            let released = if control.is_empty() { Vec::new() } else { debit(outbound.release()) };
+            for control in control {
+                self.metrics.record_slow_consumer();
+                let _ = self.control.send(control);
+            }
+            (admitted, released)
+        };
+        for (priority, frame) in released {
+            let _ = writer.push(priority, frame).await;
+        }
+        admitted.map(|(mut frame, flows)| {
+            frame.debited = flows;
+            frame
+        })
+    }
+
+    // give back the credits a dropped frame took, returning the held frames they let out
+    fn refund(&self, debited: &[u64]) -> Vec<(Priority, OutgoingFrame)> {
+        let mut outbound = self.outbound.lock();
+        outbound.refund(debited);
+        debit(outbound.release())
+    }
+
+    // apply a control frame from the peer, queueing the frames its credits let out
+    async fn handle(&self, control: FlowControl, writer: &PriorityQueue<OutgoingFrame>) {
+        let released = {
+            let mut outbound = self.outbound.lock();
+            match control {
+                FlowControl::Open { flow, topic, credits } => outbound.open(flow, topic, credits),
+                FlowControl::Credit { flow, credits } => outbound.credit(flow, credits),
+                FlowControl::Close { flow } => outbound.close(flow),
+                // the reader task hands these to the connection's PeerInterest
+                FlowControl::Interest { .. } => return,
+                FlowControl::Evict { flow } | FlowControl::Degrade { flow } => {
+                    drop(outbound);
+                    let grant = self.inbound.lock().get(&flow).and_then(Weak::upgrade);
+                    if let Some(grant) = grant {
+                        match control {
+                            FlowControl::Evict { .. } => grant.evict(),
+                            _ => grant.degrade(),
+                        }
+                    }
+                    return;
+                }
+            }
+            debit(outbound.release())
+        };
+        for (priority, frame) in released {
+            let _ = writer.push(priority, frame).await;
+        }
+    }
+}
+
+// note on released frames which flows' credits they took
+fn debit(released: Vec<(Priority, OutgoingFrame, Vec<u64>)>) -> Vec<(Priority, OutgoingFrame)> {
+    released
+        .into_iter()
+        .map(|(priority, mut frame, flows)| {
+            frame.debited = flows;
+            (priority, frame)
+        })
+        .collect()
+}
+
+// serialize a message into a length-prefixed frame
+fn encode_frame(serializer: &dyn Serializer, message: &Message, max_len: usize) -> Result<Vec<u8>, MessengerError> {
+    let payload = serializer.serialize(message)
+        .map_err(|e| MessengerError::Serialization(e.to_string()))?;
+    // the top bit of the length prefix is the control frame tag
+    let max_len = max_len.min(CONTROL_FRAME as usize - 1);
+    if payload.len() > max_len {
+        return Err(MessengerError::MessageTooLarge(payload.len(), max_len));
+    }
+    Ok(prefixed(0, &payload))
+}
+
+// encode a flow control frame, its length prefix carries the CONTROL_FRAME tag
+fn encode_control(control: &FlowControl) -> Result<Vec<u8>, MessengerError> {
+    Ok(prefixed(CONTROL_FRAME, &control.to_payload()?))
+}
+
+fn prefixed(tag: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(tag | payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// read frames until the connection fails or the transport goes away, handing every
+// frame but the flow control ones to the receiver
+async fn read_frames(
+    mut stream: OwnedReadHalf,
+    max_len: usize,
+    incoming: mpsc::Sender<Result<Vec<u8>, MessengerError>>,
+    interest: Arc<PeerInterest>,
+    flows: Option<Arc<ConnectionFlows>>,
+    writer: Arc<PriorityQueue<OutgoingFrame>>,
+) {
+    loop {
+        let (control, frame) = match read_tagged_frame(&mut stream, max_len).await {
+            Ok(frame) => frame,
+            Err(e) => {
+                let _ = incoming.send(Err(e)).await;
+                return;
+            }
+        };
+        if control {
+            match (&flows, FlowControl::from_payload(&frame)) {
+                // interest does not depend on flow control
+                (_, Ok(FlowControl::Interest { topic, filters })) => interest.set(topic, filters),
+                (Some(flows), Ok(control)) => flows.handle(control, &writer).await,
+                (None, _) => log::warn!("dropping flow control frame, flow control is off"),
+                (_, Err(e)) => log::warn!("dropping malformed flow control frame: {}", e),
+            }
+            continue;
+        }
+        if incoming.send(Ok(frame)).await.is_err() {
+            return;
+        }
+    }
+}
+
+// write frames until the queue is closed or the connection fails, a failed
+// connection closes the queue so later sends fail right away
+// control frames are written first, credits must not wait behind the frames they let out
+async fn write_frames(
+    mut stream: OwnedWriteHalf,
+    queue: Arc<PriorityQueue<OutgoingFrame>>,
+    mut control: mpsc::UnboundedReceiver<FlowControl>,
+    flows: Option<Arc<ConnectionFlows>>,
+    metrics: Arc<Metrics>,
+) {
+    // frames let out by the credits of expired frames, written ahead of the queue
+    let mut released = VecDeque::new();
+    loop {
+        let first = tokio::select! {
+            biased;
+            Some(control) = control.recv() => {
+                match encode_control(&control) {
+                    Ok(frame) => {
+                        if write_frame(&mut stream, &frame).await.is_err() {
+                            queue.close();
+                            return;
+                        }
+                    }
+                    Err(e) => log::warn!("failed to encode flow control frame: {}", e),
+                }
+                continue;
+            }
+            outgoing = next_frame(&mut released, &queue) => match outgoing {
+                Some(outgoing) => outgoing,
+                None => return,
+            },
+        };
+
+        // frames queued behind the first go out with it, in a single vectored write
+        let now = now_millis();
+        let mut batch = Vec::new();
+        let mut next = Some(first);
+        while let Some(outgoing) = next {
+            if outgoing.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                metrics.record_expired();
+                if let Some(flows) = flows.as_ref().filter(|_| !outgoing.debited.is_empty()) {
+                    released.extend(flows.refund(&outgoing.debited).into_iter().map(|(_, frame)| frame));
+                }
+                let _ = outgoing.written.send(Err(MessengerError::Expired(outgoing.id)));
+            } else {
+                batch.push(outgoing);
+            }
+            next = if batch.len() < WRITE_BATCH_SIZE { released.pop_front().or_else(|| queue.try_pop()) } else { None };
+        }
+        if batch.is_empty() {
+            continue;
+        }
+
+        let frames: Vec<&[u8]> = batch.iter().map(|outgoing| outgoing.frame.as_slice()).collect();
+        let result = write_all_vectored(&mut stream, &frames).await;
+        let failed = result.is_err();
+        for outgoing in batch {
+            let result = match &result {
+                Ok(()) => Ok(()),
+                Err(e) => Err(MessengerError::from(std::io::Error::new(e.kind(), e.to_string()))),
+            };
+            let _ = outgoing.written.send(result);
+        }
+        if failed {
+            queue.close();
+            return;
+        }
+    }
+}
+
+// the next frame to write, frames released by refunded credits first
+async fn next_frame(released: &mut VecDeque<OutgoingFrame>, queue: &PriorityQueue<OutgoingFrame>) -> Option<OutgoingFrame> {
+    match released.pop_front() {
+        Some(frame) => Some(frame),
+        None => queue.pop().await,
+    }
+}
+
+async fn write_frame(stream: &mut OwnedWriteHalf, frame: &[u8]) -> std::io::Result<()> {
+    stream.write_all(frame).await?;
+    stream.flush().await
+}
+
+async fn write_all_vectored(stream: &mut OwnedWriteHalf, frames: &[&[u8]]) -> std::io::Result<()> {
+    let mut slices: Vec<IoSlice> = frames.iter().map(|frame| IoSlice::new(frame)).collect();
+    let mut remaining = slices.as_mut_slice();
+    while !remaining.is_empty() {
+        let written = stream.write_vectored(remaining).await?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut remaining, written);
+    }
+    stream.flush().await
+}
+
+// read one length-prefixed frame from the stream
+// the length comes from the peer, so it is checked against `max_len` before
+// anything is allocated for the frame body
+pub async fn read_frame<R>(reader: &mut R, max_len: usize) -> Result<Vec<u8>, MessengerError>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    // Read message length
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes).await?;
+    read_body(reader, u32::from_be_bytes(len_bytes) as usize, max_len).await
+}
+
+// read one frame, data or flow control, reporting whether it is a control frame
+// the tag is a bit of the length prefix, so data frames are handed on without being decoded
+async fn read_tagged_frame<R>(reader: &mut R, max_len: usize) -> Result<(bool, Vec<u8>), MessengerError>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes).await?;
+    let len = u32::from_be_bytes(len_bytes);
+    let body = read_body(reader, (len & !CONTROL_FRAME) as usize, max_len).await?;
+    Ok((len & CONTROL_FRAME != 0, body))
+}
+
+async fn read_body<R>(reader: &mut R, len: usize, max_len: usize) -> Result<Vec<u8>, MessengerError>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    if len > max_len {
+        return Err(MessengerError::MessageTooLarge(len, max_len));
+    }
+
+    // Read serialized message
+    let mut buffer = vec![0u8; len];
+    reader.read_exact(&mut buffer).await?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::messenger::{MessageSubscriber, Messenger, MessengerImpl};
+    use crate::application::subscription::SubscriptionOptions;
+    use crate::infrastructure::serialization::json::JsonSerializer;
+
+    const CREDITS: u32 = 4;
+
+    // a server and a client messenger connected over localhost with flow control on
+    async fn connected() -> (MessengerImpl, MessengerImpl) {
+        let (server, client) = transports(Some(FlowControlConfig { credits: CREDITS, ..Default::default() })).await;
+        (MessengerImpl::new(Arc::new(server)), MessengerImpl::new(Arc::new(client)))
+    }
+
+    // a server and a client transport connected over localhost
+    async fn transports(flow_control: Option<FlowControlConfig>) -> (TcpTransport, TcpTransport) {
+        let config = TcpConfig { host: "127.0.0.1".into(), port: 0, max_message_size: 4096, flow_control };
+        let mut server = TcpTransport::new_server(config.clone(), Box::new(JsonSerializer)).await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let (client, accepted) = tokio::join!(
+            TcpTransport::new_client(TcpConfig { port, ..config }, Box::new(JsonSerializer)),
+            server.accept(),
+        );
+        accepted.unwrap();
+        (server, client.unwrap())
+    }
+
+    async fn receive(subscriber: &dyn MessageSubscriber) -> Message {
+        timeout(Duration::from_secs(5), subscriber.receive()).await.expect("the flow stalled").unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_the_receiver_drops_give_their_credits_back() {
+        let (server, client) = connected().await;
+        let subscriber = server.subscribe("t".into()).await.unwrap();
+        // let the flow open on the client before anything is sent
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // transaction members without an index are dropped before any subscriber sees them
+        for _ in 0..CREDITS * 3 {
+            client.publish("t".into(), &Message::new("t".into(), vec![0]).with_header(TXN_ID, "broken")).await.unwrap();
+        }
+        client.publish("t".into(), &Message::new("t".into(), vec![1])).await.unwrap();
+        assert_eq!(receive(subscriber.as_ref()).await.payload, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn frames_the_sender_drops_give_their_credits_back() {
+        let (server, client) = connected().await;
+        let subscriber = server.subscribe("t".into()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // the first frames take every credit, the rest are held back and expire waiting for more
+        for _ in 0..CREDITS * 3 {
+            let message = Message::new("t".into(), vec![0]).with_ttl(Duration::from_millis(100));
+            client.publish("t".into(), &message).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        client.publish("t".into(), &Message::new("t".into(), vec![1])).await.unwrap();
+        assert_eq!(receive(subscriber.as_ref()).await.payload, vec![1]);
+        assert!(client.metrics().expired >= CREDITS as u64);
+    }
+
+    // a server subscription taking only the messages of kind `keep`, with its interest known to the client
+    async fn filtered(server: &MessengerImpl) -> Box<dyn MessageSubscriber> {
+        let options = SubscriptionOptions { filter: Some(Filter::parse("kind == 'keep'").unwrap()), ..Default::default() };
+        let subscriber = server.subscribe_with("t".into(), options).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        subscriber
+    }
+
+    #[tokio::test]
+    async fn chunks_get_past_the_peer_filters_and_are_filtered_once_whole() {
+        let (server, client) = connected().await;
+        let subscriber = filtered(&server).await;
+
+        // only the first chunk carries the headers the filter looks at
+        let big = Message::new("t".into(), vec![7; 10_000]).with_header("kind", "keep");
+        client.publish("t".into(), &Message::new("t".into(), vec![0; 10_000]).with_header("kind", "skip")).await.unwrap();
+        client.publish("t".into(), &big).await.unwrap();
+        let received = receive(subscriber.as_ref()).await;
+        assert_eq!(received.payload, big.payload);
+        assert_eq!(received.id, big.id);
+    }
+
+    #[tokio::test]
+    async fn transaction_members_get_past_the_peer_filters() {
+        let (server, client) = connected().await;
+        let subscriber = filtered(&server).await;
+
+        // the transaction only completes on the server when the member the filter skips gets there too
+        let mut transaction = client.begin();
+        transaction.publish("t".into(), &Message::new("t".into(), vec![0]).with_header("kind", "skip")).unwrap();
+        transaction.publish("t".into(), &Message::new("t".into(), vec![1]).with_header("kind", "keep")).unwrap();
+        transaction.commit().await.unwrap();
+        assert_eq!(receive(subscriber.as_ref()).await.payload, vec![1]);
+
+        // messages outside chunks and transactions are still filtered before they are sent
+        client.publish("t".into(), &Message::new("t".into(), vec![2]).with_header("kind", "skip")).await.unwrap();
+        client.publish("t".into(), &Message::new("t".into(), vec![3]).with_header("kind", "keep")).await.unwrap();
+        assert_eq!(receive(subscriber.as_ref()).await.payload, vec![3]);
+    }
+
+    #[tokio::test]
+    async fn messages_the_peer_filters_out_are_not_sent_without_flow_control() {
+        let (server, client) = transports(None).await;
+        server.set_interest("t", Some(&[Filter::parse("kind == 'keep'").unwrap()]));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        client.send(&Message::new("t".into(), vec![0]).with_header("kind", "skip")).await.unwrap();
+        client.send(&Message::new("t".into(), vec![1]).with_header("kind", "keep")).await.unwrap();
+        assert_eq!(server.receive().await.unwrap().payload, vec![1]);
+
+        // every message is wanted again once the filters are gone
+        server.set_interest("t", None);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.send(&Message::new("t".into(), vec![2]).with_header("kind", "skip")).await.unwrap();
+        assert_eq!(server.receive().await.unwrap().payload, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn batches_dead_letter_frames_they_cannot_decode() {
+        let config = TcpConfig { host: "127.0.0.1".into(), port: 0, max_message_size: 4096, flow_control: None };
+        let mut server = TcpTransport::new_server(config, Box::new(JsonSerializer)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let (peer, accepted) = tokio::join!(TcpStream::connect(addr), server.accept());
+        accepted.unwrap();
+        let mut peer = peer.unwrap();
+        server.set_dead_letter_topic("dead");
+
+        let good = encode_frame(&JsonSerializer, &Message::new("t".into(), vec![1]), 4096).unwrap();
+        peer.write_all(&prefixed(0, &[0xff; 3])).await.unwrap();
+        peer.write_all(&good).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let batch = server.receive_batch(10, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].payload, vec![1]);
+        let dead = server.receive().await.unwrap();
+        assert_eq!(dead.topic, "dead");
+        assert_eq!(dead.payload, vec![0xff; 3]);
+        // the dead letter stays on this side, the peer that sent the frame is not sent it back
+        let mut echoed = [0u8; 4];
+        assert!(timeout(Duration::from_millis(100), peer.read_exact(&mut echoed)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_the_messenger_cannot_decode_are_kept_as_local_dead_letters() {
+        let config = TcpConfig { host: "127.0.0.1".into(), port: 0, max_message_size: 4096, flow_control: None };
+        let mut server = TcpTransport::new_server(config, Box::new(JsonSerializer)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let (peer, accepted) = tokio::join!(TcpStream::connect(addr), server.accept());
+        accepted.unwrap();
+        let mut peer = peer.unwrap();
+        let server = MessengerImpl::new(Arc::new(server)).with_dead_letter_topic("dead");
+        let dead_letters = server.subscribe("dead".into()).await.unwrap();
+
+        peer.write_all(&prefixed(0, &[0xff; 3])).await.unwrap();
+        let dead = receive(dead_letters.as_ref()).await;
+        assert_eq!(dead.payload, vec![0xff; 3]);
+        let kept = server.dead_letters("dead");
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].id, dead.id);
+
+        // it has no original topic to go back to, so replaying leaves it in the store
+        assert!(server.replay_dead_letter("dead", &dead.id).await.is_err());
+        assert_eq!(server.dead_letters("dead").len(), 1);
+        let mut echoed = [0u8; 4];
+        assert!(timeout(Duration::from_millis(100), peer.read_exact(&mut echoed)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn batches_leave_out_expired_members() {
+        let (server, client) = connected().await;
+        let subscriber = server.subscribe("t".into()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut stale = Message::new("t".into(), vec![0]);
+        stale.expires_at = Some(1);
+        let batch = [Message::new("t".into(), vec![1]), stale, Message::new("t".into(), vec![2])];
+        client.publish_batch(&batch).await.unwrap();
+        assert_eq!(receive(subscriber.as_ref()).await.payload, vec![1]);
+        assert_eq!(receive(subscriber.as_ref()).await.payload, vec![2]);
+        assert_eq!(client.metrics().expired, 1);
+    }
+
+    #[tokio::test]
+    async fn control_frames_are_told_apart_by_their_length_prefix() {
+        let control = FlowControl::Credit { flow: 7, credits: 3 };
+        let frame = encode_control(&control).unwrap();
+        let (tagged, payload) = read_tagged_frame(&mut frame.as_slice(), 4096).await.unwrap();
+        assert!(tagged);
+        assert_eq!(FlowControl::from_payload(&payload).unwrap(), control);
+
+        let frame = encode_frame(&JsonSerializer, &Message::new("t".into(), vec![1]), 4096).unwrap();
+        let (tagged, _) = read_tagged_frame(&mut frame.as_slice(), 4096).await.unwrap();
+        assert!(!tagged);
+    }
 }
@@ -23,13 +23,21 @@
 // Authors: I. Zeqiri, E. Gjergji
 
 use lazy_static::lazy_static;
-use std::ffi::{c_char, c_void};
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_void};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::runtime::Runtime;
 
-use crate::application::config::{Config, TransportType};
-use crate::application::messenger::{Messenger, MessengerImpl};
+use crate::application::config::{
+    default_segment_bytes, default_tombstone_retention_secs, BackpressurePolicy, ChunkingConfig, Config,
+    FlowControlConfig, FsyncPolicy, IpcConfig, LogConfig, SlowConsumerPolicy, TcpConfig, TransactionConfig,
+    TransportType,
+};
+use crate::application::messenger::{MessageSubscriber, Messenger, MessengerImpl};
+use crate::domain::errors::MessengerError;
 use crate::domain::message::Message;
 use crate::infrastructure::memory::pool_allocator::PoolAllocator;
 use crate::infrastructure::serialization::json::JsonSerializer;
@@ -41,16 +49,129 @@ use crate::application::instance_manager::INSTANCE_MANAGER;
 lazy_static! {
     static ref RUNTIME: Runtime = Runtime::new().expect("Failed to create Tokio runtime");
     static ref MESSENGER_MUTEX: Mutex<AtomicBool> = Mutex::new(AtomicBool::new(false));
+    // subscribers of zark_messenger_receive_batch by topic, kept between calls so nothing
+    // published between two calls is missed
+    static ref BATCH_SUBSCRIBERS: Mutex<HashMap<String, Arc<dyn MessageSubscriber>>> = Mutex::new(HashMap::new());
 }
 
+// the structs below mirror include/zark_messenger.h and have to be kept in step with it
+// enums come in as plain ints, a value C sends that has no variant is rejected instead of trusted
+
+// a message as C callers lay it out, topic and payload are copied when it is sent
+#[repr(C)]
+pub struct ZarkMessage {
+    pub topic: *const c_char,
+    pub payload: *const u8,
+    pub payload_length: usize,
+}
+
+#[repr(C)]
+pub struct ZarkIpcConfig {
+    pub shared_memory_name: *const c_char,
+    pub max_message_size: usize,
+    pub max_queue_size: usize,
+    pub max_buffer_size: usize,
+}
+
+#[repr(C)]
+pub struct ZarkTcpConfig {
+    pub host: *const c_char,
+    pub port: u16,
+    pub max_message_size: usize,
+    // NULL turns flow control off
+    pub flow_control: *const ZarkFlowControlConfig,
+}
+
+#[repr(C)]
+pub struct ZarkFlowControlConfig {
+    pub credits: u32,
+    pub buffer_size: usize,
+    // ZarkSlowConsumerPolicy
+    pub slow_consumer: c_int,
+}
+
+#[repr(C)]
+pub struct ZarkLogConfig {
+    pub directory: *const c_char,
+    // topic patterns to log, every non-system topic is logged when there are none
+    pub topics: *const *const c_char,
+    pub topic_count: usize,
+    // 0 for the default
+    pub segment_bytes: u64,
+    // 0 for no limit
+    pub retention_bytes: u64,
+    pub retention_secs: u64,
+    // ZarkFsyncPolicy, `fsync_interval_ms` only counts for ZARK_FSYNC_INTERVAL
+    pub fsync: c_int,
+    pub fsync_interval_ms: u64,
+    pub compact_topics: *const *const c_char,
+    pub compact_topic_count: usize,
+    // 0 for the default
+    pub tombstone_retention_secs: u64,
+}
+
+#[repr(C)]
+pub struct ZarkBackpressure {
+    pub pattern: *const c_char,
+    // ZarkBackpressurePolicy, `value` is the deadline for ZARK_BACKPRESSURE_BLOCK_MS and
+    // the sampling rate for ZARK_BACKPRESSURE_SAMPLE
+    pub policy: c_int,
+    pub value: u64,
+}
+
+#[repr(C)]
+pub struct ZarkChunkingConfig {
+    // 0 for the defaults
+    pub timeout_ms: u64,
+    pub max_pending_bytes: usize,
+}
+
+#[repr(C)]
+pub struct ZarkTransactionConfig {
+    // 0 for the defaults
+    pub timeout_ms: u64,
+    pub max_pending_bytes: usize,
+}
+
+#[repr(C)]
+pub struct ZarkConfig {
+    // ZarkTransportType
+    pub transport_type: c_int,
+    pub ipc_config: *const ZarkIpcConfig,
+    pub tcp_config: *const ZarkTcpConfig,
+    // NULL keeps messages in memory only
+    pub log_config: *const ZarkLogConfig,
+    pub backpressure: *const ZarkBackpressure,
+    pub backpressure_count: usize,
+    // NULL for the defaults
+    pub chunking: *const ZarkChunkingConfig,
+    // NULL for the defaults
+    pub transactions: *const ZarkTransactionConfig,
+}
+
+/// # Safety
+///
+/// `config` must point to a valid `ZarkConfig`, and every pointer in it must be NULL or point
+/// to what include/zark_messenger.h says, with NUL-terminated strings and arrays of at least
+/// the given counts.
 #[no_mangle]
-pub extern "C" fn zark_messenger_init(config: *const Config) -> *mut c_void {
+pub unsafe extern "C" fn zark_messenger_init(config: *const ZarkConfig) -> *mut c_void {
     if let Some(existing) = INSTANCE_MANAGER.get_messenger() {
         INSTANCE_MANAGER.register_instance();
         return existing;
     }
 
-    let config = unsafe { &*config };
+    if config.is_null() {
+        eprintln!("Config pointer is null");
+        return std::ptr::null_mut();
+    }
+    let config = match config_from_c(&*config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid config: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
     // Create transport and messenger as before
     let transport: Arc<dyn Transport> = match config.transport_type {
         TransportType::IPC => {
@@ -67,7 +188,16 @@ pub extern "C" fn zark_messenger_init(config: *const Config) -> *mut c_void {
         }
     };
 
-    let messenger: Box<dyn Messenger> = Box::new(MessengerImpl::new(transport));
+    let mut messenger = MessengerImpl::new(transport)
+        .with_chunking(config.chunking.clone())
+        .with_transactions(config.transactions.clone());
+    for (pattern, policy) in &config.backpressure {
+        messenger = messenger.with_backpressure(pattern.clone(), *policy);
+    }
+    if let Some(log_config) = &config.log_config {
+        messenger = messenger.with_durable_log(log_config.clone()).expect("Failed to open durable log");
+    }
+    let messenger: Box<dyn Messenger> = Box::new(messenger);
     let messenger_ptr = Box::into_raw(messenger) as *mut c_void;
     
     INSTANCE_MANAGER.set_messenger(messenger_ptr);
@@ -76,8 +206,12 @@ pub extern "C" fn zark_messenger_init(config: *const Config) -> *mut c_void {
     messenger_ptr
 }
 
+/// # Safety
+///
+/// `messenger_param` must come from `zark_messenger_init`, and `message` must point to a valid
+/// `ZarkMessage` whose topic is NUL-terminated and whose payload has `payload_length` bytes.
 #[no_mangle]
-pub extern "C" fn zark_messenger_send(messenger_param: *mut c_void, message: *const Message) -> bool {
+pub unsafe extern "C" fn zark_messenger_send(messenger_param: *mut c_void, message: *const ZarkMessage) -> bool {
     if messenger_param.is_null() {
         eprintln!("Messenger pointer is null");
         return false;
@@ -87,11 +221,14 @@ pub extern "C" fn zark_messenger_send(messenger_param: *mut c_void, message: *co
         return false;
     }
 
-    let messenger = unsafe { &*(messenger_param as *mut MessengerImpl) as &dyn Messenger };
-    let message = unsafe { &*message };
+    let messenger = &*(messenger_param as *mut MessengerImpl) as &dyn Messenger;
+    let message = match message_from_c(&*message) {
+        Some(message) => message,
+        None => return false,
+    };
 
     RUNTIME.block_on(async {
-        messenger.publish(message.topic.clone(), message).await.is_ok()
+        messenger.publish(message.topic.clone(), &message).await.is_ok()
     })
 }
 
@@ -135,6 +272,91 @@ pub extern "C" fn zark_messenger_receive(
     })
 }
 
+/// # Safety
+///
+/// `messenger_param` must come from `zark_messenger_init`, and `messages` must point to
+/// `count` valid `ZarkMessage`s, see `zark_messenger_send`.
+#[no_mangle]
+pub unsafe extern "C" fn zark_messenger_send_batch(messenger_param: *mut c_void, messages: *const ZarkMessage, count: usize) -> bool {
+    if messenger_param.is_null() {
+        eprintln!("Messenger pointer is null");
+        return false;
+    }
+    if messages.is_null() && count > 0 {
+        eprintln!("Messages pointer is null");
+        return false;
+    }
+
+    let messenger = &*(messenger_param as *mut MessengerImpl) as &dyn Messenger;
+    let messages = if count == 0 { &[] } else { std::slice::from_raw_parts(messages, count) };
+    let messages = match messages.iter().map(|message| message_from_c(message)).collect::<Option<Vec<_>>>() {
+        Some(messages) => messages,
+        None => return false,
+    };
+
+    RUNTIME.block_on(async {
+        messenger.publish_batch(&messages).await.is_ok()
+    })
+}
+
+/// Receive up to `max` messages of a topic, waiting up to `timeout_ms` for the first one.
+/// Payloads are copied back to back into `buffer`, with the length of each in `lengths`,
+/// a payload that does not fit in what is left of the buffer is truncated.
+/// The first call for a topic subscribes to it, later calls take what arrived in between.
+/// Returns the number of messages received, or -1 on error.
+///
+/// # Safety
+///
+/// `messenger_param` must come from `zark_messenger_init`, `topic` must be NUL-terminated,
+/// `buffer` must have room for `buffer_len` bytes and `lengths` for `max` entries.
+#[no_mangle]
+pub unsafe extern "C" fn zark_messenger_receive_batch(
+    messenger_param: *mut c_void,
+    topic: *const c_char,
+    max: usize,
+    timeout_ms: u64,
+    buffer: *mut c_char,
+    buffer_len: usize,
+    lengths: *mut usize,
+) -> i32 {
+    if messenger_param.is_null() || topic.is_null() || (max > 0 && (buffer.is_null() || lengths.is_null())) {
+        return -1;
+    }
+    let messenger = &*(messenger_param as *mut MessengerImpl) as &dyn Messenger;
+    let topic = c_str_to_rust_string(topic);
+
+    RUNTIME.block_on(async {
+        let subscriber = match batch_subscriber(messenger, topic).await {
+            Ok(subscriber) => subscriber,
+            Err(_) => return -1,
+        };
+
+        match subscriber.receive_batch(max, Duration::from_millis(timeout_ms)).await {
+            Ok(messages) => {
+                let mut offset = 0;
+                for (index, msg) in messages.iter().enumerate() {
+                    let copy_len = std::cmp::min(msg.payload.len(), buffer_len - offset);
+                    std::ptr::copy_nonoverlapping(msg.payload.as_ptr(), (buffer as *mut u8).add(offset), copy_len);
+                    *lengths.add(index) = copy_len;
+                    offset += copy_len;
+                }
+                messages.len() as i32
+            }
+            Err(_) => -1,
+        }
+    })
+}
+
+// the subscriber zark_messenger_receive_batch keeps for `topic`, subscribing on first use
+async fn batch_subscriber(messenger: &dyn Messenger, topic: String) -> Result<Arc<dyn MessageSubscriber>, MessengerError> {
+    if let Some(subscriber) = BATCH_SUBSCRIBERS.lock().unwrap().get(&topic) {
+        return Ok(subscriber.clone());
+    }
+    let subscriber: Arc<dyn MessageSubscriber> = Arc::from(messenger.subscribe(topic.clone()).await?);
+    // a call racing this one may have subscribed first, its subscriber is kept
+    Ok(BATCH_SUBSCRIBERS.lock().unwrap().entry(topic).or_insert(subscriber).clone())
+}
+
 #[no_mangle]
 pub extern "C" fn zark_messenger_cleanup(messenger: *mut c_void) {
     if messenger.is_null() {
@@ -142,6 +364,7 @@ pub extern "C" fn zark_messenger_cleanup(messenger: *mut c_void) {
     }
 
     let messenger = unsafe { &*(messenger as *mut MessengerImpl) as &dyn Messenger };
+    BATCH_SUBSCRIBERS.lock().unwrap().clear();
     RUNTIME.block_on(async {
         let _ = messenger.cleanup().await;
     });
@@ -163,4 +386,186 @@ fn c_str_to_rust_string(c_str: *const c_char) -> String {
             .to_string_lossy()
             .into_owned()
     }
-}
\ No newline at end of file
+}
+
+// copy a C message into a message, None when its topic is missing
+unsafe fn message_from_c(message: &ZarkMessage) -> Option<Message> {
+    if message.topic.is_null() || (message.payload.is_null() && message.payload_length > 0) {
+        eprintln!("Message topic or payload pointer is null");
+        return None;
+    }
+    let payload = if message.payload_length == 0 {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(message.payload, message.payload_length).to_vec()
+    };
+    Some(Message::new(c_str_to_rust_string(message.topic), payload))
+}
+
+// build the messenger config out of what C callers pass
+unsafe fn config_from_c(config: &ZarkConfig) -> Result<Config, String> {
+    let transport_type = match config.transport_type {
+        0 => TransportType::IPC,
+        1 => TransportType::TCP,
+        other => return Err(format!("unknown transport type {}", other)),
+    };
+    let ipc_config = config.ipc_config.as_ref().map(|ipc| IpcConfig {
+        shared_memory_name: c_str_to_rust_string(ipc.shared_memory_name),
+        max_message_size: ipc.max_message_size,
+        max_queue_size: ipc.max_queue_size,
+        max_buffer_size: ipc.max_buffer_size,
+    });
+    let tcp_config = match config.tcp_config.as_ref() {
+        Some(tcp) => Some(TcpConfig {
+            host: c_str_to_rust_string(tcp.host),
+            port: tcp.port,
+            max_message_size: tcp.max_message_size,
+            flow_control: match tcp.flow_control.as_ref() {
+                Some(flow) => Some(FlowControlConfig {
+                    credits: flow.credits,
+                    buffer_size: flow.buffer_size,
+                    slow_consumer: match flow.slow_consumer {
+                        0 => SlowConsumerPolicy::Disconnect,
+                        1 => SlowConsumerPolicy::Degrade,
+                        other => return Err(format!("unknown slow consumer policy {}", other)),
+                    },
+                }),
+                None => None,
+            },
+        }),
+        None => None,
+    };
+    let log_config = match config.log_config.as_ref() {
+        Some(log) => Some(log_config_from_c(log)?),
+        None => None,
+    };
+
+    let mut backpressure = HashMap::new();
+    if config.backpressure_count > 0 && !config.backpressure.is_null() {
+        for entry in std::slice::from_raw_parts(config.backpressure, config.backpressure_count) {
+            let policy = match entry.policy {
+                0 => BackpressurePolicy::Block,
+                1 => BackpressurePolicy::BlockMs(entry.value),
+                2 => BackpressurePolicy::DropNewest,
+                3 => BackpressurePolicy::DropOldest,
+                4 => BackpressurePolicy::Sample(u32::try_from(entry.value).map_err(|_| format!("sampling rate {} is too large", entry.value))?),
+                5 => BackpressurePolicy::Error,
+                other => return Err(format!("unknown backpressure policy {}", other)),
+            };
+            backpressure.insert(c_str_to_rust_string(entry.pattern), policy);
+        }
+    }
+
+    let mut chunking = ChunkingConfig::default();
+    if let Some(chunks) = config.chunking.as_ref() {
+        if chunks.timeout_ms > 0 {
+            chunking.timeout_ms = chunks.timeout_ms;
+        }
+        if chunks.max_pending_bytes > 0 {
+            chunking.max_pending_bytes = chunks.max_pending_bytes;
+        }
+    }
+
+    let mut transactions = TransactionConfig::default();
+    if let Some(pending) = config.transactions.as_ref() {
+        if pending.timeout_ms > 0 {
+            transactions.timeout_ms = pending.timeout_ms;
+        }
+        if pending.max_pending_bytes > 0 {
+            transactions.max_pending_bytes = pending.max_pending_bytes;
+        }
+    }
+
+    Ok(Config { transport_type, ipc_config, tcp_config, log_config, backpressure, chunking, transactions })
+}
+
+unsafe fn log_config_from_c(log: &ZarkLogConfig) -> Result<LogConfig, String> {
+    if log.directory.is_null() {
+        return Err("log config without a directory".into());
+    }
+    Ok(LogConfig {
+        directory: PathBuf::from(c_str_to_rust_string(log.directory)),
+        topics: c_strings(log.topics, log.topic_count),
+        segment_bytes: if log.segment_bytes > 0 { log.segment_bytes } else { default_segment_bytes() },
+        retention_bytes: Some(log.retention_bytes).filter(|bytes| *bytes > 0),
+        retention_secs: Some(log.retention_secs).filter(|secs| *secs > 0),
+        fsync: match log.fsync {
+            0 => FsyncPolicy::Always,
+            1 => FsyncPolicy::IntervalMs(log.fsync_interval_ms),
+            2 => FsyncPolicy::Never,
+            other => return Err(format!("unknown fsync policy {}", other)),
+        },
+        compact_topics: c_strings(log.compact_topics, log.compact_topic_count),
+        tombstone_retention_secs: if log.tombstone_retention_secs > 0 {
+            log.tombstone_retention_secs
+        } else {
+            default_tombstone_retention_secs()
+        },
+    })
+}
+
+// copy an array of C strings, NULL for none
+unsafe fn c_strings(strings: *const *const c_char, count: usize) -> Vec<String> {
+    if strings.is_null() || count == 0 {
+        return Vec::new();
+    }
+    std::slice::from_raw_parts(strings, count).iter().map(|string| c_str_to_rust_string(*string)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn batches_go_through_the_c_structs_and_keep_their_subscriber() {
+        let name = CString::new("ffi").unwrap();
+        let ipc = ZarkIpcConfig { shared_memory_name: name.as_ptr(), max_message_size: 4096, max_queue_size: 100, max_buffer_size: 4096 };
+        let chunking = ZarkChunkingConfig { timeout_ms: 0, max_pending_bytes: 1024 };
+        let config = ZarkConfig {
+            transport_type: 0,
+            ipc_config: &ipc,
+            tcp_config: std::ptr::null(),
+            log_config: std::ptr::null(),
+            backpressure: std::ptr::null(),
+            backpressure_count: 0,
+            chunking: &chunking,
+            transactions: std::ptr::null(),
+        };
+        let parsed = unsafe { config_from_c(&config) }.unwrap();
+        assert_eq!(parsed.chunking.max_pending_bytes, 1024);
+        assert_eq!(parsed.chunking.timeout_ms, ChunkingConfig::default().timeout_ms);
+        let invalid = ZarkConfig { transport_type: 7, ..config };
+        assert!(unsafe { config_from_c(&invalid) }.is_err());
+
+        let messenger = unsafe { zark_messenger_init(&config) };
+        assert!(!messenger.is_null());
+        let topic = CString::new("t").unwrap();
+        let mut buffer = [0 as c_char; 16];
+        let mut lengths = [0usize; 4];
+        // the first call subscribes, what is sent after it is kept for the next one
+        let received = unsafe { zark_messenger_receive_batch(messenger, topic.as_ptr(), 4, 0, buffer.as_mut_ptr(), buffer.len(), lengths.as_mut_ptr()) };
+        assert_eq!(received, 0);
+
+        let payloads: [&[u8]; 2] = [b"ab", b"cde"];
+        let messages: Vec<ZarkMessage> = payloads
+            .iter()
+            .map(|payload| ZarkMessage { topic: topic.as_ptr(), payload: payload.as_ptr(), payload_length: payload.len() })
+            .collect();
+        assert!(unsafe { zark_messenger_send_batch(messenger, messages.as_ptr(), messages.len()) });
+
+        // a batch holds what arrived by the time the first message did
+        let mut received = Vec::new();
+        while received.len() < payloads.len() {
+            let count = unsafe { zark_messenger_receive_batch(messenger, topic.as_ptr(), 4, 1000, buffer.as_mut_ptr(), buffer.len(), lengths.as_mut_ptr()) };
+            assert!(count > 0);
+            let mut offset = 0;
+            for length in &lengths[..count as usize] {
+                received.push(buffer[offset..offset + length].iter().map(|byte| *byte as u8).collect::<Vec<_>>());
+                offset += length;
+            }
+        }
+        assert_eq!(received, payloads);
+        zark_messenger_cleanup(messenger);
+    }
+}
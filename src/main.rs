@@ -64,6 +64,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     id: format!("sender-{}-message-{}", i, j),
                     topic: "test_topic".to_string(),
                     payload: vec![i as u8, j as u8],
+                    ..Default::default()
                 };
 
                 ipc_transport.send(&message).await.unwrap();
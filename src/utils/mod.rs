@@ -24,3 +24,5 @@
 
 pub mod zark_uid;
 pub mod platform_specific;
+pub mod clock;
+pub mod metrics;